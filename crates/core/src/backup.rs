@@ -13,6 +13,48 @@ pub enum BackupSkipReason {
     Unchanged,
 }
 
+/// Result of an integrity check of a stored backup.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VerificationStatus {
+    Ok,
+    /// The backup directory or its payload is gone.
+    Missing,
+    /// The payload no longer matches the recorded content hash.
+    HashMismatch,
+    /// The payload cannot be reassembled or is not a valid SQLite database.
+    Corrupt,
+}
+
+impl VerificationStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VerificationStatus::Ok => "ok",
+            VerificationStatus::Missing => "missing",
+            VerificationStatus::HashMismatch => "hash_mismatch",
+            VerificationStatus::Corrupt => "corrupt",
+        }
+    }
+
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "ok" => Some(VerificationStatus::Ok),
+            "missing" => Some(VerificationStatus::Missing),
+            "hash_mismatch" => Some(VerificationStatus::HashMismatch),
+            "corrupt" => Some(VerificationStatus::Corrupt),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BackupVerification {
+    pub status: VerificationStatus,
+    pub checked_at: DateTime<Utc>,
+    /// What went wrong, for anything but [`VerificationStatus::Ok`].
+    pub detail: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BackupStats {
     pub total_cards: i64,
@@ -39,8 +81,130 @@ pub struct BackupEntry {
     pub skip_reason: Option<BackupSkipReason>,
     pub source_revision: Option<String>,
     pub sync_duration_ms: Option<i64>,
+    /// Logical size of the collection in bytes.
     pub size_bytes: i64,
+    /// Bytes the backup's payload occupies at rest (after compression),
+    /// counting chunks shared with other backups in full, so it is not what
+    /// deleting the backup would free. `None` for backups recorded before
+    /// this was tracked.
+    #[serde(default)]
+    pub stored_size_bytes: Option<i64>,
     pub stats: Option<BackupStats>,
+    /// Pinned backups are never removed by retention pruning.
+    #[serde(default)]
+    pub pinned: bool,
+    /// Free-text note, typically explaining why the backup is pinned.
+    #[serde(default)]
+    pub note: Option<String>,
+    /// Outcome of the most recent integrity check, if any.
+    #[serde(default)]
+    pub verification: Option<BackupVerification>,
+    /// Id of the media set (collection.media contents) captured with this
+    /// backup; `None` when media was not backed up.
+    #[serde(default)]
+    pub media_set: Option<String>,
+}
+
+/// A recorded rollback to a backup.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RollbackEvent {
+    pub id: Uuid,
+    pub backup_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    /// Who asked for the rollback: `ui`, `api`, or `api token` when the
+    /// request carried the API bearer token. `None` for rollbacks recorded
+    /// before this was tracked.
+    #[serde(default)]
+    pub actor: Option<String>,
+    /// `None` for rollbacks recorded before uploads were tracked.
+    #[serde(default)]
+    pub upload: Option<RollbackUpload>,
+    /// Check of the collection on the server after the rollback was
+    /// uploaded; `None` for rollbacks that weren't uploaded.
+    #[serde(default)]
+    pub verification: Option<RollbackVerification>,
+}
+
+/// What became of uploading a rollback to the sync server.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RollbackUpload {
+    /// The profile has no sync account; only the current pointer moved.
+    Local,
+    /// Not finished; left as is if the daemon stopped mid-upload.
+    Pending,
+    Uploaded,
+    Failed,
+}
+
+impl RollbackUpload {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RollbackUpload::Local => "local",
+            RollbackUpload::Pending => "pending",
+            RollbackUpload::Uploaded => "uploaded",
+            RollbackUpload::Failed => "failed",
+        }
+    }
+
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "local" => Some(RollbackUpload::Local),
+            "pending" => Some(RollbackUpload::Pending),
+            "uploaded" => Some(RollbackUpload::Uploaded),
+            "failed" => Some(RollbackUpload::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// A rollback event with the backup it rolled back to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RollbackHistoryEntry {
+    #[serde(flatten)]
+    pub event: RollbackEvent,
+    /// `None` once the backup has been pruned.
+    pub backup: Option<BackupEntry>,
+}
+
+/// Result of downloading the collection again after uploading a rollback.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadCheckStatus {
+    /// The server has the notes, cards, reviews, decks and note types that
+    /// were uploaded.
+    Ok,
+    /// The server's collection differs from the uploaded one.
+    Diverged,
+    /// The collection couldn't be downloaded to check it.
+    Unchecked,
+}
+
+impl UploadCheckStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UploadCheckStatus::Ok => "ok",
+            UploadCheckStatus::Diverged => "diverged",
+            UploadCheckStatus::Unchecked => "unchecked",
+        }
+    }
+
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "ok" => Some(UploadCheckStatus::Ok),
+            "diverged" => Some(UploadCheckStatus::Diverged),
+            "unchecked" => Some(UploadCheckStatus::Unchecked),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RollbackVerification {
+    pub status: UploadCheckStatus,
+    pub checked_at: DateTime<Utc>,
+    /// How the collections differ, or why the check failed.
+    pub detail: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -53,7 +217,9 @@ pub struct NewBackupEntry {
     pub source_revision: Option<String>,
     pub sync_duration_ms: Option<i64>,
     pub size_bytes: i64,
+    pub stored_size_bytes: Option<i64>,
     pub stats: Option<BackupStats>,
+    pub media_set: Option<String>,
 }
 
 impl NewBackupEntry {
@@ -75,7 +241,9 @@ impl NewBackupEntry {
             source_revision,
             sync_duration_ms,
             size_bytes,
+            stored_size_bytes: None,
             stats: Some(stats),
+            media_set: None,
         }
     }
 
@@ -89,7 +257,9 @@ impl NewBackupEntry {
             source_revision: None,
             sync_duration_ms: None,
             size_bytes: 0,
+            stored_size_bytes: None,
             stats: None,
+            media_set: None,
         }
     }
 }
@@ -0,0 +1,520 @@
+//! Note-level differences between two collection snapshots.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Characters of a note's first field shown to identify it.
+const PREVIEW_CHARS: usize = 80;
+
+/// The parts of a collection that are compared, keyed by id.
+#[derive(Debug, Clone, Default)]
+pub struct CollectionSnapshot {
+    pub notes: BTreeMap<i64, Note>,
+    pub cards: BTreeMap<i64, Card>,
+    /// Deck names, nested decks joined with `::`.
+    pub decks: BTreeMap<i64, String>,
+    pub note_types: BTreeMap<i64, NoteType>,
+    /// Card ids by review id, which is the review's time in milliseconds.
+    pub reviews: BTreeMap<i64, i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Note {
+    pub note_type_id: i64,
+    pub fields: Vec<String>,
+    /// Space-separated, as Anki stores them.
+    pub tags: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Card {
+    pub note_id: i64,
+    pub deck_id: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoteType {
+    pub name: String,
+    /// Field names in order.
+    pub fields: Vec<String>,
+}
+
+/// What changed from one snapshot to another.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CollectionDiff {
+    pub notes_added: Vec<NoteSummary>,
+    pub notes_removed: Vec<NoteSummary>,
+    pub notes_modified: Vec<NoteChange>,
+    pub cards_moved: Vec<CardMove>,
+    pub decks_added: Vec<NamedId>,
+    pub decks_removed: Vec<NamedId>,
+    pub note_types_added: Vec<NamedId>,
+    pub note_types_removed: Vec<NamedId>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NamedId {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NoteSummary {
+    pub id: i64,
+    pub note_type: String,
+    /// The first field as plain text, shortened.
+    pub preview: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NoteChange {
+    pub id: i64,
+    pub note_type: String,
+    pub preview: String,
+    /// The old note type's name, if the note type was changed.
+    pub previous_note_type: Option<String>,
+    /// Fields whose contents differ.
+    pub fields: Vec<FieldChange>,
+    /// Old and new tags, if they differ.
+    pub tags: Option<(String, String)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FieldChange {
+    pub name: String,
+    /// `None` if the field didn't exist on that side.
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CardMove {
+    pub card_id: i64,
+    pub note_id: i64,
+    pub from: NamedId,
+    pub to: NamedId,
+}
+
+impl CollectionDiff {
+    /// Compare `old` with `new`.
+    pub fn between(old: &CollectionSnapshot, new: &CollectionSnapshot) -> Self {
+        let mut diff = Self::default();
+
+        for (&id, note) in &new.notes {
+            match old.notes.get(&id) {
+                None => diff.notes_added.push(summary(new, id, note)),
+                Some(before) if before != note => {
+                    let change = note_change(old, new, id, before, note);
+                    // Tags differing only in surrounding spaces aren't a change.
+                    if !change.fields.is_empty()
+                        || change.tags.is_some()
+                        || change.previous_note_type.is_some()
+                    {
+                        diff.notes_modified.push(change);
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+        for (&id, note) in &old.notes {
+            if !new.notes.contains_key(&id) {
+                diff.notes_removed.push(summary(old, id, note));
+            }
+        }
+
+        for (&id, card) in &new.cards {
+            if let Some(before) = old.cards.get(&id) {
+                if before.deck_id != card.deck_id {
+                    diff.cards_moved.push(CardMove {
+                        card_id: id,
+                        note_id: card.note_id,
+                        from: deck(old, before.deck_id),
+                        to: deck(new, card.deck_id),
+                    });
+                }
+            }
+        }
+
+        let named = |ids: &BTreeMap<i64, String>, other: &BTreeMap<i64, String>| {
+            ids.iter()
+                .filter(|(id, _)| !other.contains_key(id))
+                .map(|(&id, name)| NamedId {
+                    id,
+                    name: name.clone(),
+                })
+                .collect::<Vec<_>>()
+        };
+        diff.decks_added = named(&new.decks, &old.decks);
+        diff.decks_removed = named(&old.decks, &new.decks);
+        let type_names = |s: &CollectionSnapshot| {
+            s.note_types
+                .iter()
+                .map(|(&id, t)| (id, t.name.clone()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        diff.note_types_added = named(&type_names(new), &type_names(old));
+        diff.note_types_removed = named(&type_names(old), &type_names(new));
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// What rolling back from the current collection to an older one would undo.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RollbackPreview {
+    /// Changes from the current collection to the one rolled back to.
+    pub diff: CollectionDiff,
+    /// Reviews only the current collection has, newest first.
+    pub lost_reviews: Vec<LostReview>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LostReview {
+    pub reviewed_at: DateTime<Utc>,
+    pub card_id: i64,
+    /// The reviewed card's note, if the current collection still has it.
+    pub note: Option<NoteSummary>,
+}
+
+impl RollbackPreview {
+    /// Compare the `current` collection with the `target` of a rollback.
+    pub fn between(current: &CollectionSnapshot, target: &CollectionSnapshot) -> Self {
+        let lost_reviews = current
+            .reviews
+            .iter()
+            .rev()
+            .filter(|(id, _)| !target.reviews.contains_key(id))
+            .map(|(&id, &card_id)| LostReview {
+                reviewed_at: DateTime::from_timestamp_millis(id).unwrap_or_default(),
+                card_id,
+                note: current.cards.get(&card_id).and_then(|card| {
+                    let note = current.notes.get(&card.note_id)?;
+                    Some(summary(current, card.note_id, note))
+                }),
+            })
+            .collect();
+        Self {
+            diff: CollectionDiff::between(current, target),
+            lost_reviews,
+        }
+    }
+}
+
+/// How the `server`'s collection differs from the `uploaded` one it should
+/// now hold, comparing which notes, cards, reviews, decks and note types
+/// exist. Contents aren't compared: the server rewrites modification times
+/// and sync counters, so the bytes never match.
+pub fn upload_divergences(
+    uploaded: &CollectionSnapshot,
+    server: &CollectionSnapshot,
+) -> Vec<String> {
+    fn compare<V>(
+        what: &str,
+        uploaded: &BTreeMap<i64, V>,
+        server: &BTreeMap<i64, V>,
+        out: &mut Vec<String>,
+    ) {
+        if uploaded.len() != server.len() {
+            out.push(format!(
+                "{what}: {} uploaded, {} on the server",
+                uploaded.len(),
+                server.len()
+            ));
+        } else if !uploaded.keys().eq(server.keys()) {
+            out.push(format!(
+                "{what}: {} uploaded and on the server, with different ids",
+                uploaded.len()
+            ));
+        }
+    }
+
+    let mut out = Vec::new();
+    compare("notes", &uploaded.notes, &server.notes, &mut out);
+    compare("cards", &uploaded.cards, &server.cards, &mut out);
+    compare("reviews", &uploaded.reviews, &server.reviews, &mut out);
+    compare(
+        "note types",
+        &uploaded.note_types,
+        &server.note_types,
+        &mut out,
+    );
+    compare("decks", &uploaded.decks, &server.decks, &mut out);
+    if out.is_empty() && uploaded.decks != server.decks {
+        out.push("decks: named differently on the server".to_owned());
+    }
+    out
+}
+
+fn note_type_name(snapshot: &CollectionSnapshot, id: i64) -> String {
+    snapshot
+        .note_types
+        .get(&id)
+        .map(|t| t.name.clone())
+        .unwrap_or_else(|| format!("Note type {id}"))
+}
+
+fn deck(snapshot: &CollectionSnapshot, id: i64) -> NamedId {
+    NamedId {
+        id,
+        name: snapshot
+            .decks
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| format!("Deck {id}")),
+    }
+}
+
+fn summary(snapshot: &CollectionSnapshot, id: i64, note: &Note) -> NoteSummary {
+    NoteSummary {
+        id,
+        note_type: note_type_name(snapshot, note.note_type_id),
+        preview: preview(note),
+    }
+}
+
+fn note_change(
+    old: &CollectionSnapshot,
+    new: &CollectionSnapshot,
+    id: i64,
+    before: &Note,
+    after: &Note,
+) -> NoteChange {
+    let field_names = new
+        .note_types
+        .get(&after.note_type_id)
+        .map(|t| t.fields.as_slice())
+        .unwrap_or_default();
+    let fields = (0..before.fields.len().max(after.fields.len()))
+        .filter(|&i| before.fields.get(i) != after.fields.get(i))
+        .map(|i| FieldChange {
+            name: field_names
+                .get(i)
+                .cloned()
+                .unwrap_or_else(|| format!("Field {}", i + 1)),
+            before: before.fields.get(i).cloned(),
+            after: after.fields.get(i).cloned(),
+        })
+        .collect();
+    NoteChange {
+        id,
+        note_type: note_type_name(new, after.note_type_id),
+        preview: preview(after),
+        previous_note_type: (before.note_type_id != after.note_type_id)
+            .then(|| note_type_name(old, before.note_type_id)),
+        fields,
+        tags: (before.tags.trim() != after.tags.trim())
+            .then(|| (before.tags.trim().to_owned(), after.tags.trim().to_owned())),
+    }
+}
+
+/// The first field with HTML tags dropped and whitespace collapsed.
+fn preview(note: &Note) -> String {
+    let first = note.fields.first().map(String::as_str).unwrap_or_default();
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in first.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.char_indices().nth(PREVIEW_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(note_type_id: i64, fields: &[&str], tags: &str) -> Note {
+        Note {
+            note_type_id,
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+            tags: tags.to_owned(),
+        }
+    }
+
+    fn snapshot(
+        notes: &[(i64, Note)],
+        cards: &[(i64, i64, i64)],
+        decks: &[(i64, &str)],
+    ) -> CollectionSnapshot {
+        CollectionSnapshot {
+            notes: notes.iter().cloned().collect(),
+            cards: cards
+                .iter()
+                .map(|&(id, note_id, deck_id)| (id, Card { note_id, deck_id }))
+                .collect(),
+            decks: decks.iter().map(|&(id, n)| (id, n.to_owned())).collect(),
+            note_types: [
+                (
+                    100,
+                    NoteType {
+                        name: "Basic".to_owned(),
+                        fields: vec!["Front".to_owned(), "Back".to_owned()],
+                    },
+                ),
+                (
+                    200,
+                    NoteType {
+                        name: "Cloze".to_owned(),
+                        fields: vec!["Text".to_owned()],
+                    },
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            reviews: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn reports_note_card_and_deck_changes() {
+        let old = snapshot(
+            &[
+                (1, note(100, &["<b>hola</b>", "hello"], "spanish ")),
+                (2, note(100, &["adiós", "bye"], "")),
+                (3, note(100, &["gato", "cat"], "")),
+            ],
+            &[(10, 1, 1), (20, 2, 1), (30, 3, 1)],
+            &[(1, "Default"), (5, "Old")],
+        );
+        let mut new = snapshot(
+            &[
+                (1, note(100, &["<b>hola</b>", "hi"], "spanish greeting")),
+                (3, note(200, &["gato"], "")),
+                (4, note(200, &["{{c1::perro}}"], "")),
+            ],
+            &[(10, 1, 7), (30, 3, 1), (40, 4, 7)],
+            &[(1, "Default"), (7, "Spanish::Basics")],
+        );
+        new.note_types.remove(&100);
+
+        let diff = CollectionDiff::between(&old, &new);
+        assert_eq!(
+            diff.notes_added,
+            [NoteSummary {
+                id: 4,
+                note_type: "Cloze".to_owned(),
+                preview: "{{c1::perro}}".to_owned(),
+            }]
+        );
+        assert_eq!(diff.notes_removed[0].id, 2);
+        assert_eq!(diff.notes_removed[0].note_type, "Basic");
+
+        let hola = &diff.notes_modified[0];
+        assert_eq!(hola.preview, "hola");
+        assert_eq!(hola.note_type, "Note type 100");
+        assert_eq!(
+            hola.fields,
+            [FieldChange {
+                name: "Field 2".to_owned(),
+                before: Some("hello".to_owned()),
+                after: Some("hi".to_owned()),
+            }]
+        );
+        assert_eq!(
+            hola.tags,
+            Some(("spanish".to_owned(), "spanish greeting".to_owned()))
+        );
+        let gato = &diff.notes_modified[1];
+        assert_eq!(gato.previous_note_type.as_deref(), Some("Basic"));
+        assert_eq!(gato.fields[0].name, "Field 2");
+        assert_eq!(gato.fields[0].after, None);
+
+        assert_eq!(diff.cards_moved.len(), 1);
+        assert_eq!(diff.cards_moved[0].from.name, "Default");
+        assert_eq!(diff.cards_moved[0].to.name, "Spanish::Basics");
+        assert_eq!(diff.decks_added[0].name, "Spanish::Basics");
+        assert_eq!(diff.decks_removed[0].name, "Old");
+        assert!(diff.note_types_added.is_empty());
+        assert_eq!(diff.note_types_removed[0].name, "Basic");
+
+        assert!(CollectionDiff::between(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn rollback_previews_list_reviews_made_since() {
+        let mut old = snapshot(
+            &[(1, note(100, &["hola", "hello"], ""))],
+            &[(10, 1, 1)],
+            &[(1, "Default")],
+        );
+        old.reviews.insert(1_700_000_000_000, 10);
+        let mut new = old.clone();
+        new.notes.insert(2, note(100, &["gato", "cat"], ""));
+        new.reviews.insert(1_700_000_060_000, 10);
+        new.reviews.insert(1_700_000_120_000, 20);
+
+        let preview = RollbackPreview::between(&new, &old);
+        assert_eq!(preview.diff.notes_removed[0].preview, "gato");
+        let lost: Vec<_> = preview.lost_reviews.iter().map(|r| r.card_id).collect();
+        assert_eq!(lost, [20, 10]);
+        assert_eq!(
+            preview.lost_reviews[1].reviewed_at.timestamp(),
+            1_700_000_060
+        );
+        assert_eq!(preview.lost_reviews[1].note.as_ref().unwrap().id, 1);
+        assert_eq!(preview.lost_reviews[0].note, None);
+        assert!(RollbackPreview::between(&old, &new).lost_reviews.is_empty());
+    }
+
+    #[test]
+    fn upload_divergences_compare_what_exists() {
+        let uploaded = snapshot(
+            &[(1, note(100, &["hola", "hello"], ""))],
+            &[(10, 1, 1)],
+            &[(1, "Default")],
+        );
+        let mut server = uploaded.clone();
+        // Field edits aren't structure.
+        server.notes.insert(1, note(100, &["hola", "hi"], ""));
+        assert!(upload_divergences(&uploaded, &server).is_empty());
+
+        server.notes.insert(2, note(100, &["gato", "cat"], ""));
+        server.cards.remove(&10);
+        server.cards.insert(
+            11,
+            Card {
+                note_id: 1,
+                deck_id: 1,
+            },
+        );
+        server.decks.insert(1, "Renamed".to_owned());
+        assert_eq!(
+            upload_divergences(&uploaded, &server),
+            [
+                "notes: 1 uploaded, 2 on the server",
+                "cards: 1 uploaded and on the server, with different ids",
+            ]
+        );
+        server = uploaded.clone();
+        server.decks.insert(1, "Renamed".to_owned());
+        assert_eq!(
+            upload_divergences(&uploaded, &server),
+            ["decks: named differently on the server"]
+        );
+    }
+
+    #[test]
+    fn previews_are_plain_and_short() {
+        let long = format!("<div>{}</div>", "word ".repeat(40));
+        let preview = preview(&note(100, &[&long], ""));
+        assert_eq!(preview.chars().count(), PREVIEW_CHARS + 1);
+        assert!(preview.starts_with("word word") && preview.ends_with('…'));
+        assert_eq!(super::preview(&note(100, &["a<br>b"], "")), "a b");
+    }
+}
@@ -1,7 +1,14 @@
 pub mod backup;
+pub mod diff;
 pub mod hash;
 
 pub use backup::{
-    BackupEntry, BackupSkipReason, BackupStats, BackupStatus, DeckStats, NewBackupEntry,
+    BackupEntry, BackupSkipReason, BackupStats, BackupStatus, BackupVerification, DeckStats,
+    NewBackupEntry, RollbackEvent, RollbackHistoryEntry, RollbackUpload, RollbackVerification,
+    UploadCheckStatus, VerificationStatus,
+};
+pub use diff::{
+    upload_divergences, Card, CardMove, CollectionDiff, CollectionSnapshot, FieldChange,
+    LostReview, NamedId, Note, NoteChange, NoteSummary, NoteType, RollbackPreview,
 };
 pub use hash::content_hash;
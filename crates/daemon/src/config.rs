@@ -9,7 +9,11 @@ pub struct Config {
     pub server: ServerConfig,
     pub storage: StorageConfig,
     pub ankiweb: AnkiwebConfig,
+    pub source: SourceConfig,
     pub security: SecurityConfig,
+    /// Named AnkiWeb accounts to back up; when empty, `[ankiweb]` is the
+    /// single `default` profile.
+    pub profiles: Vec<ProfileConfig>,
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -22,8 +26,54 @@ pub struct ServerConfig {
 #[serde(default)]
 pub struct StorageConfig {
     pub root: Option<String>,
+    /// Legacy flat retention: delete every backup older than this many days.
+    /// Only used when no `[storage.retention]` policy is configured.
     pub retention_days: Option<i64>,
+    pub retention: Option<RetentionConfig>,
     pub database_url: Option<String>,
+    /// zstd level for stored backup chunks; `0` stores them uncompressed.
+    pub compression_level: Option<i32>,
+    /// Store backup payloads in S3-compatible object storage instead of under `root`.
+    pub s3: Option<S3StorageConfig>,
+    /// Hours between scheduled integrity scrubs; `0` disables them.
+    pub verify_interval_hours: Option<u64>,
+}
+
+/// Grandfather-father-son retention; unset fields use the storage defaults.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct RetentionConfig {
+    pub keep_all_hours: Option<i64>,
+    pub daily_days: Option<i64>,
+    pub weekly_weeks: Option<i64>,
+    pub monthly_months: Option<i64>,
+    /// Only report which backups would be removed.
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct S3StorageConfig {
+    pub endpoint: Option<String>,
+    pub bucket: Option<String>,
+    pub region: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    /// Key prefix inside the bucket.
+    pub prefix: Option<String>,
+}
+
+/// Where backups come from.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct SourceConfig {
+    /// `ankiweb` (the default, also used for self-hosted sync servers) or
+    /// `local`.
+    pub kind: Option<String>,
+    /// Anki profile directory holding `collection.anki2`, for `local`.
+    pub profile_dir: Option<String>,
+    /// Also back up the local `collection.media` folder; defaults to true.
+    pub media: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -32,13 +82,48 @@ pub struct AnkiwebConfig {
     pub username: Option<String>,
     pub password: Option<String>,
     pub endpoint: Option<String>,
+    /// Host key from an earlier login (see the `host-key` command); lets the
+    /// password be left out of the config.
+    pub host_key: Option<String>,
+    /// Also back up `collection.media`; defaults to true.
+    pub sync_media: Option<bool>,
+    /// Seconds to keep retrying a transiently failing sync request;
+    /// `0` disables retries.
+    pub retry_budget_secs: Option<u64>,
+    /// Anki version to identify as (e.g. `2.1.66`), for sync servers that
+    /// only accept particular clients.
+    pub client_version: Option<String>,
+    /// Pin the sync protocol version instead of negotiating it.
+    pub sync_version: Option<u8>,
+}
+
+/// One `[[profiles]]` entry.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct ProfileConfig {
+    /// Used in URLs and storage keys: letters, digits, `-` and `_`.
+    pub name: String,
+    pub ankiweb: AnkiwebConfig,
+    pub source: SourceConfig,
+    /// Hours between scheduled backups; defaults to 1.
+    pub interval_hours: Option<u64>,
+    /// Retention for this profile; falls back to the `[storage]` policy.
+    pub retention_days: Option<i64>,
+    pub retention: Option<RetentionConfig>,
 }
 
 #[derive(Debug, Deserialize, Default)]
 #[serde(default)]
 pub struct SecurityConfig {
     pub api_token: Option<String>,
+    /// Recorded as the actor of rollbacks made with `api_token`.
+    pub api_token_name: Option<String>,
     pub csrf_token: Option<String>,
+    /// File holding the backup encryption key (32 raw bytes or 64 hex chars).
+    pub encryption_key_file: Option<String>,
+    /// Passphrase to derive the backup encryption key from.
+    /// Ignored when `encryption_key_file` is set.
+    pub encryption_passphrase: Option<String>,
 }
 
 pub fn load_config(path: &Path) -> Result<Config> {
@@ -0,0 +1,103 @@
+//! Exporting a backup for download or restoring on another device.
+
+use std::io::Cursor;
+use std::str::FromStr;
+
+use anki_backup_core::BackupEntry;
+use anki_backup_storage::BackupRepository;
+use anyhow::{bail, Context, Result};
+
+/// How a backup is packaged for download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    /// A zstd tarball holding the bare `collection.anki2`.
+    #[default]
+    TarZst,
+    /// An Anki collection package with media, importable as-is.
+    Colpkg,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::TarZst => "tar.zst",
+            Self::Colpkg => "colpkg",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::TarZst => "application/zstd",
+            Self::Colpkg => "application/octet-stream",
+        }
+    }
+
+    /// Download file name for `backup`.
+    pub fn file_name(self, backup: &BackupEntry) -> String {
+        format!("backup-{}.{}", backup.id, self.extension())
+    }
+}
+
+/// Download file name for one deck of `backup`.
+pub fn deck_file_name(backup: &BackupEntry, deck_id: i64) -> String {
+    format!("backup-{}-deck-{deck_id}.apkg", backup.id)
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tar.zst" => Ok(Self::TarZst),
+            "colpkg" => Ok(Self::Colpkg),
+            other => bail!("unknown export format {other:?}; expected \"tar.zst\" or \"colpkg\""),
+        }
+    }
+}
+
+/// Package a created backup in `format`.
+pub async fn export_backup(
+    repo: &BackupRepository,
+    backup: &BackupEntry,
+    format: ExportFormat,
+) -> Result<Vec<u8>> {
+    match format {
+        ExportFormat::TarZst => {
+            let bytes = repo.read_backup(backup).await?;
+            tar_zst(bytes).with_context(|| format!("package backup {}", backup.id))
+        }
+        ExportFormat::Colpkg => repo.export_package(backup).await,
+    }
+}
+
+fn tar_zst(collection: Vec<u8>) -> Result<Vec<u8>> {
+    let mut tar_data = Vec::new();
+    {
+        let mut builder = tar::Builder::new(&mut tar_data);
+        let mut hdr = tar::Header::new_gnu();
+        hdr.set_size(collection.len() as u64);
+        hdr.set_mode(0o644);
+        hdr.set_cksum();
+        builder.append_data(&mut hdr, "collection.anki2", Cursor::new(collection))?;
+        builder.finish()?;
+    }
+    Ok(zstd::encode_all(Cursor::new(&tar_data), 3)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_formats() {
+        assert_eq!(
+            "colpkg".parse::<ExportFormat>().unwrap(),
+            ExportFormat::Colpkg
+        );
+        assert_eq!(
+            "tar.zst".parse::<ExportFormat>().unwrap(),
+            ExportFormat::default()
+        );
+        assert!("zip".parse::<ExportFormat>().is_err());
+    }
+}
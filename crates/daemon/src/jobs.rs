@@ -0,0 +1,388 @@
+//! Sync jobs shared by the CLI commands, the scheduler and the API.
+
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+
+use anki_backup_core::{content_hash, BackupEntry, RollbackVerification, UploadCheckStatus};
+use anki_backup_storage::{
+    BackupPayload, BackupRepository, MediaSet, MergeReport, RestoreSelection, RunOnceOutcome,
+};
+use anki_backup_sync::{
+    fetch_host_key, sync_collection, upload_collection, CollectionSource, LocalMediaFile,
+    LocalProfile, MediaSyncClient, SyncConfig, SyncError,
+};
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use tracing::{error, info, warn};
+
+/// Files downloaded (and held in memory) before they are written to the
+/// chunk store.
+const MEDIA_BATCH_SIZE: usize = 100;
+
+/// Fetch the collection (and optionally media) from a source and record a
+/// backup.
+#[derive(Debug, Clone)]
+pub struct BackupJob {
+    source: CollectionSource,
+    media: bool,
+}
+
+impl BackupJob {
+    pub fn new(source: impl Into<CollectionSource>) -> Self {
+        Self {
+            source: source.into(),
+            media: true,
+        }
+    }
+
+    /// Whether to also back up `collection.media`.
+    pub fn with_media(mut self, media: bool) -> Self {
+        self.media = media;
+        self
+    }
+
+    /// Run one backup. A failed media sync is logged and the collection is
+    /// still backed up, without media.
+    pub async fn run(&self, repo: &BackupRepository) -> Result<RunOnceOutcome> {
+        // Media is stored before the backup that refers to it.
+        let _writing = repo.begin_write().await;
+        let (sync, media_set) = match &self.source {
+            CollectionSource::Sync(config) => {
+                let sync = with_host_key(
+                    repo,
+                    config,
+                    |cfg| async move { sync_collection(&cfg).await },
+                )
+                .await?;
+                let media = self.media.then(|| {
+                    with_host_key(
+                        repo,
+                        config,
+                        |cfg| async move { sync_media(repo, &cfg).await },
+                    )
+                });
+                (sync, media_or_none(media).await)
+            }
+            CollectionSource::Local(profile) => {
+                let sync = profile.snapshot_collection().await?;
+                let media = self.media.then(|| snapshot_local_media(repo, profile));
+                (sync, media_or_none(media).await)
+            }
+        };
+
+        let hash = content_hash(&sync.collection_bytes);
+        let payload = BackupPayload {
+            bytes: sync.collection_bytes,
+            source_revision: sync.source_revision,
+            sync_duration_ms: Some(sync.sync_duration_ms),
+            media_set,
+        };
+        repo.run_once(payload, hash).await
+    }
+}
+
+/// Back up the collection as it is on the server now (without media),
+/// returning its backup, before changing what is on the server.
+///
+/// When it matches the newest backup, no backup is added and that one is
+/// returned.
+pub async fn snapshot_server(repo: &BackupRepository, sync: &SyncConfig) -> Result<BackupEntry> {
+    match BackupJob::new(sync.clone())
+        .with_media(false)
+        .run(repo)
+        .await?
+    {
+        RunOnceOutcome::Created(entry) => Ok(entry),
+        RunOnceOutcome::Skipped(_) => repo
+            .latest_created()
+            .await?
+            .context("no backup of the current collection"),
+    }
+}
+
+/// Download the collection again after uploading `backup` and check that the
+/// server has what was sent.
+///
+/// A failed download or comparison is reported as
+/// [`UploadCheckStatus::Unchecked`] rather than an error: the upload itself
+/// went through.
+pub async fn check_upload(
+    repo: &BackupRepository,
+    sync: &SyncConfig,
+    backup: &BackupEntry,
+) -> RollbackVerification {
+    let divergences = async {
+        let downloaded =
+            with_host_key(repo, sync, |cfg| async move { sync_collection(&cfg).await }).await?;
+        repo.check_upload(backup, &downloaded.collection_bytes)
+            .await
+    }
+    .await;
+    let (status, detail) = match divergences {
+        Ok(divergences) if divergences.is_empty() => (UploadCheckStatus::Ok, None),
+        Ok(divergences) => {
+            let detail = divergences.join("; ");
+            error!(backup_id = %backup.id, %detail, "uploaded collection differs on the server");
+            (UploadCheckStatus::Diverged, Some(detail))
+        }
+        Err(e) => {
+            let detail = format!("{e:#}");
+            warn!(backup_id = %backup.id, error = %detail, "could not check uploaded collection");
+            (UploadCheckStatus::Unchecked, Some(detail))
+        }
+    };
+    RollbackVerification {
+        status,
+        checked_at: Utc::now(),
+        detail,
+    }
+}
+
+/// The result of [`merge_restore`].
+#[derive(Debug, Clone)]
+pub struct RestoreOutcome {
+    /// The backup of the collection as it was before the restore.
+    pub snapshot: BackupEntry,
+    pub report: MergeReport,
+}
+
+/// Graft `selection` from `backup` into the collection on the server and
+/// upload the result.
+///
+/// The server's collection is backed up first with [`snapshot_server`], so
+/// the merge starts from the latest state and the restore can itself be
+/// undone.
+pub async fn merge_restore(
+    repo: &BackupRepository,
+    sync: &SyncConfig,
+    backup: &BackupEntry,
+    selection: &RestoreSelection,
+) -> Result<RestoreOutcome> {
+    let snapshot = snapshot_server(repo, sync).await?;
+    let current = repo.read_backup(&snapshot).await?;
+    let (merged, report) = repo.merge_restore(backup, &current, selection).await?;
+
+    let bytes = &merged;
+    with_host_key(repo, sync, |cfg| async move {
+        upload_collection(&cfg, bytes).await
+    })
+    .await?;
+    info!(
+        backup_id = %backup.id,
+        snapshot_id = %snapshot.id,
+        notes_added = report.notes_added,
+        notes_reverted = report.notes_reverted,
+        cards_added = report.cards_added,
+        "uploaded merge-restored collection"
+    );
+    Ok(RestoreOutcome { snapshot, report })
+}
+
+/// The media set id, or `None` when media is off or failed to back up.
+async fn media_or_none(media: Option<impl Future<Output = Result<String>>>) -> Option<String> {
+    match media?.await {
+        Ok(id) => Some(id),
+        Err(e) => {
+            error!(error = %format!("{e:#}"), "media backup failed; backing up collection only");
+            None
+        }
+    }
+}
+
+/// Run a sync operation with a reused host key where possible.
+///
+/// The configured `host_key` is tried first, then the key cached in the
+/// repository; a cached key the server rejects is removed from the cache.
+/// Without either, or when the server rejects them and a password is
+/// available, this logs in once, caches the new key (encrypted) and runs `op`
+/// again with it.
+pub async fn with_host_key<T, F, Fut>(
+    repo: &BackupRepository,
+    sync: &SyncConfig,
+    op: F,
+) -> Result<T>
+where
+    F: Fn(SyncConfig) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let can_login = !sync.username.is_empty() && !sync.password.is_empty();
+    let cached = match repo.cached_host_key(&sync.username, &sync.password).await {
+        Ok(key) => key,
+        Err(e) => {
+            warn!(error = %format!("{e:#}"), "failed to read host key cache");
+            None
+        }
+    };
+    let mut candidates: Vec<(String, bool)> = sync
+        .host_key
+        .iter()
+        .map(|key| (key.clone(), false))
+        .collect();
+    if let Some(key) = cached.filter(|key| sync.host_key.as_ref() != Some(key)) {
+        candidates.push((key, true));
+    }
+    let mut rejected = None;
+    for (host_key, from_cache) in candidates {
+        let result = op(SyncConfig {
+            host_key: Some(host_key),
+            ..sync.clone()
+        })
+        .await;
+        if !matches!(
+            result.as_ref().err().and_then(SyncError::classify),
+            Some(SyncError::Auth(_))
+        ) {
+            return result;
+        }
+        if from_cache {
+            info!("cached host key rejected; clearing the cache");
+            if let Err(e) = repo.clear_host_key().await {
+                warn!(error = %format!("{e:#}"), "failed to clear host key cache");
+            }
+        } else {
+            warn!("configured host key rejected");
+        }
+        rejected = Some(result);
+    }
+    if let Some(result) = rejected {
+        if !can_login {
+            return result;
+        }
+        info!("host key rejected; logging in again");
+    }
+
+    let host_key = fetch_host_key(sync).await?;
+    if let Err(e) = repo
+        .store_host_key(&sync.username, &sync.password, &host_key)
+        .await
+    {
+        warn!(error = %format!("{e:#}"), "failed to cache host key");
+    }
+    op(SyncConfig {
+        host_key: Some(host_key),
+        ..sync.clone()
+    })
+    .await
+}
+
+/// Bring the newest stored media set up to date with the server and store
+/// the result, returning its id.
+///
+/// Only files changed since the stored set's USN are downloaded; unchanged
+/// files keep pointing at chunks already in the repository.
+pub async fn sync_media(repo: &BackupRepository, config: &SyncConfig) -> Result<String> {
+    let client = MediaSyncClient::connect(config).await?;
+    let mut set = repo.latest_media_set().await?;
+    if client.server_usn() < set.usn {
+        // The server's media was reset (e.g. a full upload from another
+        // device), so our USN no longer means anything.
+        warn!(
+            local_usn = set.usn,
+            server_usn = client.server_usn(),
+            "server media usn went backwards; resyncing all media"
+        );
+        set = MediaSet::default();
+    }
+
+    // Later changes to the same file supersede earlier ones.
+    let latest: BTreeMap<String, Option<String>> = client
+        .changes_since(set.usn)
+        .await?
+        .into_iter()
+        .map(|c| (c.name, c.sha1))
+        .collect();
+    let mut wanted = HashMap::new();
+    let mut removed = 0;
+    for (name, sha1) in latest {
+        match sha1 {
+            None => removed += usize::from(set.files.remove(&name).is_some()),
+            Some(sha1) if set.files.get(&name).is_some_and(|f| f.sha1 == sha1) => {}
+            Some(sha1) => {
+                wanted.insert(name, sha1);
+            }
+        }
+    }
+
+    let names: Vec<String> = wanted.keys().cloned().collect();
+    for batch in names.chunks(MEDIA_BATCH_SIZE) {
+        for (name, bytes) in client.download_files(batch).await? {
+            let Some(expected) = wanted.get(&name) else {
+                continue;
+            };
+            let file = repo.put_media_file(&bytes).await?;
+            if &file.sha1 != expected {
+                bail!(
+                    "media file {name} has sha1 {}, server listed {expected}",
+                    file.sha1
+                );
+            }
+            set.files.insert(name, file);
+        }
+    }
+
+    set.usn = client.server_usn();
+    let id = repo.write_media_set(&set).await?;
+    info!(
+        media_set = %id,
+        usn = set.usn,
+        files = set.files.len(),
+        downloaded = wanted.len(),
+        removed,
+        "media synced"
+    );
+    Ok(id)
+}
+
+/// Store the current contents of a local profile's `collection.media`,
+/// returning the media set id.
+///
+/// Every file is hashed, but only files whose SHA-1 differs from the newest
+/// stored set are written to the chunk store.
+pub async fn snapshot_local_media(
+    repo: &BackupRepository,
+    profile: &LocalProfile,
+) -> Result<String> {
+    let previous = repo.latest_media_set().await?;
+    let known: Vec<LocalMediaFile> = previous
+        .files
+        .iter()
+        .filter_map(|(name, file)| {
+            Some(LocalMediaFile {
+                name: name.clone(),
+                sha1: file.sha1.clone(),
+                size: file.size,
+                mtime_ns: file.mtime_ns?,
+            })
+        })
+        .collect();
+    let mut set = MediaSet::default();
+    let mut stored = 0;
+    for file in profile.media_files(&known).await? {
+        let mut entry = match previous.files.get(&file.name) {
+            Some(known) if known.sha1 == file.sha1 => known.clone(),
+            _ => {
+                // The file may have changed since it was hashed; store what
+                // is there now.
+                let bytes = profile.read_media_file(&file.name).await?;
+                stored += 1;
+                repo.put_media_file(&bytes).await?
+            }
+        };
+        entry.mtime_ns = Some(file.mtime_ns);
+        set.files.insert(file.name, entry);
+    }
+
+    let id = repo.write_media_set(&set).await?;
+    info!(
+        media_set = %id,
+        files = set.files.len(),
+        stored,
+        removed = previous
+            .files
+            .keys()
+            .filter(|name| !set.files.contains_key(*name))
+            .count(),
+        "local media snapshotted"
+    );
+    Ok(id)
+}
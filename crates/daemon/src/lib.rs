@@ -1,4 +1,8 @@
 pub mod config;
+pub mod export;
+pub mod jobs;
+pub mod profiles;
+pub mod scheduler;
 mod server;
 
-pub use server::{build_router, AppState};
+pub use server::{build_router, AppState, ProfileState};
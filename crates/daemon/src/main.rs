@@ -1,24 +1,49 @@
 use std::env;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use anki_backup_core::content_hash;
+use anki_backup_core::BackupStatus;
 use anki_backup_daemon::config::{self, Config};
-use anki_backup_daemon::{build_router, AppState};
-use anki_backup_storage::{BackupPayload, BackupRepository, RunOnceOutcome};
-use anki_backup_sync::{sync_collection, SyncConfig};
+use anki_backup_daemon::export::{deck_file_name, export_backup, ExportFormat};
+use anki_backup_daemon::jobs::{merge_restore, BackupJob};
+use anki_backup_daemon::profiles::{resolve_profiles, Profile};
+use anki_backup_daemon::scheduler::{apply_retention, scheduler_loop, Retention};
+use anki_backup_daemon::{build_router, AppState, ProfileState};
+use anki_backup_storage::{
+    open_metadata_store, BackupRepository, ImportOutcome, KeySource, RestoreSelection,
+    RunOnceOutcome, S3BlobStore, S3Config, VerifyReport, DEFAULT_COMPRESSION_LEVEL,
+};
+use anki_backup_sync::{fetch_host_key, SyncConfig};
 use anyhow::{bail, Context, Result};
-use chrono::{Timelike, Utc};
-use tokio::sync::Mutex;
+use chrono::{DateTime, Utc};
 use tokio::time::{sleep, Duration};
 use tracing::{error, info, Level};
+use uuid::Uuid;
+
+/// Subcommands that act on each selected profile's backups.
+const PROFILE_COMMANDS: &[&str] = &[
+    "run-once",
+    "migrate-storage",
+    "rotate-key",
+    "prune",
+    "verify",
+    "reindex",
+];
+
+/// Profile commands that change state every profile shares (the encryption
+/// key): they always cover all profiles and stop at the first failure.
+const ALL_PROFILES_COMMANDS: &[&str] = &["rotate-key"];
+
+/// Subcommands that act on one profile, picked with `--profile` when several
+/// are configured.
+const SINGLE_PROFILE_COMMANDS: &[&str] = &["host-key", "import", "export", "diff", "restore"];
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt().with_max_level(Level::INFO).init();
 
-    let (cfg, mode) = parse_args()?;
+    let (cfg, mode, args) = parse_args()?;
 
     let root = env::var("ANKI_BACKUP_ROOT")
         .ok()
@@ -34,19 +59,123 @@ async fn main() -> Result<()> {
         .ok()
         .or_else(|| cfg.storage.database_url.clone());
 
-    let repo = BackupRepository::init(PathBuf::from(&root), database_url.as_deref()).await?;
+    let compression_level = env::var("ANKI_BACKUP_COMPRESSION_LEVEL")
+        .ok()
+        .and_then(|v| v.parse::<i32>().ok())
+        .or(cfg.storage.compression_level)
+        .unwrap_or(DEFAULT_COMPRESSION_LEVEL);
+
+    if mode.as_deref() == Some("migrate-metadata") {
+        let default_from = PathBuf::from(&root).join("state").join("metadata.db");
+        return migrate_metadata(&args, &default_from, database_url.as_deref()).await;
+    }
+
+    let mut base = BackupRepository::init(PathBuf::from(&root), database_url.as_deref())
+        .await?
+        .with_compression_level((compression_level != 0).then_some(compression_level));
+    if let Some(s3) = s3_config(&cfg)? {
+        info!(endpoint = %s3.endpoint, bucket = %s3.bucket, "storing backups in S3-compatible object storage");
+        base = base
+            .with_blob_store(Arc::new(S3BlobStore::new(s3)?))
+            .await?;
+    }
+    // Resolved once, so every profile shares the key (and passphrase salt).
+    let base = base
+        .with_encryption(encryption_key_source(&cfg).as_ref())
+        .await?;
+
+    // Subcommands run for every profile in turn unless `--profile` picks one;
+    // anything else starts the service for all of them.
+    let command = mode
+        .as_deref()
+        .filter(|m| PROFILE_COMMANDS.contains(m) || SINGLE_PROFILE_COMMANDS.contains(m));
+    let mut profiles = resolve_profiles(&cfg)?;
+    if let Some(command) = command {
+        if let Some(name) = flag_value(&args, "--profile")? {
+            if ALL_PROFILES_COMMANDS.contains(&command) {
+                bail!("{command} always covers every profile; drop --profile");
+            }
+            profiles.retain(|p| p.name == name);
+            if profiles.is_empty() {
+                bail!("no profile named {name:?} is configured");
+            }
+        }
+    }
+    let repos = profiles
+        .iter()
+        .map(|profile| {
+            base.for_profile(&profile.name)
+                .with_context(|| format!("opening profile {}", profile.name))
+        })
+        .collect::<Result<Vec<_>>>()?;
 
-    match mode.as_deref() {
-        Some("run-once") => run_once(repo, sync_config(&cfg)).await,
-        _ => run_service(repo, &listen, &cfg).await,
+    let Some(command) = command else {
+        return run_service(profiles.into_iter().zip(repos).collect(), &listen, &cfg).await;
+    };
+    if SINGLE_PROFILE_COMMANDS.contains(&command) {
+        let ([profile], [repo]) = (profiles.as_slice(), repos.as_slice()) else {
+            bail!("{command} needs --profile when several profiles are configured");
+        };
+        match command {
+            "import" => return import(repo, &args).await,
+            "export" => return export(repo, &args).await,
+            "diff" => return diff(repo, &args).await,
+            _ => {}
+        }
+        let sync = profile.sync().with_context(|| {
+            format!("{command} only applies to profiles that sync with a server")
+        })?;
+        return match command {
+            "restore" => restore(repo, sync, &args).await,
+            _ => host_key(repo, sync).await,
+        };
     }
+
+    // A failing profile does not stop the others, except for commands that
+    // must leave every profile in the same state; the command fails at the
+    // end if any of them did.
+    let dry_run = args.iter().any(|a| a == "--dry-run");
+    let mut failed = Vec::new();
+    for (profile, repo) in profiles.iter().zip(repos) {
+        if profiles.len() > 1 {
+            info!(profile = %profile.name, "running {command}");
+        }
+        let result = match command {
+            "run-once" => run_once(repo, profile.job()).await,
+            "migrate-storage" => migrate_storage(repo).await,
+            "rotate-key" => rotate_key(repo).await,
+            "prune" => prune(repo, profile.retention.clone(), dry_run).await,
+            "verify" => verify(repo).await,
+            "reindex" => reindex(repo).await,
+            other => unreachable!("{other} is not a profile command"),
+        };
+        if let Err(e) = result {
+            if profiles.len() == 1 {
+                return Err(e);
+            }
+            if ALL_PROFILES_COMMANDS.contains(&command) {
+                return Err(e.context(format!(
+                    "{command} stopped at profile {}; fix it and re-run",
+                    profile.name
+                )));
+            }
+            error!(profile = %profile.name, error = %format!("{e:#}"), "{command} failed");
+            failed.push(profile.name.as_str());
+        }
+    }
+    if !failed.is_empty() {
+        bail!("{command} failed for profile(s): {}", failed.join(", "));
+    }
+    Ok(())
 }
 
-/// Parse CLI args, returning the loaded config and optional subcommand.
-fn parse_args() -> Result<(Config, Option<String>)> {
+/// Parse CLI args, returning the loaded config, optional subcommand and any
+/// arguments following the subcommand.
+fn parse_args() -> Result<(Config, Option<String>, Vec<String>)> {
     let args: Vec<String> = env::args().collect();
     let mut config_path: Option<PathBuf> = None;
     let mut mode: Option<String> = None;
+    let mut rest = Vec::new();
     let mut i = 1;
 
     while i < args.len() {
@@ -58,9 +187,10 @@ fn parse_args() -> Result<(Config, Option<String>)> {
                 }
                 config_path = Some(PathBuf::from(&args[i]));
             }
-            other => {
+            other if mode.is_none() => {
                 mode = Some(other.to_owned());
             }
+            other => rest.push(other.to_owned()),
         }
         i += 1;
     }
@@ -73,35 +203,99 @@ fn parse_args() -> Result<(Config, Option<String>)> {
         None => Config::default(),
     };
 
-    Ok((cfg, mode))
+    Ok((cfg, mode, rest))
 }
 
-fn sync_config(cfg: &Config) -> SyncConfig {
-    SyncConfig {
-        username: env::var("ANKIWEB_USERNAME")
-            .ok()
-            .or_else(|| cfg.ankiweb.username.clone())
-            .unwrap_or_default(),
-        password: env::var("ANKIWEB_PASSWORD")
-            .ok()
-            .or_else(|| cfg.ankiweb.password.clone())
-            .unwrap_or_default(),
-        endpoint: env::var("ANKIWEB_ENDPOINT")
-            .ok()
-            .or_else(|| cfg.ankiweb.endpoint.clone()),
+/// Apply the configured retention policy once. `--dry-run` only reports.
+async fn prune(repo: BackupRepository, mut retention: Retention, dry_run: bool) -> Result<()> {
+    if dry_run {
+        match &mut retention {
+            Retention::Gfs { dry_run, .. } => *dry_run = true,
+            Retention::MaxAgeDays(_) => {
+                bail!("--dry-run requires a [storage.retention] policy; the flat retention_days cutoff is in use")
+            }
+        }
     }
+    let removed = apply_retention(&repo, &retention).await?;
+    info!(removed, "retention pruning complete");
+    Ok(())
 }
 
-async fn run_once(repo: BackupRepository, sync_config: SyncConfig) -> Result<()> {
-    let sync = sync_collection(&sync_config).await?;
-    let hash = content_hash(&sync.collection_bytes);
-    let payload = BackupPayload {
-        bytes: sync.collection_bytes,
-        source_revision: sync.source_revision,
-        sync_duration_ms: Some(sync.sync_duration_ms),
+/// Resolve S3-compatible object storage settings from env or config.
+///
+/// Object storage is enabled by setting a bucket; everything else has defaults
+/// or falls back to the standard `AWS_*` variables.
+fn s3_config(cfg: &Config) -> Result<Option<S3Config>> {
+    let file = cfg.storage.s3.as_ref();
+    let setting = |env_keys: &[&str], from_file: Option<&String>| {
+        env_keys
+            .iter()
+            .find_map(|key| env::var(key).ok().filter(|v| !v.is_empty()))
+            .or_else(|| from_file.cloned())
+    };
+
+    let Some(bucket) = setting(
+        &["ANKI_BACKUP_S3_BUCKET"],
+        file.and_then(|s| s.bucket.as_ref()),
+    ) else {
+        return Ok(None);
     };
+    let region = setting(
+        &["ANKI_BACKUP_S3_REGION", "AWS_REGION"],
+        file.and_then(|s| s.region.as_ref()),
+    )
+    .unwrap_or_else(|| "us-east-1".to_owned());
+    let endpoint = setting(
+        &["ANKI_BACKUP_S3_ENDPOINT"],
+        file.and_then(|s| s.endpoint.as_ref()),
+    )
+    .unwrap_or_else(|| format!("https://s3.{region}.amazonaws.com"));
+    let access_key_id = setting(
+        &["ANKI_BACKUP_S3_ACCESS_KEY_ID", "AWS_ACCESS_KEY_ID"],
+        file.and_then(|s| s.access_key_id.as_ref()),
+    )
+    .context("S3 storage requires an access key id")?;
+    let secret_access_key = setting(
+        &["ANKI_BACKUP_S3_SECRET_ACCESS_KEY", "AWS_SECRET_ACCESS_KEY"],
+        file.and_then(|s| s.secret_access_key.as_ref()),
+    )
+    .context("S3 storage requires a secret access key")?;
+    let prefix = setting(
+        &["ANKI_BACKUP_S3_PREFIX"],
+        file.and_then(|s| s.prefix.as_ref()),
+    );
 
-    match repo.run_once(payload, hash).await? {
+    Ok(Some(S3Config {
+        endpoint,
+        bucket,
+        region,
+        access_key_id,
+        secret_access_key,
+        prefix,
+    }))
+}
+
+/// Resolve the backup encryption key from env or config, if one is configured.
+fn encryption_key_source(cfg: &Config) -> Option<KeySource> {
+    let key_file = env::var("ANKI_BACKUP_ENCRYPTION_KEY_FILE")
+        .ok()
+        .or_else(|| cfg.security.encryption_key_file.clone());
+    let passphrase = env::var("ANKI_BACKUP_ENCRYPTION_PASSPHRASE")
+        .ok()
+        .or_else(|| cfg.security.encryption_passphrase.clone());
+    key_source(key_file, passphrase)
+}
+
+fn key_source(key_file: Option<String>, passphrase: Option<String>) -> Option<KeySource> {
+    match (key_file, passphrase) {
+        (Some(path), _) if !path.is_empty() => Some(KeySource::File(PathBuf::from(path))),
+        (_, Some(passphrase)) if !passphrase.is_empty() => Some(KeySource::Passphrase(passphrase)),
+        _ => None,
+    }
+}
+
+async fn run_once(repo: BackupRepository, job: BackupJob) -> Result<()> {
+    match job.run(&repo).await? {
         RunOnceOutcome::Created(entry) => info!(backup_id = %entry.id, "backup created"),
         RunOnceOutcome::Skipped(entry) => {
             info!(backup_id = %entry.id, "backup skipped (unchanged)")
@@ -110,32 +304,440 @@ async fn run_once(repo: BackupRepository, sync_config: SyncConfig) -> Result<()>
     Ok(())
 }
 
-async fn run_service(repo: BackupRepository, listen: &str, cfg: &Config) -> Result<()> {
-    let sc = sync_config(cfg);
+/// Import the `.colpkg` / `.apkg` files named in `args` as backups.
+///
+/// Each is dated by its collection's modification time unless `--at` gives
+/// an RFC 3339 timestamp for all of them.
+async fn import(repo: &BackupRepository, args: &[String]) -> Result<()> {
+    let created_at = flag_value(args, "--at")?
+        .map(|at| {
+            DateTime::parse_from_rfc3339(&at)
+                .map(|t| t.with_timezone(&Utc))
+                .with_context(|| format!("--at must be an RFC 3339 timestamp: {at}"))
+        })
+        .transpose()?;
+    let files = positional_args(args, &["--at", "--profile"]);
+    if files.is_empty() {
+        bail!("import needs at least one .colpkg or .apkg file");
+    }
+
+    for path in files {
+        let bytes = std::fs::read(path).with_context(|| format!("read {path}"))?;
+        match repo
+            .import_package(bytes, created_at)
+            .await
+            .with_context(|| format!("import {path}"))?
+        {
+            ImportOutcome::Imported { entry, format } => info!(
+                path = %path,
+                backup_id = %entry.id,
+                created_at = %entry.created_at,
+                format = format.as_str(),
+                "package imported"
+            ),
+            ImportOutcome::Duplicate(existing) => info!(
+                path = %path,
+                backup_id = %existing.id,
+                "package skipped (already backed up)"
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Write one backup to a file, as a `.colpkg` by default.
+///
+/// `--format tar.zst` writes the bare collection tarball instead, and
+/// `--deck <deck-id>` an `.apkg` of just that deck and its subdecks.
+/// `--output` overrides the `backup-<id>.<ext>` file name.
+async fn export(repo: &BackupRepository, args: &[String]) -> Result<()> {
+    let flags = ["--format", "--deck", "--output", "--profile"];
+    let [id] = positional_args(args, &flags)[..] else {
+        bail!("export needs exactly one backup id");
+    };
+    let id = Uuid::parse_str(id).with_context(|| format!("invalid backup id {id}"))?;
+    let deck_id = flag_value(args, "--deck")?
+        .map(|d| {
+            d.parse::<i64>()
+                .with_context(|| format!("invalid deck id {d}"))
+        })
+        .transpose()?;
+    let format = match flag_value(args, "--format")? {
+        Some(_) if deck_id.is_some() => bail!("--deck always exports an .apkg; drop --format"),
+        Some(format) => format.parse::<ExportFormat>()?,
+        None => ExportFormat::Colpkg,
+    };
+    let backup = repo
+        .get_backup(id)
+        .await?
+        .with_context(|| format!("no backup {id}"))?;
+    if backup.status != BackupStatus::Created {
+        bail!("backup {id} was skipped and has no collection");
+    }
+
+    let (bytes, file_name) = match deck_id {
+        Some(deck_id) => (
+            repo.export_deck_package(&backup, deck_id)
+                .await?
+                .with_context(|| format!("backup {id} has no deck {deck_id}"))?,
+            deck_file_name(&backup, deck_id),
+        ),
+        None => (
+            export_backup(repo, &backup, format).await?,
+            format.file_name(&backup),
+        ),
+    };
+    let output = flag_value(args, "--output")?.unwrap_or(file_name);
+    std::fs::write(&output, &bytes).with_context(|| format!("write {output}"))?;
+    info!(
+        backup_id = %backup.id,
+        path = %output,
+        bytes = bytes.len(),
+        "backup exported"
+    );
+    Ok(())
+}
+
+/// Graft notes, cards or decks from a backup into the collection on the
+/// server and upload the result.
+async fn restore(repo: &BackupRepository, sync: &SyncConfig, args: &[String]) -> Result<()> {
+    let flags = ["--profile", "--note", "--card", "--deck"];
+    let [id] = positional_args(args, &flags)[..] else {
+        bail!("restore needs a backup id");
+    };
+    let id = Uuid::parse_str(id).with_context(|| format!("invalid backup id {id}"))?;
+    let ids = |flag| -> Result<Vec<i64>> {
+        flag_values(args, flag)?
+            .into_iter()
+            .map(|v| v.parse().with_context(|| format!("invalid {flag} id {v}")))
+            .collect()
+    };
+    let selection = RestoreSelection {
+        note_ids: ids("--note")?,
+        card_ids: ids("--card")?,
+        deck_ids: ids("--deck")?,
+    };
+    if selection.is_empty() {
+        bail!("restore needs at least one --note, --card or --deck");
+    }
+    let backup = repo
+        .get_backup(id)
+        .await?
+        .with_context(|| format!("no backup {id}"))?;
+    if backup.status != BackupStatus::Created {
+        bail!("backup {id} was skipped and has no collection");
+    }
+
+    let outcome = merge_restore(repo, sync, &backup, &selection).await?;
+    let report = &outcome.report;
+    info!(
+        backup_id = %backup.id,
+        snapshot_id = %outcome.snapshot.id,
+        notes_added = report.notes_added,
+        notes_reverted = report.notes_reverted,
+        cards_added = report.cards_added,
+        revlog_added = report.revlog_added,
+        decks_added = report.decks_added,
+        note_types_added = report.note_types_added,
+        "restore uploaded"
+    );
+    Ok(())
+}
+
+/// Print what changed between two backups, oldest first; `--json` prints
+/// the full diff as the API returns it.
+async fn diff(repo: &BackupRepository, args: &[String]) -> Result<()> {
+    let [from, to] = positional_args(args, &["--profile"])[..] else {
+        bail!("diff needs two backup ids");
+    };
+    let mut backups = Vec::with_capacity(2);
+    for id in [from, to] {
+        let id = Uuid::parse_str(id).with_context(|| format!("invalid backup id {id}"))?;
+        let backup = repo
+            .get_backup(id)
+            .await?
+            .with_context(|| format!("no backup {id}"))?;
+        if backup.status != BackupStatus::Created {
+            bail!("backup {id} was skipped and has no collection");
+        }
+        backups.push(backup);
+    }
+    let diff = repo.diff_backups(&backups[0], &backups[1]).await?;
+
+    if args.iter().any(|a| a == "--json") {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+        return Ok(());
+    }
+    if diff.is_empty() {
+        println!("no changes");
+    }
+    for deck in &diff.decks_added {
+        println!("+ deck {}", deck.name);
+    }
+    for deck in &diff.decks_removed {
+        println!("- deck {}", deck.name);
+    }
+    for note_type in &diff.note_types_added {
+        println!("+ note type {}", note_type.name);
+    }
+    for note_type in &diff.note_types_removed {
+        println!("- note type {}", note_type.name);
+    }
+    for note in &diff.notes_added {
+        println!("+ note {} ({}): {}", note.id, note.note_type, note.preview);
+    }
+    for note in &diff.notes_removed {
+        println!("- note {} ({}): {}", note.id, note.note_type, note.preview);
+    }
+    for note in &diff.notes_modified {
+        println!("~ note {} ({}): {}", note.id, note.note_type, note.preview);
+        if let Some(previous) = &note.previous_note_type {
+            println!("    note type: {previous} -> {}", note.note_type);
+        }
+        for field in &note.fields {
+            println!(
+                "    {}: {:?} -> {:?}",
+                field.name,
+                field.before.as_deref().unwrap_or_default(),
+                field.after.as_deref().unwrap_or_default()
+            );
+        }
+        if let Some((before, after)) = &note.tags {
+            println!("    tags: {before:?} -> {after:?}");
+        }
+    }
+    for card in &diff.cards_moved {
+        println!(
+            "> card {} (note {}): {} -> {}",
+            card.card_id, card.note_id, card.from.name, card.to.name
+        );
+    }
+    Ok(())
+}
+
+/// Log in, cache the host key and print it so it can go in the config in
+/// place of the password.
+async fn host_key(repo: &BackupRepository, sync_config: &SyncConfig) -> Result<()> {
+    let key = fetch_host_key(sync_config).await?;
+    repo.store_host_key(&sync_config.username, &sync_config.password, &key)
+        .await?;
+    info!("host key cached");
+    println!("{key}");
+    Ok(())
+}
+
+async fn migrate_storage(repo: BackupRepository) -> Result<()> {
+    let report = repo.migrate_storage().await?;
+    info!(
+        legacy_backups_converted = report.legacy_backups_converted,
+        chunks_recompressed = report.chunks_recompressed,
+        bytes_before = report.bytes_before,
+        bytes_after = report.bytes_after,
+        "storage migration complete"
+    );
+    Ok(())
+}
+
+/// Re-encrypt all stored data under the currently configured key.
+///
+/// The key the data is currently sealed with comes from
+/// `ANKI_BACKUP_OLD_ENCRYPTION_KEY_FILE` or `ANKI_BACKUP_OLD_ENCRYPTION_PASSPHRASE`;
+/// leave both unset to encrypt a previously unencrypted repository.
+async fn rotate_key(repo: BackupRepository) -> Result<()> {
+    let old = key_source(
+        env::var("ANKI_BACKUP_OLD_ENCRYPTION_KEY_FILE").ok(),
+        env::var("ANKI_BACKUP_OLD_ENCRYPTION_PASSPHRASE").ok(),
+    );
+    let report = repo.rotate_encryption_key(old.as_ref()).await?;
+    info!(
+        chunks_rewritten = report.chunks_rewritten,
+        files_rewritten = report.files_rewritten,
+        "encryption key rotation complete"
+    );
+    Ok(())
+}
+
+/// Scrub every backup once and fail if any problem was found.
+async fn verify(repo: BackupRepository) -> Result<()> {
+    let report = repo.verify().await?;
+    log_verify_report(&report);
+    if !report.is_clean() {
+        bail!(
+            "verification found {} damaged backup(s) and {} orphaned dir(s)",
+            report.failures.len(),
+            report.orphaned_dirs.len()
+        );
+    }
+    Ok(())
+}
+
+/// Rebuild the metadata database from the per-backup `metadata.json` files.
+async fn reindex(repo: BackupRepository) -> Result<()> {
+    let report = repo.reindex().await?;
+    for conflict in &report.conflicts {
+        error!(timestamp_dir = %conflict.timestamp_dir, reason = %conflict.reason, "reindex: conflict");
+    }
+    info!(
+        scanned = report.scanned,
+        restored = report.restored,
+        recovered_from_payload = report.recovered_from_payload,
+        already_indexed = report.already_indexed,
+        conflicts = report.conflicts.len(),
+        "reindex complete"
+    );
+    Ok(())
+}
+
+/// Copy backup history from one metadata database to another.
+///
+/// `--from` defaults to the SQLite database under the storage root and `--to`
+/// to the configured `DATABASE_URL`; either accepts a `postgres://` URL or a
+/// SQLite path. Re-running only copies what is missing.
+async fn migrate_metadata(
+    args: &[String],
+    default_from: &Path,
+    database_url: Option<&str>,
+) -> Result<()> {
+    let from =
+        flag_value(args, "--from")?.unwrap_or_else(|| default_from.to_string_lossy().into_owned());
+    let to = flag_value(args, "--to")?
+        .or_else(|| database_url.map(str::to_owned))
+        .context("migrate-metadata needs a destination: pass --to or set DATABASE_URL")?;
+    if from == to {
+        bail!("source and destination metadata databases are the same: {from}");
+    }
+    let is_postgres = |location: &str| {
+        location.starts_with("postgres://") || location.starts_with("postgresql://")
+    };
+    if !is_postgres(&from) {
+        let path = Path::new(from.strip_prefix("sqlite://").unwrap_or(&from));
+        if !path.exists() {
+            bail!("source metadata database not found: {}", path.display());
+        }
+    }
+
+    let source = open_metadata_store(&from).await?;
+    let destination = open_metadata_store(&to).await?;
+    let report =
+        anki_backup_storage::migrate_metadata(source.as_ref(), destination.as_ref()).await?;
+    info!(
+        backups_copied = report.backups_copied,
+        backups_present = report.backups_present,
+        rollback_events_copied = report.rollback_events_copied,
+        rollback_events_present = report.rollback_events_present,
+        "metadata migration complete"
+    );
+    Ok(())
+}
+
+/// A subcommand's arguments other than the given flags and their values.
+fn positional_args<'a>(args: &'a [String], flags: &[&str]) -> Vec<&'a String> {
+    let mut positional = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if flags.contains(&arg.as_str()) {
+            iter.next();
+        } else if !arg.starts_with("--") {
+            positional.push(arg);
+        }
+    }
+    positional
+}
+
+/// Value following `flag` in a subcommand's arguments.
+fn flag_value(args: &[String], flag: &str) -> Result<Option<String>> {
+    match args.iter().position(|a| a == flag) {
+        Some(i) => args
+            .get(i + 1)
+            .cloned()
+            .map(Some)
+            .with_context(|| format!("{flag} requires a value")),
+        None => Ok(None),
+    }
+}
+
+/// Every value following `flag`, for flags that may be repeated.
+fn flag_values(args: &[String], flag: &str) -> Result<Vec<String>> {
+    let mut values = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == flag {
+            values.push(
+                iter.next()
+                    .cloned()
+                    .with_context(|| format!("{flag} requires a value"))?,
+            );
+        }
+    }
+    Ok(values)
+}
+
+fn log_verify_report(report: &VerifyReport) {
+    for failure in &report.failures {
+        error!(
+            backup_id = %failure.backup_id,
+            timestamp_dir = %failure.timestamp_dir,
+            status = failure.status.as_str(),
+            detail = %failure.detail,
+            "verify: backup failed"
+        );
+    }
+    for dir in &report.orphaned_dirs {
+        error!(timestamp_dir = %dir, "verify: orphaned backup dir");
+    }
+    info!(
+        checked = report.checked,
+        failed = report.failures.len(),
+        orphaned = report.orphaned_dirs.len(),
+        "verification complete"
+    );
+}
+
+/// Hours between scheduled scrubs; `0` disables them.
+fn verify_interval_hours(cfg: &Config) -> u64 {
+    env::var("ANKI_BACKUP_VERIFY_INTERVAL_HOURS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .or(cfg.storage.verify_interval_hours)
+        .unwrap_or(24)
+}
+
+async fn run_service(
+    profiles: Vec<(Profile, BackupRepository)>,
+    listen: &str,
+    cfg: &Config,
+) -> Result<()> {
     let state = AppState {
-        repo: repo.clone(),
-        rollback_gate: Arc::new(Mutex::new(None)),
+        profiles: Arc::new(
+            profiles
+                .iter()
+                .map(|(p, repo)| ProfileState::new(repo.clone(), p.upload_config()))
+                .collect(),
+        ),
         csrf_token: env::var("ANKI_BACKUP_CSRF_TOKEN")
             .ok()
             .or_else(|| cfg.security.csrf_token.clone()),
         api_token: env::var("ANKI_BACKUP_API_TOKEN")
             .ok()
             .or_else(|| cfg.security.api_token.clone()),
-        sync_config: if sc.username.is_empty() || sc.password.is_empty() {
-            None
-        } else {
-            Some(sc.clone())
-        },
+        api_token_name: env::var("ANKI_BACKUP_API_TOKEN_NAME")
+            .ok()
+            .or_else(|| cfg.security.api_token_name.clone()),
     };
 
-    let retention_days = env::var("ANKI_BACKUP_RETENTION_DAYS")
-        .ok()
-        .and_then(|v| v.parse::<i64>().ok())
-        .or(cfg.storage.retention_days)
-        .unwrap_or(90);
-
-    tokio::spawn(scheduler_loop(repo, sync_config(cfg), retention_days));
-
+    let verify_hours = verify_interval_hours(cfg);
+    for (profile, repo) in profiles {
+        info!(profile = %profile.name, interval_hours = profile.interval_hours, "scheduling backups");
+        if verify_hours > 0 {
+            tokio::spawn(verify_loop(repo.clone(), verify_hours));
+        }
+        tokio::spawn(scheduler_loop(
+            repo,
+            profile.job(),
+            profile.retention,
+            profile.interval_hours,
+        ));
+    }
 
     let addr: SocketAddr = listen
         .parse()
@@ -148,42 +750,12 @@ async fn run_service(repo: BackupRepository, listen: &str, cfg: &Config) -> Resu
     Ok(())
 }
 
-async fn scheduler_loop(repo: BackupRepository, config: SyncConfig, retention_days: i64) {
+async fn verify_loop(repo: BackupRepository, interval_hours: u64) {
     loop {
-        let now = Utc::now();
-        let secs_to_next_hour = 3600 - (now.minute() * 60 + now.second()) as u64;
-        sleep(Duration::from_secs(secs_to_next_hour.max(1))).await;
-
-        match sync_collection(&config).await {
-            Ok(sync) => {
-                let hash = content_hash(&sync.collection_bytes);
-                let payload = BackupPayload {
-                    bytes: sync.collection_bytes,
-                    source_revision: sync.source_revision,
-                    sync_duration_ms: Some(sync.sync_duration_ms),
-                };
-                match repo.run_once(payload, hash).await {
-                    Ok(RunOnceOutcome::Created(entry)) => {
-                        info!(backup_id = %entry.id, "scheduled backup created")
-                    }
-                    Ok(RunOnceOutcome::Skipped(_)) => {
-                        info!("scheduled backup skipped (unchanged)")
-                    }
-                    Err(e) => error!(error = %e, "scheduled backup failed"),
-                }
-
-                match repo.prune_created_older_than_days(retention_days).await {
-                    Ok(removed) if removed > 0 => {
-                        info!(
-                            removed,
-                            retention_days, "retention pruning removed old backups"
-                        )
-                    }
-                    Ok(_) => {}
-                    Err(e) => error!(error = %e, retention_days, "retention pruning failed"),
-                }
-            }
-            Err(e) => error!(error = %e, "ankiweb sync failed"),
+        sleep(Duration::from_secs(interval_hours * 3600)).await;
+        match repo.verify().await {
+            Ok(report) => log_verify_report(&report),
+            Err(e) => error!(profile = repo.profile(), error = %e, "scheduled verification failed"),
         }
     }
 }
@@ -0,0 +1,360 @@
+//! Resolving the configured profiles, each backing up one AnkiWeb account or
+//! local Anki profile.
+//!
+//! Without `[[profiles]]` the top-level `[ankiweb]`, `[source]` and `[storage]` settings,
+//! plus their environment overrides, form a single `default` profile. Listed
+//! profiles are configured entirely from their own sections; only retention
+//! falls back to the top-level policy.
+
+use std::collections::HashSet;
+use std::env;
+use std::time::Duration;
+
+use anki_backup_storage::{validate_profile_name, RetentionPolicy, DEFAULT_PROFILE};
+use anki_backup_sync::{ClientVersion, CollectionSource, LocalProfile, RetryPolicy, SyncConfig};
+use anyhow::{bail, Context, Result};
+
+use crate::config::{AnkiwebConfig, Config, RetentionConfig, SourceConfig};
+use crate::jobs::BackupJob;
+use crate::scheduler::Retention;
+
+/// Default hours between scheduled backups.
+const DEFAULT_INTERVAL_HOURS: u64 = 1;
+
+/// Flat cutoff applied when no retention is configured at all.
+const DEFAULT_RETENTION_DAYS: i64 = 90;
+
+/// Environment variables that select the GFS policy without a config section.
+const GFS_RETENTION_VARS: &[&str] = &[
+    "ANKI_BACKUP_RETENTION_KEEP_ALL_HOURS",
+    "ANKI_BACKUP_RETENTION_DAILY_DAYS",
+    "ANKI_BACKUP_RETENTION_WEEKLY_WEEKS",
+    "ANKI_BACKUP_RETENTION_MONTHLY_MONTHS",
+    "ANKI_BACKUP_RETENTION_DRY_RUN",
+];
+
+/// One profile's resolved settings.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub source: CollectionSource,
+    /// Also back up `collection.media`.
+    pub media: bool,
+    pub retention: Retention,
+    /// Hours between scheduled backups.
+    pub interval_hours: u64,
+}
+
+impl Profile {
+    pub fn job(&self) -> BackupJob {
+        BackupJob::new(self.source.clone()).with_media(self.media)
+    }
+
+    /// The sync server settings, unless backups come from a local profile.
+    pub fn sync(&self) -> Option<&SyncConfig> {
+        match &self.source {
+            CollectionSource::Sync(config) => Some(config),
+            CollectionSource::Local(_) => None,
+        }
+    }
+
+    /// Where rollbacks are uploaded: the sync server, when there are
+    /// credentials for it.
+    pub fn upload_config(&self) -> Option<SyncConfig> {
+        self.sync()
+            .filter(|s| s.host_key.is_some() || (!s.username.is_empty() && !s.password.is_empty()))
+            .cloned()
+    }
+}
+
+/// Every configured profile, in config order.
+pub fn resolve_profiles(cfg: &Config) -> Result<Vec<Profile>> {
+    if cfg.profiles.is_empty() {
+        let (source, media) = source(&cfg.ankiweb, &cfg.source, true)?;
+        return Ok(vec![Profile {
+            name: DEFAULT_PROFILE.to_owned(),
+            source,
+            media,
+            retention: retention(
+                env_i64("ANKI_BACKUP_RETENTION_DAYS").or(cfg.storage.retention_days),
+                cfg.storage.retention.as_ref(),
+                true,
+            ),
+            interval_hours: DEFAULT_INTERVAL_HOURS,
+        }]);
+    }
+
+    let mut seen = HashSet::new();
+    cfg.profiles
+        .iter()
+        .map(|p| {
+            validate_profile_name(&p.name)?;
+            if !seen.insert(p.name.as_str()) {
+                bail!("profile {:?} is configured more than once", p.name);
+            }
+            let (source, media) = source(&p.ankiweb, &p.source, false)
+                .with_context(|| format!("profile {}", p.name))?;
+            let own_retention = p.retention.is_some() || p.retention_days.is_some();
+            Ok(Profile {
+                name: p.name.clone(),
+                source,
+                media,
+                retention: if own_retention {
+                    retention(p.retention_days, p.retention.as_ref(), false)
+                } else {
+                    retention(
+                        env_i64("ANKI_BACKUP_RETENTION_DAYS").or(cfg.storage.retention_days),
+                        cfg.storage.retention.as_ref(),
+                        true,
+                    )
+                },
+                interval_hours: p
+                    .interval_hours
+                    .filter(|h| *h > 0)
+                    .unwrap_or(DEFAULT_INTERVAL_HOURS),
+            })
+        })
+        .collect()
+}
+
+/// `key` from the environment, when environment overrides apply.
+fn var(use_env: bool, key: &str) -> Option<String> {
+    use_env.then(|| env::var(key).ok()).flatten()
+}
+
+fn env_i64(key: &str) -> Option<i64> {
+    var(true, key).and_then(|v| v.parse::<i64>().ok())
+}
+
+/// The backup source and whether to back up its media.
+fn source(
+    ankiweb: &AnkiwebConfig,
+    source: &SourceConfig,
+    use_env: bool,
+) -> Result<(CollectionSource, bool)> {
+    let kind = var(use_env, "ANKI_BACKUP_SOURCE").or_else(|| source.kind.clone());
+    match kind.as_deref().unwrap_or("ankiweb") {
+        "ankiweb" => Ok((
+            sync_config(ankiweb, use_env).into(),
+            sync_media(ankiweb, use_env),
+        )),
+        "local" => {
+            let dir = var(use_env, "ANKI_BACKUP_LOCAL_PROFILE_DIR")
+                .or_else(|| source.profile_dir.clone())
+                .filter(|d| !d.is_empty())
+                .context("the local source needs source.profile_dir")?;
+            let media = var(use_env, "ANKI_BACKUP_LOCAL_MEDIA")
+                .and_then(|v| v.parse::<bool>().ok())
+                .or(source.media)
+                .unwrap_or(true);
+            Ok((LocalProfile::new(dir).into(), media))
+        }
+        other => bail!("unknown backup source {other:?}; expected \"ankiweb\" or \"local\""),
+    }
+}
+
+fn sync_config(ankiweb: &AnkiwebConfig, use_env: bool) -> SyncConfig {
+    let var = |key: &str| var(use_env, key);
+    SyncConfig {
+        username: var("ANKIWEB_USERNAME")
+            .or_else(|| ankiweb.username.clone())
+            .unwrap_or_default(),
+        password: var("ANKIWEB_PASSWORD")
+            .or_else(|| ankiweb.password.clone())
+            .unwrap_or_default(),
+        endpoint: var("ANKIWEB_ENDPOINT").or_else(|| ankiweb.endpoint.clone()),
+        host_key: var("ANKIWEB_HOST_KEY")
+            .or_else(|| ankiweb.host_key.clone())
+            .filter(|k| !k.is_empty()),
+        retry: match var("ANKIWEB_RETRY_BUDGET_SECS")
+            .and_then(|v| v.parse::<u64>().ok())
+            .or(ankiweb.retry_budget_secs)
+        {
+            Some(secs) => RetryPolicy::default().with_budget(Duration::from_secs(secs)),
+            None => RetryPolicy::default(),
+        },
+        client_version: var("ANKIWEB_CLIENT_VERSION")
+            .or_else(|| ankiweb.client_version.clone())
+            .filter(|v| !v.is_empty())
+            .map(|v| ClientVersion::anki(&v))
+            .unwrap_or_default(),
+        sync_version: var("ANKIWEB_SYNC_VERSION")
+            .and_then(|v| v.parse::<u8>().ok())
+            .or(ankiweb.sync_version),
+    }
+}
+
+fn sync_media(ankiweb: &AnkiwebConfig, use_env: bool) -> bool {
+    var(use_env, "ANKIWEB_SYNC_MEDIA")
+        .and_then(|v| v.parse::<bool>().ok())
+        .or(ankiweb.sync_media)
+        .unwrap_or(true)
+}
+
+/// Resolve a retention policy.
+///
+/// The GFS policy applies once a retention section (or one of its
+/// environment variables) is set. Otherwise the flat `retention_days` cutoff
+/// applies, 90 days unless configured, as it did before GFS existed.
+fn retention(
+    retention_days: Option<i64>,
+    file: Option<&RetentionConfig>,
+    use_env: bool,
+) -> Retention {
+    let gfs_env = GFS_RETENTION_VARS
+        .iter()
+        .any(|key| var(use_env, key).is_some());
+    if file.is_none() && !gfs_env {
+        return Retention::MaxAgeDays(retention_days.unwrap_or(DEFAULT_RETENTION_DAYS));
+    }
+
+    let defaults = RetentionPolicy::default();
+    let setting = |key: &str, from_file: Option<i64>, default: i64| {
+        var(use_env, key)
+            .and_then(|v| v.parse::<i64>().ok())
+            .or(from_file)
+            .unwrap_or(default)
+    };
+    Retention::Gfs {
+        policy: RetentionPolicy {
+            keep_all_hours: setting(
+                "ANKI_BACKUP_RETENTION_KEEP_ALL_HOURS",
+                file.and_then(|r| r.keep_all_hours),
+                defaults.keep_all_hours,
+            ),
+            daily_days: setting(
+                "ANKI_BACKUP_RETENTION_DAILY_DAYS",
+                file.and_then(|r| r.daily_days),
+                defaults.daily_days,
+            ),
+            weekly_weeks: setting(
+                "ANKI_BACKUP_RETENTION_WEEKLY_WEEKS",
+                file.and_then(|r| r.weekly_weeks),
+                defaults.weekly_weeks,
+            ),
+            monthly_months: setting(
+                "ANKI_BACKUP_RETENTION_MONTHLY_MONTHS",
+                file.and_then(|r| r.monthly_months),
+                defaults.monthly_months,
+            ),
+        },
+        dry_run: var(use_env, "ANKI_BACKUP_RETENTION_DRY_RUN")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or_else(|| file.is_some_and(|r| r.dry_run)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listed_profiles_use_their_own_settings() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [storage]
+            retention_days = 30
+
+            [[profiles]]
+            name = "alice"
+            interval_hours = 6
+            [profiles.ankiweb]
+            username = "alice@example.com"
+            password = "secret"
+            sync_media = false
+
+            [[profiles]]
+            name = "bob"
+            retention_days = 7
+            [profiles.ankiweb]
+            host_key = "bob-key"
+            "#,
+        )
+        .unwrap();
+
+        let profiles = resolve_profiles(&cfg).unwrap();
+        let names: Vec<_> = profiles.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["alice", "bob"]);
+        assert_eq!(profiles[0].sync().unwrap().username, "alice@example.com");
+        assert!(!profiles[0].media);
+        assert_eq!(profiles[0].interval_hours, 6);
+        assert!(matches!(profiles[0].retention, Retention::MaxAgeDays(30)));
+        assert_eq!(
+            profiles[1].upload_config().unwrap().host_key.as_deref(),
+            Some("bob-key")
+        );
+        assert_eq!(profiles[1].interval_hours, 1);
+        assert!(matches!(profiles[1].retention, Retention::MaxAgeDays(7)));
+    }
+
+    #[test]
+    fn retention_defaults_to_flat_cutoff_until_gfs_is_configured() {
+        let profiles = resolve_profiles(&toml::from_str("").unwrap()).unwrap();
+        assert!(matches!(profiles[0].retention, Retention::MaxAgeDays(90)));
+
+        let cfg: Config = toml::from_str(
+            r#"
+            [storage]
+            retention_days = 30
+            [storage.retention]
+            daily_days = 3
+            "#,
+        )
+        .unwrap();
+        match &resolve_profiles(&cfg).unwrap()[0].retention {
+            Retention::Gfs { policy, dry_run } => {
+                assert_eq!(policy.daily_days, 3);
+                assert_eq!(policy.keep_all_hours, 24);
+                assert!(!dry_run);
+            }
+            other => panic!("unexpected retention {other:?}"),
+        }
+    }
+
+    #[test]
+    fn local_source_needs_a_profile_dir() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [[profiles]]
+            name = "desktop"
+            [profiles.source]
+            kind = "local"
+            profile_dir = "/home/me/.local/share/Anki2/User 1"
+            media = false
+            "#,
+        )
+        .unwrap();
+        let profiles = resolve_profiles(&cfg).unwrap();
+        match &profiles[0].source {
+            CollectionSource::Local(local) => {
+                assert_eq!(
+                    local.dir().to_str(),
+                    Some("/home/me/.local/share/Anki2/User 1")
+                )
+            }
+            other => panic!("unexpected source {other:?}"),
+        }
+        assert!(!profiles[0].media);
+        assert!(profiles[0].upload_config().is_none());
+
+        for source in ["kind = \"local\"", "kind = \"dropbox\""] {
+            let cfg: Config = toml::from_str(&format!(
+                "[[profiles]]\nname = \"desktop\"\n[profiles.source]\n{source}\n"
+            ))
+            .unwrap();
+            assert!(resolve_profiles(&cfg).is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_and_duplicate_names() {
+        for names in [r#""a/b""#, r#""alice", "alice""#] {
+            let profiles: Vec<String> = names
+                .split(", ")
+                .map(|n| format!("[[profiles]]\nname = {n}\n"))
+                .collect();
+            let cfg: Config = toml::from_str(&profiles.join("")).unwrap();
+            assert!(resolve_profiles(&cfg).is_err(), "{names}");
+        }
+    }
+}
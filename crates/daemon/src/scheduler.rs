@@ -0,0 +1,134 @@
+//! The periodic backup scheduler and the retention pass that follows each run.
+
+use anki_backup_storage::{BackupRepository, RetentionPlan, RetentionPolicy, RunOnceOutcome};
+use anki_backup_sync::SyncError;
+use anyhow::Result;
+use chrono::Utc;
+use tokio::time::{sleep, Duration};
+use tracing::{error, info};
+
+use crate::jobs::BackupJob;
+
+/// How soon the scheduler tries again after a transient sync failure,
+/// instead of waiting for the next regular run.
+pub const TRANSIENT_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// How old backups are pruned.
+#[derive(Debug, Clone)]
+pub enum Retention {
+    /// Grandfather-father-son policy; `dry_run` only logs the plan.
+    Gfs {
+        policy: RetentionPolicy,
+        dry_run: bool,
+    },
+    /// Legacy flat cutoff from `retention_days`.
+    MaxAgeDays(i64),
+}
+
+/// Run retention once and return the number of backups removed.
+pub async fn apply_retention(repo: &BackupRepository, retention: &Retention) -> Result<usize> {
+    match retention {
+        Retention::Gfs { policy, dry_run } => {
+            let plan = repo.apply_retention(policy, *dry_run).await?;
+            if *dry_run {
+                log_retention_plan(&plan);
+                return Ok(0);
+            }
+            Ok(plan.remove.len())
+        }
+        Retention::MaxAgeDays(days) => repo.prune_created_older_than_days(*days).await,
+    }
+}
+
+fn log_retention_plan(plan: &RetentionPlan) {
+    for kept in &plan.keep {
+        let reasons: Vec<_> = kept.reasons.iter().map(|r| r.as_str()).collect();
+        info!(backup_id = %kept.backup.id, created_at = %kept.backup.created_at, reasons = %reasons.join(","), "retention: keep");
+    }
+    for removed in &plan.remove {
+        info!(backup_id = %removed.id, created_at = %removed.created_at, "retention: would remove");
+    }
+    info!(
+        keep = plan.keep.len(),
+        remove = plan.remove.len(),
+        "retention dry run complete"
+    );
+}
+
+/// Run one scheduled backup followed by retention.
+///
+/// Returns how soon to try again when the backup failed transiently, or
+/// `None` to wait for the next regular run.
+pub async fn scheduled_backup(
+    repo: &BackupRepository,
+    job: &BackupJob,
+    retention: &Retention,
+) -> Option<Duration> {
+    match job.run(repo).await {
+        Ok(outcome) => {
+            match outcome {
+                RunOnceOutcome::Created(entry) => {
+                    info!(backup_id = %entry.id, "scheduled backup created")
+                }
+                RunOnceOutcome::Skipped(_) => {
+                    info!("scheduled backup skipped (unchanged)")
+                }
+            }
+
+            match apply_retention(repo, retention).await {
+                Ok(removed) if removed > 0 => {
+                    info!(removed, "retention pruning removed old backups")
+                }
+                Ok(_) => {}
+                Err(e) => error!(error = %e, "retention pruning failed"),
+            }
+            None
+        }
+        Err(e) => match SyncError::classify(&e) {
+            Some(sync) if sync.is_transient() => {
+                let delay = sync
+                    .retry_after()
+                    .map_or(TRANSIENT_RETRY_DELAY, |d| d.max(TRANSIENT_RETRY_DELAY));
+                error!(
+                    error = %format!("{e:#}"),
+                    kind = sync.kind(),
+                    retry_in_secs = delay.as_secs(),
+                    "scheduled backup failed; retrying early"
+                );
+                Some(delay)
+            }
+            Some(sync) => {
+                error!(error = %format!("{e:#}"), kind = sync.kind(), "scheduled backup failed");
+                None
+            }
+            None => {
+                error!(error = %format!("{e:#}"), "scheduled backup failed");
+                None
+            }
+        },
+    }
+}
+
+/// Back up every `interval_hours`, aligned to the top of the hour, or sooner
+/// after a transient failure.
+pub async fn scheduler_loop(
+    repo: BackupRepository,
+    job: BackupJob,
+    retention: Retention,
+    interval_hours: u64,
+) {
+    let period = interval_hours.max(1) * 3600;
+    let mut retry_in: Option<Duration> = None;
+    loop {
+        let now = Utc::now().timestamp() as u64;
+        let until_next_run = Duration::from_secs((period - now % period).max(1));
+        sleep(
+            retry_in
+                .take()
+                .map_or(until_next_run, |d| d.min(until_next_run)),
+        )
+        .await;
+
+        retry_in = scheduled_backup(&repo, &job, &retention).await;
+    }
+}
@@ -1,28 +1,180 @@
-use std::io::Cursor;
+use std::collections::HashMap;
 use std::sync::Arc;
 
-use anki_backup_core::{BackupStatus, DeckStats};
-use anki_backup_storage::BackupRepository;
-use anki_backup_sync::SyncConfig;
+use anki_backup_core::{
+    BackupEntry, BackupStatus, CardMove, CollectionDiff, DeckStats, NamedId, NoteChange,
+    NoteSummary, RollbackHistoryEntry, RollbackUpload, RollbackVerification, UploadCheckStatus,
+};
+use anki_backup_storage::{
+    BackupRepository, ImportOutcome, RestoreRejected, RestoreSelection, VerifyReport,
+};
+use anki_backup_sync::{SyncConfig, SyncError};
+
+use crate::export::{deck_file_name, export_backup, ExportFormat};
+use crate::jobs::{check_upload, merge_restore, snapshot_server, with_host_key};
 use askama::Template;
 use askama_web::WebTemplate;
-use axum::extract::{Path, State};
-use axum::http::{header, HeaderMap, StatusCode};
+use axum::body::Bytes;
+use axum::extract::{
+    DefaultBodyLimit, FromRequestParts, OriginalUri, Path, Query, RawPathParams, State,
+};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode, Uri};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{Json, Router};
-use chrono::Utc;
-use serde::Serialize;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
+/// Largest package accepted for import.
+const MAX_IMPORT_BYTES: usize = 1024 * 1024 * 1024;
+
+/// Entries of each list shown on the diff page; the API returns them all.
+const DIFF_PAGE_ITEMS: usize = 200;
+
+/// How long a rollback preview's confirmation token can be used.
+const ROLLBACK_CONFIRM_MINUTES: i64 = 10;
+
+/// Rollbacks listed on the index page; the API returns them all.
+const ROLLBACK_HISTORY_ITEMS: usize = 20;
+
+/// Actor recorded for requests made with the API token when it has no name.
+const DEFAULT_API_TOKEN_NAME: &str = "api token";
+
 #[derive(Clone)]
 pub struct AppState {
-    pub repo: BackupRepository,
-    pub rollback_gate: Arc<Mutex<Option<chrono::DateTime<Utc>>>>,
+    /// Served profiles; the first one also answers the unprefixed routes.
+    pub profiles: Arc<Vec<ProfileState>>,
     pub csrf_token: Option<String>,
     pub api_token: Option<String>,
+    /// Recorded as the actor of rollbacks made with `api_token`.
+    pub api_token_name: Option<String>,
+}
+
+/// One profile's backups and the account rollbacks are uploaded to.
+#[derive(Clone)]
+pub struct ProfileState {
+    pub repo: BackupRepository,
     pub sync_config: Option<SyncConfig>,
+    pub rollback_gate: Arc<Mutex<Option<DateTime<Utc>>>>,
+    /// Previewed rollbacks by confirmation token.
+    pending_rollbacks: Arc<Mutex<HashMap<String, PendingRollback>>>,
+    /// The verification started from `POST /api/v1/verify`, if any.
+    verify_job: Arc<Mutex<VerifyJob>>,
+}
+
+/// A previewed rollback that can be confirmed once.
+#[derive(Debug, Clone)]
+struct PendingRollback {
+    backup_id: Uuid,
+    /// The backup of the server's collection taken for the preview.
+    snapshot_id: Uuid,
+    expires_at: DateTime<Utc>,
+}
+
+impl ProfileState {
+    pub fn new(repo: BackupRepository, sync_config: Option<SyncConfig>) -> Self {
+        Self {
+            repo,
+            sync_config,
+            rollback_gate: Arc::new(Mutex::new(None)),
+            pending_rollbacks: Arc::new(Mutex::new(HashMap::new())),
+            verify_job: Arc::new(Mutex::new(VerifyJob::default())),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.repo.profile()
+    }
+}
+
+/// Progress of the most recent verification started through the API.
+#[derive(Debug, Clone, Default)]
+struct VerifyJob {
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+    /// The report, or the error that stopped the run.
+    result: Option<Result<serde_json::Value, String>>,
+}
+
+impl VerifyJob {
+    fn running(&self) -> bool {
+        self.started_at.is_some() && self.finished_at.is_none()
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let status = match (&self.started_at, &self.result) {
+            (None, _) => "idle",
+            (Some(_), None) => "running",
+            (Some(_), Some(Ok(_))) => "finished",
+            (Some(_), Some(Err(_))) => "failed",
+        };
+        serde_json::json!({
+            "status": status,
+            "started_at": self.started_at,
+            "finished_at": self.finished_at,
+            "report": self.result.as_ref().and_then(|r| r.as_ref().ok()),
+            "error": self.result.as_ref().and_then(|r| r.as_ref().err()),
+        })
+    }
+}
+
+/// The profile named by the `{profile}` path segment, or the first
+/// configured profile on unprefixed routes. Unknown names are a 404.
+struct SelectedProfile {
+    profile: ProfileState,
+    /// Prefix for UI links, e.g. `/profiles/work`; empty on unprefixed routes.
+    base: String,
+}
+
+impl FromRequestParts<AppState> for SelectedProfile {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, StatusCode> {
+        let params = RawPathParams::from_request_parts(parts, state)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let Some((_, name)) = params.iter().find(|(key, _)| *key == "profile") else {
+            let profile = state
+                .profiles
+                .first()
+                .cloned()
+                .ok_or(StatusCode::NOT_FOUND)?;
+            return Ok(Self {
+                profile,
+                base: String::new(),
+            });
+        };
+        let profile = state
+            .profiles
+            .iter()
+            .find(|p| p.name() == name)
+            .cloned()
+            .ok_or(StatusCode::NOT_FOUND)?;
+        Ok(Self {
+            base: format!("/profiles/{name}"),
+            profile,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct BackupPath {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiffPath {
+    a: String,
+    b: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeckPath {
+    id: String,
+    deck_id: i64,
 }
 
 // --- Template view models ---
@@ -35,6 +187,44 @@ struct BackupListItem {
     total_decks: i64,
     total_notes: i64,
     size_display: String,
+    pinned: bool,
+    /// Whether `current-pointer.json` points at this backup.
+    current: bool,
+}
+
+struct RollbackHistoryItem {
+    created_at: String,
+    backup_id: String,
+    /// `None` once the backup has been pruned.
+    backup_created_at: Option<String>,
+    actor: String,
+    upload: String,
+    /// What the check of the upload found, when it wasn't fine.
+    detail: Option<String>,
+}
+
+impl RollbackHistoryItem {
+    fn new(entry: &RollbackHistoryEntry) -> Self {
+        let event = &entry.event;
+        Self {
+            created_at: event.created_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+            backup_id: event.backup_id.to_string(),
+            backup_created_at: entry
+                .backup
+                .as_ref()
+                .map(|b| b.created_at.format("%Y-%m-%d %H:%M:%S UTC").to_string()),
+            actor: event.actor.clone().unwrap_or_else(|| "unknown".to_owned()),
+            upload: event
+                .upload
+                .map_or("unknown", RollbackUpload::as_str)
+                .to_owned(),
+            detail: event
+                .verification
+                .as_ref()
+                .filter(|v| v.status != UploadCheckStatus::Ok)
+                .and_then(|v| v.detail.clone()),
+        }
+    }
 }
 
 struct BackupDetailView {
@@ -43,20 +233,108 @@ struct BackupDetailView {
     status: String,
     content_hash: String,
     size_display: String,
+    stored_size_display: Option<String>,
+    pinned: bool,
+    note: Option<String>,
+    verification: Option<VerificationView>,
+    media_display: Option<String>,
     deck_stats: Vec<DeckStats>,
+    /// The created backup before this one, to diff against.
+    previous_id: Option<String>,
+}
+
+/// One side of a diff.
+struct DiffSide {
+    id: String,
+    created_at: String,
+}
+
+/// Up to [`DIFF_PAGE_ITEMS`] entries of one part of a diff.
+struct DiffSection<T> {
+    items: Vec<T>,
+    total: usize,
+}
+
+impl<T> DiffSection<T> {
+    fn new(mut items: Vec<T>) -> Self {
+        let total = items.len();
+        items.truncate(DIFF_PAGE_ITEMS);
+        Self { items, total }
+    }
+
+    fn hidden(&self) -> usize {
+        self.total - self.items.len()
+    }
+}
+
+struct VerificationView {
+    status: String,
+    checked_at: String,
+    detail: Option<String>,
+}
+
+/// Where the UI is and which profiles it can switch between.
+struct ProfileNav {
+    /// Prefix for links within the current profile.
+    base: String,
+    /// The current profile's backup list.
+    home: String,
+    current: String,
+    /// Empty unless more than one profile is configured.
+    others: Vec<String>,
+}
+
+impl ProfileNav {
+    fn new(state: &AppState, selected: &SelectedProfile) -> Self {
+        let names: Vec<String> = state.profiles.iter().map(|p| p.name().to_owned()).collect();
+        Self {
+            home: if selected.base.is_empty() {
+                "/".to_owned()
+            } else {
+                selected.base.clone()
+            },
+            base: selected.base.clone(),
+            current: selected.profile.name().to_owned(),
+            others: if names.len() > 1 { names } else { Vec::new() },
+        }
+    }
 }
 
 #[derive(Template, WebTemplate)]
 #[template(path = "index.html")]
 struct IndexTemplate {
+    nav: ProfileNav,
     backups: Vec<BackupListItem>,
+    /// The most recent [`ROLLBACK_HISTORY_ITEMS`] rollbacks.
+    rollbacks: Vec<RollbackHistoryItem>,
 }
 
 #[derive(Template, WebTemplate)]
 #[template(path = "detail.html")]
 struct DetailTemplate {
+    nav: ProfileNav,
     backup: BackupDetailView,
     csrf_token: String,
+    /// Whether the profile syncs with a server, so rollbacks are previewed
+    /// and decks can be merge-restored.
+    syncs: bool,
+}
+
+#[derive(Template, WebTemplate)]
+#[template(path = "diff.html")]
+struct DiffTemplate {
+    nav: ProfileNav,
+    from: DiffSide,
+    to: DiffSide,
+    empty: bool,
+    notes_added: DiffSection<NoteSummary>,
+    notes_removed: DiffSection<NoteSummary>,
+    notes_modified: DiffSection<NoteChange>,
+    cards_moved: DiffSection<CardMove>,
+    decks_added: Vec<NamedId>,
+    decks_removed: Vec<NamedId>,
+    note_types_added: Vec<NamedId>,
+    note_types_removed: Vec<NamedId>,
 }
 
 fn format_size(bytes: i64) -> String {
@@ -69,18 +347,51 @@ fn format_size(bytes: i64) -> String {
     }
 }
 
+/// Per-profile routes are served both unprefixed, for the first profile, and
+/// under `/profiles/{profile}` and `/api/v1/profiles/{profile}`.
 pub fn build_router(state: AppState) -> Router {
+    Router::new()
+        .merge(ui_routes())
+        .nest("/profiles/{profile}", ui_routes())
+        .route("/api/v1/healthz", get(healthz))
+        .route("/api/v1/profiles", get(api_list_profiles))
+        .nest("/api/v1", api_routes())
+        .nest("/api/v1/profiles/{profile}", api_routes())
+        .with_state(state)
+}
+
+fn ui_routes() -> Router<AppState> {
     Router::new()
         .route("/", get(index))
         .route("/backups/{id}", get(backup_detail))
+        .route("/backups/{a}/diff/{b}", get(diff_page))
         .route("/backups/{id}/download", get(download_backup))
+        .route("/backups/{id}/decks/{deck_id}/download", get(download_deck))
+        .route("/backups/{id}/rollback/preview", post(preview_rollback))
         .route("/backups/{id}/rollback", post(rollback_backup))
-        .route("/api/v1/healthz", get(healthz))
-        .route("/api/v1/backups", get(api_list_backups))
-        .route("/api/v1/backups/{id}", get(api_backup_detail))
-        .route("/api/v1/backups/{id}/download", get(download_backup))
-        .route("/api/v1/backups/{id}/rollback", post(rollback_backup))
-        .with_state(state)
+        .route("/backups/{id}/restore", post(restore_backup))
+        .route("/backups/{id}/pin", post(pin_backup))
+        .route("/backups/{id}/unpin", post(unpin_backup))
+}
+
+fn api_routes() -> Router<AppState> {
+    Router::new()
+        .route("/backups", get(api_list_backups))
+        .route(
+            "/backups/import",
+            post(api_import_backup).layer(DefaultBodyLimit::max(MAX_IMPORT_BYTES)),
+        )
+        .route("/backups/{id}", get(api_backup_detail))
+        .route("/backups/{a}/diff/{b}", get(api_diff_backups))
+        .route("/backups/{id}/download", get(download_backup))
+        .route("/backups/{id}/decks/{deck_id}/download", get(download_deck))
+        .route("/backups/{id}/rollback/preview", post(preview_rollback))
+        .route("/backups/{id}/rollback", post(rollback_backup))
+        .route("/backups/{id}/restore", post(restore_backup))
+        .route("/backups/{id}/pin", post(pin_backup))
+        .route("/backups/{id}/unpin", post(unpin_backup))
+        .route("/rollbacks", get(api_list_rollbacks))
+        .route("/verify", get(api_verify_status).post(api_verify))
 }
 
 #[derive(Debug, Serialize)]
@@ -92,9 +403,14 @@ async fn healthz() -> Json<HealthzResponse> {
     Json(HealthzResponse { status: "ok" })
 }
 
-fn require_api_auth(state: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
+/// Check the bearer token when one is configured, returning the name of
+/// the token the request was authenticated with.
+fn require_api_auth<'a>(
+    state: &'a AppState,
+    headers: &HeaderMap,
+) -> Result<Option<&'a str>, StatusCode> {
     let Some(expected) = &state.api_token else {
-        return Ok(());
+        return Ok(None);
     };
 
     let provided = headers
@@ -103,17 +419,94 @@ fn require_api_auth(state: &AppState, headers: &HeaderMap) -> Result<(), StatusC
         .and_then(|v| v.strip_prefix("Bearer "));
 
     match provided {
-        Some(token) if token == expected => Ok(()),
+        Some(token) if token == expected => Ok(Some(
+            state
+                .api_token_name
+                .as_deref()
+                .unwrap_or(DEFAULT_API_TOKEN_NAME),
+        )),
         _ => Err(StatusCode::UNAUTHORIZED),
     }
 }
 
+async fn api_list_profiles(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<serde_json::Value>>, StatusCode> {
+    require_api_auth(&state, &headers)?;
+    let mut json = Vec::with_capacity(state.profiles.len());
+    for profile in state.profiles.iter() {
+        let backups = profile
+            .repo
+            .list_backups()
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let created: Vec<_> = backups
+            .iter()
+            .filter(|b| b.status == BackupStatus::Created)
+            .collect();
+        let latest = created.iter().max_by_key(|b| b.created_at);
+        json.push(serde_json::json!({
+            "name": profile.name(),
+            "backups": created.len(),
+            "latest_backup_id": latest.map(|b| b.id),
+            "latest_backup_at": latest.map(|b| b.created_at),
+            "sync_configured": profile.sync_config.is_some(),
+        }));
+    }
+    Ok(Json(json))
+}
+
+/// Rollbacks of the profile, newest first, and the backup the current
+/// pointer names.
+async fn api_list_rollbacks(
+    selected: SelectedProfile,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    require_api_auth(&state, &headers)?;
+    let repo = &selected.profile.repo;
+    let current = repo
+        .current_backup_id()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let history = repo
+        .list_rollback_history()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let rollbacks: Vec<_> = history
+        .into_iter()
+        .map(|r| {
+            serde_json::json!({
+                "id": r.event.id,
+                "backup_id": r.event.backup_id,
+                "created_at": r.event.created_at,
+                "actor": r.event.actor,
+                "upload": r.event.upload,
+                "verification": r.event.verification,
+                "backup": r.backup.map(|b| serde_json::json!({
+                    "id": b.id,
+                    "created_at": b.created_at,
+                    "pinned": b.pinned,
+                    "note": b.note,
+                })),
+            })
+        })
+        .collect();
+    Ok(Json(serde_json::json!({
+        "current_backup_id": current,
+        "rollbacks": rollbacks,
+    })))
+}
+
 async fn api_list_backups(
+    selected: SelectedProfile,
     State(state): State<AppState>,
     headers: HeaderMap,
 ) -> Result<Json<Vec<serde_json::Value>>, StatusCode> {
     require_api_auth(&state, &headers)?;
-    let rows = state
+    let rows = selected
+        .profile
         .repo
         .list_backups()
         .await
@@ -126,6 +519,10 @@ async fn api_list_backups(
                 "created_at": b.created_at,
                 "status": format!("{:?}", b.status),
                 "size_bytes": b.size_bytes,
+                "stored_size_bytes": b.stored_size_bytes,
+                "pinned": b.pinned,
+                "note": b.note,
+                "verification": b.verification,
                 "stats": b.stats,
             })
         })
@@ -134,13 +531,15 @@ async fn api_list_backups(
 }
 
 async fn api_backup_detail(
-    Path(id): Path<String>,
+    Path(BackupPath { id }): Path<BackupPath>,
+    selected: SelectedProfile,
     State(state): State<AppState>,
     headers: HeaderMap,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
     require_api_auth(&state, &headers)?;
     let id = Uuid::parse_str(&id).map_err(|_| StatusCode::BAD_REQUEST)?;
-    let backup = state
+    let backup = selected
+        .profile
         .repo
         .get_backup(id)
         .await
@@ -149,12 +548,142 @@ async fn api_backup_detail(
     Ok(Json(serde_json::json!(backup)))
 }
 
-async fn rollback_backup(
-    Path(id): Path<String>,
+async fn api_diff_backups(
+    Path(DiffPath { a, b }): Path<DiffPath>,
+    selected: SelectedProfile,
     State(state): State<AppState>,
     headers: HeaderMap,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
     require_api_auth(&state, &headers)?;
+    let repo = &selected.profile.repo;
+    let (from, to) = (
+        created_backup(repo, &a).await?,
+        created_backup(repo, &b).await?,
+    );
+    let diff = diff_backups(repo, &from, &to).await?;
+    Ok(Json(serde_json::json!({
+        "from": from.id,
+        "to": to.id,
+        "diff": diff,
+    })))
+}
+
+/// Start verifying every backup in the background, unless a run is already
+/// going, and return the job's status. Poll `GET /api/v1/verify` for the report.
+async fn api_verify(
+    selected: SelectedProfile,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    require_api_auth(&state, &headers)?;
+    let profile = selected.profile;
+    let mut job = profile.verify_job.lock().await;
+    if !job.running() {
+        *job = VerifyJob {
+            started_at: Some(Utc::now()),
+            ..VerifyJob::default()
+        };
+        let (repo, verify_job) = (profile.repo.clone(), profile.verify_job.clone());
+        tokio::spawn(async move {
+            let result = match repo.verify().await {
+                Ok(report) => Ok(verify_report_json(&report)),
+                Err(e) => {
+                    tracing::error!(error = %format!("{e:#}"), "backup verification failed");
+                    Err(format!("{e:#}"))
+                }
+            };
+            let mut job = verify_job.lock().await;
+            job.finished_at = Some(Utc::now());
+            job.result = Some(result);
+        });
+    }
+    Ok((StatusCode::ACCEPTED, Json(job.to_json())))
+}
+
+/// Status of the verification started by `POST /api/v1/verify`, with its
+/// report once finished.
+async fn api_verify_status(
+    selected: SelectedProfile,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    require_api_auth(&state, &headers)?;
+    let job = selected.profile.verify_job.lock().await;
+    Ok(Json(job.to_json()))
+}
+
+fn verify_report_json(report: &VerifyReport) -> serde_json::Value {
+    let failures: Vec<_> = report
+        .failures
+        .iter()
+        .map(|f| {
+            serde_json::json!({
+                "backup_id": f.backup_id,
+                "timestamp_dir": f.timestamp_dir,
+                "status": f.status,
+                "detail": f.detail,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "checked": report.checked,
+        "clean": report.is_clean(),
+        "failures": failures,
+        "orphaned_dirs": report.orphaned_dirs,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportQuery {
+    /// Date the backup at this time instead of the collection's own
+    /// modification time.
+    created_at: Option<DateTime<Utc>>,
+}
+
+/// Import a `.colpkg` / `.apkg` sent as the request body.
+async fn api_import_backup(
+    selected: SelectedProfile,
+    State(state): State<AppState>,
+    Query(query): Query<ImportQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    require_api_auth(&state, &headers)?;
+    require_csrf(&state, &headers)?;
+    let outcome = selected
+        .profile
+        .repo
+        .import_package(body.to_vec(), query.created_at)
+        .await
+        .map_err(|e| {
+            tracing::warn!(error = %format!("{e:#}"), "package import failed");
+            StatusCode::BAD_REQUEST
+        })?;
+    Ok(match outcome {
+        ImportOutcome::Imported { entry, format } => {
+            tracing::info!(backup_id = %entry.id, format = format.as_str(), "package imported");
+            (
+                StatusCode::CREATED,
+                Json(serde_json::json!({
+                    "id": entry.id,
+                    "created_at": entry.created_at,
+                    "format": format.as_str(),
+                    "duplicate": false,
+                })),
+            )
+        }
+        ImportOutcome::Duplicate(existing) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "id": existing.id,
+                "created_at": existing.created_at,
+                "duplicate": true,
+            })),
+        ),
+    })
+}
+
+fn require_csrf(state: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
     if let Some(expected_csrf) = &state.csrf_token {
         let provided = headers
             .get("x-csrf-token")
@@ -164,7 +693,134 @@ async fn rollback_backup(
             return Err(StatusCode::FORBIDDEN);
         }
     }
-    let mut gate = state.rollback_gate.lock().await;
+    Ok(())
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PinRequest {
+    note: Option<String>,
+}
+
+async fn pin_backup(
+    Path(BackupPath { id }): Path<BackupPath>,
+    selected: SelectedProfile,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Option<Json<PinRequest>>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let note = body.map(|Json(b)| b).unwrap_or_default().note;
+    set_pinned(&state, &selected.profile, &headers, &id, true, note).await
+}
+
+async fn unpin_backup(
+    Path(BackupPath { id }): Path<BackupPath>,
+    selected: SelectedProfile,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    set_pinned(&state, &selected.profile, &headers, &id, false, None).await
+}
+
+async fn set_pinned(
+    state: &AppState,
+    profile: &ProfileState,
+    headers: &HeaderMap,
+    id: &str,
+    pinned: bool,
+    note: Option<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    require_api_auth(state, headers)?;
+    require_csrf(state, headers)?;
+    let id = Uuid::parse_str(id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    profile
+        .repo
+        .get_backup(id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let updated = profile
+        .repo
+        .set_pinned(id, pinned, note)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    tracing::info!(profile = profile.name(), backup_id = %id, pinned, "updated backup pin");
+    Ok(Json(
+        serde_json::json!({"id": updated.id, "pinned": updated.pinned, "note": updated.note}),
+    ))
+}
+
+/// Back up the server's collection as it is now and report what rolling
+/// back to this backup would undo, with a token to confirm the rollback.
+async fn preview_rollback(
+    Path(BackupPath { id }): Path<BackupPath>,
+    SelectedProfile { profile, .. }: SelectedProfile,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    require_api_auth(&state, &headers)?;
+    require_csrf(&state, &headers)?;
+    let Some(sync_cfg) = &profile.sync_config else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    let target = created_backup(&profile.repo, &id).await?;
+
+    let snapshot = snapshot_server(&profile.repo, sync_cfg)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %format!("{e:#}"), "failed to back up the server's collection before rollback");
+            StatusCode::BAD_GATEWAY
+        })?;
+    let preview = profile
+        .repo
+        .rollback_preview(&snapshot, &target)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, backup_id = %target.id, "failed to preview rollback");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let token = Uuid::new_v4().simple().to_string();
+    let expires_at = Utc::now() + Duration::minutes(ROLLBACK_CONFIRM_MINUTES);
+    let mut pending = profile.pending_rollbacks.lock().await;
+    pending.retain(|_, p| p.expires_at > Utc::now());
+    pending.insert(
+        token.clone(),
+        PendingRollback {
+            backup_id: target.id,
+            snapshot_id: snapshot.id,
+            expires_at,
+        },
+    );
+    tracing::info!(backup_id = %target.id, snapshot_id = %snapshot.id, lost_reviews = preview.lost_reviews.len(), "rollback previewed");
+    Ok(Json(serde_json::json!({
+        "backup_id": target.id,
+        "snapshot_id": snapshot.id,
+        "confirm_token": token,
+        "expires_at": expires_at,
+        "lost_review_count": preview.lost_reviews.len(),
+        "preview": preview,
+    })))
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RollbackRequest {
+    confirm_token: Option<String>,
+}
+
+/// Roll back to this backup. When the profile syncs, the upload replaces the
+/// server's collection, so this needs the token from a preview of the same
+/// rollback; each token works once.
+async fn rollback_backup(
+    Path(BackupPath { id }): Path<BackupPath>,
+    SelectedProfile { profile, .. }: SelectedProfile,
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    body: Option<Json<RollbackRequest>>,
+) -> Result<Response, StatusCode> {
+    let identity = require_api_auth(&state, &headers)?;
+    require_csrf(&state, &headers)?;
+    let mut gate = profile.rollback_gate.lock().await;
     if let Some(last) = *gate {
         if (Utc::now() - last).num_seconds() < 10 {
             return Err(StatusCode::TOO_MANY_REQUESTS);
@@ -172,93 +828,320 @@ async fn rollback_backup(
     }
 
     let id = Uuid::parse_str(&id).map_err(|_| StatusCode::BAD_REQUEST)?;
-    let rolled = state
+    let mut snapshot_id = None;
+    if let Some(sync_cfg) = &profile.sync_config {
+        let token = body
+            .and_then(|Json(b)| b.confirm_token)
+            .ok_or(StatusCode::CONFLICT)?;
+        let previewed = match profile.pending_rollbacks.lock().await.remove(&token) {
+            Some(p) if p.backup_id == id && p.expires_at > Utc::now() => p.snapshot_id,
+            _ => return Err(StatusCode::CONFLICT),
+        };
+        // The server may have been synced since the preview; the token only
+        // covers overwriting the collection that was snapshotted then.
+        let snapshot = snapshot_server(&profile.repo, sync_cfg)
+            .await
+            .map_err(|e| {
+                tracing::error!(error = %format!("{e:#}"), "failed to back up the server's collection before rollback");
+                StatusCode::BAD_GATEWAY
+            })?;
+        if snapshot.id != previewed {
+            tracing::warn!(previewed = %previewed, current = %snapshot.id, "server changed since the rollback preview");
+            return Err(StatusCode::CONFLICT);
+        }
+        snapshot_id = Some(previewed);
+    }
+    let upload = if profile.sync_config.is_some() {
+        RollbackUpload::Pending
+    } else {
+        RollbackUpload::Local
+    };
+    let (rolled, event) = profile
         .repo
-        .rollback_to(id)
+        .rollback_to(id, rollback_actor(identity, &uri), upload)
         .await
         .map_err(|_| StatusCode::BAD_REQUEST)?;
 
     // Upload the rolled-back collection to AnkiWeb
-    if let Some(sync_cfg) = &state.sync_config {
-        let backup_path = state.repo.backup_file_path(&rolled);
-        let collection_bytes = std::fs::read(&backup_path).map_err(|e| {
-            tracing::error!(error = %e, ?backup_path, "failed to read backup file for upload");
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-        anki_backup_sync::upload_collection(sync_cfg, &collection_bytes)
-            .await
-            .map_err(|e| {
-                tracing::error!(error = %e, "failed to upload rolled-back collection to AnkiWeb");
-                StatusCode::BAD_GATEWAY
-            })?;
-        tracing::info!(backup_id = %rolled.id, "uploaded rolled-back collection to AnkiWeb");
+    let mut verification = None;
+    if let Some(sync_cfg) = &profile.sync_config {
+        let uploaded = match profile.repo.read_backup(&rolled).await {
+            Ok(collection_bytes) => {
+                let bytes = &collection_bytes;
+                with_host_key(&profile.repo, sync_cfg, |cfg| async move {
+                    anki_backup_sync::upload_collection(&cfg, bytes).await
+                })
+                .await
+                .map_err(|e| {
+                    tracing::error!(error = %e, "failed to upload rolled-back collection to AnkiWeb");
+                    StatusCode::BAD_GATEWAY
+                })
+            }
+            Err(e) => {
+                tracing::error!(error = %e, backup_id = %rolled.id, "failed to read backup for upload");
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        };
+        if let Err(status) = uploaded {
+            record_rollback_upload(&profile.repo, event.id, RollbackUpload::Failed, None).await;
+            return Err(status);
+        }
+        tracing::info!(backup_id = %rolled.id, snapshot_id = ?snapshot_id, "uploaded rolled-back collection to AnkiWeb");
+
+        let checked = check_upload(&profile.repo, sync_cfg, &rolled).await;
+        let upload = if checked.status == UploadCheckStatus::Diverged {
+            RollbackUpload::Failed
+        } else {
+            RollbackUpload::Uploaded
+        };
+        record_rollback_upload(&profile.repo, event.id, upload, Some(&checked)).await;
+        verification = Some(checked);
     } else {
         tracing::warn!("no AnkiWeb credentials configured; rollback is local-only");
     }
 
     *gate = Some(Utc::now());
-    Ok(Json(serde_json::json!({"rolled_back_to": rolled.id, "uploaded": state.sync_config.is_some()})))
+    // A server that doesn't hold what was uploaded means the rollback failed,
+    // even though the pointer moved and the upload was accepted.
+    let failed = verification
+        .as_ref()
+        .is_some_and(|v| v.status == UploadCheckStatus::Diverged);
+    let body = Json(serde_json::json!({
+        "rolled_back_to": rolled.id,
+        "rollback_id": event.id,
+        "uploaded": profile.sync_config.is_some(),
+        "snapshot_id": snapshot_id,
+        "status": if failed { "failed" } else { "ok" },
+        "verification": verification,
+    }));
+    if failed {
+        return Ok((StatusCode::BAD_GATEWAY, body).into_response());
+    }
+    Ok(body.into_response())
 }
 
-async fn download_backup(
-    Path(id): Path<String>,
+/// Who a rollback request came from, for its event: the API token it was
+/// authenticated with, or without a token configured, the web UI or the API.
+fn rollback_actor<'a>(identity: Option<&'a str>, uri: &Uri) -> &'a str {
+    match identity {
+        Some(name) => name,
+        None if uri.path().starts_with("/api/") => "api",
+        None => "ui",
+    }
+}
+
+/// Log rather than fail: the rollback itself has already happened.
+async fn record_rollback_upload(
+    repo: &BackupRepository,
+    event_id: Uuid,
+    upload: RollbackUpload,
+    verification: Option<&RollbackVerification>,
+) {
+    if let Err(e) = repo
+        .record_rollback_upload(event_id, upload, verification)
+        .await
+    {
+        tracing::error!(error = %e, rollback_id = %event_id, "failed to record rollback upload");
+    }
+}
+
+/// Graft notes, cards or decks from a backup into the collection on
+/// AnkiWeb, keeping everything else (and all review history) as it is now.
+async fn restore_backup(
+    Path(BackupPath { id }): Path<BackupPath>,
+    SelectedProfile { profile, .. }: SelectedProfile,
     State(state): State<AppState>,
     headers: HeaderMap,
-) -> Result<Response, StatusCode> {
+    Json(selection): Json<RestoreSelection>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
     require_api_auth(&state, &headers)?;
-    let id = Uuid::parse_str(&id).map_err(|_| StatusCode::BAD_REQUEST)?;
-    let backup = state
-        .repo
-        .get_backup(id)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-        .ok_or(StatusCode::NOT_FOUND)?;
-    if backup.status != BackupStatus::Created {
+    require_csrf(&state, &headers)?;
+    let Some(sync_cfg) = &profile.sync_config else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    if selection.is_empty() {
         return Err(StatusCode::BAD_REQUEST);
     }
+    let mut gate = profile.rollback_gate.lock().await;
+    if let Some(last) = *gate {
+        if (Utc::now() - last).num_seconds() < 10 {
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+    }
 
-    let source = state.repo.backup_file_path(&backup);
-    let bytes = std::fs::read(&source).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let backup = created_backup(&profile.repo, &id).await?;
+    let outcome = merge_restore(&profile.repo, sync_cfg, &backup, &selection)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %format!("{e:#}"), backup_id = %backup.id, "merge restore failed");
+            if SyncError::classify(&e).is_some() {
+                StatusCode::BAD_GATEWAY
+            } else if RestoreRejected::classify(&e).is_some() {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
 
-    // Build tar archive
-    let mut tar_data = Vec::new();
-    {
-        let mut builder = tar::Builder::new(&mut tar_data);
-        let mut hdr = tar::Header::new_gnu();
-        hdr.set_size(bytes.len() as u64);
-        hdr.set_mode(0o644);
-        hdr.set_cksum();
-        builder
-            .append_data(&mut hdr, "collection.anki2", Cursor::new(bytes))
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        builder
-            .finish()
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    }
+    *gate = Some(Utc::now());
+    Ok(Json(serde_json::json!({
+        "restored_from": backup.id,
+        "snapshot_id": outcome.snapshot.id,
+        "report": outcome.report,
+    })))
+}
 
-    // Compress with zstd
-    let compressed = zstd::encode_all(Cursor::new(&tar_data), 3)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+#[derive(Debug, Deserialize)]
+struct DownloadQuery {
+    format: Option<String>,
+}
+
+async fn download_backup(
+    Path(BackupPath { id }): Path<BackupPath>,
+    Query(query): Query<DownloadQuery>,
+    SelectedProfile { profile, .. }: SelectedProfile,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    require_api_auth(&state, &headers)?;
+    let format = match query.format.as_deref() {
+        Some(format) => format
+            .parse::<ExportFormat>()
+            .map_err(|_| StatusCode::BAD_REQUEST)?,
+        None => ExportFormat::default(),
+    };
+    let backup = created_backup(&profile.repo, &id).await?;
+
+    let bytes = export_backup(&profile.repo, &backup, format)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, backup_id = %backup.id, "failed to export backup");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
 
-    let mut response = compressed.into_response();
+    let mut response = bytes.into_response();
     response
         .headers_mut()
-        .insert(header::CONTENT_TYPE, "application/zstd".parse().unwrap());
+        .insert(header::CONTENT_TYPE, format.content_type().parse().unwrap());
     response.headers_mut().insert(
         header::CONTENT_DISPOSITION,
-        format!("attachment; filename=backup-{}.tar.zst", backup.id)
+        format!("attachment; filename={}", format.file_name(&backup))
             .parse()
             .unwrap(),
     );
     Ok(response)
 }
 
-async fn index(State(state): State<AppState>) -> Result<IndexTemplate, StatusCode> {
-    let backups = state
+/// One deck of a backup, with its subdecks, as an `.apkg`.
+async fn download_deck(
+    Path(DeckPath { id, deck_id }): Path<DeckPath>,
+    SelectedProfile { profile, .. }: SelectedProfile,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    require_api_auth(&state, &headers)?;
+    let backup = created_backup(&profile.repo, &id).await?;
+
+    let bytes = profile
         .repo
+        .export_deck_package(&backup, deck_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, backup_id = %backup.id, deck_id, "failed to export deck");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut response = bytes.into_response();
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        "application/octet-stream".parse().unwrap(),
+    );
+    response.headers_mut().insert(
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename={}", deck_file_name(&backup, deck_id))
+            .parse()
+            .unwrap(),
+    );
+    Ok(response)
+}
+
+/// The created backup `id` refers to: 400 for a malformed id or a skipped
+/// backup, 404 for an unknown one.
+async fn created_backup(repo: &BackupRepository, id: &str) -> Result<BackupEntry, StatusCode> {
+    let id = Uuid::parse_str(id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let backup = repo
+        .get_backup(id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if backup.status != BackupStatus::Created {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(backup)
+}
+
+async fn diff_backups(
+    repo: &BackupRepository,
+    from: &BackupEntry,
+    to: &BackupEntry,
+) -> Result<CollectionDiff, StatusCode> {
+    repo.diff_backups(from, to).await.map_err(|e| {
+        tracing::error!(error = %e, from = %from.id, to = %to.id, "failed to diff backups");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+async fn diff_page(
+    Path(DiffPath { a, b }): Path<DiffPath>,
+    selected: SelectedProfile,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<DiffTemplate, StatusCode> {
+    require_api_auth(&state, &headers)?;
+    let repo = &selected.profile.repo;
+    let (from, to) = (
+        created_backup(repo, &a).await?,
+        created_backup(repo, &b).await?,
+    );
+    let diff = diff_backups(repo, &from, &to).await?;
+    let side = |b: &BackupEntry| DiffSide {
+        id: b.id.to_string(),
+        created_at: b.created_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+    };
+    Ok(DiffTemplate {
+        nav: ProfileNav::new(&state, &selected),
+        from: side(&from),
+        to: side(&to),
+        empty: diff.is_empty(),
+        notes_added: DiffSection::new(diff.notes_added),
+        notes_removed: DiffSection::new(diff.notes_removed),
+        notes_modified: DiffSection::new(diff.notes_modified),
+        cards_moved: DiffSection::new(diff.cards_moved),
+        decks_added: diff.decks_added,
+        decks_removed: diff.decks_removed,
+        note_types_added: diff.note_types_added,
+        note_types_removed: diff.note_types_removed,
+    })
+}
+
+async fn index(
+    selected: SelectedProfile,
+    State(state): State<AppState>,
+) -> Result<IndexTemplate, StatusCode> {
+    let repo = &selected.profile.repo;
+    let backups = repo
         .list_backups()
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let current = repo
+        .current_backup_id()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let rollbacks = repo
+        .list_rollback_history()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let items = backups
         .into_iter()
         .map(|b| {
@@ -274,18 +1157,30 @@ async fn index(State(state): State<AppState>) -> Result<IndexTemplate, StatusCod
                 total_decks: stats.map(|s| s.total_decks).unwrap_or(0),
                 total_notes: stats.map(|s| s.total_notes).unwrap_or(0),
                 size_display: format_size(b.size_bytes),
+                pinned: b.pinned,
+                current: current == Some(b.id),
             }
         })
         .collect();
-    Ok(IndexTemplate { backups: items })
+    Ok(IndexTemplate {
+        nav: ProfileNav::new(&state, &selected),
+        backups: items,
+        rollbacks: rollbacks
+            .iter()
+            .take(ROLLBACK_HISTORY_ITEMS)
+            .map(RollbackHistoryItem::new)
+            .collect(),
+    })
 }
 
 async fn backup_detail(
-    Path(id): Path<String>,
+    Path(BackupPath { id }): Path<BackupPath>,
+    selected: SelectedProfile,
     State(state): State<AppState>,
 ) -> Result<DetailTemplate, StatusCode> {
     let id = Uuid::parse_str(&id).map_err(|_| StatusCode::BAD_REQUEST)?;
-    let b = state
+    let b = selected
+        .profile
         .repo
         .get_backup(id)
         .await
@@ -298,7 +1193,33 @@ async fn backup_detail(
         .map(|s| s.deck_stats.clone())
         .unwrap_or_default();
 
+    let media_display = match &b.media_set {
+        Some(set_id) => Some(match selected.profile.repo.read_media_set(set_id).await {
+            Ok(set) => format!(
+                "{} files, {}",
+                set.files.len(),
+                format_size(set.total_size() as i64)
+            ),
+            Err(e) => {
+                tracing::warn!(error = %e, media_set = %set_id, "failed to read media set");
+                "unavailable".to_owned()
+            }
+        }),
+        None => None,
+    };
+
+    let previous_id = selected
+        .profile
+        .repo
+        .list_backups()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .find(|p| p.status == BackupStatus::Created && p.created_at < b.created_at)
+        .map(|p| p.id.to_string());
+
     Ok(DetailTemplate {
+        nav: ProfileNav::new(&state, &selected),
         backup: BackupDetailView {
             id: b.id.to_string(),
             created_at: b.created_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
@@ -308,8 +1229,19 @@ async fn backup_detail(
             },
             content_hash: b.content_hash.clone(),
             size_display: format_size(b.size_bytes),
+            stored_size_display: b.stored_size_bytes.map(format_size),
+            pinned: b.pinned,
+            note: b.note.clone(),
+            verification: b.verification.as_ref().map(|v| VerificationView {
+                status: v.status.as_str().to_owned(),
+                checked_at: v.checked_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+                detail: v.detail.clone(),
+            }),
+            media_display,
             deck_stats,
+            previous_id,
         },
         csrf_token: state.csrf_token.clone().unwrap_or_default(),
+        syncs: selected.profile.sync_config.is_some(),
     })
 }
@@ -1,11 +1,17 @@
 use std::sync::Arc;
 
-use anki_backup_core::content_hash;
-use anki_backup_daemon::{build_router, AppState};
-use anki_backup_storage::{BackupPayload, BackupRepository, RunOnceOutcome};
+use anki_backup_core::{content_hash, BackupStatus};
+use anki_backup_daemon::jobs::BackupJob;
+use anki_backup_daemon::scheduler::{scheduled_backup, Retention, TRANSIENT_RETRY_DELAY};
+use anki_backup_daemon::{build_router, AppState, ProfileState};
+use anki_backup_storage::test_support::LegacyCollection;
+use anki_backup_storage::{
+    BackupPayload, BackupRepository, ImportOutcome, KeySource, MediaSet, Package, RunOnceOutcome,
+};
+use anki_backup_sync::fake_server::{FakeSyncServer, Fault, FAKE_PASSWORD, FAKE_USERNAME};
+use anki_backup_sync::{LocalProfile, RetryPolicy, SyncConfig};
 use chrono::Utc;
 use rusqlite::Connection;
-use tokio::sync::Mutex;
 
 fn sample_collection() -> Vec<u8> {
     let tmp = tempfile::NamedTempFile::new().unwrap();
@@ -52,13 +58,38 @@ async fn start_server(
     api_token: Option<String>,
     csrf_token: Option<String>,
 ) -> TestServer {
-    let state = AppState {
-        repo,
-        rollback_gate: Arc::new(Mutex::new(None)),
+    start_server_with_sync(repo, api_token, csrf_token, None).await
+}
+
+async fn start_server_with_sync(
+    repo: BackupRepository,
+    api_token: Option<String>,
+    csrf_token: Option<String>,
+    sync_config: Option<SyncConfig>,
+) -> TestServer {
+    start_server_with_profiles(
+        vec![ProfileState::new(repo, sync_config)],
+        api_token,
+        csrf_token,
+    )
+    .await
+}
+
+async fn start_server_with_profiles(
+    profiles: Vec<ProfileState>,
+    api_token: Option<String>,
+    csrf_token: Option<String>,
+) -> TestServer {
+    serve(AppState {
+        profiles: Arc::new(profiles),
         csrf_token,
         api_token,
-        sync_config: None,
-    };
+        api_token_name: None,
+    })
+    .await
+}
+
+async fn serve(state: AppState) -> TestServer {
     let app = build_router(state);
     let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
@@ -79,6 +110,7 @@ async fn create_backup(repo: &BackupRepository, data: &[u8]) -> RunOnceOutcome {
             bytes: data.to_vec(),
             source_revision: None,
             sync_duration_ms: Some(1),
+            media_set: None,
         },
         hash,
     )
@@ -252,20 +284,24 @@ async fn test_retention_pruning() {
         RunOnceOutcome::Created(e) => e,
         _ => panic!("expected created"),
     };
+    let current = match create_backup(&repo, &sample_collection_v2()).await {
+        RunOnceOutcome::Created(e) => e,
+        _ => panic!("expected created"),
+    };
 
-    // Backdate the entry
+    // Backdate both entries; the current one must survive anyway
     let conn = rusqlite::Connection::open(tmp.path().join("state").join("metadata.db")).unwrap();
     let old = (Utc::now() - chrono::Duration::days(200)).to_rfc3339();
-    conn.execute(
-        "UPDATE backups SET created_at = ?1 WHERE id = ?2",
-        rusqlite::params![old, entry.id.to_string()],
-    )
-    .unwrap();
+    conn.execute("UPDATE backups SET created_at = ?1", rusqlite::params![old])
+        .unwrap();
     drop(conn);
 
     let removed = repo.prune_created_older_than_days(90).await.unwrap();
     assert_eq!(removed, 1);
-    assert!(repo.list_backups().await.unwrap().is_empty());
+    let remaining = repo.list_backups().await.unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].id, current.id);
+    assert!(repo.get_backup(entry.id).await.unwrap().is_none());
 }
 
 #[tokio::test]
@@ -284,6 +320,30 @@ async fn test_api_auth_rejected_without_token() {
     assert_eq!(resp.status(), 401);
 }
 
+#[tokio::test]
+async fn test_diff_page_requires_token() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let RunOnceOutcome::Created(entry) = create_backup(&repo, &spanish_collection().build()).await
+    else {
+        panic!("expected created");
+    };
+    let srv = start_server(repo, Some("secret-token".to_string()), None).await;
+
+    // Diff pages show note contents, so they need the token like downloads.
+    let diff = format!("{}/backups/{}/diff/{}", srv.base_url, entry.id, entry.id);
+    let resp = srv.client.get(&diff).send().await.unwrap();
+    assert_eq!(resp.status(), 401);
+    let resp = srv
+        .client
+        .get(&diff)
+        .bearer_auth("secret-token")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+}
+
 #[tokio::test]
 async fn test_api_auth_accepted_with_token() {
     let tmp = tempfile::tempdir().unwrap();
@@ -355,3 +415,1171 @@ async fn test_backup_detail_html() {
     assert!(body.contains("Default"));
     assert!(body.contains("Spanish"));
 }
+
+#[tokio::test]
+async fn test_backup_detail_shows_media() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let mut set = MediaSet::default();
+    set.files.insert(
+        "cat.jpg".to_owned(),
+        repo.put_media_file(b"meow").await.unwrap(),
+    );
+    let media_set = repo.write_media_set(&set).await.unwrap();
+    let data = sample_collection();
+    let id = match repo
+        .run_once(
+            BackupPayload {
+                bytes: data.clone(),
+                source_revision: None,
+                sync_duration_ms: None,
+                media_set: Some(media_set.clone()),
+            },
+            content_hash(&data),
+        )
+        .await
+        .unwrap()
+    {
+        RunOnceOutcome::Created(e) => e.id,
+        _ => panic!("expected created"),
+    };
+    let srv = start_server(repo, None, None).await;
+
+    let body = srv
+        .client
+        .get(format!("{}/backups/{id}", srv.base_url))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(body.contains("1 files, 4 B"));
+
+    let detail: serde_json::Value = srv
+        .client
+        .get(format!("{}/api/v1/backups/{id}", srv.base_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(detail["media_set"], media_set);
+}
+
+#[tokio::test]
+async fn test_pin_and_unpin_backup() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let outcome = create_backup(&repo, &sample_collection()).await;
+    let id = match outcome {
+        RunOnceOutcome::Created(e) => e.id,
+        _ => panic!("expected created"),
+    };
+    let srv = start_server(repo, None, Some("csrf-secret".to_string())).await;
+
+    // Without CSRF token -> 403
+    let resp = srv
+        .client
+        .post(format!("{}/api/v1/backups/{id}/pin", srv.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+
+    let resp = srv
+        .client
+        .post(format!("{}/api/v1/backups/{id}/pin", srv.base_url))
+        .header("x-csrf-token", "csrf-secret")
+        .json(&serde_json::json!({"note": "before add-on install"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let body: serde_json::Value = srv
+        .client
+        .get(format!("{}/api/v1/backups/{id}", srv.base_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body["pinned"], true);
+    assert_eq!(body["note"], "before add-on install");
+
+    let index = srv
+        .client
+        .get(format!("{}/", srv.base_url))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(index.contains("badge-pinned"));
+    let detail = srv
+        .client
+        .get(format!("{}/backups/{id}", srv.base_url))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(detail.contains("before add-on install"));
+
+    let resp = srv
+        .client
+        .post(format!("{}/api/v1/backups/{id}/unpin", srv.base_url))
+        .header("x-csrf-token", "csrf-secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["pinned"], false);
+    assert!(body["note"].is_null());
+}
+
+#[tokio::test]
+async fn test_verify_endpoint() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let id = match create_backup(&repo, &sample_collection()).await {
+        RunOnceOutcome::Created(e) => e.id,
+        _ => panic!("expected created"),
+    };
+    let srv = start_server(repo, None, None).await;
+
+    let idle: serde_json::Value = srv
+        .client
+        .get(format!("{}/api/v1/verify", srv.base_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(idle["status"], "idle");
+
+    let body = run_verify(&srv).await;
+    assert_eq!(body["checked"], 1);
+    assert_eq!(body["clean"], true);
+
+    let body: serde_json::Value = srv
+        .client
+        .get(format!("{}/api/v1/backups/{id}", srv.base_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body["verification"]["status"], "ok");
+
+    std::fs::create_dir_all(tmp.path().join("backups").join("stray")).unwrap();
+    std::fs::write(tmp.path().join("backups/stray/metadata.json"), b"{}").unwrap();
+    let body = run_verify(&srv).await;
+    assert_eq!(body["clean"], false);
+    assert_eq!(body["orphaned_dirs"][0], "stray");
+
+    let detail = srv
+        .client
+        .get(format!("{}/backups/{id}", srv.base_url))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(detail.contains("verify-ok"));
+}
+
+/// Start a verification through the API and wait for its report.
+async fn run_verify(srv: &TestServer) -> serde_json::Value {
+    let resp = srv
+        .client
+        .post(format!("{}/api/v1/verify", srv.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 202);
+    for _ in 0..100 {
+        let job: serde_json::Value = srv
+            .client
+            .get(format!("{}/api/v1/verify", srv.base_url))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        match job["status"].as_str().unwrap() {
+            "running" => tokio::time::sleep(std::time::Duration::from_millis(50)).await,
+            "finished" => return job["report"].clone(),
+            status => panic!("verification {status}: {job}"),
+        }
+    }
+    panic!("verification did not finish");
+}
+
+async fn created_backups(repo: &BackupRepository) -> usize {
+    repo.list_backups()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|b| b.status == BackupStatus::Created)
+        .count()
+}
+
+/// Long enough that retention never prunes anything in these tests.
+const KEEP_EVERYTHING: Retention = Retention::MaxAgeDays(36500);
+
+#[tokio::test]
+async fn test_scheduled_backup_from_fake_server() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let server = FakeSyncServer::start(sample_collection()).await;
+    server.add_media("cat.jpg", b"meow");
+    let job = BackupJob::new(server.sync_config());
+
+    assert_eq!(scheduled_backup(&repo, &job, &KEEP_EVERYTHING).await, None);
+    let backups = repo.list_backups().await.unwrap();
+    assert_eq!(backups.len(), 1);
+    assert_eq!(backups[0].stats.as_ref().unwrap().total_cards, 3);
+    let media = repo
+        .read_media_set(backups[0].media_set.as_deref().unwrap())
+        .await
+        .unwrap();
+    assert!(media.files.contains_key("cat.jpg"));
+
+    // Unchanged collection: skipped, and the cached host key is reused.
+    assert_eq!(scheduled_backup(&repo, &job, &KEEP_EVERYTHING).await, None);
+    assert_eq!(created_backups(&repo).await, 1);
+    assert_eq!(server.request_count("hostKey"), 1);
+
+    server.set_collection(sample_collection_v2());
+    assert_eq!(scheduled_backup(&repo, &job, &KEEP_EVERYTHING).await, None);
+    assert_eq!(created_backups(&repo).await, 2);
+}
+
+#[tokio::test]
+async fn test_scheduled_backup_retries_early_after_transient_failure() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let server = FakeSyncServer::start(sample_collection()).await;
+    let job = BackupJob::new(SyncConfig {
+        retry: RetryPolicy::none(),
+        ..server.sync_config()
+    })
+    .with_media(false);
+
+    server.fail_next("download", Fault::Status(503));
+    assert_eq!(
+        scheduled_backup(&repo, &job, &KEEP_EVERYTHING).await,
+        Some(TRANSIENT_RETRY_DELAY)
+    );
+    server.fail_next(
+        "download",
+        Fault::RateLimited {
+            retry_after_secs: Some(900),
+        },
+    );
+    assert_eq!(
+        scheduled_backup(&repo, &job, &KEEP_EVERYTHING).await,
+        Some(std::time::Duration::from_secs(900))
+    );
+    assert_eq!(created_backups(&repo).await, 0);
+
+    // Within the client's retry budget the fault is absorbed.
+    let job = BackupJob::new(server.sync_config()).with_media(false);
+    server.fail_next("download", Fault::Status(502));
+    assert_eq!(scheduled_backup(&repo, &job, &KEEP_EVERYTHING).await, None);
+    assert_eq!(created_backups(&repo).await, 1);
+}
+
+#[tokio::test]
+async fn test_scheduled_backup_waits_after_auth_failure() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let server = FakeSyncServer::start(sample_collection()).await;
+    let job = BackupJob::new(SyncConfig {
+        password: "wrong".to_owned(),
+        ..server.sync_config()
+    });
+
+    assert_eq!(scheduled_backup(&repo, &job, &KEEP_EVERYTHING).await, None);
+    assert_eq!(created_backups(&repo).await, 0);
+    assert_eq!(server.request_count("hostKey"), 1);
+}
+
+#[tokio::test]
+async fn test_rotated_host_key_is_refreshed() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let server = FakeSyncServer::start(sample_collection()).await;
+    let job = BackupJob::new(server.sync_config()).with_media(false);
+
+    job.run(&repo).await.unwrap();
+    server.rotate_host_key();
+    job.run(&repo).await.unwrap();
+    assert_eq!(server.request_count("hostKey"), 2);
+    assert_eq!(
+        repo.cached_host_key(FAKE_USERNAME, FAKE_PASSWORD)
+            .await
+            .unwrap(),
+        Some(server.host_key())
+    );
+}
+
+#[tokio::test]
+async fn test_configured_host_key_beats_stale_cache() {
+    let tmp = tempfile::tempdir().unwrap();
+    let key_file = tmp.path().join("backup.key");
+    std::fs::write(&key_file, [7u8; 32]).unwrap();
+    let repo = BackupRepository::new(tmp.path())
+        .unwrap()
+        .with_encryption(Some(&KeySource::File(key_file)))
+        .await
+        .unwrap();
+    let server = FakeSyncServer::start(sample_collection()).await;
+    repo.store_host_key(FAKE_USERNAME, "", "stale")
+        .await
+        .unwrap();
+
+    // Without a password there is no login to fall back on.
+    let config = SyncConfig {
+        password: String::new(),
+        host_key: Some(server.host_key()),
+        ..server.sync_config()
+    };
+    BackupJob::new(config.clone())
+        .with_media(false)
+        .run(&repo)
+        .await
+        .unwrap();
+    assert_eq!(server.request_count("hostKey"), 0);
+
+    // The stale cached key is rejected and dropped.
+    let no_key = SyncConfig {
+        host_key: None,
+        ..config
+    };
+    assert!(BackupJob::new(no_key)
+        .with_media(false)
+        .run(&repo)
+        .await
+        .is_err());
+    assert_eq!(repo.cached_host_key(FAKE_USERNAME, "").await.unwrap(), None);
+}
+
+/// Preview a rollback, returning the response body.
+async fn preview_rollback(srv: &TestServer, id: uuid::Uuid) -> serde_json::Value {
+    let resp = srv
+        .client
+        .post(format!("{}/backups/{id}/rollback/preview", srv.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    resp.json().await.unwrap()
+}
+
+async fn confirm_rollback(srv: &TestServer, id: uuid::Uuid, token: &str) -> reqwest::Response {
+    srv.client
+        .post(format!("{}/backups/{id}/rollback", srv.base_url))
+        .json(&serde_json::json!({"confirm_token": token}))
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_rollback_uploads_to_sync_server() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let old = spanish_collection().build();
+    let server = FakeSyncServer::start(old.clone()).await;
+    let id = match BackupJob::new(server.sync_config())
+        .with_media(false)
+        .run(&repo)
+        .await
+        .unwrap()
+    {
+        RunOnceOutcome::Created(e) => e.id,
+        _ => panic!("expected created"),
+    };
+    // Reviewed and deleted a note on another device since the last backup.
+    let newer = spanish_collection()
+        .with_sql(
+            "INSERT INTO revlog VALUES (1700000000000, 1);
+         DELETE FROM notes WHERE id = 2;",
+        )
+        .build();
+    server.set_collection(newer.clone());
+    let srv = start_server_with_sync(repo.clone(), None, None, Some(server.sync_config())).await;
+
+    // Uploading needs a confirmed preview.
+    let resp = srv
+        .client
+        .post(format!("{}/backups/{id}/rollback", srv.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 409);
+    assert!(server.uploads().is_empty());
+
+    let preview = preview_rollback(&srv, id).await;
+    assert_eq!(preview["lost_review_count"], 1);
+    assert_eq!(preview["preview"]["lost_reviews"][0]["card_id"], 1);
+    assert_eq!(preview["preview"]["diff"]["notes_added"][0]["id"], 2);
+    let snapshot_id = uuid::Uuid::parse_str(preview["snapshot_id"].as_str().unwrap()).unwrap();
+    let snapshot = repo.get_backup(snapshot_id).await.unwrap().unwrap();
+    assert_eq!(repo.read_backup(&snapshot).await.unwrap(), newer);
+
+    // Tokens are tied to the previewed backup and work once.
+    let token = preview["confirm_token"].as_str().unwrap();
+    let resp = confirm_rollback(&srv, snapshot_id, token).await;
+    assert_eq!(resp.status(), 409);
+    let resp = confirm_rollback(&srv, id, token).await;
+    assert_eq!(resp.status(), 409);
+    assert!(server.uploads().is_empty());
+
+    let preview = preview_rollback(&srv, id).await;
+    assert_eq!(preview["snapshot_id"], snapshot_id.to_string());
+    let resp = confirm_rollback(&srv, id, preview["confirm_token"].as_str().unwrap()).await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["uploaded"], true);
+    assert_eq!(body["snapshot_id"], snapshot_id.to_string());
+    assert_eq!(body["status"], "ok");
+    assert_eq!(body["verification"]["status"], "ok");
+    assert_eq!(server.uploads(), std::slice::from_ref(&old));
+    assert_eq!(server.collection(), old);
+}
+
+#[tokio::test]
+async fn test_rollback_refused_when_server_changed_since_preview() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let old = spanish_collection().build();
+    let id = match create_backup(&repo, &old).await {
+        RunOnceOutcome::Created(e) => e.id,
+        _ => panic!("expected created"),
+    };
+    let server = FakeSyncServer::start(
+        spanish_collection()
+            .with_sql("DELETE FROM notes WHERE id = 2;")
+            .build(),
+    )
+    .await;
+    let srv = start_server_with_sync(repo.clone(), None, None, Some(server.sync_config())).await;
+
+    let preview = preview_rollback(&srv, id).await;
+    // Reviewed on another device after the preview was shown.
+    let reviewed = spanish_collection()
+        .with_sql(
+            "INSERT INTO revlog VALUES (1700000000000, 1);
+         DELETE FROM notes WHERE id = 2;",
+        )
+        .build();
+    server.set_collection(reviewed.clone());
+    let resp = confirm_rollback(&srv, id, preview["confirm_token"].as_str().unwrap()).await;
+    assert_eq!(resp.status(), 409);
+    assert!(server.uploads().is_empty());
+    // The new state was still backed up before being refused.
+    let latest = repo.latest_created().await.unwrap().unwrap();
+    assert_eq!(repo.read_backup(&latest).await.unwrap(), reviewed);
+
+    // A fresh preview covers the new state and can be confirmed.
+    let preview = preview_rollback(&srv, id).await;
+    assert_eq!(preview["snapshot_id"], latest.id.to_string());
+    assert_eq!(preview["lost_review_count"], 1);
+    let resp = confirm_rollback(&srv, id, preview["confirm_token"].as_str().unwrap()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(server.uploads(), std::slice::from_ref(&old));
+}
+
+#[tokio::test]
+async fn test_rollback_fails_when_upload_diverges() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let id = match create_backup(&repo, &spanish_collection().build()).await {
+        RunOnceOutcome::Created(e) => e.id,
+        _ => panic!("expected created"),
+    };
+    let server = FakeSyncServer::start(
+        spanish_collection()
+            .with_sql("DELETE FROM notes WHERE id = 2;")
+            .build(),
+    )
+    .await;
+    server.discard_uploads(true);
+    let srv = start_server_with_sync(repo, None, None, Some(server.sync_config())).await;
+
+    let preview = preview_rollback(&srv, id).await;
+    let resp = confirm_rollback(&srv, id, preview["confirm_token"].as_str().unwrap()).await;
+    assert_eq!(resp.status(), 502);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["status"], "failed");
+    assert_eq!(body["verification"]["status"], "diverged");
+    assert_eq!(
+        body["verification"]["detail"],
+        "notes: 2 uploaded, 1 on the server"
+    );
+    assert_eq!(server.uploads().len(), 1);
+}
+
+#[tokio::test]
+async fn test_rollback_reports_failed_upload() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let id = match create_backup(&repo, &spanish_collection().build()).await {
+        RunOnceOutcome::Created(e) => e.id,
+        _ => panic!("expected created"),
+    };
+    let newer = spanish_collection()
+        .with_sql("DELETE FROM notes WHERE id = 2;")
+        .build();
+    let server = FakeSyncServer::start(newer.clone()).await;
+    server.fail_next("upload", Fault::Status(500));
+    let sync = SyncConfig {
+        retry: RetryPolicy::none(),
+        ..server.sync_config()
+    };
+    let srv = start_server_with_sync(repo, None, None, Some(sync)).await;
+
+    let preview = preview_rollback(&srv, id).await;
+    let resp = confirm_rollback(&srv, id, preview["confirm_token"].as_str().unwrap()).await;
+    assert_eq!(resp.status(), 502);
+    assert!(server.uploads().is_empty());
+    assert_eq!(server.collection(), newer);
+
+    let history: serde_json::Value = srv
+        .client
+        .get(format!("{}/api/v1/rollbacks", srv.base_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(history["rollbacks"][0]["actor"], "ui");
+    assert_eq!(history["rollbacks"][0]["upload"], "failed");
+}
+
+#[tokio::test]
+async fn test_rollback_actor_is_the_token_name() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let first = match create_backup(&repo, &sample_collection()).await {
+        RunOnceOutcome::Created(e) => e.id,
+        _ => panic!("expected created"),
+    };
+    create_backup(&repo, &sample_collection_v2()).await;
+    let srv = serve(AppState {
+        profiles: Arc::new(vec![ProfileState::new(repo.clone(), None)]),
+        csrf_token: None,
+        api_token: Some("secret".to_owned()),
+        api_token_name: Some("cron".to_owned()),
+    })
+    .await;
+
+    // A UI route called with the token is the token's doing, not the UI's.
+    let resp = srv
+        .client
+        .post(format!("{}/backups/{first}/rollback", srv.base_url))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let history: serde_json::Value = srv
+        .client
+        .get(format!("{}/api/v1/rollbacks", srv.base_url))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(history["rollbacks"][0]["actor"], "cron");
+}
+
+#[tokio::test]
+async fn test_rollback_history() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let first = match create_backup(&repo, &sample_collection()).await {
+        RunOnceOutcome::Created(e) => e.id,
+        _ => panic!("expected created"),
+    };
+    let second = match create_backup(&repo, &sample_collection_v2()).await {
+        RunOnceOutcome::Created(e) => e.id,
+        _ => panic!("expected created"),
+    };
+    let srv = start_server(repo, Some("secret".to_string()), None).await;
+    let rollbacks = || async {
+        let resp = srv
+            .client
+            .get(format!("{}/api/v1/rollbacks", srv.base_url))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        resp.json::<serde_json::Value>().await.unwrap()
+    };
+
+    let before = rollbacks().await;
+    assert_eq!(before["current_backup_id"], second.to_string());
+    assert_eq!(before["rollbacks"].as_array().unwrap().len(), 0);
+
+    let resp = srv
+        .client
+        .post(format!("{}/api/v1/backups/{first}/rollback", srv.base_url))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let rollback_id = resp.json::<serde_json::Value>().await.unwrap()["rollback_id"].clone();
+
+    let after = rollbacks().await;
+    assert_eq!(after["current_backup_id"], first.to_string());
+    let event = &after["rollbacks"][0];
+    assert_eq!(event["id"], rollback_id);
+    assert_eq!(event["backup_id"], first.to_string());
+    assert_eq!(event["backup"]["id"], first.to_string());
+    assert_eq!(event["actor"], "api token");
+    assert_eq!(event["upload"], "local");
+
+    let html = srv
+        .client
+        .get(format!("{}/", srv.base_url))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(html.contains("Rollback history"));
+    assert!(html.contains(">api token<"));
+    let current_item = html.find("backup-item current").unwrap();
+    let first_link = html.find(&format!("/backups/{first}\">View")).unwrap();
+    let second_link = html.find(&format!("/backups/{second}\">View")).unwrap();
+    // Newest first: the second backup's item comes before the current one.
+    assert!(second_link < current_item && current_item < first_link);
+}
+
+/// A server with a `default` and a `work` profile, each holding one backup.
+async fn start_two_profile_server(tmp: &std::path::Path) -> (TestServer, uuid::Uuid, uuid::Uuid) {
+    let default = BackupRepository::new(tmp).unwrap();
+    let work = default.for_profile("work").unwrap();
+    let RunOnceOutcome::Created(default_backup) =
+        create_backup(&default, &sample_collection()).await
+    else {
+        panic!("expected a new backup");
+    };
+    let RunOnceOutcome::Created(work_backup) = create_backup(&work, &sample_collection_v2()).await
+    else {
+        panic!("expected a new backup");
+    };
+    let srv = start_server_with_profiles(
+        vec![
+            ProfileState::new(default, None),
+            ProfileState::new(work, None),
+        ],
+        None,
+        None,
+    )
+    .await;
+    (srv, default_backup.id, work_backup.id)
+}
+
+#[tokio::test]
+async fn test_profiles_are_listed_and_scoped() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (srv, default_id, work_id) = start_two_profile_server(tmp.path()).await;
+
+    let profiles: serde_json::Value = srv
+        .client
+        .get(format!("{}/api/v1/profiles", srv.base_url))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(profiles[0]["name"], "default");
+    assert_eq!(profiles[1]["name"], "work");
+    assert_eq!(profiles[1]["backups"], 1);
+    assert_eq!(profiles[1]["latest_backup_id"], work_id.to_string());
+
+    for (path, expected) in [
+        ("/api/v1/backups", default_id),
+        ("/api/v1/profiles/default/backups", default_id),
+        ("/api/v1/profiles/work/backups", work_id),
+    ] {
+        let list: Vec<serde_json::Value> = srv
+            .client
+            .get(format!("{}{path}", srv.base_url))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(list.len(), 1, "{path}");
+        assert_eq!(list[0]["id"], expected.to_string(), "{path}");
+    }
+
+    let resp = srv
+        .client
+        .get(format!(
+            "{}/api/v1/profiles/work/backups/{work_id}",
+            srv.base_url
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+}
+
+#[tokio::test]
+async fn test_profiles_do_not_see_each_others_backups() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (srv, default_id, work_id) = start_two_profile_server(tmp.path()).await;
+
+    for path in [
+        format!("/api/v1/backups/{work_id}"),
+        format!("/api/v1/profiles/work/backups/{default_id}"),
+        format!("/api/v1/profiles/work/backups/{default_id}/download"),
+        format!("/profiles/work/backups/{default_id}"),
+        "/api/v1/profiles/missing/backups".to_owned(),
+        "/profiles/missing".to_owned(),
+    ] {
+        let resp = srv
+            .client
+            .get(format!("{}{path}", srv.base_url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 404, "{path}");
+    }
+}
+
+#[tokio::test]
+async fn test_ui_links_stay_within_profile() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (srv, _, work_id) = start_two_profile_server(tmp.path()).await;
+
+    let html = srv
+        .client
+        .get(format!("{}/profiles/work", srv.base_url))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(html.contains(&format!("/profiles/work/backups/{work_id}")));
+    assert!(html.contains(r#"href="/profiles/default""#));
+
+    let html = srv
+        .client
+        .get(format!("{}/profiles/work/backups/{work_id}", srv.base_url))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(html.contains(r#"href="/profiles/work">"#));
+    assert!(html.contains(&format!("/profiles/work/backups/{work_id}/rollback")));
+}
+
+#[tokio::test]
+async fn test_backup_from_local_profile() {
+    let anki = tempfile::tempdir().unwrap();
+    std::fs::write(anki.path().join("collection.anki2"), sample_collection()).unwrap();
+    let media_dir = anki.path().join("collection.media");
+    std::fs::create_dir(&media_dir).unwrap();
+    std::fs::write(media_dir.join("cat.jpg"), b"meow").unwrap();
+    std::fs::write(media_dir.join("dog.mp3"), b"woof").unwrap();
+
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let job = BackupJob::new(LocalProfile::new(anki.path()));
+
+    let RunOnceOutcome::Created(first) = job.run(&repo).await.unwrap() else {
+        panic!("expected a new backup");
+    };
+    assert_eq!(first.stats.as_ref().unwrap().total_cards, 3);
+    let set = repo
+        .read_media_set(first.media_set.as_deref().unwrap())
+        .await
+        .unwrap();
+    let names: Vec<_> = set.files.keys().map(String::as_str).collect();
+    assert_eq!(names, ["cat.jpg", "dog.mp3"]);
+
+    // Nothing changed: the snapshot hashes the same and is skipped.
+    assert!(matches!(
+        job.run(&repo).await.unwrap(),
+        RunOnceOutcome::Skipped(_)
+    ));
+
+    std::fs::remove_file(media_dir.join("cat.jpg")).unwrap();
+    std::fs::write(media_dir.join("dog.mp3"), b"bark").unwrap();
+    std::fs::write(anki.path().join("collection.anki2"), sample_collection_v2()).unwrap();
+    let RunOnceOutcome::Created(second) = job.run(&repo).await.unwrap() else {
+        panic!("expected a new backup");
+    };
+    let set = repo
+        .read_media_set(second.media_set.as_deref().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(set.files.keys().collect::<Vec<_>>(), ["dog.mp3"]);
+    assert_eq!(
+        repo.read_media_file(&set.files["dog.mp3"]).await.unwrap(),
+        b"bark"
+    );
+}
+
+/// A legacy `.colpkg`: the collection as `collection.anki2` and one media file.
+fn legacy_colpkg(collection: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for (name, contents) in [
+        ("collection.anki2", collection),
+        ("media", br#"{"0":"cat.jpg"}"#.as_slice()),
+        ("0", b"meow"),
+    ] {
+        zip.start_file(name, zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(contents).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+#[tokio::test]
+async fn test_import_package_via_api() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let srv = start_server(repo.clone(), None, Some("csrf".to_owned())).await;
+    let url = format!(
+        "{}/api/v1/backups/import?created_at=2020-05-01T12:00:00Z",
+        srv.base_url
+    );
+    let package = legacy_colpkg(&sample_collection());
+
+    let resp = srv
+        .client
+        .post(&url)
+        .body(package.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+
+    let resp = srv
+        .client
+        .post(&url)
+        .header("x-csrf-token", "csrf")
+        .body(package.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["format"], "legacy");
+    assert_eq!(body["duplicate"], false);
+    assert_eq!(created_backups(&repo).await, 1);
+    let backups = repo.list_backups().await.unwrap();
+    assert_eq!(backups[0].id.to_string(), body["id"].as_str().unwrap());
+    assert_eq!(
+        backups[0].created_at.to_rfc3339(),
+        "2020-05-01T12:00:00+00:00"
+    );
+    assert!(backups[0].media_set.is_some());
+
+    let resp = srv
+        .client
+        .post(&url)
+        .header("x-csrf-token", "csrf")
+        .body(package)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let dup: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(dup["duplicate"], true);
+    assert_eq!(dup["id"], body["id"]);
+
+    let resp = srv
+        .client
+        .post(&url)
+        .header("x-csrf-token", "csrf")
+        .body("not a package")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    assert_eq!(created_backups(&repo).await, 1);
+}
+
+#[tokio::test]
+async fn test_download_colpkg() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let ImportOutcome::Imported { entry, .. } = repo
+        .import_package(legacy_colpkg(&sample_collection()), None)
+        .await
+        .unwrap()
+    else {
+        panic!("expected an import");
+    };
+    let srv = start_server(repo, None, None).await;
+    let url = format!("{}/api/v1/backups/{}/download", srv.base_url, entry.id);
+
+    let resp = srv
+        .client
+        .get(format!("{url}?format=colpkg"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let cd = resp.headers()["content-disposition"].to_str().unwrap();
+    assert!(cd.ends_with(&format!("backup-{}.colpkg", entry.id)), "{cd}");
+    let mut package = Package::open(resp.bytes().await.unwrap().to_vec()).unwrap();
+    assert_eq!(package.collection().unwrap(), sample_collection());
+    assert_eq!(
+        package.read_media(0).unwrap(),
+        ("cat.jpg".to_owned(), b"meow".to_vec())
+    );
+
+    let resp = srv
+        .client
+        .get(format!("{url}?format=zip"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+}
+
+/// A legacy-schema collection with a Spanish deck (20) next to Default, and
+/// one reviewed Basic note in each.
+fn spanish_collection() -> LegacyCollection {
+    LegacyCollection::new()
+        .with_deck(20, "Spanish")
+        .with_note_type(100, "Basic", &["Front", "Back"])
+        .with_note(1, 100, &["hola", "hello"])
+        .with_note(2, 100, &["adiós", "bye"])
+        .with_card(1, 1, 1)
+        .with_card(2, 2, 20)
+        .with_review(1, 1)
+        .with_review(2, 2)
+}
+
+#[tokio::test]
+async fn test_diff_backups() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let RunOnceOutcome::Created(old) = create_backup(&repo, &spanish_collection().build()).await
+    else {
+        panic!("expected created");
+    };
+    let new_collection = spanish_collection()
+        .with_sql(
+            "UPDATE notes SET flds = 'hola' || char(31) || 'hi' WHERE id = 1;
+         UPDATE cards SET did = 20 WHERE id = 1;
+         DELETE FROM notes WHERE id = 2;
+         INSERT INTO notes VALUES (3, 100, 0, 0, 'animals', 'gato' || char(31) || 'cat');",
+        )
+        .build();
+    let RunOnceOutcome::Created(new) = create_backup(&repo, &new_collection).await else {
+        panic!("expected created");
+    };
+    let srv = start_server(repo, None, None).await;
+
+    let resp = srv
+        .client
+        .get(format!(
+            "{}/api/v1/backups/{}/diff/{}",
+            srv.base_url, old.id, new.id
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    let diff = &body["diff"];
+    assert_eq!(diff["notes_added"][0]["preview"], "gato");
+    assert_eq!(diff["notes_removed"][0]["id"], 2);
+    assert_eq!(diff["notes_modified"][0]["fields"][0]["name"], "Back");
+    assert_eq!(diff["notes_modified"][0]["fields"][0]["after"], "hi");
+    assert_eq!(diff["cards_moved"][0]["to"]["name"], "Spanish");
+
+    let page = srv
+        .client
+        .get(format!(
+            "{}/backups/{}/diff/{}",
+            srv.base_url, old.id, new.id
+        ))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(page.contains("Modified notes (1)"), "{page}");
+    assert!(page.contains("gato"));
+    let detail = srv
+        .client
+        .get(format!("{}/backups/{}", srv.base_url, new.id))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(detail.contains(&format!("/backups/{}/diff/{}", old.id, new.id)));
+
+    let resp = srv
+        .client
+        .get(format!(
+            "{}/api/v1/backups/{}/diff/{}",
+            srv.base_url,
+            old.id,
+            uuid::Uuid::new_v4()
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+}
+
+#[tokio::test]
+async fn test_download_deck_apkg() {
+    let collection = spanish_collection().build();
+    let repo_dir = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(repo_dir.path()).unwrap();
+    let RunOnceOutcome::Created(entry) = create_backup(&repo, &collection).await else {
+        panic!("expected created");
+    };
+    let srv = start_server(repo, None, None).await;
+    let url = |deck: &str| {
+        format!(
+            "{}/api/v1/backups/{}/decks/{deck}/download",
+            srv.base_url, entry.id
+        )
+    };
+
+    let resp = srv.client.get(url("20")).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let cd = resp.headers()["content-disposition"].to_str().unwrap();
+    assert!(
+        cd.ends_with(&format!("backup-{}-deck-20.apkg", entry.id)),
+        "{cd}"
+    );
+    let mut package = Package::open(resp.bytes().await.unwrap().to_vec()).unwrap();
+    let exported = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(exported.path(), package.collection().unwrap()).unwrap();
+    let conn = Connection::open(exported.path()).unwrap();
+    for table in ["cards", "notes", "revlog"] {
+        let ids: String = conn
+            .query_row(&format!("SELECT group_concat(id) FROM {table}"), [], |r| {
+                r.get(0)
+            })
+            .unwrap();
+        assert_eq!(ids, "2", "{table}");
+    }
+
+    for (deck, status) in [("99", 404), ("spanish", 400)] {
+        let resp = srv.client.get(url(deck)).send().await.unwrap();
+        assert_eq!(resp.status(), status, "{deck}");
+    }
+}
+
+#[tokio::test]
+async fn test_merge_restore_uploads_to_sync_server() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = BackupRepository::new(tmp.path()).unwrap();
+    let RunOnceOutcome::Created(old) = create_backup(&repo, &spanish_collection().build()).await
+    else {
+        panic!("expected created");
+    };
+    // Since the backup, the Spanish deck was deleted and note 1 reviewed.
+    let current = spanish_collection()
+        .with_sql(
+            r#"UPDATE col SET decks = '{"1":{"name":"Default","conf":1}}';
+           DELETE FROM notes WHERE id = 2;
+           DELETE FROM cards WHERE id = 2;
+           DELETE FROM revlog;
+           INSERT INTO revlog VALUES (5, 1);"#,
+        )
+        .build();
+    let server = FakeSyncServer::start(current.clone()).await;
+    let srv = start_server_with_sync(
+        repo.clone(),
+        None,
+        Some("csrf".to_owned()),
+        Some(server.sync_config()),
+    )
+    .await;
+    let url = format!("{}/api/v1/backups/{}/restore", srv.base_url, old.id);
+
+    let resp = srv
+        .client
+        .post(&url)
+        .json(&serde_json::json!({"deck_ids": [20]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+    let resp = srv
+        .client
+        .post(&url)
+        .header("x-csrf-token", "csrf")
+        .json(&serde_json::json!({"deck_ids": [99]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    assert!(server.uploads().is_empty());
+
+    // The rate limit only starts after a successful restore.
+    let resp = srv
+        .client
+        .post(&url)
+        .header("x-csrf-token", "csrf")
+        .json(&serde_json::json!({"deck_ids": [20]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["report"]["notes_added"], 1);
+    assert_eq!(body["report"]["decks_added"], 1);
+
+    // The pre-restore state was backed up first.
+    let snapshot_id = uuid::Uuid::parse_str(body["snapshot_id"].as_str().unwrap()).unwrap();
+    let snapshot = repo.get_backup(snapshot_id).await.unwrap().unwrap();
+    assert_eq!(repo.read_backup(&snapshot).await.unwrap(), current);
+
+    let [uploaded] = &server.uploads()[..] else {
+        panic!("expected one upload");
+    };
+    let merged = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(merged.path(), uploaded).unwrap();
+    let conn = Connection::open(merged.path()).unwrap();
+    let ids = |sql: &str| -> String { conn.query_row(sql, [], |r| r.get(0)).unwrap() };
+    assert_eq!(ids("SELECT group_concat(id) FROM notes"), "1,2");
+    assert_eq!(ids("SELECT group_concat(id) FROM revlog"), "2,5");
+    assert!(ids("SELECT decks FROM col").contains("Spanish"));
+}
@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+
+/// Key/value storage for backup payloads, implemented by the local filesystem
+/// and S3-compatible object storage backends.
+///
+/// Keys are `/`-separated relative paths such as `backups/<timestamp>/manifest.json`
+/// or `objects/ab/<sha256>.zst`.
+#[async_trait::async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Store `bytes` under `key`, replacing any existing value atomically.
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<()>;
+
+    /// Fetch the value under `key`, or `None` if it does not exist.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Size in bytes of the value under `key`, or `None` if it does not exist.
+    async fn size(&self, key: &str) -> Result<Option<u64>>;
+
+    /// When the value under `key` was last written, or `None` if it does not exist.
+    async fn modified(&self, key: &str) -> Result<Option<DateTime<Utc>>>;
+
+    /// Delete the value under `key`. Deleting a missing key is not an error.
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// List every key starting with `prefix`, in no particular order.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>>;
+}
+
+/// A view of another blob store with every key under `prefix`, used to give
+/// each profile its own namespace in a shared store.
+pub struct PrefixedBlobStore {
+    inner: Arc<dyn BlobStore>,
+    /// Ends with `/`.
+    prefix: String,
+}
+
+impl PrefixedBlobStore {
+    pub fn new(inner: Arc<dyn BlobStore>, prefix: &str) -> Self {
+        Self {
+            inner,
+            prefix: format!("{}/", prefix.trim_end_matches('/')),
+        }
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+}
+
+#[async_trait::async_trait]
+impl BlobStore for PrefixedBlobStore {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+        self.inner.put(&self.key(key), bytes).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.get(&self.key(key)).await
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>> {
+        self.inner.size(&self.key(key)).await
+    }
+
+    async fn modified(&self, key: &str) -> Result<Option<DateTime<Utc>>> {
+        self.inner.modified(&self.key(key)).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.inner.delete(&self.key(key)).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(self
+            .inner
+            .list(&self.key(prefix))
+            .await?
+            .into_iter()
+            .filter_map(|key| key.strip_prefix(&self.prefix).map(str::to_owned))
+            .collect())
+    }
+}
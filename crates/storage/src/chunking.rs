@@ -0,0 +1,138 @@
+//! Content-defined chunking over SQLite pages.
+//!
+//! SQLite writes whole pages, so a handful of reviews only dirties a handful of
+//! pages. Chunk boundaries are therefore only ever placed *between* pages, and
+//! whether a page ends a chunk is decided by a hash of that page's content.
+//! Boundaries resynchronise after inserted or removed pages, so an unchanged
+//! region of the file maps onto the same chunks from one backup to the next.
+
+use std::ops::Range;
+
+/// Page size assumed when the payload is not a SQLite database.
+const DEFAULT_PAGE_SIZE: usize = 4096;
+
+/// Average chunk size the boundary predicate aims for.
+const TARGET_CHUNK_SIZE: usize = 64 * 1024;
+
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+/// Split `data` into content-defined chunks and return their byte ranges.
+///
+/// The ranges are contiguous, non-empty and cover the whole input.
+pub fn chunk_ranges(data: &[u8]) -> Vec<Range<usize>> {
+    let page_size = sqlite_page_size(data).unwrap_or(DEFAULT_PAGE_SIZE);
+    let avg_pages = (TARGET_CHUNK_SIZE / page_size).max(1) as u64;
+    let min_pages = (avg_pages / 4).max(1);
+    let max_pages = avg_pages * 4;
+
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut pages_in_chunk = 0u64;
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let end = (offset + page_size).min(data.len());
+        pages_in_chunk += 1;
+
+        let at_boundary = pages_in_chunk >= max_pages
+            || (pages_in_chunk >= min_pages && fnv1a(&data[offset..end]).is_multiple_of(avg_pages));
+        if at_boundary {
+            ranges.push(start..end);
+            start = end;
+            pages_in_chunk = 0;
+        }
+        offset = end;
+    }
+
+    if start < data.len() {
+        ranges.push(start..data.len());
+    }
+    ranges
+}
+
+/// Read the page size from a SQLite database header, if `data` is one.
+fn sqlite_page_size(data: &[u8]) -> Option<usize> {
+    if data.len() < 100 || !data.starts_with(SQLITE_HEADER) {
+        return None;
+    }
+    // Bytes 16..18 hold the page size; the value 1 means 65536.
+    match u16::from_be_bytes([data[16], data[17]]) {
+        1 => Some(65536),
+        n if n >= 512 && n.is_power_of_two() => Some(n as usize),
+        _ => None,
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pseudo_random_pages(pages: usize, page_size: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..pages * page_size)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ranges_cover_input_on_page_boundaries() {
+        let data = pseudo_random_pages(300, DEFAULT_PAGE_SIZE, 7);
+        let ranges = chunk_ranges(&data);
+        assert!(ranges.len() > 1);
+        assert_eq!(ranges.first().unwrap().start, 0);
+        assert_eq!(ranges.last().unwrap().end, data.len());
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+            assert_eq!(pair[0].end % DEFAULT_PAGE_SIZE, 0);
+        }
+    }
+
+    #[test]
+    fn single_page_edit_only_changes_one_chunk() {
+        let before = pseudo_random_pages(300, DEFAULT_PAGE_SIZE, 11);
+        let mut after = before.clone();
+        after[150 * DEFAULT_PAGE_SIZE + 10] ^= 0xff;
+
+        let a: Vec<&[u8]> = chunk_ranges(&before)
+            .into_iter()
+            .map(|r| &before[r])
+            .collect();
+        let b: Vec<&[u8]> = chunk_ranges(&after)
+            .into_iter()
+            .map(|r| &after[r])
+            .collect();
+        let changed = b.iter().filter(|chunk| !a.contains(chunk)).count();
+        assert!(
+            changed <= 2,
+            "expected a local change, got {changed} new chunks"
+        );
+    }
+
+    #[test]
+    fn reads_sqlite_page_size() {
+        let mut header = vec![0u8; 100];
+        header[..16].copy_from_slice(SQLITE_HEADER);
+        header[16..18].copy_from_slice(&8192u16.to_be_bytes());
+        assert_eq!(sqlite_page_size(&header), Some(8192));
+        header[16..18].copy_from_slice(&1u16.to_be_bytes());
+        assert_eq!(sqlite_page_size(&header), Some(65536));
+        assert_eq!(sqlite_page_size(b"not a database"), None);
+    }
+
+    #[test]
+    fn empty_input_has_no_chunks() {
+        assert!(chunk_ranges(&[]).is_empty());
+    }
+}
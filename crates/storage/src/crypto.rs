@@ -0,0 +1,291 @@
+//! Optional encryption of everything written under `backups/` and `objects/`.
+//!
+//! Sealed files use XChaCha20-Poly1305 with a random nonce per file and start
+//! with a fixed magic plus an 8-byte key id, so readers can tell encrypted and
+//! plaintext files apart and report which key a file was sealed with.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{AeadCore, XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::blob_store::BlobStore;
+
+const MAGIC: &[u8; 8] = b"ABTENC01";
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN + NONCE_LEN;
+
+/// File under the repository root recording the passphrase KDF salt.
+pub const KDF_PARAMS_FILE: &str = "encryption.json";
+
+/// Where the repository encryption key comes from.
+#[derive(Clone)]
+pub enum KeySource {
+    /// A file holding 32 raw bytes or 64 hex characters.
+    File(std::path::PathBuf),
+    /// A passphrase stretched with Argon2id.
+    Passphrase(String),
+}
+
+impl fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySource::File(path) => f.debug_tuple("File").field(path).finish(),
+            KeySource::Passphrase(_) => f.write_str("Passphrase(..)"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EncryptionKey {
+    key: [u8; 32],
+    id: [u8; KEY_ID_LEN],
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &hex::encode(self.id))
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct KdfParams {
+    kdf: String,
+    salt: String,
+}
+
+impl EncryptionKey {
+    fn from_bytes(key: [u8; 32]) -> Self {
+        let digest = Sha256::digest(key);
+        let mut id = [0u8; KEY_ID_LEN];
+        id.copy_from_slice(&digest[..KEY_ID_LEN]);
+        Self { key, id }
+    }
+
+    /// Resolve a key for the repository stored in `blobs`.
+    ///
+    /// Passphrase keys use a salt stored in `encryption.json` at the root of
+    /// the blob store, created on first use.
+    pub async fn resolve(source: &KeySource, blobs: &dyn BlobStore) -> Result<Self> {
+        match source {
+            KeySource::File(path) => Self::from_key_file(path),
+            KeySource::Passphrase(passphrase) => {
+                let salt = load_or_create_salt(blobs).await?;
+                let passphrase = passphrase.clone();
+                tokio::task::spawn_blocking(move || Self::from_passphrase(&passphrase, &salt))
+                    .await?
+            }
+        }
+    }
+
+    pub fn from_key_file(path: &Path) -> Result<Self> {
+        let raw = fs::read(path)
+            .with_context(|| format!("read encryption key file: {}", path.display()))?;
+        let key: [u8; 32] = match raw.len() {
+            32 => raw.as_slice().try_into().expect("length checked"),
+            _ => {
+                let text = std::str::from_utf8(&raw).unwrap_or_default().trim();
+                hex::decode(text)
+                    .ok()
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .ok_or_else(|| {
+                        anyhow!("encryption key file must hold 32 raw bytes or 64 hex characters")
+                    })?
+            }
+        };
+        Ok(Self::from_bytes(key))
+    }
+
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> Result<Self> {
+        if passphrase.is_empty() {
+            return Err(anyhow!("encryption passphrase must not be empty"));
+        }
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| anyhow!("derive encryption key: {e}"))?;
+        Ok(Self::from_bytes(key))
+    }
+
+    /// Short hex identifier of the key, safe to log.
+    pub fn id_hex(&self) -> String {
+        hex::encode(self.id)
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let cipher = XChaCha20Poly1305::new((&self.key).into());
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &self.id,
+                },
+            )
+            .map_err(|_| anyhow!("encrypt payload"))?;
+        let mut out = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&self.id);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        let key_id = sealed_key_id(sealed).ok_or_else(|| anyhow!("data is not encrypted"))?;
+        if key_id != self.id {
+            return Err(anyhow!(
+                "data was encrypted with key {}, configured key is {}",
+                hex::encode(key_id),
+                self.id_hex()
+            ));
+        }
+        let nonce = XNonce::from_slice(&sealed[MAGIC.len() + KEY_ID_LEN..HEADER_LEN]);
+        XChaCha20Poly1305::new((&self.key).into())
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: &sealed[HEADER_LEN..],
+                    aad: &self.id,
+                },
+            )
+            .map_err(|_| anyhow!("decryption failed: data is corrupt or the key is wrong"))
+    }
+
+    /// Whether `sealed` was encrypted with this key.
+    pub fn sealed_this(&self, sealed: &[u8]) -> bool {
+        sealed_key_id(sealed) == Some(self.id)
+    }
+}
+
+/// Whether `data` carries the encryption header.
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN && data.starts_with(MAGIC)
+}
+
+fn sealed_key_id(data: &[u8]) -> Option<[u8; KEY_ID_LEN]> {
+    is_sealed(data).then(|| {
+        data[MAGIC.len()..MAGIC.len() + KEY_ID_LEN]
+            .try_into()
+            .expect("slice has key id length")
+    })
+}
+
+/// Decrypt `data` if it is sealed, or pass it through unchanged.
+pub fn open_if_sealed(key: Option<&EncryptionKey>, data: Vec<u8>) -> Result<Vec<u8>> {
+    if !is_sealed(&data) {
+        return Ok(data);
+    }
+    match key {
+        Some(key) => key.open(&data),
+        None => Err(anyhow!(
+            "backup data is encrypted but no encryption key is configured"
+        )),
+    }
+}
+
+/// Encrypt `data` when a key is configured, or pass it through unchanged.
+pub fn seal_if_keyed(key: Option<&EncryptionKey>, data: Vec<u8>) -> Result<Vec<u8>> {
+    match key {
+        Some(key) => key.seal(&data),
+        None => Ok(data),
+    }
+}
+
+async fn load_or_create_salt(blobs: &dyn BlobStore) -> Result<Vec<u8>> {
+    if let Some(raw) = blobs.get(KDF_PARAMS_FILE).await? {
+        let params: KdfParams =
+            serde_json::from_slice(&raw).with_context(|| format!("parse {KDF_PARAMS_FILE}"))?;
+        if params.kdf != "argon2id" {
+            return Err(anyhow!(
+                "unsupported key derivation function: {}",
+                params.kdf
+            ));
+        }
+        return hex::decode(&params.salt).context("decode kdf salt");
+    }
+    let mut salt = vec![0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let params = KdfParams {
+        kdf: "argon2id".to_owned(),
+        salt: hex::encode(&salt),
+    };
+    blobs
+        .put(KDF_PARAMS_FILE, serde_json::to_vec_pretty(&params)?)
+        .await
+        .with_context(|| format!("write {KDF_PARAMS_FILE}"))?;
+    Ok(salt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_blob_store::LocalBlobStore;
+
+    fn test_key(byte: u8) -> EncryptionKey {
+        EncryptionKey::from_bytes([byte; 32])
+    }
+
+    #[test]
+    fn seal_open_roundtrip() {
+        let key = test_key(1);
+        let sealed = key.seal(b"study data").unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(key.sealed_this(&sealed));
+        assert_eq!(key.open(&sealed).unwrap(), b"study data");
+    }
+
+    #[test]
+    fn wrong_key_and_tampering_are_rejected() {
+        let key = test_key(1);
+        let mut sealed = key.seal(b"study data").unwrap();
+        assert!(test_key(2).open(&sealed).is_err());
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(key.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn plaintext_passes_through_without_key() {
+        assert_eq!(open_if_sealed(None, b"{}".to_vec()).unwrap(), b"{}");
+        let sealed = test_key(1).seal(b"x").unwrap();
+        assert!(open_if_sealed(None, sealed).is_err());
+    }
+
+    #[test]
+    fn key_file_accepts_hex_and_raw() {
+        let dir = tempfile::tempdir().unwrap();
+        let hex_path = dir.path().join("hex.key");
+        fs::write(&hex_path, format!("{}\n", "ab".repeat(32))).unwrap();
+        let raw_path = dir.path().join("raw.key");
+        fs::write(&raw_path, [0xab; 32]).unwrap();
+        let a = EncryptionKey::from_key_file(&hex_path).unwrap();
+        let b = EncryptionKey::from_key_file(&raw_path).unwrap();
+        assert_eq!(a.id_hex(), b.id_hex());
+
+        fs::write(&hex_path, "too short").unwrap();
+        assert!(EncryptionKey::from_key_file(&hex_path).is_err());
+    }
+
+    #[tokio::test]
+    async fn passphrase_key_reuses_stored_salt() {
+        let dir = tempfile::tempdir().unwrap();
+        let blobs = LocalBlobStore::new(dir.path());
+        let source = KeySource::Passphrase("correct horse".to_owned());
+        let first = EncryptionKey::resolve(&source, &blobs).await.unwrap();
+        let second = EncryptionKey::resolve(&source, &blobs).await.unwrap();
+        assert_eq!(first.id_hex(), second.id_hex());
+        assert!(dir.path().join(KDF_PARAMS_FILE).exists());
+    }
+}
@@ -0,0 +1,310 @@
+//! Cutting one deck out of a collection, for `.apkg` export.
+//!
+//! The collection is copied and everything outside the deck and its
+//! subdecks is deleted: other decks' cards, notes left without cards, their
+//! review history, unused note types and the other decks (except `Default`,
+//! which every collection has). Deck option presets are kept as they are.
+//! Cards borrowed by a filtered deck go back to their home deck first. The
+//! copy is vacuumed so deleted rows don't linger in free pages.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use serde_json::{Map, Value};
+
+use crate::repository::{parse_deck_names_legacy, parse_deck_names_new};
+
+/// Deck id of `Default`.
+const DEFAULT_DECK_ID: i64 = 1;
+
+/// One deck extracted by [`extract_deck`].
+pub(crate) struct DeckExport {
+    /// A standalone collection holding only the deck.
+    pub collection: Vec<u8>,
+    /// Media file names the deck's notes refer to.
+    pub media: BTreeSet<String>,
+}
+
+/// Extract deck `deck_id` and its subdecks, or `None` if the collection has
+/// no such deck.
+pub(crate) fn extract_deck(collection: &[u8], deck_id: i64) -> Result<Option<DeckExport>> {
+    let tmp = tempfile::NamedTempFile::new().context("create temp collection file")?;
+    fs::write(tmp.path(), collection).context("write temp collection file")?;
+    let conn = Connection::open(tmp.path()).context("open collection copy")?;
+
+    // Schema 15+ keeps decks and note types in tables, older schemas as JSON
+    // in `col`. Nested deck names are separated by 0x1f or `::` respectively.
+    let modern = table_exists(&conn, "decks")?;
+    let names = if modern {
+        parse_deck_names_new(&conn)?
+    } else {
+        let json: String = conn.query_row("SELECT decks FROM col LIMIT 1", [], |r| r.get(0))?;
+        parse_deck_names_legacy(&json)?
+    };
+    let Some(name) = names.get(&deck_id) else {
+        return Ok(None);
+    };
+    let child_prefix = format!("{name}{}", if modern { "\x1f" } else { "::" });
+    let decks: Vec<i64> = names
+        .iter()
+        .filter(|(id, name)| **id == deck_id || name.starts_with(&child_prefix))
+        .map(|(id, _)| *id)
+        .collect();
+
+    conn.execute_batch("CREATE TEMP TABLE export_decks (id INTEGER PRIMARY KEY)")?;
+    for id in &decks {
+        conn.execute("INSERT INTO export_decks(id) VALUES (?1)", params![id])?;
+    }
+    conn.execute_batch(
+        "UPDATE cards
+            SET did = odid, due = CASE WHEN odue != 0 THEN odue ELSE due END, odid = 0, odue = 0
+          WHERE odid IN (SELECT id FROM export_decks)
+            AND did NOT IN (SELECT id FROM export_decks);
+         DELETE FROM cards WHERE did NOT IN (SELECT id FROM export_decks);
+         DELETE FROM notes WHERE id NOT IN (SELECT nid FROM cards);
+         DELETE FROM revlog WHERE cid NOT IN (SELECT id FROM cards);
+         DELETE FROM graves;",
+    )
+    .context("remove other decks' cards and notes")?;
+
+    if modern {
+        conn.execute(
+            "DELETE FROM decks WHERE id != ?1 AND id NOT IN (SELECT id FROM export_decks)",
+            params![DEFAULT_DECK_ID],
+        )?;
+        conn.execute_batch(
+            "DELETE FROM notetypes WHERE id NOT IN (SELECT mid FROM notes);
+             DELETE FROM fields WHERE ntid NOT IN (SELECT id FROM notetypes);
+             DELETE FROM templates WHERE ntid NOT IN (SELECT id FROM notetypes);",
+        )
+        .context("remove unused note types")?;
+    } else {
+        let (deck_json, model_json): (String, String) =
+            conn.query_row("SELECT decks, models FROM col LIMIT 1", [], |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })?;
+        let note_types = ids(&conn, "SELECT DISTINCT mid FROM notes")?;
+        let deck_json = retain_ids(&deck_json, |id| {
+            id == DEFAULT_DECK_ID || decks.contains(&id)
+        })
+        .context("parse col.decks json")?;
+        let model_json = retain_ids(&model_json, |id| note_types.contains(&id))
+            .context("parse col.models json")?;
+        conn.execute(
+            "UPDATE col SET decks = ?1, models = ?2",
+            params![deck_json, model_json],
+        )?;
+    }
+
+    let mut media = BTreeSet::new();
+    let mut stmt = conn.prepare("SELECT flds FROM notes")?;
+    for fields in stmt.query_map([], |r| r.get::<_, String>(0))? {
+        media_references(&fields?, &mut media);
+    }
+    drop(stmt);
+
+    conn.execute_batch("DROP TABLE export_decks; VACUUM;")
+        .context("vacuum collection copy")?;
+    drop(conn);
+    let collection = fs::read(tmp.path()).context("read collection copy")?;
+    Ok(Some(DeckExport { collection, media }))
+}
+
+pub(crate) fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        params![table],
+        |r| r.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+fn ids(conn: &Connection, sql: &str) -> Result<Vec<i64>> {
+    let mut stmt = conn.prepare(sql)?;
+    let ids = stmt
+        .query_map([], |r| r.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(ids)
+}
+
+/// Keep the entries of a JSON object keyed by id that `keep` accepts.
+fn retain_ids(json: &str, keep: impl Fn(i64) -> bool) -> Result<String> {
+    let map: Map<String, Value> = serde_json::from_str(json)?;
+    let kept: HashMap<_, _> = map
+        .into_iter()
+        .filter(|(id, _)| id.parse().is_ok_and(&keep))
+        .collect();
+    Ok(serde_json::to_string(&kept)?)
+}
+
+/// Add the file names referenced by `src=` attributes and `[sound:]` tags in
+/// a note's fields. Names that aren't media files, like URLs, are filtered
+/// out later against the media set.
+fn media_references(fields: &str, out: &mut BTreeSet<String>) {
+    let mut rest = fields;
+    while let Some(start) = rest.find("[sound:") {
+        rest = &rest[start + "[sound:".len()..];
+        let Some(end) = rest.find(']') else { break };
+        out.insert(rest[..end].to_owned());
+        rest = &rest[end..];
+    }
+
+    let lower = fields.to_ascii_lowercase();
+    let mut offset = 0;
+    while let Some(start) = lower[offset..].find("src=") {
+        let value = &fields[offset + start + "src=".len()..];
+        let (name, len) = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let inner = &value[1..];
+                let end = inner.find(quote).unwrap_or(inner.len());
+                (&inner[..end], end + 1)
+            }
+            _ => {
+                let end = value
+                    .find(|c: char| c.is_whitespace() || c == '>')
+                    .unwrap_or(value.len());
+                (&value[..end], end)
+            }
+        };
+        if !name.is_empty() {
+            out.insert(name.to_owned());
+        }
+        offset += start + "src=".len() + len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::LegacyCollection;
+
+    /// Decks Default, Spanish (10), Spanish::Verbs (11), French (20) and a
+    /// filtered deck (30) holding a Spanish card.
+    fn legacy_collection() -> Vec<u8> {
+        LegacyCollection::new()
+            .with_deck(10, "Spanish")
+            .with_deck(11, "Spanish::Verbs")
+            .with_deck(20, "French")
+            .with_filtered_deck(30, "Filtered Deck 1")
+            .with_note_type(100, "Basic", &["Front", "Back"])
+            .with_note_type(200, "Cloze", &["Text"])
+            .with_note(1, 100, &["hola", r#"<img src="hola.jpg">"#])
+            .with_note(2, 100, &["ser", "[sound:ser.mp3]"])
+            .with_note(3, 200, &["bonjour <img src=bonjour.png>"])
+            .with_note(4, 100, &["adiós"])
+            .with_card(1, 1, 10)
+            .with_card(2, 2, 11)
+            .with_card(3, 3, 20)
+            .with_filtered_card(4, 4, 30, 10, 5)
+            .with_review(1, 1)
+            .with_review(2, 3)
+            .with_review(3, 4)
+            .with_grave(1, 99, 0)
+            .build()
+    }
+
+    fn collection(sql: &str) -> Vec<u8> {
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        Connection::open(tmp.path())
+            .unwrap()
+            .execute_batch(sql)
+            .unwrap();
+        fs::read(tmp.path()).unwrap()
+    }
+
+    fn open(bytes: &[u8]) -> (tempfile::NamedTempFile, Connection) {
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        fs::write(tmp.path(), bytes).unwrap();
+        let conn = Connection::open(tmp.path()).unwrap();
+        (tmp, conn)
+    }
+
+    #[test]
+    fn extracts_a_legacy_deck_with_subdecks() {
+        let export = extract_deck(&legacy_collection(), 10).unwrap().unwrap();
+        let (_tmp, conn) = open(&export.collection);
+        assert_eq!(ids(&conn, "SELECT id FROM cards").unwrap(), [1, 2, 4]);
+        assert_eq!(ids(&conn, "SELECT id FROM notes").unwrap(), [1, 2, 4]);
+        assert_eq!(ids(&conn, "SELECT id FROM revlog").unwrap(), [1, 3]);
+        assert!(ids(&conn, "SELECT oid FROM graves").unwrap().is_empty());
+        assert_eq!(
+            ids(
+                &conn,
+                "SELECT did FROM cards WHERE id = 4 AND due = 5 AND odid = 0"
+            )
+            .unwrap(),
+            [10]
+        );
+
+        let (decks, models): (String, String) = conn
+            .query_row("SELECT decks, models FROM col", [], |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })
+            .unwrap();
+        let mut decks: Vec<_> = parse_deck_names_legacy(&decks)
+            .unwrap()
+            .into_values()
+            .collect();
+        decks.sort();
+        assert_eq!(decks, ["Default", "Spanish", "Spanish::Verbs"]);
+        let models: Map<String, Value> = serde_json::from_str(&models).unwrap();
+        assert_eq!(models.keys().collect::<Vec<_>>(), ["100"]);
+        assert_eq!(
+            export.media.iter().collect::<Vec<_>>(),
+            ["hola.jpg", "ser.mp3"]
+        );
+
+        let verbs = extract_deck(&legacy_collection(), 11).unwrap().unwrap();
+        let (_tmp, conn) = open(&verbs.collection);
+        assert_eq!(ids(&conn, "SELECT id FROM cards").unwrap(), [2]);
+        assert!(extract_deck(&legacy_collection(), 999).unwrap().is_none());
+    }
+
+    #[test]
+    fn extracts_a_deck_from_table_schemas() {
+        let bytes = collection(
+            "CREATE TABLE decks (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+             CREATE TABLE notetypes (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+             CREATE TABLE fields (ntid INTEGER NOT NULL, ord INTEGER NOT NULL);
+             CREATE TABLE templates (ntid INTEGER NOT NULL, ord INTEGER NOT NULL);
+             CREATE TABLE notes (id INTEGER PRIMARY KEY, mid INTEGER NOT NULL, flds TEXT NOT NULL);
+             CREATE TABLE cards (id INTEGER PRIMARY KEY, nid INTEGER NOT NULL, did INTEGER NOT NULL,
+                                 due INTEGER NOT NULL, odue INTEGER NOT NULL, odid INTEGER NOT NULL);
+             CREATE TABLE revlog (id INTEGER PRIMARY KEY, cid INTEGER NOT NULL);
+             CREATE TABLE graves (oid INTEGER, type INTEGER, usn INTEGER);
+             INSERT INTO decks VALUES (1, 'Default'), (10, 'Spanish'),
+                 (11, 'Spanish' || char(31) || 'Verbs'), (12, 'Spanish Extra');
+             INSERT INTO notetypes VALUES (100, 'Basic'), (200, 'Cloze');
+             INSERT INTO fields VALUES (100, 0), (100, 1), (200, 0);
+             INSERT INTO templates VALUES (100, 0), (200, 0);
+             INSERT INTO notes VALUES (1, 100, 'a'), (2, 200, 'b'), (3, 200, 'c');
+             INSERT INTO cards VALUES (1, 1, 10, 0, 0, 0), (2, 2, 11, 0, 0, 0), (3, 3, 12, 0, 0, 0);",
+        );
+        let export = extract_deck(&bytes, 10).unwrap().unwrap();
+        let (_tmp, conn) = open(&export.collection);
+        assert_eq!(ids(&conn, "SELECT id FROM decks").unwrap(), [1, 10, 11]);
+        assert_eq!(ids(&conn, "SELECT id FROM cards").unwrap(), [1, 2]);
+        assert_eq!(ids(&conn, "SELECT id FROM notetypes").unwrap(), [100, 200]);
+
+        let extra = extract_deck(&bytes, 12).unwrap().unwrap();
+        let (_tmp, conn) = open(&extra.collection);
+        assert_eq!(ids(&conn, "SELECT id FROM notetypes").unwrap(), [200]);
+        assert_eq!(ids(&conn, "SELECT ntid FROM fields").unwrap(), [200]);
+        assert_eq!(ids(&conn, "SELECT ntid FROM templates").unwrap(), [200]);
+    }
+
+    #[test]
+    fn finds_media_references() {
+        let mut media = BTreeSet::new();
+        media_references(
+            "<IMG SRC='a b.jpg'><img src=c.png alt=x>[sound:d.mp3][sound:e",
+            &mut media,
+        );
+        assert_eq!(
+            media.iter().collect::<Vec<_>>(),
+            ["a b.jpg", "c.png", "d.mp3"]
+        );
+    }
+}
@@ -1,7 +1,37 @@
+pub mod blob_store;
+mod chunking;
+pub mod crypto;
+mod deck;
+pub mod local_blob_store;
+pub mod media;
+pub mod metadata_migration;
+pub mod migrations;
+pub mod object_store;
+pub mod package;
 pub mod postgres_store;
 mod repository;
+pub mod restore;
+pub mod retention;
+pub mod s3_blob_store;
+mod snapshot;
 pub mod sqlite_store;
 pub mod store;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 
-pub use repository::{BackupPayload, BackupRepository, RunOnceOutcome};
-pub use store::MetadataStore;
+pub use blob_store::{BlobStore, PrefixedBlobStore};
+pub use crypto::KeySource;
+pub use local_blob_store::LocalBlobStore;
+pub use media::{MediaFile, MediaSet};
+pub use metadata_migration::{migrate_metadata, open_metadata_store, MetadataMigrationReport};
+pub use object_store::WriteGuard;
+pub use package::{Package, PackageFormat, PackageWriter};
+pub use repository::{
+    validate_profile_name, BackupPayload, BackupRepository, ImportOutcome, KeyRotationReport,
+    ReindexConflict, ReindexReport, RunOnceOutcome, StorageMigrationReport, VerifyFailure,
+    VerifyReport, DEFAULT_COMPRESSION_LEVEL,
+};
+pub use restore::{MergeReport, RestoreRejected, RestoreSelection};
+pub use retention::{plan_retention, RetentionPlan, RetentionPolicy, RetentionReason};
+pub use s3_blob_store::{S3BlobStore, S3Config};
+pub use store::{MetadataStore, DEFAULT_PROFILE};
@@ -0,0 +1,208 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+
+use crate::blob_store::BlobStore;
+
+const TMP_SUFFIX: &str = ".tmp";
+
+/// Filesystem-backed blob store. Keys map directly onto paths under `root`,
+/// which keeps the on-disk layout identical to earlier versions.
+#[derive(Debug, Clone)]
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        if key.is_empty()
+            || key.starts_with('/')
+            || key.split('/').any(|part| part == ".." || part == ".")
+        {
+            return Err(anyhow!("invalid blob key: {key:?}"));
+        }
+        Ok(self.root.join(key))
+    }
+
+    /// Remove now-empty parent directories of `path`, stopping at the root.
+    fn prune_empty_dirs(&self, path: &Path) {
+        let mut dir = path.parent();
+        while let Some(d) = dir {
+            if d == self.root || fs::remove_dir(d).is_err() {
+                break;
+            }
+            dir = d.parent();
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+        let path = self.path(key)?;
+        tokio::task::spawn_blocking(move || {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("create directory: {}", parent.display()))?;
+            }
+            let mut tmp = path.clone().into_os_string();
+            tmp.push(TMP_SUFFIX);
+            fs::write(&tmp, &bytes).with_context(|| format!("write {}", path.display()))?;
+            fs::rename(&tmp, &path).with_context(|| format!("rename {}", path.display()))
+        })
+        .await?
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(key)?;
+        tokio::task::spawn_blocking(move || match fs::read(&path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("read {}", path.display())),
+        })
+        .await?
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>> {
+        let path = self.path(key)?;
+        tokio::task::spawn_blocking(move || match fs::metadata(&path) {
+            Ok(meta) if meta.is_file() => Ok(Some(meta.len())),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("stat {}", path.display())),
+        })
+        .await?
+    }
+
+    async fn modified(&self, key: &str) -> Result<Option<DateTime<Utc>>> {
+        let path = self.path(key)?;
+        tokio::task::spawn_blocking(move || match fs::metadata(&path) {
+            Ok(meta) if meta.is_file() => {
+                let modified = meta
+                    .modified()
+                    .with_context(|| format!("modification time of {}", path.display()))?;
+                Ok(Some(modified.into()))
+            }
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("stat {}", path.display())),
+        })
+        .await?
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key)?;
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || match fs::remove_file(&path) {
+            Ok(()) => {
+                store.prune_empty_dirs(&path);
+                Ok(())
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("remove {}", path.display())),
+        })
+        .await?
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let root = self.root.clone();
+        let prefix = prefix.to_owned();
+        tokio::task::spawn_blocking(move || {
+            // Only walk the deepest directory the prefix fully names.
+            let base = match prefix.rfind('/') {
+                Some(idx) => root.join(&prefix[..idx]),
+                None => root.clone(),
+            };
+            let mut keys = Vec::new();
+            let mut pending = vec![base];
+            while let Some(dir) = pending.pop() {
+                let entries = match fs::read_dir(&dir) {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e).with_context(|| format!("list {}", dir.display())),
+                };
+                for entry in entries {
+                    let path = entry?.path();
+                    if path.is_dir() {
+                        pending.push(path);
+                        continue;
+                    }
+                    let Ok(rel) = path.strip_prefix(&root) else {
+                        continue;
+                    };
+                    let key = rel
+                        .components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    if key.starts_with(&prefix) && !key.ends_with(TMP_SUFFIX) {
+                        keys.push(key);
+                    }
+                }
+            }
+            Ok(keys)
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn put_get_list_delete() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = LocalBlobStore::new(tmp.path());
+
+        store
+            .put("backups/a/manifest.json", b"{}".to_vec())
+            .await
+            .unwrap();
+        store
+            .put("backups/b/manifest.json", b"[]".to_vec())
+            .await
+            .unwrap();
+        store
+            .put("objects/ab/abcd", b"chunk".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store.get("backups/a/manifest.json").await.unwrap().unwrap(),
+            b"{}"
+        );
+        assert_eq!(store.size("objects/ab/abcd").await.unwrap(), Some(5));
+        let modified = store.modified("objects/ab/abcd").await.unwrap().unwrap();
+        assert!((Utc::now() - modified).num_minutes() < 1);
+        assert_eq!(store.get("missing").await.unwrap(), None);
+        assert_eq!(store.modified("missing").await.unwrap(), None);
+
+        let mut keys = store.list("backups/").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, ["backups/a/manifest.json", "backups/b/manifest.json"]);
+
+        store.delete("backups/a/manifest.json").await.unwrap();
+        store.delete("backups/a/manifest.json").await.unwrap();
+        assert!(!tmp.path().join("backups/a").exists());
+        assert_eq!(
+            store.list("backups/").await.unwrap(),
+            ["backups/b/manifest.json"]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_escaping_keys() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = LocalBlobStore::new(tmp.path());
+        assert!(store.put("../escape", vec![]).await.is_err());
+        assert!(store.get("/etc/passwd").await.is_err());
+    }
+}
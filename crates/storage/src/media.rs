@@ -0,0 +1,94 @@
+//! Media files (the contents of `collection.media`) captured with backups.
+//!
+//! File contents go through the shared chunk store, so a picture referenced by
+//! a hundred backups is stored once. Each backup points at a *media set*: a
+//! JSON listing of file names and their chunks, stored under
+//! `media/<sha256 of the listing>.json`. Consecutive backups with unchanged
+//! media share the same set.
+
+use std::collections::BTreeMap;
+
+use anki_backup_core::content_hash;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::object_store::ChunkRef;
+
+/// Key prefix under which media sets are stored.
+pub const MEDIA_PREFIX: &str = "media/";
+
+/// The media files present when a backup was taken.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MediaSet {
+    /// Media USN on the sync server this set is current to, so the next sync
+    /// only asks for later changes.
+    pub usn: i64,
+    pub files: BTreeMap<String, MediaFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MediaFile {
+    /// Hex SHA-1 of the contents, as used by the sync protocol.
+    pub sha1: String,
+    pub size: u64,
+    pub chunks: Vec<ChunkRef>,
+    /// Modification time (ns since the Unix epoch) of the local file this was
+    /// read from, so unchanged files need not be hashed again. Not part of
+    /// the set id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime_ns: Option<i64>,
+}
+
+impl MediaSet {
+    /// Content-derived id of the set; equal sets share an id.
+    pub fn id(&self) -> Result<String> {
+        let mut contents = self.clone();
+        for file in contents.files.values_mut() {
+            file.mtime_ns = None;
+        }
+        Ok(content_hash(
+            &serde_json::to_vec(&contents).context("serialize media set")?,
+        ))
+    }
+
+    /// Sum of the logical sizes of every file.
+    pub fn total_size(&self) -> u64 {
+        self.files.values().map(|f| f.size).sum()
+    }
+}
+
+pub(crate) fn media_set_key(id: &str) -> String {
+    format!("{MEDIA_PREFIX}{id}.json")
+}
+
+pub(crate) fn sha1_hex(data: &[u8]) -> String {
+    hex::encode(Sha1::digest(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_follows_contents() {
+        let mut set = MediaSet::default();
+        let empty = set.id().unwrap();
+        set.files.insert(
+            "cat.jpg".to_owned(),
+            MediaFile {
+                sha1: sha1_hex(b"meow"),
+                size: 4,
+                chunks: Vec::new(),
+                mtime_ns: None,
+            },
+        );
+        assert_ne!(set.id().unwrap(), empty);
+        assert_eq!(set.id().unwrap(), set.clone().id().unwrap());
+
+        let mut touched = set.clone();
+        touched.files.get_mut("cat.jpg").unwrap().mtime_ns = Some(1);
+        assert_eq!(touched.id().unwrap(), set.id().unwrap());
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+}
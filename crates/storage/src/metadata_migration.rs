@@ -0,0 +1,263 @@
+//! Copy backup history between metadata stores, e.g. from the default SQLite
+//! database to Postgres when moving to a multi-instance deployment.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::postgres_store::PostgresStore;
+use crate::sqlite_store::SqliteStore;
+use crate::store::MetadataStore;
+
+/// Summary of [`migrate_metadata`].
+#[derive(Debug, Clone, Default)]
+pub struct MetadataMigrationReport {
+    pub backups_copied: usize,
+    /// Backups already present in the destination with the same content.
+    pub backups_present: usize,
+    pub rollback_events_copied: usize,
+    pub rollback_events_present: usize,
+}
+
+/// Open a metadata store from a `postgres://` URL or a SQLite database path
+/// (optionally written as `sqlite://<path>`).
+pub async fn open_metadata_store(location: &str) -> Result<Arc<dyn MetadataStore>> {
+    if location.starts_with("postgres://") || location.starts_with("postgresql://") {
+        return Ok(Arc::new(PostgresStore::new(location).await?));
+    }
+    let path = PathBuf::from(location.strip_prefix("sqlite://").unwrap_or(location));
+    Ok(Arc::new(SqliteStore::new(path)?))
+}
+
+/// Copy every backup and rollback event of every profile from `from` into `to`.
+///
+/// Rows are matched by id: ones already in the destination are left alone,
+/// so an interrupted or repeated run only copies what is missing. A backup
+/// whose id exists in the destination with a different directory or content
+/// hash is an error, and nothing more is written for that profile. Afterwards
+/// every source id is checked to be present in the destination.
+pub async fn migrate_metadata(
+    from: &dyn MetadataStore,
+    to: &dyn MetadataStore,
+) -> Result<MetadataMigrationReport> {
+    let mut report = MetadataMigrationReport::default();
+    for profile in from.list_profiles().await.context("list source profiles")? {
+        migrate_profile(
+            from.with_profile(&profile).as_ref(),
+            to.with_profile(&profile).as_ref(),
+            &mut report,
+        )
+        .await
+        .with_context(|| format!("migrate profile {profile}"))?;
+    }
+    Ok(report)
+}
+
+async fn migrate_profile(
+    from: &dyn MetadataStore,
+    to: &dyn MetadataStore,
+    report: &mut MetadataMigrationReport,
+) -> Result<()> {
+    let backups = from.list_backups().await.context("list source backups")?;
+    let events = from
+        .list_rollback_events()
+        .await
+        .context("list source rollback events")?;
+    let existing: HashMap<_, _> = to
+        .list_backups()
+        .await
+        .context("list destination backups")?
+        .into_iter()
+        .map(|b| (b.id, b))
+        .collect();
+    let existing_events: HashSet<_> = to
+        .list_rollback_events()
+        .await
+        .context("list destination rollback events")?
+        .into_iter()
+        .map(|e| e.id)
+        .collect();
+
+    for backup in &backups {
+        if let Some(other) = existing.get(&backup.id) {
+            if other.timestamp_dir != backup.timestamp_dir
+                || other.content_hash != backup.content_hash
+            {
+                bail!(
+                    "backup {} differs between source and destination ({} vs {})",
+                    backup.id,
+                    backup.timestamp_dir,
+                    other.timestamp_dir
+                );
+            }
+        }
+    }
+
+    // Oldest first, so an interrupted copy leaves a contiguous prefix of history.
+    for backup in backups.iter().rev() {
+        if existing.contains_key(&backup.id) {
+            report.backups_present += 1;
+            continue;
+        }
+        to.insert_entry(backup)
+            .await
+            .with_context(|| format!("copy backup {}", backup.id))?;
+        report.backups_copied += 1;
+    }
+    for event in events.iter().rev() {
+        if existing_events.contains(&event.id) {
+            report.rollback_events_present += 1;
+            continue;
+        }
+        to.insert_rollback_event(event)
+            .await
+            .with_context(|| format!("copy rollback event {}", event.id))?;
+        report.rollback_events_copied += 1;
+    }
+
+    let copied: HashSet<_> = to.list_backups().await?.into_iter().map(|b| b.id).collect();
+    if let Some(missing) = backups.iter().find(|b| !copied.contains(&b.id)) {
+        return Err(anyhow!(
+            "backup {} is missing from the destination after migration",
+            missing.id
+        ));
+    }
+    let copied: HashSet<_> = to
+        .list_rollback_events()
+        .await?
+        .into_iter()
+        .map(|e| e.id)
+        .collect();
+    if let Some(missing) = events.iter().find(|e| !copied.contains(&e.id)) {
+        return Err(anyhow!(
+            "rollback event {} is missing from the destination after migration",
+            missing.id
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anki_backup_core::{
+        BackupEntry, BackupStatus, RollbackEvent, RollbackUpload, RollbackVerification,
+        UploadCheckStatus,
+    };
+    use chrono::{Duration, Utc};
+    use uuid::Uuid;
+
+    fn backup(hours_ago: i64) -> BackupEntry {
+        let created_at = Utc::now() - Duration::hours(hours_ago);
+        BackupEntry {
+            id: Uuid::new_v4(),
+            created_at,
+            timestamp_dir: format!("dir-{hours_ago}"),
+            content_hash: format!("hash-{hours_ago}"),
+            status: BackupStatus::Created,
+            skip_reason: None,
+            source_revision: None,
+            sync_duration_ms: Some(5),
+            size_bytes: 100,
+            stored_size_bytes: Some(40),
+            stats: None,
+            pinned: hours_ago == 2,
+            note: (hours_ago == 2).then(|| "keep".to_owned()),
+            verification: None,
+            media_set: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn copies_history_once_and_verifies() {
+        let tmp = tempfile::tempdir().unwrap();
+        let from = open_metadata_store(tmp.path().join("from.db").to_str().unwrap())
+            .await
+            .unwrap();
+        let to = open_metadata_store(&format!("sqlite://{}", tmp.path().join("to.db").display()))
+            .await
+            .unwrap();
+
+        let backups = [backup(3), backup(2), backup(1)];
+        for b in &backups {
+            from.insert_entry(b).await.unwrap();
+        }
+        let event = RollbackEvent {
+            id: Uuid::new_v4(),
+            backup_id: backups[0].id,
+            created_at: Utc::now(),
+            actor: Some("api token".to_owned()),
+            upload: Some(RollbackUpload::Uploaded),
+            verification: Some(RollbackVerification {
+                status: UploadCheckStatus::Diverged,
+                checked_at: Utc::now(),
+                detail: Some("notes: 2 uploaded, 1 on the server".to_owned()),
+            }),
+        };
+        from.insert_rollback_event(&event).await.unwrap();
+        // Pretend an earlier run was interrupted after the first backup.
+        to.insert_entry(&backups[0]).await.unwrap();
+
+        let report = migrate_metadata(from.as_ref(), to.as_ref()).await.unwrap();
+        assert_eq!(report.backups_copied, 2);
+        assert_eq!(report.backups_present, 1);
+        assert_eq!(report.rollback_events_copied, 1);
+
+        let pinned = to.get_backup(backups[1].id).await.unwrap().unwrap();
+        assert!(pinned.pinned);
+        assert_eq!(pinned.note.as_deref(), Some("keep"));
+        assert_eq!(to.list_rollback_events().await.unwrap(), [event]);
+
+        let again = migrate_metadata(from.as_ref(), to.as_ref()).await.unwrap();
+        assert_eq!(again.backups_copied, 0);
+        assert_eq!(again.backups_present, 3);
+        assert_eq!(again.rollback_events_copied, 0);
+        assert_eq!(again.rollback_events_present, 1);
+    }
+
+    #[tokio::test]
+    async fn copies_every_profile_separately() {
+        let tmp = tempfile::tempdir().unwrap();
+        let from = SqliteStore::new(tmp.path().join("from.db")).unwrap();
+        let to = SqliteStore::new(tmp.path().join("to.db")).unwrap();
+        let default = backup(2);
+        let other = backup(1);
+        from.insert_entry(&default).await.unwrap();
+        from.with_profile("alice")
+            .insert_entry(&other)
+            .await
+            .unwrap();
+
+        let report = migrate_metadata(&from, &to).await.unwrap();
+        assert_eq!(report.backups_copied, 2);
+        assert_eq!(to.list_profiles().await.unwrap(), ["alice", "default"]);
+        let ids = |entries: Vec<BackupEntry>| entries.iter().map(|b| b.id).collect::<Vec<_>>();
+        assert_eq!(ids(to.list_backups().await.unwrap()), [default.id]);
+        assert_eq!(
+            ids(to.with_profile("alice").list_backups().await.unwrap()),
+            [other.id]
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_conflicting_ids() {
+        let tmp = tempfile::tempdir().unwrap();
+        let from = SqliteStore::new(tmp.path().join("from.db")).unwrap();
+        let to = SqliteStore::new(tmp.path().join("to.db")).unwrap();
+        let original = backup(1);
+        from.insert_entry(&original).await.unwrap();
+        to.insert_entry(&BackupEntry {
+            content_hash: "other".to_owned(),
+            ..original.clone()
+        })
+        .await
+        .unwrap();
+        let second = backup(2);
+        from.insert_entry(&second).await.unwrap();
+
+        assert!(migrate_metadata(&from, &to).await.is_err());
+        assert!(to.get_backup(second.id).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,477 @@
+//! Numbered schema migrations shared by the SQLite and Postgres stores.
+//!
+//! Applied versions are recorded in a `schema_version` table, one row per
+//! migration. Stores run every pending migration at startup and refuse to
+//! open a database migrated by a newer binary. Databases created before
+//! versioning have no `schema_version` table and are brought up to date from
+//! version 1; every step tolerates tables and columns that already exist.
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension};
+use sqlx::PgPool;
+
+/// Arbitrary key for the Postgres advisory lock serialising migrations.
+const PG_MIGRATION_LOCK: i64 = 0x616e_6b69_6261_636b;
+
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    steps: &'static [Step],
+}
+
+enum Step {
+    /// Idempotent SQL, spelled per backend.
+    Sql {
+        sqlite: &'static str,
+        postgres: &'static str,
+    },
+    /// Add a column unless it already exists.
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        sqlite: &'static str,
+        postgres: &'static str,
+    },
+}
+
+/// Every migration, in order. Append only: never edit a released entry.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create backups and rollback_events",
+        steps: &[
+            Step::Sql {
+                sqlite: "CREATE TABLE IF NOT EXISTS backups (
+                    id TEXT PRIMARY KEY,
+                    created_at TEXT NOT NULL,
+                    timestamp_dir TEXT NOT NULL,
+                    content_hash TEXT NOT NULL,
+                    status TEXT NOT NULL,
+                    skip_reason TEXT,
+                    source_revision TEXT,
+                    sync_duration_ms INTEGER,
+                    size_bytes INTEGER NOT NULL DEFAULT 0,
+                    stats_json TEXT
+                )",
+                postgres: "CREATE TABLE IF NOT EXISTS backups (
+                    id UUID PRIMARY KEY,
+                    created_at TIMESTAMPTZ NOT NULL,
+                    timestamp_dir TEXT NOT NULL,
+                    content_hash TEXT NOT NULL,
+                    status TEXT NOT NULL,
+                    skip_reason TEXT,
+                    source_revision TEXT,
+                    sync_duration_ms BIGINT,
+                    size_bytes BIGINT NOT NULL DEFAULT 0,
+                    stats_json TEXT
+                )",
+            },
+            Step::Sql {
+                sqlite: "CREATE TABLE IF NOT EXISTS rollback_events (
+                    id TEXT PRIMARY KEY,
+                    backup_id TEXT NOT NULL,
+                    created_at TEXT NOT NULL
+                )",
+                postgres: "CREATE TABLE IF NOT EXISTS rollback_events (
+                    id UUID PRIMARY KEY,
+                    backup_id UUID NOT NULL,
+                    created_at TIMESTAMPTZ NOT NULL
+                )",
+            },
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "record stored size of backups",
+        steps: &[Step::AddColumn {
+            table: "backups",
+            column: "stored_size_bytes",
+            sqlite: "INTEGER",
+            postgres: "BIGINT",
+        }],
+    },
+    Migration {
+        version: 3,
+        description: "pinned backups with notes",
+        steps: &[
+            Step::AddColumn {
+                table: "backups",
+                column: "pinned",
+                sqlite: "INTEGER NOT NULL DEFAULT 0",
+                postgres: "BOOLEAN NOT NULL DEFAULT FALSE",
+            },
+            Step::AddColumn {
+                table: "backups",
+                column: "note",
+                sqlite: "TEXT",
+                postgres: "TEXT",
+            },
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "backup verification status",
+        steps: &[
+            Step::AddColumn {
+                table: "backups",
+                column: "verification_status",
+                sqlite: "TEXT",
+                postgres: "TEXT",
+            },
+            Step::AddColumn {
+                table: "backups",
+                column: "verified_at",
+                sqlite: "TEXT",
+                postgres: "TIMESTAMPTZ",
+            },
+            Step::AddColumn {
+                table: "backups",
+                column: "verification_detail",
+                sqlite: "TEXT",
+                postgres: "TEXT",
+            },
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "media set captured with each backup",
+        steps: &[Step::AddColumn {
+            table: "backups",
+            column: "media_set",
+            sqlite: "TEXT",
+            postgres: "TEXT",
+        }],
+    },
+    Migration {
+        version: 6,
+        description: "profile namespace for backups and rollback events",
+        steps: &[
+            Step::AddColumn {
+                table: "backups",
+                column: "profile",
+                sqlite: "TEXT NOT NULL DEFAULT 'default'",
+                postgres: "TEXT NOT NULL DEFAULT 'default'",
+            },
+            Step::AddColumn {
+                table: "rollback_events",
+                column: "profile",
+                sqlite: "TEXT NOT NULL DEFAULT 'default'",
+                postgres: "TEXT NOT NULL DEFAULT 'default'",
+            },
+            Step::Sql {
+                sqlite: "CREATE INDEX IF NOT EXISTS backups_profile_created_at
+                    ON backups (profile, created_at)",
+                postgres: "CREATE INDEX IF NOT EXISTS backups_profile_created_at
+                    ON backups (profile, created_at)",
+            },
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "verification of uploaded rollbacks",
+        steps: &[
+            Step::AddColumn {
+                table: "rollback_events",
+                column: "verification_status",
+                sqlite: "TEXT",
+                postgres: "TEXT",
+            },
+            Step::AddColumn {
+                table: "rollback_events",
+                column: "verified_at",
+                sqlite: "TEXT",
+                postgres: "TIMESTAMPTZ",
+            },
+            Step::AddColumn {
+                table: "rollback_events",
+                column: "verification_detail",
+                sqlite: "TEXT",
+                postgres: "TEXT",
+            },
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "actor and upload result of rollbacks",
+        steps: &[
+            Step::AddColumn {
+                table: "rollback_events",
+                column: "actor",
+                sqlite: "TEXT",
+                postgres: "TEXT",
+            },
+            Step::AddColumn {
+                table: "rollback_events",
+                column: "upload_status",
+                sqlite: "TEXT",
+                postgres: "TEXT",
+            },
+        ],
+    },
+];
+
+/// Schema version this binary migrates databases to.
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+fn ensure_not_newer(current: i64) -> Result<()> {
+    let latest = latest_version();
+    if current > latest {
+        bail!(
+            "metadata database is at schema version {current}, but this binary only knows \
+             versions up to {latest}; upgrade anki-backup-tool before starting it against this database"
+        );
+    }
+    Ok(())
+}
+
+/// Apply pending migrations to a SQLite database and return its version.
+pub(crate) fn migrate_sqlite(conn: &mut Connection) -> Result<i64> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TEXT NOT NULL
+        )",
+    )
+    .context("create schema_version table")?;
+    let current = sqlite_version(conn)?;
+    ensure_not_newer(current)?;
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let tx = conn.transaction()?;
+        for step in migration.steps {
+            match step {
+                Step::Sql { sqlite, .. } => tx.execute_batch(sqlite)?,
+                Step::AddColumn {
+                    table,
+                    column,
+                    sqlite,
+                    ..
+                } => {
+                    if !sqlite_has_column(&tx, table, column)? {
+                        tx.execute_batch(&format!(
+                            "ALTER TABLE {table} ADD COLUMN {column} {sqlite}"
+                        ))?;
+                    }
+                }
+            }
+        }
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+            rusqlite::params![
+                migration.version,
+                migration.description,
+                Utc::now().to_rfc3339()
+            ],
+        )?;
+        tx.commit()
+            .with_context(|| format!("apply migration {}", migration.version))?;
+        tracing::info!(
+            version = migration.version,
+            description = migration.description,
+            "applied metadata schema migration"
+        );
+    }
+    Ok(latest_version().max(current))
+}
+
+/// Current schema version of a SQLite database; `0` if never migrated.
+fn sqlite_version(conn: &Connection) -> Result<i64> {
+    let version: Option<i64> = conn
+        .query_row("SELECT MAX(version) FROM schema_version", [], |r| r.get(0))
+        .optional()?
+        .flatten();
+    Ok(version.unwrap_or(0))
+}
+
+fn sqlite_has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let names = stmt
+        .query_map([], |r| r.get::<_, String>(1))?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(names.iter().any(|name| name == column))
+}
+
+/// Apply pending migrations to a Postgres database and return its version.
+///
+/// Runs in one transaction under an advisory lock, so concurrent daemons
+/// starting against the same database migrate it once.
+pub(crate) async fn migrate_postgres(pool: &PgPool) -> Result<i64> {
+    let mut tx = pool.begin().await.context("begin migration transaction")?;
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(PG_MIGRATION_LOCK)
+        .execute(&mut *tx)
+        .await
+        .context("acquire migration lock")?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version BIGINT PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL
+        )",
+    )
+    .execute(&mut *tx)
+    .await
+    .context("create schema_version table")?;
+    let current: Option<i64> = sqlx::query_scalar("SELECT MAX(version) FROM schema_version")
+        .fetch_one(&mut *tx)
+        .await
+        .context("read schema version")?;
+    let current = current.unwrap_or(0);
+    ensure_not_newer(current)?;
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        for step in migration.steps {
+            let sql = match step {
+                Step::Sql { postgres, .. } => (*postgres).to_owned(),
+                Step::AddColumn {
+                    table,
+                    column,
+                    postgres,
+                    ..
+                } => format!("ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {column} {postgres}"),
+            };
+            sqlx::query(&sql)
+                .execute(&mut *tx)
+                .await
+                .with_context(|| format!("apply migration {}", migration.version))?;
+        }
+        sqlx::query(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES ($1, $2, $3)",
+        )
+        .bind(migration.version)
+        .bind(migration.description)
+        .bind(Utc::now())
+        .execute(&mut *tx)
+        .await?;
+        tracing::info!(
+            version = migration.version,
+            description = migration.description,
+            "applied metadata schema migration"
+        );
+    }
+    tx.commit().await.context("commit migrations")?;
+    Ok(latest_version().max(current))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn
+            .prepare(&format!("PRAGMA table_info({table})"))
+            .unwrap();
+        stmt.query_map([], |r| r.get::<_, String>(1))
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn versions_are_contiguous_from_one() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i64 + 1, "{}", migration.description);
+        }
+    }
+
+    #[test]
+    fn fresh_database_migrates_to_latest() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(migrate_sqlite(&mut conn).unwrap(), latest_version());
+        let applied: i64 = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(applied, MIGRATIONS.len() as i64);
+        let backups = columns(&conn, "backups");
+        for column in [
+            "stored_size_bytes",
+            "pinned",
+            "note",
+            "verified_at",
+            "profile",
+        ] {
+            assert!(backups.iter().any(|c| c == column), "{column}");
+        }
+        let rollback_events = columns(&conn, "rollback_events");
+        assert!(rollback_events.iter().any(|c| c == "verification_status"));
+        assert!(rollback_events.iter().any(|c| c == "upload_status"));
+
+        // Re-running is a no-op.
+        assert_eq!(migrate_sqlite(&mut conn).unwrap(), latest_version());
+        let applied: i64 = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(applied, MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn unversioned_database_is_upgraded_in_place() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        // Original schema, plus one column an unversioned release added ad hoc.
+        conn.execute_batch(
+            "CREATE TABLE backups (
+                id TEXT PRIMARY KEY,
+                created_at TEXT NOT NULL,
+                timestamp_dir TEXT NOT NULL,
+                content_hash TEXT NOT NULL,
+                status TEXT NOT NULL,
+                skip_reason TEXT,
+                source_revision TEXT,
+                sync_duration_ms INTEGER,
+                size_bytes INTEGER NOT NULL DEFAULT 0,
+                stats_json TEXT,
+                stored_size_bytes INTEGER
+            );
+            CREATE TABLE rollback_events (
+                id TEXT PRIMARY KEY,
+                backup_id TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            );
+            INSERT INTO backups (id, created_at, timestamp_dir, content_hash, status, size_bytes)
+            VALUES ('a', '2026-01-01T00:00:00Z', 'dir', 'hash', 'created', 10);",
+        )
+        .unwrap();
+
+        assert_eq!(migrate_sqlite(&mut conn).unwrap(), latest_version());
+        let (hash, pinned): (String, i64) = conn
+            .query_row("SELECT content_hash, pinned FROM backups", [], |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(hash, "hash");
+        assert_eq!(pinned, 0);
+    }
+
+    #[test]
+    fn refuses_database_from_newer_binary() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_sqlite(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, 'future', '')",
+            [latest_version() + 1],
+        )
+        .unwrap();
+        let err = migrate_sqlite(&mut conn).unwrap_err();
+        assert!(err.to_string().contains("upgrade anki-backup-tool"));
+    }
+
+    /// Run with: ANKI_BACKUP_TEST_DATABASE_URL=postgres://... cargo test -p anki-backup-storage -- --ignored
+    #[tokio::test]
+    #[ignore] // requires ANKI_BACKUP_TEST_DATABASE_URL pointing at a scratch Postgres database
+    async fn postgres_migrates_to_latest_idempotently() {
+        let url =
+            std::env::var("ANKI_BACKUP_TEST_DATABASE_URL").expect("ANKI_BACKUP_TEST_DATABASE_URL");
+        let pool = PgPool::connect(&url).await.unwrap();
+        assert_eq!(migrate_postgres(&pool).await.unwrap(), latest_version());
+        assert_eq!(migrate_postgres(&pool).await.unwrap(), latest_version());
+        let version: Option<i64> = sqlx::query_scalar("SELECT MAX(version) FROM schema_version")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(version, Some(latest_version()));
+    }
+}
@@ -0,0 +1,645 @@
+//! Content-addressed chunk store shared by every backup.
+//!
+//! Chunks live under `objects/<first two hex chars>/<sha256>` and are written
+//! once no matter how many backups reference them. Each backup directory holds
+//! a `manifest.json` listing the chunks that reassemble its collection.
+//!
+//! When compression is enabled chunks are stored zstd-compressed with a `.zst`
+//! suffix. The hash always covers the uncompressed bytes, so compressed and
+//! uncompressed copies of a chunk are interchangeable. With an encryption key
+//! configured, chunks and manifests are additionally sealed (see [`crate::crypto`]).
+//!
+//! All reads and writes go through a [`BlobStore`], so the same layout is used
+//! on local disk and in S3-compatible object storage.
+//!
+//! Chunks only become reachable once a manifest or media set refers to them,
+//! so writers hold a [`WriteGuard`] until that reference is stored and
+//! garbage collection waits for them with [`ObjectStore::begin_collect`].
+//! The gate is per process: pruning from the CLI while the daemon is backing
+//! up the same storage is not covered.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use anki_backup_core::content_hash;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::blob_store::BlobStore;
+use crate::chunking::chunk_ranges;
+use crate::crypto::{is_sealed, open_if_sealed, seal_if_keyed, EncryptionKey};
+
+/// File name of the per-backup manifest inside `backups/<timestamp>/`.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Key prefix under which chunks are stored.
+pub const OBJECTS_PREFIX: &str = "objects/";
+
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChunkRef {
+    pub hash: String,
+    pub size: u64,
+    /// Bytes the chunk occupies in storage (after compression).
+    #[serde(default)]
+    pub stored_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub version: u32,
+    pub size: u64,
+    pub chunks: Vec<ChunkRef>,
+}
+
+impl BackupManifest {
+    /// Sum of the stored sizes of every referenced chunk.
+    ///
+    /// This is the compressed size of the whole payload, not what it added
+    /// to storage: chunks shared with other backups are counted in each of
+    /// them. See [`StoredPayload::new_stored_size`] for the latter.
+    pub fn stored_size(&self) -> u64 {
+        self.chunks.iter().map(|c| c.stored_size).sum()
+    }
+}
+
+/// Result of storing a payload: its manifest and how many chunks were new.
+#[derive(Debug, Clone)]
+pub struct StoredPayload {
+    pub manifest: BackupManifest,
+    pub new_chunks: usize,
+    /// Stored bytes of the chunks this payload added.
+    pub new_stored_size: u64,
+}
+
+/// Counts reported by [`ObjectStore::recompress`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecompressReport {
+    pub chunks: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+#[derive(Clone)]
+pub struct ObjectStore {
+    blobs: Arc<dyn BlobStore>,
+    compression_level: Option<i32>,
+    key: Option<EncryptionKey>,
+    gate: Arc<WriteGate>,
+}
+
+/// Keeps garbage collection and writes that aren't referenced yet apart.
+///
+/// A collection waits for every writer, and writers wait for a running
+/// collection, but not for one that is only waiting: a writer already
+/// holding a guard can take another without deadlocking.
+#[derive(Debug, Default)]
+struct WriteGate {
+    state: Mutex<GateState>,
+    changed: Notify,
+}
+
+#[derive(Debug, Default)]
+struct GateState {
+    writers: usize,
+    collecting: bool,
+}
+
+impl WriteGate {
+    /// Wait until `ready` holds, then apply `take`, under the state lock.
+    async fn acquire(&self, ready: fn(&GateState) -> bool, take: fn(&mut GateState)) {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            {
+                let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                if ready(&state) {
+                    take(&mut state);
+                    return;
+                }
+            }
+            changed.await;
+        }
+    }
+
+    fn release(&self, give_back: fn(&mut GateState)) {
+        give_back(&mut self.state.lock().unwrap_or_else(|e| e.into_inner()));
+        self.changed.notify_waiters();
+    }
+}
+
+/// Held while writing chunks or media sets that nothing refers to yet; see
+/// [`ObjectStore::begin_write`].
+#[must_use]
+pub struct WriteGuard(Arc<WriteGate>);
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        self.0.release(|s| s.writers -= 1);
+    }
+}
+
+/// Held while collecting garbage; see [`ObjectStore::begin_collect`].
+#[must_use]
+pub struct CollectGuard(Arc<WriteGate>);
+
+impl Drop for CollectGuard {
+    fn drop(&mut self) {
+        self.0.release(|s| s.collecting = false);
+    }
+}
+
+impl std::fmt::Debug for ObjectStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObjectStore")
+            .field("compression_level", &self.compression_level)
+            .field("key", &self.key)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ObjectStore {
+    pub fn new(blobs: Arc<dyn BlobStore>) -> Self {
+        Self {
+            blobs,
+            compression_level: None,
+            key: None,
+            gate: Arc::default(),
+        }
+    }
+
+    /// Keep garbage collection away until the guard is dropped. Take it
+    /// before writing chunks and hold it until a stored manifest, media set or
+    /// metadata row refers to them.
+    pub async fn begin_write(&self) -> WriteGuard {
+        self.gate
+            .acquire(|s| !s.collecting, |s| s.writers += 1)
+            .await;
+        WriteGuard(self.gate.clone())
+    }
+
+    /// Wait for in-flight writes to finish and hold new ones off until the
+    /// guard is dropped.
+    pub async fn begin_collect(&self) -> CollectGuard {
+        self.gate
+            .acquire(|s| s.writers == 0 && !s.collecting, |s| s.collecting = true)
+            .await;
+        CollectGuard(self.gate.clone())
+    }
+
+    /// Encrypt newly written chunks and files with `key`; `None` writes plaintext.
+    pub fn with_encryption_key(mut self, key: Option<EncryptionKey>) -> Self {
+        self.key = key;
+        self
+    }
+
+    pub fn encryption_key(&self) -> Option<&EncryptionKey> {
+        self.key.as_ref()
+    }
+
+    /// Compress newly written chunks with zstd at `level`; `None` stores them raw.
+    pub fn with_compression_level(mut self, level: Option<i32>) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    pub fn compression_level(&self) -> Option<i32> {
+        self.compression_level
+    }
+
+    pub fn blobs(&self) -> &Arc<dyn BlobStore> {
+        &self.blobs
+    }
+
+    /// Chunk `data`, write any chunks not already present and return the manifest.
+    pub async fn put_payload(&self, data: &[u8]) -> Result<StoredPayload> {
+        let mut chunks = Vec::new();
+        let mut new_chunks = 0;
+        let mut new_stored_size = 0;
+        for range in chunk_ranges(data) {
+            let chunk = &data[range];
+            let hash = content_hash(chunk);
+            let (stored_size, is_new) = self.put_chunk(&hash, chunk).await?;
+            if is_new {
+                new_chunks += 1;
+                new_stored_size += stored_size;
+            }
+            chunks.push(ChunkRef {
+                hash,
+                size: chunk.len() as u64,
+                stored_size,
+            });
+        }
+        Ok(StoredPayload {
+            manifest: BackupManifest {
+                version: MANIFEST_VERSION,
+                size: data.len() as u64,
+                chunks,
+            },
+            new_chunks,
+            new_stored_size,
+        })
+    }
+
+    /// Reassemble a payload from its manifest, verifying every chunk hash.
+    pub async fn read_payload(&self, manifest: &BackupManifest) -> Result<Vec<u8>> {
+        self.read_chunks(&manifest.chunks, manifest.size).await
+    }
+
+    /// Concatenate `chunks`, verifying every hash and the total `size`.
+    pub async fn read_chunks(&self, chunks: &[ChunkRef], size: u64) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(size as usize);
+        for chunk in chunks {
+            let bytes = self.get_chunk(&chunk.hash).await?;
+            if content_hash(&bytes) != chunk.hash {
+                return Err(anyhow!("chunk {} is corrupt", chunk.hash));
+            }
+            out.extend_from_slice(&bytes);
+        }
+        if out.len() as u64 != size {
+            return Err(anyhow!(
+                "reassembled payload is {} bytes, manifest says {}",
+                out.len(),
+                size
+            ));
+        }
+        Ok(out)
+    }
+
+    /// Move every chunk of `chunks` that cannot be read back or doesn't hash
+    /// to its name under `quarantine/`, returning their hashes.
+    ///
+    /// The next payload holding the same bytes then stores them again, which
+    /// repairs every backup referring to them. Missing chunks are skipped.
+    pub async fn quarantine_corrupt(&self, chunks: &[ChunkRef]) -> Result<Vec<String>> {
+        let mut quarantined = Vec::new();
+        for chunk in chunks {
+            if quarantined.contains(&chunk.hash) || self.stored_size(&chunk.hash).await?.is_none() {
+                continue;
+            }
+            if let Ok(bytes) = self.get_chunk(&chunk.hash).await {
+                if content_hash(&bytes) == chunk.hash {
+                    continue;
+                }
+            }
+            for key in candidate_keys(&chunk.hash) {
+                let Some(stored) = self.blobs.get(&key).await? else {
+                    continue;
+                };
+                let target = format!("{QUARANTINE_PREFIX}{key}");
+                self.blobs
+                    .put(&target, stored)
+                    .await
+                    .with_context(|| format!("write {target}"))?;
+                self.blobs
+                    .delete(&key)
+                    .await
+                    .with_context(|| format!("remove chunk: {key}"))?;
+            }
+            tracing::warn!(hash = %chunk.hash, "moved corrupt chunk to quarantine");
+            quarantined.push(chunk.hash.clone());
+        }
+        Ok(quarantined)
+    }
+
+    /// Delete every chunk whose hash is not in `referenced`. Returns the number removed.
+    pub async fn remove_unreferenced(&self, referenced: &HashSet<String>) -> Result<usize> {
+        let mut removed = 0;
+        for key in self.object_keys().await? {
+            if !referenced.contains(object_hash(&key)) {
+                self.blobs
+                    .delete(&key)
+                    .await
+                    .with_context(|| format!("remove chunk: {key}"))?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Rewrite every uncompressed chunk using the configured compression level.
+    ///
+    /// This is the migration path for chunks written before compression was
+    /// enabled; it is a no-op when compression is disabled.
+    pub async fn recompress(&self) -> Result<RecompressReport> {
+        let mut report = RecompressReport::default();
+        let Some(level) = self.compression_level else {
+            return Ok(report);
+        };
+        for key in self.object_keys().await? {
+            if key.ends_with(COMPRESSED_SUFFIX) {
+                continue;
+            }
+            let stored = self.read_blob(&key).await?;
+            let raw = open_if_sealed(self.key.as_ref(), stored.clone())?;
+            let compressed = zstd::encode_all(raw.as_slice(), level).context("compress chunk")?;
+            let sealed = seal_if_keyed(self.key.as_ref(), compressed)?;
+            report.bytes_before += stored.len() as u64;
+            report.bytes_after += sealed.len() as u64;
+            self.blobs
+                .put(&format!("{key}{COMPRESSED_SUFFIX}"), sealed)
+                .await?;
+            self.blobs
+                .delete(&key)
+                .await
+                .with_context(|| format!("remove chunk: {key}"))?;
+            report.chunks += 1;
+        }
+        Ok(report)
+    }
+
+    /// Re-encrypt every chunk under the configured key.
+    ///
+    /// Chunks already sealed with the configured key are skipped, so an
+    /// interrupted rotation can simply be re-run. `old` decrypts chunks sealed
+    /// under the previous key; with no configured key chunks are decrypted.
+    /// Returns the number of chunks rewritten.
+    pub async fn rekey(&self, old: Option<&EncryptionKey>) -> Result<usize> {
+        let mut rewritten = 0;
+        for key in self.object_keys().await? {
+            if self.rekey_file(&key, old).await? {
+                rewritten += 1;
+            }
+        }
+        Ok(rewritten)
+    }
+
+    /// Re-encrypt a single blob under the configured key; see [`ObjectStore::rekey`].
+    pub async fn rekey_file(&self, key: &str, old: Option<&EncryptionKey>) -> Result<bool> {
+        let stored = self.read_blob(key).await?;
+        let already_current = match &self.key {
+            Some(current) => current.sealed_this(&stored),
+            None => !is_sealed(&stored),
+        };
+        if already_current {
+            return Ok(false);
+        }
+        let plaintext = open_if_sealed(old, stored)
+            .with_context(|| format!("decrypt {key} with the old key"))?;
+        self.blobs
+            .put(key, seal_if_keyed(self.key.as_ref(), plaintext)?)
+            .await?;
+        Ok(true)
+    }
+
+    /// Stored size of a chunk, if present.
+    pub async fn stored_size(&self, hash: &str) -> Result<Option<u64>> {
+        for key in candidate_keys(hash) {
+            if let Some(size) = self
+                .blobs
+                .size(&key)
+                .await
+                .with_context(|| format!("stat chunk: {key}"))?
+            {
+                return Ok(Some(size));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read a blob written by [`ObjectStore::write_file`], decrypting it if needed.
+    pub async fn read_file(&self, key: &str) -> Result<Vec<u8>> {
+        let stored = self.read_blob(key).await?;
+        open_if_sealed(self.key.as_ref(), stored).with_context(|| format!("decrypt {key}"))
+    }
+
+    /// Write a blob, encrypting it when a key is configured.
+    pub async fn write_file(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+        self.blobs
+            .put(key, seal_if_keyed(self.key.as_ref(), bytes)?)
+            .await
+            .with_context(|| format!("write {key}"))
+    }
+
+    pub async fn read_manifest(&self, key: &str) -> Result<BackupManifest> {
+        let raw = self.read_file(key).await?;
+        serde_json::from_slice(&raw).with_context(|| format!("parse manifest: {key}"))
+    }
+
+    pub async fn write_manifest(&self, key: &str, manifest: &BackupManifest) -> Result<()> {
+        let serialized = serde_json::to_vec_pretty(manifest).context("serialize manifest")?;
+        self.write_file(key, serialized).await
+    }
+
+    async fn read_blob(&self, key: &str) -> Result<Vec<u8>> {
+        self.blobs
+            .get(key)
+            .await
+            .with_context(|| format!("read {key}"))?
+            .ok_or_else(|| anyhow!("{key} does not exist"))
+    }
+
+    /// Returns the chunk's stored size and whether it was newly written.
+    ///
+    /// A chunk already present is trusted; verification finds corrupt ones
+    /// and moves them aside with [`Self::quarantine_corrupt`].
+    async fn put_chunk(&self, hash: &str, bytes: &[u8]) -> Result<(u64, bool)> {
+        if let Some(existing) = self.stored_size(hash).await? {
+            return Ok((existing, false));
+        }
+        let key = chunk_key(hash);
+        let (key, encoded) = match self.compression_level {
+            Some(level) => (
+                format!("{key}{COMPRESSED_SUFFIX}"),
+                zstd::encode_all(bytes, level).context("compress chunk")?,
+            ),
+            None => (key, bytes.to_vec()),
+        };
+        let sealed = seal_if_keyed(self.key.as_ref(), encoded)?;
+        let stored_size = sealed.len() as u64;
+        self.blobs
+            .put(&key, sealed)
+            .await
+            .with_context(|| format!("write chunk: {key}"))?;
+        Ok((stored_size, true))
+    }
+
+    async fn get_chunk(&self, hash: &str) -> Result<Vec<u8>> {
+        let compressed_key = format!("{}{COMPRESSED_SUFFIX}", chunk_key(hash));
+        if let Some(stored) = self
+            .blobs
+            .get(&compressed_key)
+            .await
+            .with_context(|| format!("read chunk: {compressed_key}"))?
+        {
+            let bytes = open_if_sealed(self.key.as_ref(), stored)?;
+            return zstd::decode_all(bytes.as_slice())
+                .with_context(|| format!("decompress chunk: {compressed_key}"));
+        }
+        self.read_file(&chunk_key(hash)).await
+    }
+
+    async fn object_keys(&self) -> Result<Vec<String>> {
+        self.blobs
+            .list(OBJECTS_PREFIX)
+            .await
+            .context("list objects")
+    }
+}
+
+const COMPRESSED_SUFFIX: &str = ".zst";
+
+/// Where corrupt chunks are moved, out of reach of reads and GC.
+const QUARANTINE_PREFIX: &str = "quarantine/";
+
+fn chunk_key(hash: &str) -> String {
+    format!("{OBJECTS_PREFIX}{}/{hash}", &hash[..2])
+}
+
+fn candidate_keys(hash: &str) -> [String; 2] {
+    let key = chunk_key(hash);
+    [format!("{key}{COMPRESSED_SUFFIX}"), key]
+}
+
+/// The chunk hash encoded in an object key, ignoring any `.zst` suffix.
+fn object_hash(key: &str) -> &str {
+    let name = key.rsplit('/').next().unwrap_or(key);
+    name.strip_suffix(COMPRESSED_SUFFIX).unwrap_or(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_blob_store::LocalBlobStore;
+
+    fn local_store(dir: &std::path::Path) -> ObjectStore {
+        ObjectStore::new(Arc::new(LocalBlobStore::new(dir)))
+    }
+
+    #[tokio::test]
+    async fn payload_roundtrip_dedups_chunks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = local_store(tmp.path());
+        let data: Vec<u8> = (0..600_000u32).map(|i| (i % 251) as u8).collect();
+
+        let first = store.put_payload(&data).await.unwrap();
+        assert!(first.new_chunks > 0);
+        assert_eq!(first.new_stored_size, first.manifest.stored_size());
+        assert_eq!(store.read_payload(&first.manifest).await.unwrap(), data);
+
+        let second = store.put_payload(&data).await.unwrap();
+        assert_eq!(second.new_chunks, 0);
+        assert_eq!(second.new_stored_size, 0);
+        assert_eq!(second.manifest.stored_size(), first.manifest.stored_size());
+    }
+
+    #[tokio::test]
+    async fn corrupt_chunk_is_detected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = local_store(tmp.path());
+        let stored = store.put_payload(b"some collection bytes").await.unwrap();
+        let hash = &stored.manifest.chunks[0].hash;
+        std::fs::write(tmp.path().join(chunk_key(hash)), b"tampered").unwrap();
+        assert!(store.read_payload(&stored.manifest).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn quarantined_chunks_are_stored_again() {
+        let tmp = tempfile::tempdir().unwrap();
+        let raw = local_store(tmp.path());
+        let stored = raw.put_payload(b"some collection bytes").await.unwrap();
+        let hash = &stored.manifest.chunks[0].hash;
+        std::fs::write(tmp.path().join(chunk_key(hash)), b"tampered").unwrap();
+
+        // Existing chunks are trusted when storing.
+        let again = raw.put_payload(b"some collection bytes").await.unwrap();
+        assert_eq!(again.new_chunks, 0);
+
+        let chunks = &stored.manifest.chunks;
+        assert_eq!(
+            raw.quarantine_corrupt(chunks).await.unwrap(),
+            std::slice::from_ref(hash)
+        );
+        assert!(!tmp.path().join(chunk_key(hash)).exists());
+        assert!(tmp
+            .path()
+            .join(QUARANTINE_PREFIX)
+            .join(chunk_key(hash))
+            .exists());
+        assert!(raw.quarantine_corrupt(chunks).await.unwrap().is_empty());
+
+        let compressed = raw.clone().with_compression_level(Some(3));
+        let again = compressed
+            .put_payload(b"some collection bytes")
+            .await
+            .unwrap();
+        assert_eq!(again.new_chunks, 1);
+        assert!(compressed
+            .quarantine_corrupt(chunks)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            raw.read_payload(&stored.manifest).await.unwrap(),
+            b"some collection bytes"
+        );
+    }
+
+    #[tokio::test]
+    async fn collection_waits_for_writers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = local_store(tmp.path());
+        let writing = store.begin_write().await;
+        let collector = store.clone();
+        let collecting = tokio::spawn(async move {
+            let _guard = collector.begin_collect().await;
+        });
+        tokio::task::yield_now().await;
+        // A waiting collection doesn't hold up writers that nest.
+        drop(store.begin_write().await);
+        assert!(!collecting.is_finished());
+
+        drop(writing);
+        collecting.await.unwrap();
+        drop(store.begin_write().await);
+    }
+
+    #[tokio::test]
+    async fn compressed_chunks_roundtrip_and_shrink() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = local_store(tmp.path()).with_compression_level(Some(3));
+        let data = vec![0u8; 200_000];
+
+        let stored = store.put_payload(&data).await.unwrap();
+        assert!(stored.manifest.stored_size() < stored.manifest.size);
+        assert_eq!(store.read_payload(&stored.manifest).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn recompress_migrates_raw_chunks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let raw = local_store(tmp.path());
+        let data = vec![7u8; 100_000];
+        let stored = raw.put_payload(&data).await.unwrap();
+
+        let compressed = raw.clone().with_compression_level(Some(3));
+        let report = compressed.recompress().await.unwrap();
+        assert_eq!(report.chunks, stored.manifest.chunks.len());
+        assert!(report.bytes_after < report.bytes_before);
+        assert_eq!(raw.read_payload(&stored.manifest).await.unwrap(), data);
+        assert_eq!(compressed.recompress().await.unwrap().chunks, 0);
+    }
+
+    #[tokio::test]
+    async fn remove_unreferenced_keeps_live_chunks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = local_store(tmp.path());
+        let keep = store.put_payload(b"keep me").await.unwrap();
+        store.put_payload(b"drop me").await.unwrap();
+
+        let referenced = keep
+            .manifest
+            .chunks
+            .iter()
+            .map(|c| c.hash.clone())
+            .collect();
+        assert_eq!(store.remove_unreferenced(&referenced).await.unwrap(), 1);
+        assert_eq!(
+            store.read_payload(&keep.manifest).await.unwrap(),
+            b"keep me"
+        );
+    }
+}
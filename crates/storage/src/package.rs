@@ -0,0 +1,475 @@
+//! Reading and writing Anki `.colpkg` / `.apkg` packages.
+//!
+//! Both are zip archives holding the collection database, a `media` map from
+//! zip entry names (`0`, `1`, ...) to file names, and the media files. There
+//! are two layouts:
+//!
+//! - legacy (Anki < 2.1.50): `collection.anki2` or `collection.anki21`, a
+//!   JSON media map and the media files as-is;
+//! - modern: `collection.anki21b` plus a `meta` entry, with the collection,
+//!   the media map (protobuf) and every media file zstd-compressed. Modern
+//!   packages also carry a stub `collection.anki2` telling old clients to
+//!   upgrade, so `collection.anki21b` always wins.
+//!
+//! [`PackageWriter`] writes the modern layout without the stub, which Anki
+//! 2.1.50 and later import.
+
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+
+use anyhow::{bail, Context, Result};
+use sha1::{Digest, Sha1};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// First bytes of every SQLite database file.
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
+/// `PackageMetadata { version: LATEST }`, marking a modern package.
+const MODERN_META: &[u8] = &[8, 3];
+
+/// zstd level Anki itself uses for package entries.
+const PACKAGE_ZSTD_LEVEL: i32 = 0;
+
+/// Largest entry read from a package, before and after decompression. Sizes
+/// in zip headers and zstd frames are not trusted.
+const MAX_ENTRY_SIZE: u64 = 4 << 30;
+
+/// Collection entries, newest format first.
+const COLLECTION_ENTRIES: &[(&str, PackageFormat)] = &[
+    ("collection.anki21b", PackageFormat::Modern),
+    ("collection.anki21", PackageFormat::Legacy),
+    ("collection.anki2", PackageFormat::Legacy),
+];
+
+/// Which package layout was read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageFormat {
+    Legacy,
+    Modern,
+}
+
+impl PackageFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Legacy => "legacy",
+            Self::Modern => "modern",
+        }
+    }
+}
+
+/// An opened package. Media files are only decompressed when read.
+pub struct Package {
+    archive: ZipArchive<Cursor<Vec<u8>>>,
+    format: PackageFormat,
+    collection_entry: &'static str,
+    /// `(zip entry, file name)` for every media file.
+    media: Vec<(String, String)>,
+}
+
+impl Package {
+    pub fn open(bytes: Vec<u8>) -> Result<Self> {
+        let mut archive =
+            ZipArchive::new(Cursor::new(bytes)).context("package is not a zip archive")?;
+        let Some(&(collection_entry, format)) = COLLECTION_ENTRIES
+            .iter()
+            .find(|(name, _)| archive.index_for_name(name).is_some())
+        else {
+            bail!("package contains no collection");
+        };
+        let media = match read_entry(&mut archive, "media")? {
+            None => Vec::new(),
+            Some(raw) if format == PackageFormat::Modern => {
+                let raw = decompress(&raw).context("decompress media map")?;
+                parse_media_entries(&raw).context("parse media map")?
+            }
+            Some(raw) => {
+                let map: HashMap<String, String> =
+                    serde_json::from_slice(&raw).context("parse media map")?;
+                let mut media: Vec<_> = map.into_iter().collect();
+                media.sort_by_key(|(entry, _)| entry.parse::<u64>().unwrap_or(u64::MAX));
+                media
+            }
+        };
+        Ok(Self {
+            archive,
+            format,
+            collection_entry,
+            media,
+        })
+    }
+
+    pub fn format(&self) -> PackageFormat {
+        self.format
+    }
+
+    /// The collection as a standalone SQLite database in rollback-journal
+    /// mode, ready to be stored as `collection.anki2`.
+    pub fn collection(&mut self) -> Result<Vec<u8>> {
+        let raw = read_entry(&mut self.archive, self.collection_entry)?
+            .with_context(|| format!("read {}", self.collection_entry))?;
+        let mut bytes = match self.format {
+            PackageFormat::Modern => decompress(&raw).context("decompress collection")?,
+            PackageFormat::Legacy => raw,
+        };
+        if !bytes.starts_with(SQLITE_MAGIC) || bytes.len() < 100 {
+            bail!("{} is not a SQLite database", self.collection_entry);
+        }
+        // Header bytes 18 and 19 are 2 for WAL mode. Packages never include a
+        // WAL, so switch to the rollback journal like downloaded collections.
+        if bytes[18] == 2 && bytes[19] == 2 {
+            bytes[18] = 1;
+            bytes[19] = 1;
+        }
+        Ok(bytes)
+    }
+
+    /// Names of the media files, in package order.
+    pub fn media_names(&self) -> impl Iterator<Item = &str> {
+        self.media.iter().map(|(_, name)| name.as_str())
+    }
+
+    pub fn media_len(&self) -> usize {
+        self.media.len()
+    }
+
+    /// The name and contents of the `index`th media file.
+    pub fn read_media(&mut self, index: usize) -> Result<(String, Vec<u8>)> {
+        let (entry, name) = self
+            .media
+            .get(index)
+            .cloned()
+            .with_context(|| format!("no media file {index}"))?;
+        let raw = read_entry(&mut self.archive, &entry)?
+            .with_context(|| format!("media file {name} (entry {entry}) is missing"))?;
+        let bytes = match self.format {
+            PackageFormat::Modern => {
+                decompress(&raw).with_context(|| format!("decompress media file {name}"))?
+            }
+            PackageFormat::Legacy => raw,
+        };
+        Ok((name, bytes))
+    }
+}
+
+/// Builds a modern package in memory.
+///
+/// Entries are stored uncompressed in the zip since they are already
+/// zstd-compressed, as Anki does.
+pub struct PackageWriter {
+    zip: ZipWriter<Cursor<Vec<u8>>>,
+    /// Encoded `MediaEntries`.
+    media: Vec<u8>,
+    media_len: usize,
+}
+
+impl PackageWriter {
+    /// Start a package holding `collection`, a SQLite database.
+    pub fn new(collection: &[u8]) -> Result<Self> {
+        if !collection.starts_with(SQLITE_MAGIC) {
+            bail!("collection is not a SQLite database");
+        }
+        let mut writer = Self {
+            zip: ZipWriter::new(Cursor::new(Vec::new())),
+            media: Vec::new(),
+            media_len: 0,
+        };
+        writer.add_entry("collection.anki21b", &compress(collection)?)?;
+        writer.add_entry("meta", MODERN_META)?;
+        Ok(writer)
+    }
+
+    /// Add a media file, stored as the next numbered entry.
+    pub fn add_media(&mut self, name: &str, contents: &[u8]) -> Result<()> {
+        let mut entry = Vec::new();
+        write_bytes_field(1, name.as_bytes(), &mut entry);
+        write_varint(2 << 3, &mut entry);
+        write_varint(contents.len() as u64, &mut entry);
+        write_bytes_field(3, &sha1_digest(contents), &mut entry);
+        write_bytes_field(1, &entry, &mut self.media);
+
+        let zip_name = self.media_len.to_string();
+        self.add_entry(&zip_name, &compress(contents)?)
+            .with_context(|| format!("add media file {name}"))?;
+        self.media_len += 1;
+        Ok(())
+    }
+
+    /// Write the media map and return the finished package.
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        let media = compress(&self.media)?;
+        self.add_entry("media", &media)?;
+        Ok(self.zip.finish().context("finish package")?.into_inner())
+    }
+
+    fn add_entry(&mut self, name: &str, contents: &[u8]) -> Result<()> {
+        // Entries of 4 GiB or more need zip64 sizes, which must be chosen
+        // before the entry is written.
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(contents.len() as u64 >= u64::from(u32::MAX));
+        self.zip
+            .start_file(name, options)
+            .with_context(|| format!("start package entry {name}"))?;
+        self.zip
+            .write_all(contents)
+            .with_context(|| format!("write package entry {name}"))
+    }
+}
+
+fn compress(bytes: &[u8]) -> Result<Vec<u8>> {
+    zstd::encode_all(bytes, PACKAGE_ZSTD_LEVEL).context("compress package entry")
+}
+
+fn decompress(raw: &[u8]) -> Result<Vec<u8>> {
+    read_limited(zstd::Decoder::new(raw)?, MAX_ENTRY_SIZE)
+}
+
+/// Read `reader` to the end, failing once it yields more than `limit` bytes.
+fn read_limited(reader: impl Read, limit: u64) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > limit {
+        bail!("entry is larger than {limit} bytes");
+    }
+    Ok(bytes)
+}
+
+fn sha1_digest(bytes: &[u8]) -> Vec<u8> {
+    Sha1::digest(bytes).to_vec()
+}
+
+fn read_entry(archive: &mut ZipArchive<Cursor<Vec<u8>>>, name: &str) -> Result<Option<Vec<u8>>> {
+    let file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("open package entry {name}")),
+    };
+    read_limited(file, MAX_ENTRY_SIZE)
+        .with_context(|| format!("read package entry {name}"))
+        .map(Some)
+}
+
+/// Decode Anki's `MediaEntries` protobuf:
+///
+/// ```text
+/// message MediaEntries { repeated MediaEntry entries = 1; }
+/// message MediaEntry {
+///   string name = 1;
+///   uint32 size = 2;
+///   bytes sha1 = 3;
+///   optional uint32 legacy_zip_filename = 255;
+/// }
+/// ```
+///
+/// The `n`th entry is stored as zip entry `n` unless it carries a
+/// `legacy_zip_filename`.
+fn parse_media_entries(mut buf: &[u8]) -> Result<Vec<(String, String)>> {
+    let mut media = Vec::new();
+    while !buf.is_empty() {
+        if let (1, Field::Bytes(mut entry)) = read_field(&mut buf)? {
+            let mut name = None;
+            let mut zip_name = None;
+            while !entry.is_empty() {
+                match read_field(&mut entry)? {
+                    (1, Field::Bytes(n)) => {
+                        name = Some(String::from_utf8(n.to_vec()).context("media file name")?)
+                    }
+                    (255, Field::Varint(n)) => zip_name = Some(n.to_string()),
+                    _ => {}
+                }
+            }
+            let name = name.context("media entry without a name")?;
+            media.push((zip_name.unwrap_or_else(|| media.len().to_string()), name));
+        }
+    }
+    Ok(media)
+}
+
+pub(crate) enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+pub(crate) fn read_field<'a>(buf: &mut &'a [u8]) -> Result<(u64, Field<'a>)> {
+    let key = read_varint(buf)?;
+    let field = match key & 7 {
+        0 => Field::Varint(read_varint(buf)?),
+        1 | 5 => {
+            let len = if key & 7 == 1 { 8 } else { 4 };
+            if buf.len() < len {
+                bail!("truncated protobuf");
+            }
+            *buf = &buf[len..];
+            Field::Fixed
+        }
+        2 => {
+            let len = read_varint(buf)? as usize;
+            if buf.len() < len {
+                bail!("truncated protobuf");
+            }
+            let (bytes, rest) = buf.split_at(len);
+            *buf = rest;
+            Field::Bytes(bytes)
+        }
+        wire => bail!("unsupported protobuf wire type {wire}"),
+    };
+    Ok((key >> 3, field))
+}
+
+fn write_varint(mut n: u64, out: &mut Vec<u8>) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn write_bytes_field(field: u64, bytes: &[u8], out: &mut Vec<u8>) {
+    write_varint(field << 3 | 2, out);
+    write_varint(bytes.len() as u64, out);
+    out.extend_from_slice(bytes);
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first().context("truncated protobuf")?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("protobuf varint too long")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in entries {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// A legacy package with `collection` as `collection.anki2`.
+    pub(crate) fn legacy_package(collection: &[u8], media: &[(&str, &[u8])]) -> Vec<u8> {
+        let map: HashMap<String, &str> = media
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| (i.to_string(), *name))
+            .collect();
+        let map = serde_json::to_vec(&map).unwrap();
+        let names: Vec<String> = (0..media.len()).map(|i| i.to_string()).collect();
+        let mut entries: Vec<(&str, &[u8])> =
+            vec![("collection.anki2", collection), ("media", &map)];
+        entries.extend(names.iter().zip(media).map(|(n, (_, c))| (n.as_str(), *c)));
+        zip(&entries)
+    }
+
+    /// A modern package with `collection` as zstd `collection.anki21b`.
+    pub(crate) fn modern_package(collection: &[u8], media: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut map = Vec::new();
+        for (name, contents) in media {
+            let mut entry = Vec::new();
+            write_bytes_field(1, name.as_bytes(), &mut entry);
+            write_varint(2 << 3, &mut entry);
+            write_varint(contents.len() as u64, &mut entry);
+            write_bytes_field(1, &entry, &mut map);
+        }
+        let compress = |b: &[u8]| zstd::encode_all(b, 0).unwrap();
+        let collection = compress(collection);
+        let map = compress(&map);
+        let files: Vec<(String, Vec<u8>)> = media
+            .iter()
+            .enumerate()
+            .map(|(i, (_, c))| (i.to_string(), compress(c)))
+            .collect();
+        let mut entries: Vec<(&str, &[u8])> = vec![
+            ("collection.anki2", b"please update Anki"),
+            ("collection.anki21b", &collection),
+            ("meta", &[8, 3]),
+            ("media", &map),
+        ];
+        entries.extend(files.iter().map(|(n, c)| (n.as_str(), c.as_slice())));
+        zip(&entries)
+    }
+
+    fn wal_collection() -> Vec<u8> {
+        let mut bytes = SQLITE_MAGIC.to_vec();
+        bytes.resize(100, 0);
+        bytes[18] = 2;
+        bytes[19] = 2;
+        bytes
+    }
+
+    #[test]
+    fn reads_legacy_packages() {
+        let bytes = legacy_package(&wal_collection(), &[("cat.jpg", b"meow")]);
+        let mut package = Package::open(bytes).unwrap();
+        assert_eq!(package.format(), PackageFormat::Legacy);
+        let collection = package.collection().unwrap();
+        assert_eq!(&collection[18..20], &[1, 1]);
+        assert_eq!(package.media_names().collect::<Vec<_>>(), ["cat.jpg"]);
+        assert_eq!(
+            package.read_media(0).unwrap(),
+            ("cat.jpg".to_owned(), b"meow".to_vec())
+        );
+    }
+
+    #[test]
+    fn prefers_the_modern_collection() {
+        let media: &[(&str, &[u8])] = &[("a.mp3", b"woof"), ("b.jpg", b"meow")];
+        let mut package = Package::open(modern_package(&wal_collection(), media)).unwrap();
+        assert_eq!(package.format(), PackageFormat::Modern);
+        assert!(package.collection().unwrap().starts_with(SQLITE_MAGIC));
+        assert_eq!(package.media_len(), 2);
+        assert_eq!(package.read_media(1).unwrap().1, b"meow");
+    }
+
+    #[test]
+    fn written_packages_read_back() {
+        let mut writer = PackageWriter::new(&wal_collection()).unwrap();
+        writer.add_media("a.mp3", b"woof").unwrap();
+        writer.add_media("b.jpg", b"meow").unwrap();
+        let mut package = Package::open(writer.finish().unwrap()).unwrap();
+        assert_eq!(package.format(), PackageFormat::Modern);
+        assert!(package.collection().unwrap().starts_with(SQLITE_MAGIC));
+        assert_eq!(
+            package.media_names().collect::<Vec<_>>(),
+            ["a.mp3", "b.jpg"]
+        );
+        assert_eq!(
+            package.read_media(1).unwrap(),
+            ("b.jpg".to_owned(), b"meow".to_vec())
+        );
+
+        let empty = PackageWriter::new(&wal_collection()).unwrap().finish();
+        assert_eq!(Package::open(empty.unwrap()).unwrap().media_len(), 0);
+        assert!(PackageWriter::new(b"junk").is_err());
+    }
+
+    #[test]
+    fn limits_entry_sizes() {
+        let bomb = zstd::encode_all(&[0u8; 4096][..], 0).unwrap();
+        let decoder = zstd::Decoder::new(bomb.as_slice()).unwrap();
+        assert!(read_limited(decoder, 4095).is_err());
+        let decoder = zstd::Decoder::new(bomb.as_slice()).unwrap();
+        assert_eq!(read_limited(decoder, 4096).unwrap().len(), 4096);
+    }
+
+    #[test]
+    fn rejects_packages_without_a_collection() {
+        assert!(Package::open(b"not a zip".to_vec()).is_err());
+        assert!(Package::open(zip(&[("media", b"{}")])).is_err());
+        let mut package = Package::open(zip(&[("collection.anki2", b"junk")])).unwrap();
+        assert!(package.collection().is_err());
+    }
+}
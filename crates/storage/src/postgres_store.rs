@@ -1,15 +1,28 @@
-use anki_backup_core::{BackupEntry, BackupSkipReason, BackupStats, BackupStatus};
+use anki_backup_core::{
+    BackupEntry, BackupSkipReason, BackupStats, BackupStatus, BackupVerification, RollbackEvent,
+    RollbackUpload, RollbackVerification, UploadCheckStatus, VerificationStatus,
+};
+use std::sync::Arc;
+
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::{PgPool, Row};
 use uuid::Uuid;
 
-use crate::store::MetadataStore;
+use crate::migrations;
+use crate::store::{MetadataStore, DEFAULT_PROFILE};
+
+/// Columns read by [`pg_row_to_entry`].
+const BACKUP_COLUMNS: &str = "id, created_at, timestamp_dir, content_hash, status, skip_reason,
+    source_revision, sync_duration_ms, size_bytes, stats_json, stored_size_bytes, pinned, note,
+    verification_status, verified_at, verification_detail, media_set";
 
 /// Postgres-backed metadata store.
 pub struct PostgresStore {
     pool: PgPool,
+    /// Profile whose rows this store reads and writes.
+    profile: String,
 }
 
 impl PostgresStore {
@@ -19,41 +32,16 @@ impl PostgresStore {
             .connect(database_url)
             .await
             .context("connect to postgres")?;
-        let store = Self { pool };
+        let store = Self {
+            pool,
+            profile: DEFAULT_PROFILE.to_owned(),
+        };
         store.run_migrations().await?;
         Ok(store)
     }
 
     async fn run_migrations(&self) -> Result<()> {
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS backups (
-                id UUID PRIMARY KEY,
-                created_at TIMESTAMPTZ NOT NULL,
-                timestamp_dir TEXT NOT NULL,
-                content_hash TEXT NOT NULL,
-                status TEXT NOT NULL,
-                skip_reason TEXT,
-                source_revision TEXT,
-                sync_duration_ms BIGINT,
-                size_bytes BIGINT NOT NULL DEFAULT 0,
-                stats_json TEXT
-            )",
-        )
-        .execute(&self.pool)
-        .await
-        .context("create backups table")?;
-
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS rollback_events (
-                id UUID PRIMARY KEY,
-                backup_id UUID NOT NULL,
-                created_at TIMESTAMPTZ NOT NULL
-            )",
-        )
-        .execute(&self.pool)
-        .await
-        .context("create rollback_events table")?;
-
+        migrations::migrate_postgres(&self.pool).await?;
         Ok(())
     }
 }
@@ -63,8 +51,9 @@ impl MetadataStore for PostgresStore {
     async fn insert_entry(&self, entry: &BackupEntry) -> Result<()> {
         sqlx::query(
             "INSERT INTO backups (id, created_at, timestamp_dir, content_hash, status, skip_reason,
-             source_revision, sync_duration_ms, size_bytes, stats_json)
-             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+             source_revision, sync_duration_ms, size_bytes, stats_json, stored_size_bytes, pinned, note,
+             verification_status, verified_at, verification_detail, media_set, profile)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)",
         )
         .bind(entry.id)
         .bind(entry.created_at)
@@ -82,17 +71,24 @@ impl MetadataStore for PostgresStore {
                 .map(serde_json::to_string)
                 .transpose()?,
         )
+        .bind(entry.stored_size_bytes)
+        .bind(entry.pinned)
+        .bind(&entry.note)
+        .bind(entry.verification.as_ref().map(|v| v.status.as_str()))
+        .bind(entry.verification.as_ref().map(|v| v.checked_at))
+        .bind(entry.verification.as_ref().and_then(|v| v.detail.clone()))
+        .bind(&entry.media_set)
+        .bind(&self.profile)
         .execute(&self.pool)
         .await?;
         Ok(())
     }
 
     async fn list_backups(&self) -> Result<Vec<BackupEntry>> {
-        let rows = sqlx::query(
-            "SELECT id, created_at, timestamp_dir, content_hash, status, skip_reason, source_revision,
-             sync_duration_ms, size_bytes, stats_json
-             FROM backups ORDER BY created_at DESC",
-        )
+        let rows = sqlx::query(&format!(
+            "SELECT {BACKUP_COLUMNS} FROM backups WHERE profile = $1 ORDER BY created_at DESC"
+        ))
+        .bind(&self.profile)
         .fetch_all(&self.pool)
         .await?;
 
@@ -100,12 +96,11 @@ impl MetadataStore for PostgresStore {
     }
 
     async fn get_backup(&self, id: Uuid) -> Result<Option<BackupEntry>> {
-        let row = sqlx::query(
-            "SELECT id, created_at, timestamp_dir, content_hash, status, skip_reason, source_revision,
-             sync_duration_ms, size_bytes, stats_json
-             FROM backups WHERE id = $1",
-        )
+        let row = sqlx::query(&format!(
+            "SELECT {BACKUP_COLUMNS} FROM backups WHERE id = $1 AND profile = $2"
+        ))
         .bind(id)
+        .bind(&self.profile)
         .fetch_optional(&self.pool)
         .await?;
 
@@ -115,32 +110,157 @@ impl MetadataStore for PostgresStore {
         }
     }
 
-    async fn insert_rollback_event(&self, backup_id: Uuid) -> Result<()> {
-        sqlx::query("INSERT INTO rollback_events (id, backup_id, created_at) VALUES ($1, $2, $3)")
-            .bind(Uuid::new_v4())
-            .bind(backup_id)
-            .bind(Utc::now())
-            .execute(&self.pool)
-            .await?;
+    async fn insert_rollback_event(&self, event: &RollbackEvent) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO rollback_events (id, backup_id, created_at, profile,
+             verification_status, verified_at, verification_detail, actor, upload_status)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        )
+        .bind(event.id)
+        .bind(event.backup_id)
+        .bind(event.created_at)
+        .bind(&self.profile)
+        .bind(event.verification.as_ref().map(|v| v.status.as_str()))
+        .bind(event.verification.as_ref().map(|v| v.checked_at))
+        .bind(event.verification.as_ref().and_then(|v| v.detail.clone()))
+        .bind(&event.actor)
+        .bind(event.upload.map(RollbackUpload::as_str))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_rollback_events(&self) -> Result<Vec<RollbackEvent>> {
+        let rows = sqlx::query(
+            "SELECT id, backup_id, created_at, verification_status, verified_at,
+             verification_detail, actor, upload_status FROM rollback_events WHERE profile = $1
+             ORDER BY created_at DESC",
+        )
+        .bind(&self.profile)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|r| RollbackEvent {
+                id: r.get("id"),
+                backup_id: r.get("backup_id"),
+                created_at: r.get("created_at"),
+                actor: r.get("actor"),
+                upload: r
+                    .get::<Option<String>, _>("upload_status")
+                    .as_deref()
+                    .and_then(RollbackUpload::parse),
+                verification: match (
+                    r.get::<Option<String>, _>("verification_status")
+                        .as_deref()
+                        .and_then(UploadCheckStatus::parse),
+                    r.get::<Option<DateTime<Utc>>, _>("verified_at"),
+                ) {
+                    (Some(status), Some(checked_at)) => Some(RollbackVerification {
+                        status,
+                        checked_at,
+                        detail: r.get("verification_detail"),
+                    }),
+                    _ => None,
+                },
+            })
+            .collect())
+    }
+
+    async fn record_rollback_upload(
+        &self,
+        id: Uuid,
+        upload: RollbackUpload,
+        verification: Option<&RollbackVerification>,
+    ) -> Result<()> {
+        sqlx::query(
+            "UPDATE rollback_events SET upload_status = $1, verification_status = $2,
+             verified_at = $3, verification_detail = $4 WHERE id = $5 AND profile = $6",
+        )
+        .bind(upload.as_str())
+        .bind(verification.map(|v| v.status.as_str()))
+        .bind(verification.map(|v| v.checked_at))
+        .bind(verification.and_then(|v| v.detail.clone()))
+        .bind(id)
+        .bind(&self.profile)
+        .execute(&self.pool)
+        .await?;
         Ok(())
     }
 
     async fn last_created_hash(&self) -> Result<Option<String>> {
         let row = sqlx::query(
-            "SELECT content_hash FROM backups WHERE status = 'created' ORDER BY created_at DESC LIMIT 1",
+            "SELECT content_hash FROM backups WHERE status = 'created' AND profile = $1
+             ORDER BY created_at DESC LIMIT 1",
         )
+        .bind(&self.profile)
         .fetch_optional(&self.pool)
         .await?;
 
         Ok(row.map(|r| r.get("content_hash")))
     }
 
-    async fn prune_created_before(&self, cutoff: DateTime<Utc>) -> Result<Vec<(String, String)>> {
+    async fn set_stored_size(&self, id: Uuid, stored_size_bytes: i64) -> Result<()> {
+        sqlx::query("UPDATE backups SET stored_size_bytes = $1 WHERE id = $2 AND profile = $3")
+            .bind(stored_size_bytes)
+            .bind(id)
+            .bind(&self.profile)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_pinned(&self, id: Uuid, pinned: bool, note: Option<&str>) -> Result<()> {
+        sqlx::query("UPDATE backups SET pinned = $1, note = $2 WHERE id = $3 AND profile = $4")
+            .bind(pinned)
+            .bind(note)
+            .bind(id)
+            .bind(&self.profile)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn record_verification(&self, id: Uuid, verification: &BackupVerification) -> Result<()> {
+        sqlx::query(
+            "UPDATE backups SET verification_status = $1, verified_at = $2, verification_detail = $3
+             WHERE id = $4 AND profile = $5",
+        )
+        .bind(verification.status.as_str())
+        .bind(verification.checked_at)
+        .bind(&verification.detail)
+        .bind(id)
+        .bind(&self.profile)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_backups(&self, ids: &[Uuid]) -> Result<Vec<Uuid>> {
+        let rows = sqlx::query(
+            "DELETE FROM backups WHERE id = ANY($1) AND profile = $2 AND NOT pinned RETURNING id",
+        )
+        .bind(ids)
+        .bind(&self.profile)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| row.try_get("id").context("read deleted backup id"))
+            .collect()
+    }
+
+    async fn prune_created_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        keep: Option<Uuid>,
+    ) -> Result<Vec<(String, String)>> {
         let rows = sqlx::query(
-            "DELETE FROM backups WHERE status = 'created' AND created_at < $1
-             RETURNING id::text, timestamp_dir",
+            "DELETE FROM backups WHERE status = 'created' AND NOT pinned AND created_at < $1
+             AND profile = $2 AND id IS DISTINCT FROM $3 RETURNING id::text, timestamp_dir",
         )
         .bind(cutoff)
+        .bind(&self.profile)
+        .bind(keep)
         .fetch_all(&self.pool)
         .await?;
 
@@ -154,6 +274,22 @@ impl MetadataStore for PostgresStore {
             })
             .collect())
     }
+
+    fn with_profile(&self, profile: &str) -> Arc<dyn MetadataStore> {
+        Arc::new(Self {
+            pool: self.pool.clone(),
+            profile: profile.to_owned(),
+        })
+    }
+
+    async fn list_profiles(&self) -> Result<Vec<String>> {
+        let rows = sqlx::query(
+            "SELECT profile FROM backups UNION SELECT profile FROM rollback_events ORDER BY profile",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.iter().map(|r| r.get("profile")).collect())
+    }
 }
 
 fn pg_row_to_entry(row: &sqlx::postgres::PgRow) -> Result<BackupEntry> {
@@ -171,10 +307,27 @@ fn pg_row_to_entry(row: &sqlx::postgres::PgRow) -> Result<BackupEntry> {
         source_revision: row.get("source_revision"),
         sync_duration_ms: row.get("sync_duration_ms"),
         size_bytes: row.get("size_bytes"),
+        stored_size_bytes: row.get("stored_size_bytes"),
         stats: stats_json
             .map(|raw| serde_json::from_str::<BackupStats>(&raw))
             .transpose()
             .context("parse stats_json")?,
+        pinned: row.get("pinned"),
+        note: row.get("note"),
+        verification: match (
+            row.get::<Option<String>, _>("verification_status")
+                .as_deref()
+                .and_then(VerificationStatus::parse),
+            row.get::<Option<DateTime<Utc>>, _>("verified_at"),
+        ) {
+            (Some(status), Some(checked_at)) => Some(BackupVerification {
+                status,
+                checked_at,
+                detail: row.get("verification_detail"),
+            }),
+            _ => None,
+        },
+        media_set: row.get("media_set"),
     })
 }
 
@@ -1,24 +1,69 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use anki_backup_core::{BackupEntry, BackupStats, BackupStatus, DeckStats, NewBackupEntry};
+use anki_backup_core::{
+    content_hash, upload_divergences, BackupEntry, BackupStats, BackupStatus, BackupVerification,
+    CollectionDiff, DeckStats, NewBackupEntry, RollbackEvent, RollbackHistoryEntry,
+    RollbackPreview, RollbackUpload, RollbackVerification, VerificationStatus,
+};
 use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, SecondsFormat, Utc};
 use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
+use crate::blob_store::{BlobStore, PrefixedBlobStore};
+use crate::crypto::{EncryptionKey, KeySource};
+use crate::deck::extract_deck;
+use crate::local_blob_store::LocalBlobStore;
+use crate::media::{media_set_key, sha1_hex, MediaFile, MediaSet, MEDIA_PREFIX};
+use crate::object_store::{ChunkRef, ObjectStore, WriteGuard, MANIFEST_FILE};
+use crate::package::{Package, PackageFormat, PackageWriter};
 use crate::postgres_store::PostgresStore;
+use crate::restore::{merge_into, MergeReport, RestoreSelection};
+use crate::retention::{plan_retention, RetentionPlan, RetentionPolicy};
+use crate::snapshot::read_snapshot;
 use crate::sqlite_store::SqliteStore;
-use crate::store::MetadataStore;
+use crate::store::{MetadataStore, DEFAULT_PROFILE};
+
+/// Payload file name used by backups created before chunked storage.
+const LEGACY_PAYLOAD_FILE: &str = "collection.anki2";
+
+/// Per-backup copy of the metadata row, kept for disaster recovery.
+const METADATA_FILE: &str = "metadata.json";
+
+/// Key prefix of the per-backup directories in the blob store.
+const BACKUPS_PREFIX: &str = "backups/";
+
+/// Pointer to the backup most recently created or rolled back to.
+const CURRENT_POINTER_KEY: &str = "state/current-pointer.json";
+
+/// Key prefix of the namespaces of profiles other than the default one.
+const PROFILES_PREFIX: &str = "profiles/";
+
+/// Cached AnkiWeb host key, always stored encrypted.
+const HOST_KEY_CACHE_KEY: &str = "state/ankiweb-host-key";
+
+/// Backup directories and media sets no metadata row refers to yet are assumed
+/// to still be written by a run (possibly in another process) while younger
+/// than this, rather than orphaned.
+const WRITE_GRACE_MINUTES: i64 = 60;
+
+/// zstd level used for chunks unless overridden with [`BackupRepository::with_compression_level`].
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
 
 #[derive(Debug, Clone)]
 pub struct BackupPayload {
     pub bytes: Vec<u8>,
     pub source_revision: Option<String>,
     pub sync_duration_ms: Option<i64>,
+    /// Media set written with [`BackupRepository::write_media_set`], if media
+    /// was captured.
+    pub media_set: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -27,44 +72,180 @@ pub enum RunOnceOutcome {
     Skipped(BackupEntry),
 }
 
+/// Result of [`BackupRepository::import_package`].
+#[derive(Debug, Clone)]
+pub enum ImportOutcome {
+    Imported {
+        entry: BackupEntry,
+        format: PackageFormat,
+    },
+    /// A created backup already holds the same collection.
+    Duplicate(BackupEntry),
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedHostKey {
+    username: String,
+    host_key: String,
+}
+
+/// The part of `current-pointer.json` read back; the rest is for operators.
+#[derive(Deserialize)]
+struct CurrentPointer {
+    backup_id: Uuid,
+}
+
+/// Summary of [`BackupRepository::rotate_encryption_key`].
+#[derive(Debug, Clone, Default)]
+pub struct KeyRotationReport {
+    pub chunks_rewritten: usize,
+    pub files_rewritten: usize,
+}
+
+/// Summary of [`BackupRepository::migrate_storage`].
+#[derive(Debug, Clone, Default)]
+pub struct StorageMigrationReport {
+    /// Legacy `collection.anki2` directories converted to chunk manifests.
+    pub legacy_backups_converted: usize,
+    /// Uncompressed chunks rewritten with compression.
+    pub chunks_recompressed: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// A backup that failed [`BackupRepository::verify`].
+#[derive(Debug, Clone)]
+pub struct VerifyFailure {
+    pub backup_id: Uuid,
+    pub timestamp_dir: String,
+    pub status: VerificationStatus,
+    pub detail: String,
+}
+
+/// Summary of [`BackupRepository::verify`].
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub failures: Vec<VerifyFailure>,
+    /// Directories under `backups/` that no created backup refers to,
+    /// other than ones a run may still be writing.
+    pub orphaned_dirs: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.failures.is_empty() && self.orphaned_dirs.is_empty()
+    }
+}
+
+/// A backup directory [`BackupRepository::reindex`] could not restore.
+#[derive(Debug, Clone)]
+pub struct ReindexConflict {
+    pub timestamp_dir: String,
+    pub reason: String,
+}
+
+/// Summary of [`BackupRepository::reindex`].
+#[derive(Debug, Clone, Default)]
+pub struct ReindexReport {
+    /// Backup directories found under `backups/`.
+    pub scanned: usize,
+    /// Entries inserted into the metadata store.
+    pub restored: usize,
+    /// Of `restored`, entries rebuilt from the payload because `metadata.json`
+    /// was missing or unreadable.
+    pub recovered_from_payload: usize,
+    /// Directories whose entry was already in the metadata store.
+    pub already_indexed: usize,
+    pub conflicts: Vec<ReindexConflict>,
+}
+
 #[derive(Clone)]
 pub struct BackupRepository {
     root: PathBuf,
+    profile: String,
     store: Arc<dyn MetadataStore>,
+    /// Blob store shared by every profile; `objects` may be a prefixed view of it.
+    shared_blobs: Arc<dyn BlobStore>,
+    objects: ObjectStore,
+    /// Where the encryption key came from, to resolve it again when the blob
+    /// store changes.
+    key_source: Option<KeySource>,
+    /// Backup directories claimed by runs that haven't written to them yet.
+    reserved_dirs: Arc<Mutex<HashSet<String>>>,
+}
+
+/// A backup directory name claimed by
+/// [`BackupRepository::allocate_backup_dir`], released when dropped. Hold it
+/// until something has been written to the directory.
+struct ReservedDir {
+    name: String,
+    reserved: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Drop for ReservedDir {
+    fn drop(&mut self) {
+        self.reserved
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.name);
+    }
 }
 
 impl std::fmt::Debug for BackupRepository {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("BackupRepository")
             .field("root", &self.root)
+            .field("profile", &self.profile)
             .finish()
     }
 }
 
+/// Check that `name` can be used as a profile name: 1-64 ASCII letters,
+/// digits, `-` or `_`.
+pub fn validate_profile_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(anyhow!(
+            "invalid profile name {name:?}: use 1-64 letters, digits, '-' or '_'"
+        ));
+    }
+    Ok(())
+}
+
 impl BackupRepository {
     /// Create a repository with SQLite backend (original behaviour).
     pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
         let root = root.into();
-        fs::create_dir_all(root.join("backups")).context("create backups directory")?;
         fs::create_dir_all(root.join("state")).context("create state directory")?;
         let db_path = root.join("state").join("metadata.db");
         let store = SqliteStore::new(db_path)?;
-        Ok(Self {
-            root,
-            store: Arc::new(store),
-        })
+        Ok(Self::with_store(root, Arc::new(store)))
     }
 
     /// Create a repository with Postgres backend.
     pub async fn with_postgres(root: impl Into<PathBuf>, database_url: &str) -> Result<Self> {
         let root = root.into();
-        fs::create_dir_all(root.join("backups")).context("create backups directory")?;
         fs::create_dir_all(root.join("state")).context("create state directory")?;
         let store = PostgresStore::new(database_url).await?;
-        Ok(Self {
+        Ok(Self::with_store(root, Arc::new(store)))
+    }
+
+    fn with_store(root: PathBuf, store: Arc<dyn MetadataStore>) -> Self {
+        let objects = local_objects(&root);
+        Self {
             root,
-            store: Arc::new(store),
-        })
+            profile: DEFAULT_PROFILE.to_owned(),
+            store,
+            shared_blobs: objects.blobs().clone(),
+            objects,
+            key_source: None,
+            reserved_dirs: Arc::default(),
+        }
     }
 
     /// Auto-detect backend from DATABASE_URL env var.
@@ -85,15 +266,105 @@ impl BackupRepository {
         }
     }
 
+    /// Store backup payloads, manifests and state in `blobs` instead of under
+    /// the repository root. The SQLite metadata database stays local.
+    ///
+    /// A configured encryption key is resolved again against `blobs`, so a
+    /// passphrase uses the salt stored there.
+    pub async fn with_blob_store(mut self, blobs: Arc<dyn BlobStore>) -> Result<Self> {
+        self.shared_blobs = blobs.clone();
+        self.objects =
+            ObjectStore::new(blobs).with_compression_level(self.objects.compression_level());
+        let source = self.key_source.take();
+        self.with_encryption(source.as_ref()).await
+    }
+
+    /// The same storage, namespaced for `profile`.
+    ///
+    /// The profile's backups, chunks, media and state live under
+    /// `profiles/<name>/` in the blob store and its metadata rows are tagged
+    /// with its name; the default profile keeps the original unprefixed
+    /// layout. Profiles share no data, so chunks are not deduplicated across
+    /// them, but they do share this repository's encryption key; a passphrase
+    /// key keeps using the salt in the root `encryption.json`.
+    pub fn for_profile(&self, profile: &str) -> Result<Self> {
+        validate_profile_name(profile)?;
+        let blobs: Arc<dyn BlobStore> = if profile == DEFAULT_PROFILE {
+            self.shared_blobs.clone()
+        } else {
+            Arc::new(PrefixedBlobStore::new(
+                self.shared_blobs.clone(),
+                &format!("{PROFILES_PREFIX}{profile}"),
+            ))
+        };
+        Ok(Self {
+            root: self.root.clone(),
+            profile: profile.to_owned(),
+            store: self.store.with_profile(profile),
+            shared_blobs: self.shared_blobs.clone(),
+            objects: ObjectStore::new(blobs)
+                .with_compression_level(self.objects.compression_level())
+                .with_encryption_key(self.objects.encryption_key().cloned()),
+            key_source: self.key_source.clone(),
+            reserved_dirs: Arc::default(),
+        })
+    }
+
+    /// Name of the profile this repository is scoped to.
+    pub fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    /// Set the zstd level for newly stored chunks; `None` stores them uncompressed.
+    pub fn with_compression_level(mut self, level: Option<i32>) -> Self {
+        self.objects = self.objects.with_compression_level(level);
+        self
+    }
+
+    /// Encrypt everything this repository writes with the key from `source`.
+    /// A passphrase's salt lives in the root `encryption.json`, shared by all
+    /// profiles.
+    pub async fn with_encryption(mut self, source: Option<&KeySource>) -> Result<Self> {
+        let key = match source {
+            Some(source) => Some(
+                EncryptionKey::resolve(source, self.shared_blobs.as_ref())
+                    .await
+                    .context("load encryption key")?,
+            ),
+            None => None,
+        };
+        if let Some(key) = &key {
+            tracing::info!(key_id = %key.id_hex(), "backup encryption enabled");
+        }
+        self.objects = self.objects.with_encryption_key(key);
+        self.key_source = source.cloned();
+        Ok(self)
+    }
+
     pub async fn run_once(
         &self,
         payload: BackupPayload,
         content_hash: String,
     ) -> Result<RunOnceOutcome> {
-        let now = Utc::now();
+        self.run_once_at(payload, content_hash, Utc::now()).await
+    }
+
+    /// [`Self::run_once`] for a collection captured at `now`, which may be in
+    /// the past. The current pointer only moves when the new backup is the
+    /// newest one.
+    pub async fn run_once_at(
+        &self,
+        payload: BackupPayload,
+        content_hash: String,
+        now: DateTime<Utc>,
+    ) -> Result<RunOnceOutcome> {
+        let _writing = self.begin_write().await;
+        let newest = self.latest_created().await?.map(|b| b.created_at);
 
         if let Some(last_hash) = self.store.last_created_hash().await? {
-            if last_hash == content_hash {
+            if last_hash == content_hash
+                && self.media_unchanged(payload.media_set.as_deref()).await?
+            {
                 let skipped = self
                     .create_and_insert_entry(NewBackupEntry::skipped_unchanged(now, content_hash))
                     .await?;
@@ -101,36 +372,298 @@ impl BackupRepository {
             }
         }
 
-        let timestamp_dir = format_timestamp_dir(now);
-        let backup_dir = self.root.join("backups").join(&timestamp_dir);
-        fs::create_dir_all(&backup_dir)
-            .with_context(|| format!("create backup dir: {}", backup_dir.display()))?;
-
-        let payload_path = backup_dir.join("collection.anki2");
-        fs::write(&payload_path, &payload.bytes)
-            .with_context(|| format!("write payload file: {}", payload_path.display()))?;
+        let reserved = self.allocate_backup_dir(now).await?;
+        let timestamp_dir = reserved.name.clone();
 
-        let stats = extract_stats(&payload_path).context("extract backup stats")?;
-        let size_bytes = fs::metadata(&payload_path)
-            .with_context(|| format!("stat payload file: {}", payload_path.display()))?
-            .len() as i64;
+        let stats = extract_stats_from_bytes(&payload.bytes).context("extract backup stats")?;
+        let stored = self
+            .objects
+            .put_payload(&payload.bytes)
+            .await
+            .context("store payload chunks")?;
+        self.objects
+            .write_manifest(&dir_key(&timestamp_dir, MANIFEST_FILE), &stored.manifest)
+            .await?;
+        drop(reserved);
+        tracing::debug!(
+            chunks = stored.manifest.chunks.len(),
+            new_chunks = stored.new_chunks,
+            new_stored_bytes = stored.new_stored_size,
+            "stored backup payload"
+        );
+        let size_bytes = payload.bytes.len() as i64;
 
         let created = self
-            .create_and_insert_entry(NewBackupEntry::created(
-                now,
-                timestamp_dir,
-                content_hash,
-                payload.source_revision,
-                payload.sync_duration_ms,
-                size_bytes,
-                stats,
-            ))
+            .create_and_insert_entry(NewBackupEntry {
+                stored_size_bytes: Some(stored.manifest.stored_size() as i64),
+                media_set: payload.media_set,
+                ..NewBackupEntry::created(
+                    now,
+                    timestamp_dir,
+                    content_hash,
+                    payload.source_revision,
+                    payload.sync_duration_ms,
+                    size_bytes,
+                    stats,
+                )
+            })
             .await?;
 
-        self.write_current_pointer(&created)?;
+        if newest.is_none_or(|newest| newest <= created.created_at) {
+            self.write_current_pointer(&created).await?;
+        }
         Ok(RunOnceOutcome::Created(created))
     }
 
+    /// Import an Anki `.colpkg` or `.apkg` export, with its media, as a
+    /// backup.
+    ///
+    /// The backup is dated `created_at`, or else the collection's own
+    /// modification time. A package whose collection matches any created
+    /// backup is not stored again.
+    pub async fn import_package(
+        &self,
+        bytes: Vec<u8>,
+        created_at: Option<DateTime<Utc>>,
+    ) -> Result<ImportOutcome> {
+        let _writing = self.begin_write().await;
+        // Unzipping, decompressing and opening the collection are blocking.
+        let (mut package, collection, hash, modified_at) =
+            tokio::task::spawn_blocking(move || -> Result<_> {
+                let mut package = Package::open(bytes)?;
+                let collection = package.collection()?;
+                let hash = content_hash(&collection);
+                let modified_at = collection_modified_at(&collection)?;
+                Ok((package, collection, hash, modified_at))
+            })
+            .await??;
+        if let Some(existing) = self
+            .store
+            .list_backups()
+            .await?
+            .into_iter()
+            .find(|b| b.status == BackupStatus::Created && b.content_hash == hash)
+        {
+            return Ok(ImportOutcome::Duplicate(existing));
+        }
+
+        let created_at = created_at.or(modified_at).unwrap_or_else(Utc::now);
+        let media_set = if package.media_len() > 0 {
+            let mut set = MediaSet::default();
+            for i in 0..package.media_len() {
+                let (returned, read) = tokio::task::spawn_blocking(move || {
+                    let read = package.read_media(i);
+                    (package, read)
+                })
+                .await?;
+                package = returned;
+                let (name, contents) = read?;
+                set.files
+                    .insert(name, self.put_media_file(&contents).await?);
+            }
+            Some(self.write_media_set(&set).await?)
+        } else {
+            None
+        };
+
+        let payload = BackupPayload {
+            bytes: collection,
+            source_revision: modified_at.map(|t| t.timestamp_millis().to_string()),
+            sync_duration_ms: None,
+            media_set,
+        };
+        match self.run_once_at(payload, hash, created_at).await? {
+            RunOnceOutcome::Created(entry) => Ok(ImportOutcome::Imported {
+                entry,
+                format: package.format(),
+            }),
+            RunOnceOutcome::Skipped(entry) => Ok(ImportOutcome::Duplicate(entry)),
+        }
+    }
+
+    /// Hold off garbage collection until the guard is dropped.
+    ///
+    /// Media files and sets stored ahead of the backup that refers to them
+    /// must be written under one guard that lasts until the backup is
+    /// recorded; see [`ObjectStore::begin_write`].
+    pub async fn begin_write(&self) -> WriteGuard {
+        self.objects.begin_write().await
+    }
+
+    /// Whether `media_set` matches the media of the newest created backup.
+    /// Payloads without media never count as a change.
+    async fn media_unchanged(&self, media_set: Option<&str>) -> Result<bool> {
+        let Some(media_set) = media_set else {
+            return Ok(true);
+        };
+        let last = self.latest_created().await?;
+        Ok(last.and_then(|b| b.media_set).as_deref() == Some(media_set))
+    }
+
+    /// The newest created backup.
+    pub async fn latest_created(&self) -> Result<Option<BackupEntry>> {
+        Ok(self
+            .store
+            .list_backups()
+            .await?
+            .into_iter()
+            .find(|b| b.status == BackupStatus::Created))
+    }
+
+    /// Media set of the newest backup that captured media, or an empty set.
+    ///
+    /// Media syncs start from here and only fetch later changes.
+    pub async fn latest_media_set(&self) -> Result<MediaSet> {
+        let backups = self.store.list_backups().await?;
+        let latest = backups
+            .iter()
+            .filter(|b| b.status == BackupStatus::Created)
+            .find_map(|b| b.media_set.as_deref());
+        match latest {
+            Some(id) => self.read_media_set(id).await,
+            None => Ok(MediaSet::default()),
+        }
+    }
+
+    pub async fn read_media_set(&self, id: &str) -> Result<MediaSet> {
+        let key = media_set_key(id);
+        let raw = self.objects.read_file(&key).await?;
+        serde_json::from_slice(&raw).with_context(|| format!("parse media set {key}"))
+    }
+
+    /// Store a media set and return its id. A set already stored is written
+    /// again, restarting the grace period [`Self::collect_garbage`] gives
+    /// sets no backup refers to yet.
+    pub async fn write_media_set(&self, set: &MediaSet) -> Result<String> {
+        let id = set.id()?;
+        let serialized = serde_json::to_vec_pretty(set).context("serialize media set")?;
+        self.objects
+            .write_file(&media_set_key(&id), serialized)
+            .await?;
+        Ok(id)
+    }
+
+    /// Store the contents of one media file in the shared chunk store.
+    pub async fn put_media_file(&self, bytes: &[u8]) -> Result<MediaFile> {
+        let stored = self
+            .objects
+            .put_payload(bytes)
+            .await
+            .context("store media file")?;
+        Ok(MediaFile {
+            sha1: sha1_hex(bytes),
+            size: bytes.len() as u64,
+            chunks: stored.manifest.chunks,
+            mtime_ns: None,
+        })
+    }
+
+    pub async fn read_media_file(&self, file: &MediaFile) -> Result<Vec<u8>> {
+        self.objects.read_chunks(&file.chunks, file.size).await
+    }
+
+    /// The host key cached for `username` by [`Self::store_host_key`], if any.
+    ///
+    /// A cache that cannot be decrypted (e.g. after a key rotation or a
+    /// password change) or belongs to another account counts as empty.
+    pub async fn cached_host_key(&self, username: &str, password: &str) -> Result<Option<String>> {
+        let Some(sealed) = self.objects.blobs().get(HOST_KEY_CACHE_KEY).await? else {
+            return Ok(None);
+        };
+        let Some(key) = self.host_key_cipher(username, password).await? else {
+            return Ok(None);
+        };
+        let cached: CachedHostKey = match key
+            .open(&sealed)
+            .and_then(|raw| Ok(serde_json::from_slice(&raw)?))
+        {
+            Ok(cached) => cached,
+            Err(e) => {
+                tracing::warn!(error = %e, "ignoring unreadable host key cache");
+                return Ok(None);
+            }
+        };
+        Ok((cached.username == username).then_some(cached.host_key))
+    }
+
+    /// Cache the AnkiWeb host key for `username` under `state/`.
+    ///
+    /// The cache is sealed with the repository encryption key when one is
+    /// configured, otherwise with a key derived from the AnkiWeb password.
+    pub async fn store_host_key(
+        &self,
+        username: &str,
+        password: &str,
+        host_key: &str,
+    ) -> Result<()> {
+        let key = self
+            .host_key_cipher(username, password)
+            .await?
+            .ok_or_else(|| anyhow!("caching a host key needs a password or encryption key"))?;
+        let cached = serde_json::to_vec(&CachedHostKey {
+            username: username.to_owned(),
+            host_key: host_key.to_owned(),
+        })?;
+        self.objects
+            .blobs()
+            .put(HOST_KEY_CACHE_KEY, key.seal(&cached)?)
+            .await
+            .context("write host key cache")
+    }
+
+    pub async fn clear_host_key(&self) -> Result<()> {
+        self.objects.blobs().delete(HOST_KEY_CACHE_KEY).await
+    }
+
+    async fn host_key_cipher(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<EncryptionKey>> {
+        if let Some(key) = self.objects.encryption_key() {
+            return Ok(Some(key.clone()));
+        }
+        if password.is_empty() {
+            return Ok(None);
+        }
+        let salt = Sha256::digest(format!("anki-backup-tool host key:{username}"));
+        let password = password.to_owned();
+        // Argon2 takes a noticeable amount of CPU time.
+        tokio::task::spawn_blocking(move || EncryptionKey::from_passphrase(&password, &salt))
+            .await?
+            .map(Some)
+    }
+
+    /// Re-seal the host key cache under the configured key after a rotation.
+    ///
+    /// A cache sealed with the account password (no old key), or one the old
+    /// key cannot open, cannot be carried over and is removed; the next sync
+    /// logs in again.
+    async fn reseal_host_key(&self, old: Option<&EncryptionKey>) -> Result<bool> {
+        let Some(sealed) = self.objects.blobs().get(HOST_KEY_CACHE_KEY).await? else {
+            return Ok(false);
+        };
+        let current = self.objects.encryption_key();
+        if current.is_some_and(|key| key.sealed_this(&sealed)) {
+            return Ok(false);
+        }
+        match (current, old.map(|key| key.open(&sealed))) {
+            (Some(current), Some(Ok(plaintext))) => {
+                self.objects
+                    .blobs()
+                    .put(HOST_KEY_CACHE_KEY, current.seal(&plaintext)?)
+                    .await
+                    .context("write host key cache")?;
+                Ok(true)
+            }
+            _ => {
+                tracing::info!("dropping host key cache that cannot be re-sealed");
+                self.clear_host_key().await?;
+                Ok(false)
+            }
+        }
+    }
+
     pub async fn list_backups(&self) -> Result<Vec<BackupEntry>> {
         self.store.list_backups().await
     }
@@ -139,7 +672,14 @@ impl BackupRepository {
         self.store.get_backup(id).await
     }
 
-    pub async fn rollback_to(&self, id: Uuid) -> Result<BackupEntry> {
+    /// Point `current-pointer.json` at backup `id` and record the rollback,
+    /// attributed to `actor`, with `upload` as its starting upload state.
+    pub async fn rollback_to(
+        &self,
+        id: Uuid,
+        actor: &str,
+        upload: RollbackUpload,
+    ) -> Result<(BackupEntry, RollbackEvent)> {
         let backup = self
             .get_backup(id)
             .await?
@@ -147,133 +687,960 @@ impl BackupRepository {
         if backup.status != BackupStatus::Created {
             return Err(anyhow!("cannot rollback to skipped backup {}", backup.id));
         }
-        self.write_current_pointer(&backup)?;
-        self.store.insert_rollback_event(backup.id).await?;
-        Ok(backup)
+        self.write_current_pointer(&backup).await?;
+        let event = RollbackEvent {
+            id: Uuid::new_v4(),
+            backup_id: backup.id,
+            created_at: Utc::now(),
+            actor: Some(actor.to_owned()),
+            upload: Some(upload),
+            verification: None,
+        };
+        self.store.insert_rollback_event(&event).await?;
+        Ok((backup, event))
     }
 
-    pub fn backup_file_path(&self, entry: &BackupEntry) -> PathBuf {
-        self.root
-            .join("backups")
-            .join(&entry.timestamp_dir)
-            .join("collection.anki2")
+    /// How the collection downloaded from the server after uploading
+    /// `backup` differs from it; empty when the server has everything.
+    pub async fn check_upload(
+        &self,
+        backup: &BackupEntry,
+        downloaded: &[u8],
+    ) -> Result<Vec<String>> {
+        let uploaded = read_snapshot(&self.read_backup(backup).await?)
+            .with_context(|| format!("read collection of backup {}", backup.id))?;
+        let server = read_snapshot(downloaded).context("read downloaded collection")?;
+        Ok(upload_divergences(&uploaded, &server))
     }
 
-    pub async fn prune_created_older_than_days(&self, retention_days: i64) -> Result<usize> {
-        if retention_days <= 0 {
-            return Ok(0);
+    /// Record how uploading a rollback went, and the check of the upload.
+    pub async fn record_rollback_upload(
+        &self,
+        event_id: Uuid,
+        upload: RollbackUpload,
+        verification: Option<&RollbackVerification>,
+    ) -> Result<()> {
+        self.store
+            .record_rollback_upload(event_id, upload, verification)
+            .await
+    }
+
+    pub async fn list_rollback_history(&self) -> Result<Vec<RollbackHistoryEntry>> {
+        self.store.list_rollback_history().await
+    }
+
+    /// The backup `current-pointer.json` points at, if any backup or
+    /// rollback has written it yet.
+    pub async fn current_backup_id(&self) -> Result<Option<Uuid>> {
+        let Some(raw) = self.objects.blobs().get(CURRENT_POINTER_KEY).await? else {
+            return Ok(None);
+        };
+        let ptr: CurrentPointer = serde_json::from_slice(&raw).context("parse current pointer")?;
+        Ok(Some(ptr.backup_id))
+    }
+
+    /// Pin or unpin a created backup, replacing its note.
+    ///
+    /// Pinned backups are skipped by every retention path. The backup's
+    /// `metadata.json` is rewritten so the flag survives a metadata rebuild.
+    pub async fn set_pinned(
+        &self,
+        id: Uuid,
+        pinned: bool,
+        note: Option<String>,
+    ) -> Result<BackupEntry> {
+        let backup = self
+            .get_backup(id)
+            .await?
+            .ok_or_else(|| anyhow!("backup not found: {id}"))?;
+        if backup.status != BackupStatus::Created {
+            return Err(anyhow!("cannot pin skipped backup {}", backup.id));
         }
+        let note = note.map(|n| n.trim().to_owned()).filter(|n| !n.is_empty());
+        self.store.set_pinned(id, pinned, note.as_deref()).await?;
+        let updated = BackupEntry {
+            pinned,
+            note,
+            ..backup
+        };
+        self.write_metadata_file(&updated).await?;
+        Ok(updated)
+    }
 
-        let cutoff = Utc::now() - chrono::Duration::days(retention_days);
-        let doomed = self.store.prune_created_before(cutoff).await?;
+    /// Reassemble the stored `collection.anki2` bytes for a created backup.
+    ///
+    /// Backups written before chunked storage keep a plain `collection.anki2`
+    /// in their directory and are read directly.
+    pub async fn read_backup(&self, entry: &BackupEntry) -> Result<Vec<u8>> {
+        let manifest_key = entry_key(entry, MANIFEST_FILE);
+        if self.exists(&manifest_key).await? {
+            let manifest = self.objects.read_manifest(&manifest_key).await?;
+            return self
+                .objects
+                .read_payload(&manifest)
+                .await
+                .with_context(|| format!("reassemble backup {}", entry.id));
+        }
+        self.objects
+            .read_file(&entry_key(entry, LEGACY_PAYLOAD_FILE))
+            .await
+    }
 
-        for (_, timestamp_dir) in &doomed {
-            let dir = self.root.join("backups").join(timestamp_dir);
-            if dir.exists() {
-                fs::remove_dir_all(&dir)
-                    .with_context(|| format!("remove old backup dir: {}", dir.display()))?;
+    /// The backup as a `.colpkg` Anki can import directly, including its
+    /// media when media was backed up.
+    pub async fn export_package(&self, entry: &BackupEntry) -> Result<Vec<u8>> {
+        let collection = self.read_backup(entry).await?;
+        let mut package = PackageWriter::new(&collection)
+            .with_context(|| format!("export backup {}", entry.id))?;
+        if let Some(id) = &entry.media_set {
+            let set = self.read_media_set(id).await?;
+            for (name, file) in &set.files {
+                let contents = self.read_media_file(file).await?;
+                package.add_media(name, &contents)?;
             }
         }
+        package.finish()
+    }
 
-        Ok(doomed.len())
+    /// Notes, cards, decks and note types that changed from `old` to `new`.
+    pub async fn diff_backups(
+        &self,
+        old: &BackupEntry,
+        new: &BackupEntry,
+    ) -> Result<CollectionDiff> {
+        let before = read_snapshot(&self.read_backup(old).await?)
+            .with_context(|| format!("read collection of backup {}", old.id))?;
+        let after = read_snapshot(&self.read_backup(new).await?)
+            .with_context(|| format!("read collection of backup {}", new.id))?;
+        Ok(CollectionDiff::between(&before, &after))
     }
 
-    fn write_current_pointer(&self, backup: &BackupEntry) -> Result<()> {
-        let ptr = serde_json::json!({
-            "backup_id": backup.id,
-            "timestamp_dir": backup.timestamp_dir,
-            "updated_at": Utc::now(),
-        });
-        let tmp = self.root.join("state").join("current-pointer.json.tmp");
-        let dst = self.root.join("state").join("current-pointer.json");
-        fs::write(&tmp, serde_json::to_vec_pretty(&ptr)?).context("write current pointer tmp")?;
-        fs::rename(&tmp, &dst).context("atomic rename current pointer")?;
-        Ok(())
+    /// What rolling back from the `current` collection to `target` would
+    /// undo: the changes between them and the reviews only `current` has.
+    pub async fn rollback_preview(
+        &self,
+        current: &BackupEntry,
+        target: &BackupEntry,
+    ) -> Result<RollbackPreview> {
+        let before = read_snapshot(&self.read_backup(current).await?)
+            .with_context(|| format!("read collection of backup {}", current.id))?;
+        let after = read_snapshot(&self.read_backup(target).await?)
+            .with_context(|| format!("read collection of backup {}", target.id))?;
+        Ok(RollbackPreview::between(&before, &after))
     }
 
-    async fn create_and_insert_entry(&self, new_entry: NewBackupEntry) -> Result<BackupEntry> {
-        let entry = BackupEntry {
-            id: Uuid::new_v4(),
-            created_at: new_entry.created_at,
-            timestamp_dir: new_entry.timestamp_dir,
-            content_hash: new_entry.content_hash,
-            status: new_entry.status,
-            skip_reason: new_entry.skip_reason,
-            source_revision: new_entry.source_revision,
-            sync_duration_ms: new_entry.sync_duration_ms,
-            size_bytes: new_entry.size_bytes,
-            stats: new_entry.stats,
+    /// `current` with the notes, cards and decks in `selection` grafted in
+    /// from `backup`; see [`crate::restore`].
+    pub async fn merge_restore(
+        &self,
+        backup: &BackupEntry,
+        current: &[u8],
+        selection: &RestoreSelection,
+    ) -> Result<(Vec<u8>, MergeReport)> {
+        let source = self.read_backup(backup).await?;
+        merge_into(current, &source, selection)
+            .with_context(|| format!("restore from backup {}", backup.id))
+    }
+
+    /// One deck of the backup, with its subdecks, as an `.apkg`: its notes,
+    /// cards, note types, scheduling and review history, plus the backed-up
+    /// media its notes refer to. `None` if the backup has no such deck.
+    pub async fn export_deck_package(
+        &self,
+        entry: &BackupEntry,
+        deck_id: i64,
+    ) -> Result<Option<Vec<u8>>> {
+        let collection = self.read_backup(entry).await?;
+        let Some(deck) = extract_deck(&collection, deck_id)
+            .with_context(|| format!("extract deck {deck_id} from backup {}", entry.id))?
+        else {
+            return Ok(None);
         };
+        let mut package = PackageWriter::new(&deck.collection)?;
+        if let Some(id) = &entry.media_set {
+            let set = self.read_media_set(id).await?;
+            for name in &deck.media {
+                if let Some(file) = set.files.get(name) {
+                    package.add_media(name, &self.read_media_file(file).await?)?;
+                }
+            }
+        }
+        package.finish().map(Some)
+    }
 
-        self.store.insert_entry(&entry).await?;
+    /// Check every created backup and record the result in the metadata store.
+    ///
+    /// Each payload is reassembled, rehashed against its recorded content
+    /// hash and opened with SQLite `PRAGMA integrity_check`, and each media
+    /// file is reassembled and checked against its SHA-1 (once per run, however
+    /// many backups share it). Backups whose
+    /// directory or media has disappeared are reported as missing, and directories
+    /// under `backups/` that no metadata row refers to are reported as
+    /// orphaned (but left in place). Directories reserved by a run in this
+    /// process, or named for a time less than an hour ago, are skipped since
+    /// their row may not be written yet.
+    pub async fn verify(&self) -> Result<VerifyReport> {
+        let backups = self.store.list_backups().await?;
+        let mut report = VerifyReport::default();
+        let mut known = HashSet::new();
+        let mut verified_media = HashSet::new();
 
-        if matches!(entry.status, BackupStatus::Created) {
-            let metadata_json_path = self
-                .root
-                .join("backups")
-                .join(&entry.timestamp_dir)
-                .join("metadata.json");
-            let serialized =
-                serde_json::to_string_pretty(&entry).context("serialize backup metadata")?;
-            fs::write(&metadata_json_path, serialized).with_context(|| {
-                format!("write backup metadata: {}", metadata_json_path.display())
-            })?;
+        for entry in backups.iter().filter(|b| b.status == BackupStatus::Created) {
+            known.insert(entry.timestamp_dir.as_str());
+            let (status, detail) = self.check_backup(entry, &mut verified_media).await?;
+            let verification = BackupVerification {
+                status,
+                checked_at: Utc::now(),
+                detail: detail.clone(),
+            };
+            self.store
+                .record_verification(entry.id, &verification)
+                .await?;
+            report.checked += 1;
+            if status != VerificationStatus::Ok {
+                let detail = detail.unwrap_or_default();
+                tracing::warn!(backup_id = %entry.id, status = status.as_str(), %detail, "backup failed verification");
+                report.failures.push(VerifyFailure {
+                    backup_id: entry.id,
+                    timestamp_dir: entry.timestamp_dir.clone(),
+                    status,
+                    detail,
+                });
+            }
         }
 
-        Ok(entry)
+        let dirs: BTreeSet<String> = self
+            .objects
+            .blobs()
+            .list(BACKUPS_PREFIX)
+            .await?
+            .iter()
+            .filter_map(|key| key.strip_prefix(BACKUPS_PREFIX)?.split_once('/'))
+            .map(|(dir, _)| dir.to_owned())
+            .collect();
+        let grace_cutoff = Utc::now() - chrono::Duration::minutes(WRITE_GRACE_MINUTES);
+        let reserved = self
+            .reserved_dirs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        report.orphaned_dirs = dirs
+            .into_iter()
+            .filter(|dir| !known.contains(dir.as_str()) && !reserved.contains(dir))
+            .filter(|dir| parse_timestamp_dir(dir).is_none_or(|t| t < grace_cutoff))
+            .collect();
+        for dir in &report.orphaned_dirs {
+            tracing::warn!(%dir, "backup directory has no metadata entry");
+        }
+        Ok(report)
     }
-}
 
-fn extract_stats(path: &Path) -> Result<BackupStats> {
-    let conn = Connection::open(path)
-        .with_context(|| format!("open collection db: {}", path.display()))?;
-    let total_cards: i64 = conn.query_row("SELECT COUNT(*) FROM cards", [], |r| r.get(0))?;
-    let total_notes: i64 = conn.query_row("SELECT COUNT(*) FROM notes", [], |r| r.get(0))?;
-    let total_revlog: i64 = conn.query_row("SELECT COUNT(*) FROM revlog", [], |r| r.get(0))?;
+    /// Integrity-check a single backup. Errors are reserved for failures to
+    /// reach storage; problems with the backup itself become a status.
+    /// Media files whose SHA-1 is in `verified_media` were checked earlier in
+    /// the same run and are skipped.
+    async fn check_backup(
+        &self,
+        entry: &BackupEntry,
+        verified_media: &mut HashSet<String>,
+    ) -> Result<(VerificationStatus, Option<String>)> {
+        let dir = entry_key(entry, "");
+        if self.objects.blobs().list(&dir).await?.is_empty() {
+            return Ok((
+                VerificationStatus::Missing,
+                Some(format!("{dir} does not exist")),
+            ));
+        }
+        let bytes = match self.read_backup(entry).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                let mut detail = format!("{e:#}");
+                let manifest_key = entry_key(entry, MANIFEST_FILE);
+                if self.exists(&manifest_key).await? {
+                    if let Ok(manifest) = self.objects.read_manifest(&manifest_key).await {
+                        detail += &self.quarantine_note(&manifest.chunks).await?;
+                    }
+                }
+                return Ok((VerificationStatus::Corrupt, Some(detail)));
+            }
+        };
+        let actual = content_hash(&bytes);
+        if actual != entry.content_hash {
+            return Ok((
+                VerificationStatus::HashMismatch,
+                Some(format!(
+                    "payload hashes to {actual}, expected {}",
+                    entry.content_hash
+                )),
+            ));
+        }
+        if let Err(e) = check_sqlite_integrity(&bytes) {
+            return Ok((VerificationStatus::Corrupt, Some(format!("{e:#}"))));
+        }
+        if let Some(id) = &entry.media_set {
+            let set = match self.read_media_set(id).await {
+                Ok(set) => set,
+                Err(e) => {
+                    return Ok((
+                        VerificationStatus::Missing,
+                        Some(format!("media set {id}: {e:#}")),
+                    ))
+                }
+            };
+            for (name, file) in &set.files {
+                if verified_media.contains(&file.sha1) {
+                    continue;
+                }
+                for chunk in &file.chunks {
+                    if self.objects.stored_size(&chunk.hash).await?.is_none() {
+                        return Ok((
+                            VerificationStatus::Missing,
+                            Some(format!("media file {name} is missing chunk {}", chunk.hash)),
+                        ));
+                    }
+                }
+                let bytes = match self.read_media_file(file).await {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        let note = self.quarantine_note(&file.chunks).await?;
+                        return Ok((
+                            VerificationStatus::Corrupt,
+                            Some(format!("media file {name}: {e:#}{note}")),
+                        ));
+                    }
+                };
+                let actual = sha1_hex(&bytes);
+                if actual != file.sha1 {
+                    return Ok((
+                        VerificationStatus::HashMismatch,
+                        Some(format!(
+                            "media file {name} hashes to {actual}, expected {}",
+                            file.sha1
+                        )),
+                    ));
+                }
+                verified_media.insert(file.sha1.clone());
+            }
+        }
+        Ok((VerificationStatus::Ok, None))
+    }
 
-    // Modern Anki (schema 18+) stores decks in a `decks` table;
-    // older schemas store them as JSON in `col.decks`.
-    let deck_names = parse_deck_names_new(&conn)
-        .or_else(|_| {
-            let json: String =
-                conn.query_row("SELECT decks FROM col LIMIT 1", [], |r| r.get(0))?;
-            parse_deck_names_legacy(&json)
+    /// Quarantine the corrupt chunks among `chunks` so the next backup holding
+    /// the same bytes stores them again, and describe that for a verify
+    /// failure.
+    async fn quarantine_note(&self, chunks: &[ChunkRef]) -> Result<String> {
+        let quarantined = self.objects.quarantine_corrupt(chunks).await?;
+        Ok(match quarantined.len() {
+            0 => String::new(),
+            n => {
+                format!("; moved {n} corrupt chunk(s) to quarantine for the next backup to rewrite")
+            }
         })
-        .context("extract deck names")?;
-    let total_decks = deck_names.len() as i64;
-
-    let mut stmt = conn.prepare("SELECT did, COUNT(*) AS c FROM cards GROUP BY did")?;
-    let rows = stmt.query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)?)))?;
-    let mut deck_stats = Vec::new();
-    for row in rows {
-        let (did, count) = row?;
-        deck_stats.push(DeckStats {
-            deck_id: did,
-            deck_name: deck_names
-                .get(&did)
-                .cloned()
-                .unwrap_or_else(|| format!("Deck {did}")),
-            card_count: count,
-        });
     }
 
-    deck_stats.sort_by(|a, b| a.deck_name.cmp(&b.deck_name));
+    /// Rebuild the metadata store from the backup directories in storage.
+    ///
+    /// Each `backups/<dir>/metadata.json` is inserted as-is; when it is
+    /// missing or unreadable the entry is rebuilt from the payload, taking the
+    /// creation time from the directory name. Entries already present are
+    /// left alone, and directories whose id or name clash with an existing
+    /// entry are reported as conflicts instead of being inserted. Rebuilt
+    /// entries get their `metadata.json` rewritten. Skipped entries have no
+    /// directory and cannot be recovered.
+    pub async fn reindex(&self) -> Result<ReindexReport> {
+        let existing = self.store.list_backups().await?;
+        let mut ids: HashMap<Uuid, String> = existing
+            .iter()
+            .map(|b| (b.id, b.timestamp_dir.clone()))
+            .collect();
+        let mut dirs_indexed: HashSet<String> = existing
+            .into_iter()
+            .filter(|b| b.status == BackupStatus::Created)
+            .map(|b| b.timestamp_dir)
+            .collect();
+        let mut report = ReindexReport::default();
 
-    Ok(BackupStats {
-        total_cards,
-        total_decks,
-        total_notes,
-        total_revlog,
-        deck_stats,
-    })
-}
+        let dirs: BTreeSet<String> = self
+            .objects
+            .blobs()
+            .list(BACKUPS_PREFIX)
+            .await?
+            .iter()
+            .filter_map(|key| key.strip_prefix(BACKUPS_PREFIX)?.split_once('/'))
+            .map(|(dir, _)| dir.to_owned())
+            .collect();
 
-/// Schema 18+: read deck names from the `decks` table.
-fn parse_deck_names_new(conn: &Connection) -> Result<HashMap<i64, String>> {
-    let mut stmt = conn.prepare("SELECT id, name FROM decks")?;
-    let rows = stmt.query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, String>(1)?)))?;
-    let mut out = HashMap::new();
-    for row in rows {
+        for dir in dirs {
+            report.scanned += 1;
+            let mut conflict = |reason: String| {
+                tracing::warn!(timestamp_dir = %dir, %reason, "reindex conflict");
+                report.conflicts.push(ReindexConflict {
+                    timestamp_dir: dir.clone(),
+                    reason,
+                });
+            };
+
+            let (entry, from_payload) = match self.read_metadata_file(&dir).await {
+                Ok(Some(entry)) => (entry, false),
+                Ok(None) => match self.entry_from_payload(&dir).await {
+                    Ok(entry) => (entry, true),
+                    Err(e) => {
+                        conflict(format!("no metadata.json and payload unreadable: {e:#}"));
+                        continue;
+                    }
+                },
+                Err(e) => match self.entry_from_payload(&dir).await {
+                    Ok(entry) => {
+                        tracing::warn!(timestamp_dir = %dir, error = %e, "unreadable metadata.json; rebuilt from payload");
+                        (entry, true)
+                    }
+                    Err(payload_err) => {
+                        conflict(format!(
+                            "unreadable metadata.json ({e:#}) and payload ({payload_err:#})"
+                        ));
+                        continue;
+                    }
+                },
+            };
+
+            if entry.timestamp_dir != dir {
+                conflict(format!(
+                    "metadata.json names directory {}",
+                    entry.timestamp_dir
+                ));
+                continue;
+            }
+            if let Some(indexed_dir) = ids.get(&entry.id) {
+                if *indexed_dir == dir {
+                    report.already_indexed += 1;
+                } else {
+                    conflict(format!(
+                        "backup id {} is already indexed for {indexed_dir}",
+                        entry.id
+                    ));
+                }
+                continue;
+            }
+            if dirs_indexed.contains(&dir) {
+                if from_payload {
+                    // A rebuilt entry gets a fresh id, so the name is all we can match on.
+                    report.already_indexed += 1;
+                    continue;
+                }
+                conflict("directory is already indexed under a different id".to_owned());
+                continue;
+            }
+
+            self.store
+                .insert_entry(&entry)
+                .await
+                .with_context(|| format!("insert reindexed backup {dir}"))?;
+            ids.insert(entry.id, dir.clone());
+            dirs_indexed.insert(dir);
+            report.restored += 1;
+            if from_payload {
+                self.write_metadata_file(&entry).await?;
+                report.recovered_from_payload += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    async fn read_metadata_file(&self, timestamp_dir: &str) -> Result<Option<BackupEntry>> {
+        let key = dir_key(timestamp_dir, METADATA_FILE);
+        if !self.exists(&key).await? {
+            return Ok(None);
+        }
+        let raw = self.objects.read_file(&key).await?;
+        let entry = serde_json::from_slice(&raw).with_context(|| format!("parse {key}"))?;
+        Ok(Some(entry))
+    }
+
+    /// Rebuild a created entry for `timestamp_dir` from its stored payload.
+    async fn entry_from_payload(&self, timestamp_dir: &str) -> Result<BackupEntry> {
+        let created_at = parse_timestamp_dir(timestamp_dir)
+            .ok_or_else(|| anyhow!("cannot parse creation time from {timestamp_dir}"))?;
+        let manifest_key = dir_key(timestamp_dir, MANIFEST_FILE);
+        let (bytes, stored_size_bytes) = if self.exists(&manifest_key).await? {
+            let manifest = self.objects.read_manifest(&manifest_key).await?;
+            let bytes = self.objects.read_payload(&manifest).await?;
+            (bytes, Some(manifest.stored_size() as i64))
+        } else {
+            let bytes = self
+                .objects
+                .read_file(&dir_key(timestamp_dir, LEGACY_PAYLOAD_FILE))
+                .await?;
+            (bytes, None)
+        };
+        let stats = extract_stats_from_bytes(&bytes).context("extract backup stats")?;
+        Ok(BackupEntry {
+            id: Uuid::new_v4(),
+            created_at,
+            timestamp_dir: timestamp_dir.to_owned(),
+            content_hash: content_hash(&bytes),
+            status: BackupStatus::Created,
+            skip_reason: None,
+            source_revision: None,
+            sync_duration_ms: None,
+            size_bytes: bytes.len() as i64,
+            stored_size_bytes,
+            stats: Some(stats),
+            pinned: false,
+            note: None,
+            verification: None,
+            media_set: None,
+        })
+    }
+
+    /// Claim an unused `backups/<timestamp>` directory name, suffixing it
+    /// when several backups land in the same second.
+    ///
+    /// Names are reserved in memory until the caller has written to the
+    /// directory, so concurrent runs in this process never share one.
+    async fn allocate_backup_dir(&self, now: DateTime<Utc>) -> Result<ReservedDir> {
+        let base = format_timestamp_dir(now);
+        let mut n = 0;
+        loop {
+            let timestamp_dir = match n {
+                0 => base.clone(),
+                n => format!("{base}-{n}"),
+            };
+            n += 1;
+            let taken = |dir: &str| {
+                self.reserved_dirs
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .contains(dir)
+            };
+            if taken(&timestamp_dir)
+                || !self
+                    .objects
+                    .blobs()
+                    .list(&dir_key(&timestamp_dir, ""))
+                    .await?
+                    .is_empty()
+            {
+                continue;
+            }
+            // Another run may have claimed it while we were listing.
+            if self
+                .reserved_dirs
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(timestamp_dir.clone())
+            {
+                return Ok(ReservedDir {
+                    name: timestamp_dir,
+                    reserved: self.reserved_dirs.clone(),
+                });
+            }
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.objects.blobs().size(key).await?.is_some())
+    }
+
+    /// Delete created backups older than `retention_days`, except pinned ones
+    /// and the one the current pointer names.
+    pub async fn prune_created_older_than_days(&self, retention_days: i64) -> Result<usize> {
+        if retention_days <= 0 {
+            return Ok(0);
+        }
+
+        let cutoff = Utc::now() - chrono::Duration::days(retention_days);
+        let current = self.current_backup_id().await?;
+        let doomed = self.store.prune_created_before(cutoff, current).await?;
+
+        for (_, timestamp_dir) in &doomed {
+            self.remove_backup_dir(timestamp_dir).await?;
+        }
+
+        if !doomed.is_empty() {
+            self.collect_garbage().await?;
+        }
+
+        Ok(doomed.len())
+    }
+
+    /// Apply a grandfather-father-son retention policy.
+    ///
+    /// Returns the plan that was evaluated; with `dry_run` nothing is deleted
+    /// and the plan only reports what would be removed.
+    pub async fn apply_retention(
+        &self,
+        policy: &RetentionPolicy,
+        dry_run: bool,
+    ) -> Result<RetentionPlan> {
+        let backups = self.store.list_backups().await?;
+        let current = self.current_backup_id().await?;
+        let plan = plan_retention(policy, &backups, current, Utc::now());
+        if dry_run || plan.remove.is_empty() {
+            return Ok(plan);
+        }
+
+        // A backup pinned since it was listed is skipped by the store; keep
+        // its directory and leave it out of the reported plan.
+        let ids: Vec<Uuid> = plan.remove.iter().map(|b| b.id).collect();
+        let deleted: HashSet<Uuid> = self.store.delete_backups(&ids).await?.into_iter().collect();
+        let mut plan = plan;
+        plan.remove.retain(|b| deleted.contains(&b.id));
+        for backup in &plan.remove {
+            self.remove_backup_dir(&backup.timestamp_dir).await?;
+        }
+        if !plan.remove.is_empty() {
+            self.collect_garbage().await?;
+        }
+        Ok(plan)
+    }
+
+    async fn remove_backup_dir(&self, timestamp_dir: &str) -> Result<()> {
+        let blobs = self.objects.blobs();
+        for key in blobs.list(&dir_key(timestamp_dir, "")).await? {
+            blobs
+                .delete(&key)
+                .await
+                .with_context(|| format!("remove old backup file: {key}"))?;
+        }
+        Ok(())
+    }
+
+    /// Bring backups written by older versions up to the current storage format.
+    ///
+    /// Legacy directories holding a plain `collection.anki2` are converted to
+    /// chunk manifests, uncompressed chunks are recompressed at the configured
+    /// level, and every manifest and metadata row gets an up-to-date stored size.
+    /// Safe to re-run; already-migrated backups are left untouched.
+    pub async fn migrate_storage(&self) -> Result<StorageMigrationReport> {
+        let _writing = self.begin_write().await;
+        let mut report = StorageMigrationReport::default();
+        let backups = self.store.list_backups().await?;
+
+        for entry in backups.iter().filter(|b| b.status == BackupStatus::Created) {
+            let legacy_key = entry_key(entry, LEGACY_PAYLOAD_FILE);
+            let manifest_key = entry_key(entry, MANIFEST_FILE);
+            if self.exists(&manifest_key).await? || !self.exists(&legacy_key).await? {
+                continue;
+            }
+            let bytes = self.objects.read_file(&legacy_key).await?;
+            let stored = self.objects.put_payload(&bytes).await?;
+            self.objects
+                .write_manifest(&manifest_key, &stored.manifest)
+                .await?;
+            self.objects
+                .blobs()
+                .delete(&legacy_key)
+                .await
+                .with_context(|| format!("remove legacy payload: {legacy_key}"))?;
+            report.legacy_backups_converted += 1;
+        }
+
+        let recompressed = self.objects.recompress().await?;
+        report.chunks_recompressed = recompressed.chunks;
+        report.bytes_before = recompressed.bytes_before;
+        report.bytes_after = recompressed.bytes_after;
+
+        for entry in backups.iter().filter(|b| b.status == BackupStatus::Created) {
+            let manifest_key = entry_key(entry, MANIFEST_FILE);
+            if !self.exists(&manifest_key).await? {
+                continue;
+            }
+            let mut manifest = self.objects.read_manifest(&manifest_key).await?;
+            let mut changed = false;
+            for chunk in &mut manifest.chunks {
+                let size = self
+                    .objects
+                    .stored_size(&chunk.hash)
+                    .await?
+                    .ok_or_else(|| {
+                        anyhow!("backup {} is missing chunk {}", entry.id, chunk.hash)
+                    })?;
+                if chunk.stored_size != size {
+                    chunk.stored_size = size;
+                    changed = true;
+                }
+            }
+            if changed {
+                self.objects
+                    .write_manifest(&manifest_key, &manifest)
+                    .await?;
+            }
+            let stored_size = manifest.stored_size() as i64;
+            if entry.stored_size_bytes != Some(stored_size) {
+                self.store.set_stored_size(entry.id, stored_size).await?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Re-encrypt every stored chunk, manifest, metadata file, legacy payload,
+    /// media set and the cached host key under the repository's configured key.
+    ///
+    /// `old` is the key the data is currently sealed with (`None` if it is
+    /// plaintext). Running without a configured key decrypts everything.
+    /// Files already under the configured key are skipped, so an interrupted
+    /// rotation can be resumed by running it again with the same arguments.
+    pub async fn rotate_encryption_key(
+        &self,
+        old: Option<&KeySource>,
+    ) -> Result<KeyRotationReport> {
+        let old_key = match old {
+            Some(source) => Some(
+                EncryptionKey::resolve(source, self.shared_blobs.as_ref())
+                    .await
+                    .context("load old encryption key")?,
+            ),
+            None => None,
+        };
+        let mut report = KeyRotationReport {
+            chunks_rewritten: self.objects.rekey(old_key.as_ref()).await?,
+            ..Default::default()
+        };
+        for key in self.objects.blobs().list(BACKUPS_PREFIX).await? {
+            let name = key.rsplit('/').next().unwrap_or_default();
+            if [MANIFEST_FILE, METADATA_FILE, LEGACY_PAYLOAD_FILE].contains(&name)
+                && self.objects.rekey_file(&key, old_key.as_ref()).await?
+            {
+                report.files_rewritten += 1;
+            }
+        }
+        for key in self.objects.blobs().list(MEDIA_PREFIX).await? {
+            if self.objects.rekey_file(&key, old_key.as_ref()).await? {
+                report.files_rewritten += 1;
+            }
+        }
+        if self.reseal_host_key(old_key.as_ref()).await? {
+            report.files_rewritten += 1;
+        }
+        Ok(report)
+    }
+
+    /// Remove chunks no longer referenced by any manifest under `backups/`.
+    ///
+    /// Stored manifests are the source of truth rather than the metadata
+    /// store, so a chunk is never dropped while any backup directory needs it.
+    /// Media sets are kept while a backup in the metadata store refers to
+    /// them or for an hour after being written, as a backup being written
+    /// by another process may not be recorded yet, and their files' chunks
+    /// are kept with them. A set that cannot be read is logged and skipped.
+    /// Waits for backups being written in this process to be recorded first.
+    pub async fn collect_garbage(&self) -> Result<usize> {
+        let _collecting = self.objects.begin_collect().await;
+        let mut referenced = HashSet::new();
+        let manifest_suffix = format!("/{MANIFEST_FILE}");
+        for key in self.objects.blobs().list(BACKUPS_PREFIX).await? {
+            if key.ends_with(&manifest_suffix) {
+                let manifest = self.objects.read_manifest(&key).await?;
+                referenced.extend(manifest.chunks.into_iter().map(|c| c.hash));
+            }
+        }
+
+        let mut media_sets: HashSet<String> = self
+            .store
+            .list_backups()
+            .await?
+            .into_iter()
+            .filter_map(|b| b.media_set)
+            .collect();
+        let grace_cutoff = Utc::now() - chrono::Duration::minutes(WRITE_GRACE_MINUTES);
+        for key in self.objects.blobs().list(MEDIA_PREFIX).await? {
+            let id = key
+                .strip_prefix(MEDIA_PREFIX)
+                .and_then(|name| name.strip_suffix(".json"))
+                .unwrap_or_default();
+            if media_sets.contains(id) {
+                continue;
+            }
+            let modified = self.objects.blobs().modified(&key).await?;
+            if modified.is_some_and(|t| t >= grace_cutoff) {
+                media_sets.insert(id.to_owned());
+                continue;
+            }
+            self.objects
+                .blobs()
+                .delete(&key)
+                .await
+                .with_context(|| format!("remove media set: {key}"))?;
+        }
+        for id in &media_sets {
+            match self.read_media_set(id).await {
+                Ok(set) => referenced.extend(
+                    set.files
+                        .into_values()
+                        .flat_map(|f| f.chunks)
+                        .map(|c| c.hash),
+                ),
+                Err(e) => {
+                    // Storage being unreachable still aborts collection; only
+                    // a set that is gone or unreadable is skipped.
+                    self.objects.blobs().size(&media_set_key(id)).await?;
+                    tracing::warn!(media_set = %id, error = %format!("{e:#}"), "skipping unreadable media set");
+                }
+            }
+        }
+        let removed = self.objects.remove_unreferenced(&referenced).await?;
+        if removed > 0 {
+            tracing::info!(removed, "removed unreferenced chunks");
+        }
+        Ok(removed)
+    }
+
+    async fn write_current_pointer(&self, backup: &BackupEntry) -> Result<()> {
+        let ptr = serde_json::json!({
+            "backup_id": backup.id,
+            "timestamp_dir": backup.timestamp_dir,
+            "updated_at": Utc::now(),
+        });
+        self.objects
+            .blobs()
+            .put(CURRENT_POINTER_KEY, serde_json::to_vec_pretty(&ptr)?)
+            .await
+            .context("write current pointer")
+    }
+
+    async fn create_and_insert_entry(&self, new_entry: NewBackupEntry) -> Result<BackupEntry> {
+        let entry = BackupEntry {
+            id: Uuid::new_v4(),
+            created_at: new_entry.created_at,
+            timestamp_dir: new_entry.timestamp_dir,
+            content_hash: new_entry.content_hash,
+            status: new_entry.status,
+            skip_reason: new_entry.skip_reason,
+            source_revision: new_entry.source_revision,
+            sync_duration_ms: new_entry.sync_duration_ms,
+            size_bytes: new_entry.size_bytes,
+            stored_size_bytes: new_entry.stored_size_bytes,
+            stats: new_entry.stats,
+            pinned: false,
+            note: None,
+            verification: None,
+            media_set: new_entry.media_set,
+        };
+
+        self.store.insert_entry(&entry).await?;
+
+        if matches!(entry.status, BackupStatus::Created) {
+            self.write_metadata_file(&entry).await?;
+        }
+
+        Ok(entry)
+    }
+
+    async fn write_metadata_file(&self, entry: &BackupEntry) -> Result<()> {
+        let metadata_key = entry_key(entry, METADATA_FILE);
+        let serialized = serde_json::to_vec_pretty(entry).context("serialize backup metadata")?;
+        self.objects
+            .write_file(&metadata_key, serialized)
+            .await
+            .with_context(|| format!("write backup metadata: {metadata_key}"))
+    }
+}
+
+fn local_objects(root: &Path) -> ObjectStore {
+    ObjectStore::new(Arc::new(LocalBlobStore::new(root)))
+        .with_compression_level(Some(DEFAULT_COMPRESSION_LEVEL))
+}
+
+/// Blob key of `name` inside the backup directory `timestamp_dir`.
+fn dir_key(timestamp_dir: &str, name: &str) -> String {
+    format!("{BACKUPS_PREFIX}{timestamp_dir}/{name}")
+}
+
+fn entry_key(entry: &BackupEntry, name: &str) -> String {
+    dir_key(&entry.timestamp_dir, name)
+}
+
+/// When the collection was last modified (`col.mod`), if it records it.
+fn collection_modified_at(bytes: &[u8]) -> Result<Option<DateTime<Utc>>> {
+    let tmp = tempfile::NamedTempFile::new().context("create temp collection file")?;
+    fs::write(tmp.path(), bytes).context("write temp collection file")?;
+    let conn = Connection::open(tmp.path()).context("open collection db")?;
+    let Ok(modified) = conn.query_row("SELECT mod FROM col", [], |r| r.get::<_, i64>(0)) else {
+        return Ok(None);
+    };
+    // Anki 2.1 stores milliseconds; 2.0 stored seconds.
+    let millis = if modified < 100_000_000_000 {
+        modified * 1000
+    } else {
+        modified
+    };
+    Ok(DateTime::from_timestamp_millis(millis).filter(|_| millis > 0))
+}
+
+/// Run `PRAGMA integrity_check` against a collection payload.
+pub(crate) fn check_sqlite_integrity(bytes: &[u8]) -> Result<()> {
+    let tmp = tempfile::NamedTempFile::new().context("create temp collection file")?;
+    fs::write(tmp.path(), bytes).context("write temp collection file")?;
+    let conn = Connection::open(tmp.path()).context("open collection db")?;
+    let mut stmt = conn
+        .prepare("PRAGMA integrity_check")
+        .context("collection is not a valid SQLite database")?;
+    let problems = stmt
+        .query_map([], |r| r.get::<_, String>(0))?
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .context("collection is not a valid SQLite database")?;
+    if problems != ["ok"] {
+        return Err(anyhow!("integrity check failed: {}", problems.join("; ")));
+    }
+    Ok(())
+}
+
+fn extract_stats_from_bytes(bytes: &[u8]) -> Result<BackupStats> {
+    let tmp = tempfile::NamedTempFile::new().context("create temp collection file")?;
+    fs::write(tmp.path(), bytes).context("write temp collection file")?;
+    extract_stats(tmp.path())
+}
+
+fn extract_stats(path: &Path) -> Result<BackupStats> {
+    let conn = Connection::open(path)
+        .with_context(|| format!("open collection db: {}", path.display()))?;
+    let total_cards: i64 = conn.query_row("SELECT COUNT(*) FROM cards", [], |r| r.get(0))?;
+    let total_notes: i64 = conn.query_row("SELECT COUNT(*) FROM notes", [], |r| r.get(0))?;
+    let total_revlog: i64 = conn.query_row("SELECT COUNT(*) FROM revlog", [], |r| r.get(0))?;
+
+    // Modern Anki (schema 18+) stores decks in a `decks` table;
+    // older schemas store them as JSON in `col.decks`.
+    let deck_names = parse_deck_names_new(&conn)
+        .or_else(|_| {
+            let json: String = conn.query_row("SELECT decks FROM col LIMIT 1", [], |r| r.get(0))?;
+            parse_deck_names_legacy(&json)
+        })
+        .context("extract deck names")?;
+    let total_decks = deck_names.len() as i64;
+
+    let mut stmt = conn.prepare("SELECT did, COUNT(*) AS c FROM cards GROUP BY did")?;
+    let rows = stmt.query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)?)))?;
+    let mut deck_stats = Vec::new();
+    for row in rows {
+        let (did, count) = row?;
+        deck_stats.push(DeckStats {
+            deck_id: did,
+            deck_name: deck_names
+                .get(&did)
+                .cloned()
+                .unwrap_or_else(|| format!("Deck {did}")),
+            card_count: count,
+        });
+    }
+
+    deck_stats.sort_by(|a, b| a.deck_name.cmp(&b.deck_name));
+
+    Ok(BackupStats {
+        total_cards,
+        total_decks,
+        total_notes,
+        total_revlog,
+        deck_stats,
+    })
+}
+
+/// Schema 18+: read deck names from the `decks` table.
+pub(crate) fn parse_deck_names_new(conn: &Connection) -> Result<HashMap<i64, String>> {
+    let mut stmt = conn.prepare("SELECT id, name FROM decks")?;
+    let rows = stmt.query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, String>(1)?)))?;
+    let mut out = HashMap::new();
+    for row in rows {
         let (id, name) = row?;
         out.insert(id, name);
     }
@@ -281,7 +1648,7 @@ fn parse_deck_names_new(conn: &Connection) -> Result<HashMap<i64, String>> {
 }
 
 /// Legacy schema: deck names stored as JSON in `col.decks`.
-fn parse_deck_names_legacy(raw: &str) -> Result<HashMap<i64, String>> {
+pub(crate) fn parse_deck_names_legacy(raw: &str) -> Result<HashMap<i64, String>> {
     let v: Value = serde_json::from_str(raw).context("parse col.decks json")?;
     let mut out = HashMap::new();
     let obj = v
@@ -303,9 +1670,18 @@ fn format_timestamp_dir(now: DateTime<Utc>) -> String {
         .replace(':', "-")
 }
 
+/// Inverse of [`format_timestamp_dir`], ignoring any `-N` collision suffix.
+fn parse_timestamp_dir(timestamp_dir: &str) -> Option<DateTime<Utc>> {
+    let (stamp, _) = timestamp_dir.split_once('Z')?;
+    chrono::NaiveDateTime::parse_from_str(stamp, "%Y-%m-%dT%H-%M-%S")
+        .ok()
+        .map(|t| t.and_utc())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::object_store::OBJECTS_PREFIX;
     use anki_backup_core::content_hash;
 
     fn sample_collection() -> Vec<u8> {
@@ -338,6 +1714,7 @@ mod tests {
                     bytes: payload.clone(),
                     source_revision: None,
                     sync_duration_ms: Some(1),
+                    media_set: None,
                 },
                 hash.clone(),
             )
@@ -351,6 +1728,7 @@ mod tests {
                     bytes: payload,
                     source_revision: None,
                     sync_duration_ms: Some(1),
+                    media_set: None,
                 },
                 hash,
             )
@@ -359,6 +1737,996 @@ mod tests {
         assert!(matches!(second, RunOnceOutcome::Skipped(_)));
     }
 
+    #[tokio::test]
+    async fn concurrent_runs_get_their_own_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        let now = Utc::now();
+        let (a, b) = tokio::join!(repo.allocate_backup_dir(now), repo.allocate_backup_dir(now));
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_ne!(a.name, b.name);
+        let base = format_timestamp_dir(now);
+        let first = if a.name == base { a } else { b };
+        assert_eq!(first.name, base);
+
+        drop(first);
+        // Released without being written to, so the name is free again.
+        let again = repo.allocate_backup_dir(now).await.unwrap();
+        assert_eq!(again.name, format_timestamp_dir(now));
+    }
+
+    #[tokio::test]
+    async fn created_backups_share_chunks_and_reassemble() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        let v1 = sample_collection();
+        let v2 = {
+            let tmp = tempfile::NamedTempFile::new().unwrap();
+            std::fs::write(tmp.path(), &v1).unwrap();
+            let conn = Connection::open(tmp.path()).unwrap();
+            conn.execute("INSERT INTO notes(id) VALUES (3)", [])
+                .unwrap();
+            drop(conn);
+            std::fs::read(tmp.path()).unwrap()
+        };
+
+        let mut entries = Vec::new();
+        for payload in [&v1, &v2] {
+            let outcome = repo
+                .run_once(
+                    BackupPayload {
+                        bytes: payload.clone(),
+                        source_revision: None,
+                        sync_duration_ms: None,
+                        media_set: None,
+                    },
+                    content_hash(payload),
+                )
+                .await
+                .unwrap();
+            match outcome {
+                RunOnceOutcome::Created(e) => entries.push(e),
+                RunOnceOutcome::Skipped(_) => panic!("expected created backup"),
+            }
+        }
+
+        assert_eq!(repo.read_backup(&entries[0]).await.unwrap(), v1);
+        assert_eq!(repo.read_backup(&entries[1]).await.unwrap(), v2);
+        assert!(!repo
+            .exists(&entry_key(&entries[0], LEGACY_PAYLOAD_FILE))
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn reads_legacy_uncompressed_backup_dirs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        let payload = sample_collection();
+        let created = match repo
+            .run_once(
+                BackupPayload {
+                    bytes: payload.clone(),
+                    source_revision: None,
+                    sync_duration_ms: None,
+                    media_set: None,
+                },
+                content_hash(&payload),
+            )
+            .await
+            .unwrap()
+        {
+            RunOnceOutcome::Created(e) => e,
+            RunOnceOutcome::Skipped(_) => panic!("expected created backup"),
+        };
+
+        let dir = tmp.path().join("backups").join(&created.timestamp_dir);
+        fs::remove_file(dir.join(MANIFEST_FILE)).unwrap();
+        fs::write(dir.join(LEGACY_PAYLOAD_FILE), &payload).unwrap();
+        assert_eq!(repo.read_backup(&created).await.unwrap(), payload);
+    }
+
+    #[tokio::test]
+    async fn migrate_storage_converts_and_compresses_legacy_backups() {
+        let tmp = tempfile::tempdir().unwrap();
+        let raw_repo = BackupRepository::new(tmp.path())
+            .unwrap()
+            .with_compression_level(None);
+        let payload = sample_collection();
+        let created = match raw_repo
+            .run_once(
+                BackupPayload {
+                    bytes: payload.clone(),
+                    source_revision: None,
+                    sync_duration_ms: None,
+                    media_set: None,
+                },
+                content_hash(&payload),
+            )
+            .await
+            .unwrap()
+        {
+            RunOnceOutcome::Created(e) => e,
+            RunOnceOutcome::Skipped(_) => panic!("expected created backup"),
+        };
+        assert_eq!(created.stored_size_bytes, Some(payload.len() as i64));
+
+        // Simulate a backup directory written before chunked storage.
+        let dir = tmp.path().join("backups").join(&created.timestamp_dir);
+        fs::remove_file(dir.join(MANIFEST_FILE)).unwrap();
+        fs::write(dir.join(LEGACY_PAYLOAD_FILE), &payload).unwrap();
+
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        let report = repo.migrate_storage().await.unwrap();
+        assert_eq!(report.legacy_backups_converted, 1);
+        assert!(!dir.join(LEGACY_PAYLOAD_FILE).exists());
+        assert_eq!(repo.read_backup(&created).await.unwrap(), payload);
+
+        let migrated = repo.get_backup(created.id).await.unwrap().unwrap();
+        let stored = migrated.stored_size_bytes.unwrap();
+        assert!(stored < migrated.size_bytes);
+
+        let again = repo.migrate_storage().await.unwrap();
+        assert_eq!(again.legacy_backups_converted, 0);
+        assert_eq!(again.chunks_recompressed, 0);
+    }
+
+    #[tokio::test]
+    async fn encrypted_backups_roundtrip_and_rotate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let old_source = KeySource::Passphrase("first passphrase".to_owned());
+        let repo = BackupRepository::new(tmp.path())
+            .unwrap()
+            .with_encryption(Some(&old_source))
+            .await
+            .unwrap();
+        let payload = sample_collection();
+        let created = match repo
+            .run_once(
+                BackupPayload {
+                    bytes: payload.clone(),
+                    source_revision: None,
+                    sync_duration_ms: None,
+                    media_set: None,
+                },
+                content_hash(&payload),
+            )
+            .await
+            .unwrap()
+        {
+            RunOnceOutcome::Created(e) => e,
+            RunOnceOutcome::Skipped(_) => panic!("expected created backup"),
+        };
+
+        let metadata = fs::read(
+            tmp.path()
+                .join("backups")
+                .join(&created.timestamp_dir)
+                .join(METADATA_FILE),
+        )
+        .unwrap();
+        assert!(crate::crypto::is_sealed(&metadata));
+        assert_eq!(repo.read_backup(&created).await.unwrap(), payload);
+
+        let plain = BackupRepository::new(tmp.path()).unwrap();
+        assert!(plain.read_backup(&created).await.is_err());
+        repo.store_host_key("me", "", "hkey-1").await.unwrap();
+
+        let key_file = tmp.path().join("new.key");
+        fs::write(&key_file, "42".repeat(32)).unwrap();
+        let new_source = KeySource::File(key_file);
+        let rotated = BackupRepository::new(tmp.path())
+            .unwrap()
+            .with_encryption(Some(&new_source))
+            .await
+            .unwrap();
+        let report = rotated
+            .rotate_encryption_key(Some(&old_source))
+            .await
+            .unwrap();
+        assert!(report.chunks_rewritten > 0);
+        assert_eq!(report.files_rewritten, 3);
+        assert_eq!(rotated.read_backup(&created).await.unwrap(), payload);
+        assert_eq!(
+            rotated.cached_host_key("me", "").await.unwrap().as_deref(),
+            Some("hkey-1")
+        );
+        assert!(repo.read_backup(&created).await.is_err());
+
+        let again = rotated
+            .rotate_encryption_key(Some(&old_source))
+            .await
+            .unwrap();
+        assert_eq!(again.chunks_rewritten + again.files_rewritten, 0);
+    }
+
+    #[tokio::test]
+    async fn gfs_retention_dry_run_then_apply() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        let mut created = Vec::new();
+        for hash in ["h1", "h2", "h3"] {
+            match repo
+                .run_once(
+                    BackupPayload {
+                        bytes: sample_collection(),
+                        source_revision: None,
+                        sync_duration_ms: None,
+                        media_set: None,
+                    },
+                    hash.to_string(),
+                )
+                .await
+                .unwrap()
+            {
+                RunOnceOutcome::Created(e) => created.push(e),
+                RunOnceOutcome::Skipped(_) => panic!("expected created backup"),
+            }
+        }
+
+        // Move the first two onto the same day ten days ago.
+        let day = (Utc::now() - chrono::Duration::days(10)).date_naive();
+        let conn = Connection::open(tmp.path().join("state").join("metadata.db")).unwrap();
+        for (entry, hour) in created.iter().zip([1, 2]) {
+            let at = day.and_hms_opt(hour, 0, 0).unwrap().and_utc().to_rfc3339();
+            conn.execute(
+                "UPDATE backups SET created_at = ?1 WHERE id = ?2",
+                rusqlite::params![at, entry.id.to_string()],
+            )
+            .unwrap();
+        }
+
+        let policy = RetentionPolicy {
+            keep_all_hours: 24,
+            daily_days: 0,
+            weekly_weeks: 0,
+            monthly_months: 0,
+        };
+        let plan = repo.apply_retention(&policy, true).await.unwrap();
+        assert!(plan.remove.iter().any(|b| b.id == created[0].id));
+        assert_eq!(repo.list_backups().await.unwrap().len(), 3);
+
+        let applied = repo.apply_retention(&policy, false).await.unwrap();
+        assert_eq!(applied.remove.len(), plan.remove.len());
+        assert!(repo.get_backup(created[0].id).await.unwrap().is_none());
+        assert!(repo.get_backup(created[2].id).await.unwrap().is_some());
+        assert!(!tmp
+            .path()
+            .join("backups")
+            .join(&created[0].timestamp_dir)
+            .exists());
+        assert_eq!(
+            repo.read_backup(&created[2]).await.unwrap(),
+            sample_collection()
+        );
+    }
+
+    #[tokio::test]
+    async fn pinned_backups_survive_pruning() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        let mut created = Vec::new();
+        for hash in ["h1", "h2", "h3"] {
+            match repo
+                .run_once(
+                    BackupPayload {
+                        bytes: sample_collection(),
+                        source_revision: None,
+                        sync_duration_ms: None,
+                        media_set: None,
+                    },
+                    hash.to_string(),
+                )
+                .await
+                .unwrap()
+            {
+                RunOnceOutcome::Created(e) => created.push(e),
+                RunOnceOutcome::Skipped(_) => panic!("expected created backup"),
+            }
+        }
+        // The newest backup is current and is kept like a pinned one.
+        let conn = Connection::open(tmp.path().join("state").join("metadata.db")).unwrap();
+        let old = (Utc::now() - chrono::Duration::days(400)).to_rfc3339();
+        conn.execute("UPDATE backups SET created_at = ?1", [old])
+            .unwrap();
+
+        let pinned = repo
+            .set_pinned(created[0].id, true, Some(" before bulk edit ".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(pinned.note.as_deref(), Some("before bulk edit"));
+
+        assert_eq!(repo.prune_created_older_than_days(90).await.unwrap(), 1);
+        let remaining = repo.list_backups().await.unwrap();
+        assert_eq!(remaining.len(), 2);
+        assert!(remaining.iter().any(|e| e.id == created[2].id));
+        let kept = remaining.iter().find(|e| e.pinned).unwrap();
+        assert_eq!(kept.id, created[0].id);
+        assert_eq!(repo.read_backup(kept).await.unwrap(), sample_collection());
+
+        let metadata: BackupEntry = serde_json::from_slice(
+            &repo
+                .objects
+                .read_file(&entry_key(kept, METADATA_FILE))
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(metadata.pinned);
+
+        repo.set_pinned(created[0].id, false, None).await.unwrap();
+        assert_eq!(repo.prune_created_older_than_days(90).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn deleting_backups_reports_only_unpinned_rows() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        let mut created = Vec::new();
+        for hash in ["h1", "h2"] {
+            match repo
+                .run_once(
+                    BackupPayload {
+                        bytes: sample_collection(),
+                        source_revision: None,
+                        sync_duration_ms: None,
+                        media_set: None,
+                    },
+                    hash.to_string(),
+                )
+                .await
+                .unwrap()
+            {
+                RunOnceOutcome::Created(e) => created.push(e),
+                RunOnceOutcome::Skipped(_) => panic!("expected created backup"),
+            }
+        }
+        repo.set_pinned(created[0].id, true, None).await.unwrap();
+
+        let ids: Vec<Uuid> = created.iter().map(|e| e.id).collect();
+        let deleted = repo.store.delete_backups(&ids).await.unwrap();
+        assert_eq!(deleted, vec![created[1].id]);
+        assert!(repo.get_backup(created[0].id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn rollback_history_follows_uploads_and_pointer() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        assert_eq!(repo.current_backup_id().await.unwrap(), None);
+        let mut created = Vec::new();
+        for hash in ["h1", "h2"] {
+            match repo
+                .run_once(
+                    BackupPayload {
+                        bytes: sample_collection(),
+                        source_revision: None,
+                        sync_duration_ms: None,
+                        media_set: None,
+                    },
+                    hash.to_string(),
+                )
+                .await
+                .unwrap()
+            {
+                RunOnceOutcome::Created(e) => created.push(e),
+                RunOnceOutcome::Skipped(_) => panic!("expected created backup"),
+            }
+        }
+        assert_eq!(repo.current_backup_id().await.unwrap(), Some(created[1].id));
+
+        let (_, event) = repo
+            .rollback_to(created[0].id, "api token", RollbackUpload::Pending)
+            .await
+            .unwrap();
+        assert_eq!(repo.current_backup_id().await.unwrap(), Some(created[0].id));
+        repo.record_rollback_upload(event.id, RollbackUpload::Failed, None)
+            .await
+            .unwrap();
+        repo.rollback_to(created[1].id, "ui", RollbackUpload::Local)
+            .await
+            .unwrap();
+
+        let history = repo.list_rollback_history().await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].event.actor.as_deref(), Some("ui"));
+        assert_eq!(history[0].event.upload, Some(RollbackUpload::Local));
+        assert_eq!(history[1].event.id, event.id);
+        assert_eq!(history[1].event.upload, Some(RollbackUpload::Failed));
+        assert_eq!(history[1].backup.as_ref().unwrap().id, created[0].id);
+
+        // Events outlive the backups they rolled back to.
+        let conn = Connection::open(tmp.path().join("state").join("metadata.db")).unwrap();
+        let old = (Utc::now() - chrono::Duration::days(400)).to_rfc3339();
+        conn.execute(
+            "UPDATE backups SET created_at = ?1 WHERE id = ?2",
+            [old, created[0].id.to_string()],
+        )
+        .unwrap();
+        assert_eq!(repo.prune_created_older_than_days(90).await.unwrap(), 1);
+        let history = repo.list_rollback_history().await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(history[1].backup.is_none());
+    }
+
+    #[tokio::test]
+    async fn media_is_shared_between_backups_and_collected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        let payload = sample_collection();
+        let hash = content_hash(&payload);
+
+        let mut set = repo.latest_media_set().await.unwrap();
+        assert!(set.files.is_empty());
+        set.usn = 1;
+        set.files.insert(
+            "cat.jpg".to_owned(),
+            repo.put_media_file(b"meow").await.unwrap(),
+        );
+        let first_set = repo.write_media_set(&set).await.unwrap();
+        let backup = |media_set: &str| BackupPayload {
+            bytes: payload.clone(),
+            source_revision: None,
+            sync_duration_ms: None,
+            media_set: Some(media_set.to_owned()),
+        };
+        let RunOnceOutcome::Created(first) = repo
+            .run_once(backup(&first_set), hash.clone())
+            .await
+            .unwrap()
+        else {
+            panic!("expected created backup");
+        };
+        assert_eq!(first.media_set.as_deref(), Some(first_set.as_str()));
+        assert!(matches!(
+            repo.run_once(backup(&first_set), hash.clone())
+                .await
+                .unwrap(),
+            RunOnceOutcome::Skipped(_)
+        ));
+
+        // Same collection, new media: a new backup sharing the unchanged file.
+        let mut set = repo.latest_media_set().await.unwrap();
+        assert_eq!(set.usn, 1);
+        set.usn = 2;
+        set.files.insert(
+            "dog.mp3".to_owned(),
+            repo.put_media_file(b"woof").await.unwrap(),
+        );
+        let second_set = repo.write_media_set(&set).await.unwrap();
+        let RunOnceOutcome::Created(second) =
+            repo.run_once(backup(&second_set), hash).await.unwrap()
+        else {
+            panic!("expected created backup");
+        };
+        let stored = repo.read_media_set(&second_set).await.unwrap();
+        assert_eq!(stored.total_size(), 8);
+        assert_eq!(
+            repo.read_media_file(&stored.files["cat.jpg"])
+                .await
+                .unwrap(),
+            b"meow"
+        );
+        assert_eq!(stored.files["dog.mp3"].sha1, sha1_hex(b"woof"));
+
+        // A set no backup refers to is removed once past the grace period;
+        // the files of live sets stay, and so do sets written moments ago.
+        let orphan_id = repo
+            .write_media_set(&MediaSet {
+                usn: 3,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let young_id = repo
+            .write_media_set(&MediaSet {
+                usn: 4,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let backdated = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 3600);
+        for id in [&orphan_id, &first_set] {
+            fs::File::options()
+                .write(true)
+                .open(tmp.path().join(media_set_key(id)))
+                .unwrap()
+                .set_modified(backdated)
+                .unwrap();
+        }
+        repo.store.delete_backups(&[first.id]).await.unwrap();
+        repo.remove_backup_dir(&first.timestamp_dir).await.unwrap();
+        repo.collect_garbage().await.unwrap();
+        assert!(repo.read_media_set(&orphan_id).await.is_err());
+        assert!(repo.read_media_set(&first_set).await.is_err());
+        assert!(repo.read_media_set(&young_id).await.is_ok());
+        assert_eq!(
+            repo.read_media_file(&stored.files["cat.jpg"])
+                .await
+                .unwrap(),
+            b"meow"
+        );
+        assert_eq!(repo.verify().await.unwrap().failures.len(), 0);
+        assert_eq!(second.media_set.as_deref(), Some(second_set.as_str()));
+
+        // An unreadable set is skipped rather than stopping collection.
+        fs::write(tmp.path().join(media_set_key(&young_id)), b"garbage").unwrap();
+        repo.collect_garbage().await.unwrap();
+        assert_eq!(
+            repo.read_media_file(&stored.files["dog.mp3"])
+                .await
+                .unwrap(),
+            b"woof"
+        );
+
+        // Media contents are checked against their SHA-1.
+        let mut tampered = stored.clone();
+        tampered.files.get_mut("dog.mp3").unwrap().sha1 = sha1_hex(b"bark");
+        fs::write(
+            tmp.path().join(media_set_key(&second_set)),
+            serde_json::to_vec(&tampered).unwrap(),
+        )
+        .unwrap();
+        let report = repo.verify().await.unwrap();
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].status, VerificationStatus::HashMismatch);
+        assert!(report.failures[0].detail.contains("dog.mp3"));
+    }
+
+    #[tokio::test]
+    async fn imports_packages_at_their_own_time_once() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        let current = || {
+            let raw = std::fs::read(tmp.path().join(CURRENT_POINTER_KEY)).unwrap();
+            serde_json::from_slice::<Value>(&raw).unwrap()["backup_id"].clone()
+        };
+        let RunOnceOutcome::Created(latest) = repo
+            .run_once(
+                BackupPayload {
+                    bytes: sample_collection(),
+                    source_revision: None,
+                    sync_duration_ms: None,
+                    media_set: None,
+                },
+                content_hash(&sample_collection()),
+            )
+            .await
+            .unwrap()
+        else {
+            panic!("expected a new backup");
+        };
+
+        let changed = |sql: &str| {
+            let tmp = tempfile::NamedTempFile::new().unwrap();
+            std::fs::write(tmp.path(), sample_collection()).unwrap();
+            Connection::open(tmp.path())
+                .unwrap()
+                .execute_batch(sql)
+                .unwrap();
+            std::fs::read(tmp.path()).unwrap()
+        };
+
+        // An older export, dated by its col.mod.
+        let old = changed(
+            "ALTER TABLE col ADD COLUMN mod INTEGER NOT NULL DEFAULT 1600000000000;
+             INSERT INTO notes(id) VALUES (3);",
+        );
+        let package = crate::package::tests::modern_package(&old, &[("cat.jpg", b"meow")]);
+        let ImportOutcome::Imported { entry, format } =
+            repo.import_package(package.clone(), None).await.unwrap()
+        else {
+            panic!("expected an import");
+        };
+        assert_eq!(format, PackageFormat::Modern);
+        assert_eq!(entry.created_at.timestamp(), 1_600_000_000);
+        assert_eq!(repo.read_backup(&entry).await.unwrap(), old);
+        let media = repo
+            .read_media_set(entry.media_set.as_deref().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(media.files.keys().collect::<Vec<_>>(), ["cat.jpg"]);
+        // Older than the latest backup, so the current pointer stays put.
+        assert_eq!(current(), serde_json::json!(latest.id));
+
+        let ImportOutcome::Duplicate(existing) = repo.import_package(package, None).await.unwrap()
+        else {
+            panic!("expected a duplicate");
+        };
+        assert_eq!(existing.id, entry.id);
+        let legacy = crate::package::tests::legacy_package(&sample_collection(), &[]);
+        assert!(matches!(
+            repo.import_package(legacy, None).await.unwrap(),
+            ImportOutcome::Duplicate(existing) if existing.id == latest.id
+        ));
+
+        let at = Utc::now() + chrono::Duration::hours(1);
+        let newer = crate::package::tests::legacy_package(
+            &changed("INSERT INTO notes(id) VALUES (4)"),
+            &[],
+        );
+        let ImportOutcome::Imported { entry, .. } =
+            repo.import_package(newer, Some(at)).await.unwrap()
+        else {
+            panic!("expected an import");
+        };
+        assert_eq!(entry.created_at, at);
+        assert_eq!(current(), serde_json::json!(entry.id));
+    }
+
+    #[tokio::test]
+    async fn exports_backups_as_packages() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        let media: &[(&str, &[u8])] = &[("cat.jpg", b"meow"), ("dog.mp3", b"woof")];
+        let package = crate::package::tests::legacy_package(&sample_collection(), media);
+        let ImportOutcome::Imported { entry, .. } =
+            repo.import_package(package, None).await.unwrap()
+        else {
+            panic!("expected an import");
+        };
+
+        let mut exported = Package::open(repo.export_package(&entry).await.unwrap()).unwrap();
+        assert_eq!(exported.format(), PackageFormat::Modern);
+        assert_eq!(exported.collection().unwrap(), sample_collection());
+        let files: Vec<_> = (0..exported.media_len())
+            .map(|i| exported.read_media(i).unwrap())
+            .collect();
+        assert_eq!(
+            files,
+            media
+                .iter()
+                .map(|(n, c)| (n.to_string(), c.to_vec()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn host_key_cache_is_sealed_and_per_account() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        assert_eq!(repo.cached_host_key("me", "pw").await.unwrap(), None);
+
+        repo.store_host_key("me", "pw", "hkey-123").await.unwrap();
+        let on_disk = std::fs::read(tmp.path().join("state").join("ankiweb-host-key")).unwrap();
+        assert!(!on_disk.windows(8).any(|w| w == b"hkey-123"));
+        assert_eq!(
+            repo.cached_host_key("me", "pw").await.unwrap().as_deref(),
+            Some("hkey-123")
+        );
+        assert_eq!(repo.cached_host_key("me", "changed").await.unwrap(), None);
+        assert_eq!(repo.cached_host_key("you", "pw").await.unwrap(), None);
+        assert!(repo.store_host_key("me", "", "hkey-123").await.is_err());
+
+        let key_file = tmp.path().join("backup.key");
+        std::fs::write(&key_file, [7u8; 32]).unwrap();
+        let repo = repo
+            .with_encryption(Some(&KeySource::File(key_file)))
+            .await
+            .unwrap();
+        repo.store_host_key("me", "", "hkey-456").await.unwrap();
+        assert_eq!(
+            repo.cached_host_key("me", "").await.unwrap().as_deref(),
+            Some("hkey-456")
+        );
+        repo.clear_host_key().await.unwrap();
+        assert_eq!(repo.cached_host_key("me", "").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn profiles_share_the_encryption_key() {
+        let tmp = tempfile::tempdir().unwrap();
+        let default = BackupRepository::new(tmp.path())
+            .unwrap()
+            .with_encryption(Some(&KeySource::Passphrase("pass".to_owned())))
+            .await
+            .unwrap();
+        let alice = default.for_profile("alice").unwrap();
+        assert!(alice.objects.encryption_key().is_some());
+
+        alice.store_host_key("alice", "", "hkey-a").await.unwrap();
+        assert!(tmp.path().join("encryption.json").exists());
+        assert!(!tmp.path().join("profiles/alice/encryption.json").exists());
+        let reopened = BackupRepository::new(tmp.path())
+            .unwrap()
+            .with_encryption(Some(&KeySource::Passphrase("pass".to_owned())))
+            .await
+            .unwrap()
+            .for_profile("alice")
+            .unwrap();
+        assert_eq!(
+            reopened
+                .cached_host_key("alice", "")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("hkey-a")
+        );
+    }
+
+    #[tokio::test]
+    async fn profiles_are_isolated() {
+        let tmp = tempfile::tempdir().unwrap();
+        let default = BackupRepository::new(tmp.path()).unwrap();
+        let alice = default.for_profile("alice").unwrap();
+        assert!(default.for_profile("../bob").is_err());
+        let payload = sample_collection();
+        let backup = |repo: BackupRepository| {
+            let payload = payload.clone();
+            async move {
+                let hash = content_hash(&payload);
+                let outcome = repo
+                    .run_once(
+                        BackupPayload {
+                            bytes: payload,
+                            source_revision: None,
+                            sync_duration_ms: None,
+                            media_set: None,
+                        },
+                        hash,
+                    )
+                    .await
+                    .unwrap();
+                match outcome {
+                    RunOnceOutcome::Created(e) => e,
+                    RunOnceOutcome::Skipped(_) => panic!("expected created"),
+                }
+            }
+        };
+
+        // Same content in both: neither profile sees the other's backup.
+        let theirs = backup(alice.clone()).await;
+        // Nothing in the default profile references alice's chunks.
+        default.collect_garbage().await.unwrap();
+        let ours = backup(default.clone()).await;
+        assert_eq!(default.list_backups().await.unwrap().len(), 1);
+        assert_eq!(alice.list_backups().await.unwrap()[0].id, theirs.id);
+        assert!(alice.get_backup(ours.id).await.unwrap().is_none());
+        assert!(alice
+            .rollback_to(ours.id, "ui", RollbackUpload::Local)
+            .await
+            .is_err());
+        assert!(tmp
+            .path()
+            .join("profiles/alice/backups")
+            .join(&theirs.timestamp_dir)
+            .exists());
+
+        alice.store_host_key("alice", "pw", "hkey-a").await.unwrap();
+        assert_eq!(default.cached_host_key("alice", "pw").await.unwrap(), None);
+        assert_eq!(alice.read_backup(&theirs).await.unwrap(), payload);
+        assert!(alice.verify().await.unwrap().is_clean());
+    }
+
+    #[tokio::test]
+    async fn verify_quarantines_corrupt_chunks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        let payload = sample_collection();
+        repo.run_once(
+            BackupPayload {
+                bytes: payload.clone(),
+                source_revision: None,
+                sync_duration_ms: None,
+                media_set: None,
+            },
+            content_hash(&payload),
+        )
+        .await
+        .unwrap();
+        let chunks = repo.objects.blobs().list(OBJECTS_PREFIX).await.unwrap();
+        for key in &chunks {
+            fs::write(tmp.path().join(key), b"tampered").unwrap();
+        }
+
+        let report = repo.verify().await.unwrap();
+        assert_eq!(report.failures[0].status, VerificationStatus::Corrupt);
+        assert!(
+            report.failures[0].detail.contains("quarantine"),
+            "{}",
+            report.failures[0].detail
+        );
+        assert!(repo
+            .objects
+            .blobs()
+            .list("objects/")
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Storing the same bytes again repairs the backup.
+        repo.objects.put_payload(&payload).await.unwrap();
+        assert!(repo.verify().await.unwrap().is_clean());
+    }
+
+    #[tokio::test]
+    async fn verify_reports_missing_mismatched_corrupt_and_orphaned() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        let mut created = Vec::new();
+        for extra_note in 10..14 {
+            let payload = {
+                let db = tempfile::NamedTempFile::new().unwrap();
+                fs::write(db.path(), sample_collection()).unwrap();
+                let conn = Connection::open(db.path()).unwrap();
+                conn.execute("INSERT INTO notes(id) VALUES (?1)", [extra_note])
+                    .unwrap();
+                drop(conn);
+                fs::read(db.path()).unwrap()
+            };
+            match repo
+                .run_once(
+                    BackupPayload {
+                        bytes: payload.clone(),
+                        source_revision: None,
+                        sync_duration_ms: None,
+                        media_set: None,
+                    },
+                    content_hash(&payload),
+                )
+                .await
+                .unwrap()
+            {
+                RunOnceOutcome::Created(e) => created.push(e),
+                RunOnceOutcome::Skipped(_) => panic!("expected created backup"),
+            }
+        }
+        let backups_dir = tmp.path().join("backups");
+        let conn = Connection::open(tmp.path().join("state").join("metadata.db")).unwrap();
+
+        // [1]: recorded hash no longer matches.
+        conn.execute(
+            "UPDATE backups SET content_hash = 'bogus' WHERE id = ?1",
+            [created[1].id.to_string()],
+        )
+        .unwrap();
+        // [2]: directory deleted.
+        fs::remove_dir_all(backups_dir.join(&created[2].timestamp_dir)).unwrap();
+        // [3]: payload replaced by something that is not a database.
+        let dir = backups_dir.join(&created[3].timestamp_dir);
+        fs::remove_file(dir.join(MANIFEST_FILE)).unwrap();
+        fs::write(dir.join(LEGACY_PAYLOAD_FILE), b"not a database").unwrap();
+        conn.execute(
+            "UPDATE backups SET content_hash = ?1 WHERE id = ?2",
+            [content_hash(b"not a database"), created[3].id.to_string()],
+        )
+        .unwrap();
+        // Orphaned directories with no metadata row, and one a run started
+        // moments ago may still be writing.
+        let stale = format_timestamp_dir(Utc::now() - chrono::Duration::hours(2));
+        let fresh = format_timestamp_dir(Utc::now() - chrono::Duration::minutes(5));
+        for dir in ["stray", &stale, &fresh] {
+            fs::create_dir_all(backups_dir.join(dir)).unwrap();
+            fs::write(backups_dir.join(dir).join(METADATA_FILE), b"{}").unwrap();
+        }
+
+        let report = repo.verify().await.unwrap();
+        assert_eq!(report.checked, 4);
+        assert!(!report.is_clean());
+        assert_eq!(report.orphaned_dirs, [stale.as_str(), "stray"]);
+        let status_of = |id| {
+            report
+                .failures
+                .iter()
+                .find(|f| f.backup_id == id)
+                .map(|f| f.status)
+        };
+        assert_eq!(status_of(created[0].id), None);
+        assert_eq!(
+            status_of(created[1].id),
+            Some(VerificationStatus::HashMismatch)
+        );
+        assert_eq!(status_of(created[2].id), Some(VerificationStatus::Missing));
+        assert_eq!(status_of(created[3].id), Some(VerificationStatus::Corrupt));
+
+        let recorded = repo.get_backup(created[0].id).await.unwrap().unwrap();
+        assert_eq!(
+            recorded.verification.unwrap().status,
+            VerificationStatus::Ok
+        );
+        let recorded = repo.get_backup(created[2].id).await.unwrap().unwrap();
+        let verification = recorded.verification.unwrap();
+        assert_eq!(verification.status, VerificationStatus::Missing);
+        assert!(verification.detail.is_some());
+    }
+
+    #[tokio::test]
+    async fn reindex_rebuilds_lost_metadata_db() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        let first = sample_collection();
+        let second = {
+            let db = tempfile::NamedTempFile::new().unwrap();
+            fs::write(db.path(), &first).unwrap();
+            let conn = Connection::open(db.path()).unwrap();
+            conn.execute("INSERT INTO notes(id) VALUES (3)", [])
+                .unwrap();
+            drop(conn);
+            fs::read(db.path()).unwrap()
+        };
+        let mut created = Vec::new();
+        for payload in [&first, &second] {
+            match repo
+                .run_once(
+                    BackupPayload {
+                        bytes: payload.clone(),
+                        source_revision: None,
+                        sync_duration_ms: None,
+                        media_set: None,
+                    },
+                    content_hash(payload),
+                )
+                .await
+                .unwrap()
+            {
+                RunOnceOutcome::Created(e) => created.push(e),
+                RunOnceOutcome::Skipped(_) => panic!("expected created backup"),
+            }
+        }
+        repo.set_pinned(created[0].id, true, Some("keep".to_owned()))
+            .await
+            .unwrap();
+        let backups_dir = tmp.path().join("backups");
+        fs::remove_file(
+            backups_dir
+                .join(&created[1].timestamp_dir)
+                .join(METADATA_FILE),
+        )
+        .unwrap();
+        // A copy of the first backup under another name clashes on id.
+        let copy = backups_dir.join("2001-01-01T00-00-00Z");
+        fs::create_dir_all(&copy).unwrap();
+        for name in [MANIFEST_FILE, METADATA_FILE] {
+            fs::copy(
+                backups_dir.join(&created[0].timestamp_dir).join(name),
+                copy.join(name),
+            )
+            .unwrap();
+        }
+        drop(repo);
+        fs::remove_file(tmp.path().join("state").join("metadata.db")).unwrap();
+
+        let repo = BackupRepository::new(tmp.path()).unwrap();
+        let report = repo.reindex().await.unwrap();
+        assert_eq!(report.scanned, 3);
+        assert_eq!(report.restored, 2);
+        assert_eq!(report.recovered_from_payload, 1);
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(report.conflicts[0].timestamp_dir, "2001-01-01T00-00-00Z");
+
+        let restored = repo.get_backup(created[0].id).await.unwrap().unwrap();
+        assert!(restored.pinned);
+        assert_eq!(restored.note.as_deref(), Some("keep"));
+        let rebuilt = repo
+            .list_backups()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|b| b.timestamp_dir == created[1].timestamp_dir)
+            .unwrap();
+        assert_eq!(rebuilt.content_hash, created[1].content_hash);
+        assert_eq!(rebuilt.stats.unwrap().total_notes, 3);
+        assert_eq!(
+            rebuilt.created_at.timestamp(),
+            created[1].created_at.timestamp()
+        );
+        assert_eq!(repo.read_backup(&restored).await.unwrap(), first);
+
+        let again = repo.reindex().await.unwrap();
+        assert_eq!(again.restored, 0);
+        assert_eq!(again.already_indexed, 2);
+        assert_eq!(again.conflicts.len(), 1);
+    }
+
+    #[test]
+    fn timestamp_dirs_parse_back() {
+        let now = chrono::TimeZone::with_ymd_and_hms(&Utc, 2026, 10, 15, 8, 30, 5).unwrap();
+        let dir = format_timestamp_dir(now);
+        assert_eq!(parse_timestamp_dir(&dir), Some(now));
+        assert_eq!(parse_timestamp_dir(&format!("{dir}-2")), Some(now));
+        assert_eq!(parse_timestamp_dir("stray"), None);
+    }
+
     #[tokio::test]
     async fn prune_retention_deletes_old_created_backups() {
         let tmp = tempfile::tempdir().unwrap();
@@ -370,6 +2738,7 @@ mod tests {
                     bytes: payload,
                     source_revision: None,
                     sync_duration_ms: Some(1),
+                    media_set: None,
                 },
                 "hash1".to_string(),
             )
@@ -380,7 +2749,8 @@ mod tests {
             RunOnceOutcome::Skipped(_) => panic!("expected created backup"),
         };
 
-        // Backdate via direct SQLite access
+        // Backdate via direct SQLite access, and drop the current pointer so
+        // the only backup is not protected as current.
         let conn = Connection::open(tmp.path().join("state").join("metadata.db")).unwrap();
         let old = (Utc::now() - chrono::Duration::days(400)).to_rfc3339();
         conn.execute(
@@ -388,12 +2758,14 @@ mod tests {
             rusqlite::params![old, created.id.to_string()],
         )
         .unwrap();
+        std::fs::remove_file(tmp.path().join(CURRENT_POINTER_KEY)).unwrap();
 
         let removed = repo.prune_created_older_than_days(90).await.unwrap();
         assert_eq!(removed, 1);
 
         let remaining = repo.list_backups().await.unwrap();
         assert!(remaining.is_empty());
+        assert!(!tmp.path().join("objects").exists());
         assert!(!repo
             .root
             .join("backups")
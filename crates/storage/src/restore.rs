@@ -0,0 +1,703 @@
+//! Grafting notes, cards and decks from a backup into a newer collection, to
+//! restore part of a collection without rolling back the rest.
+//!
+//! Selected notes are written back as they were in the backup, reviving
+//! deleted ones and reverting edits. Their cards that no longer exist are
+//! added back with the review history they had; cards that still exist keep
+//! their current deck and scheduling, and no review is ever removed. Decks,
+//! deck option presets and note types the restored notes need are copied
+//! over when missing, reusing a current deck with the same name.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use rusqlite::{params, Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::deck::table_exists;
+use crate::package::{read_field, Field};
+use crate::repository::{check_sqlite_integrity, parse_deck_names_legacy, parse_deck_names_new};
+
+/// What to restore from a backup.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestoreSelection {
+    #[serde(default)]
+    pub note_ids: Vec<i64>,
+    /// Cards whose notes are restored.
+    #[serde(default)]
+    pub card_ids: Vec<i64>,
+    /// Decks restored with their subdecks and every note with a card in them.
+    #[serde(default)]
+    pub deck_ids: Vec<i64>,
+}
+
+impl RestoreSelection {
+    pub fn is_empty(&self) -> bool {
+        self.note_ids.is_empty() && self.card_ids.is_empty() && self.deck_ids.is_empty()
+    }
+}
+
+/// A selection that cannot be merged into the current collection, as opposed
+/// to a failure reading or writing either collection.
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct RestoreRejected(pub String);
+
+impl RestoreRejected {
+    /// The rejection behind `err`, looking through any context added on the
+    /// way up.
+    pub fn classify(err: &anyhow::Error) -> Option<&RestoreRejected> {
+        err.chain()
+            .find_map(|e| e.downcast_ref::<RestoreRejected>())
+    }
+}
+
+/// What a merge changed in the current collection.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeReport {
+    /// Notes missing from the current collection that were added back.
+    pub notes_added: usize,
+    /// Existing notes whose fields, tags or note type were reverted.
+    pub notes_reverted: usize,
+    pub cards_added: usize,
+    pub revlog_added: usize,
+    pub decks_added: usize,
+    pub note_types_added: usize,
+}
+
+/// `current` with `selection` grafted in from `backup`, validated.
+pub(crate) fn merge_into(
+    current: &[u8],
+    backup: &[u8],
+    selection: &RestoreSelection,
+) -> Result<(Vec<u8>, MergeReport)> {
+    if selection.is_empty() {
+        return Err(RestoreRejected("nothing selected to restore".to_owned()).into());
+    }
+    let dir = tempfile::tempdir().context("create merge directory")?;
+    let current_path = dir.path().join("current.anki2");
+    let backup_path = dir.path().join("backup.anki2");
+    fs::write(&current_path, current).context("write current collection")?;
+    fs::write(&backup_path, backup).context("write backup collection")?;
+
+    let source = Connection::open_with_flags(&backup_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .context("open backup collection")?;
+    let mut conn = Connection::open(&current_path).context("open current collection")?;
+    let modern = table_exists(&conn, "decks")?;
+    if table_exists(&source, "decks")? != modern {
+        return Err(RestoreRejected(
+            "the backup and the current collection use different schema versions".to_owned(),
+        )
+        .into());
+    }
+    conn.execute(
+        "ATTACH DATABASE ?1 AS backup",
+        params![backup_path.to_string_lossy()],
+    )
+    .context("attach backup collection")?;
+
+    let mut report = MergeReport::default();
+    let tx = conn.transaction()?;
+    let source_decks = deck_names(&source, modern)?;
+    let separator = if modern { "\x1f" } else { "::" };
+    tx.execute_batch(
+        "CREATE TEMP TABLE restore_decks (id INTEGER PRIMARY KEY);
+         CREATE TEMP TABLE restore_notes (id INTEGER PRIMARY KEY);
+         CREATE TEMP TABLE restore_cards (id INTEGER PRIMARY KEY);",
+    )?;
+
+    // Resolve the selection against the backup.
+    for &id in &selection.deck_ids {
+        let name = source_decks
+            .get(&id)
+            .ok_or_else(|| RestoreRejected(format!("the backup has no deck {id}")))?;
+        let child_prefix = format!("{name}{separator}");
+        for (deck, _) in source_decks
+            .iter()
+            .filter(|(deck, name)| **deck == id || name.starts_with(&child_prefix))
+        {
+            tx.execute(
+                "INSERT OR IGNORE INTO temp.restore_decks(id) VALUES (?1)",
+                params![deck],
+            )?;
+        }
+    }
+    for &id in &selection.note_ids {
+        if tx.execute(
+            "INSERT OR IGNORE INTO temp.restore_notes(id) SELECT id FROM backup.notes WHERE id = ?1",
+            params![id],
+        )? == 0
+            && !exists(&tx, "SELECT 1 FROM temp.restore_notes WHERE id = ?1", id)?
+        {
+            return Err(RestoreRejected(format!("the backup has no note {id}")).into());
+        }
+    }
+    for &id in &selection.card_ids {
+        if !exists(&tx, "SELECT 1 FROM backup.cards WHERE id = ?1", id)? {
+            return Err(RestoreRejected(format!("the backup has no card {id}")).into());
+        }
+        tx.execute(
+            "INSERT OR IGNORE INTO temp.restore_notes(id) SELECT nid FROM backup.cards WHERE id = ?1",
+            params![id],
+        )?;
+    }
+    tx.execute_batch(
+        "INSERT OR IGNORE INTO temp.restore_notes(id)
+             SELECT nid FROM backup.cards
+              WHERE did IN (SELECT id FROM temp.restore_decks)
+                 OR odid IN (SELECT id FROM temp.restore_decks);",
+    )?;
+
+    report.notes_added = count(
+        &tx,
+        "SELECT COUNT(*) FROM temp.restore_notes WHERE id NOT IN (SELECT id FROM main.notes)",
+    )?;
+    report.notes_reverted = count(
+        &tx,
+        "SELECT COUNT(*) FROM main.notes n JOIN backup.notes b ON b.id = n.id
+          WHERE n.id IN (SELECT id FROM temp.restore_notes)
+            AND (n.flds != b.flds OR n.tags != b.tags OR n.mid != b.mid)",
+    )?;
+
+    // Notes and their missing cards and review history, leaving cards that
+    // still exist (and every current review) alone. Cards that were in a
+    // filtered deck go back to their home deck.
+    let now = Utc::now();
+    tx.execute_batch(
+        "INSERT INTO temp.restore_cards(id)
+             SELECT id FROM backup.cards
+              WHERE nid IN (SELECT id FROM temp.restore_notes)
+                AND id NOT IN (SELECT id FROM main.cards);
+         INSERT INTO main.cards SELECT * FROM backup.cards
+          WHERE id IN (SELECT id FROM temp.restore_cards);
+         UPDATE main.cards
+            SET did = odid, due = CASE WHEN odue != 0 THEN odue ELSE due END, odid = 0, odue = 0
+          WHERE id IN (SELECT id FROM temp.restore_cards) AND odid != 0;
+         INSERT OR REPLACE INTO main.notes SELECT * FROM backup.notes
+          WHERE id IN (SELECT id FROM temp.restore_notes);",
+    )
+    .context("copy notes and cards")?;
+    report.cards_added = count(&tx, "SELECT COUNT(*) FROM temp.restore_cards")?;
+    report.revlog_added = tx
+        .execute(
+            "INSERT OR IGNORE INTO main.revlog SELECT * FROM backup.revlog
+              WHERE cid IN (SELECT id FROM temp.restore_cards)",
+            [],
+        )
+        .context("copy review history")?;
+    // usn -1 marks the rows as changed locally, like an edit in Anki would.
+    tx.execute(
+        "UPDATE main.notes SET mod = ?1, usn = -1 WHERE id IN (SELECT id FROM temp.restore_notes)",
+        params![now.timestamp()],
+    )?;
+    tx.execute(
+        "UPDATE main.cards SET mod = ?1, usn = -1 WHERE id IN (SELECT id FROM temp.restore_cards)",
+        params![now.timestamp()],
+    )?;
+    tx.execute_batch(
+        "DELETE FROM main.graves
+          WHERE oid IN (SELECT id FROM temp.restore_notes)
+             OR oid IN (SELECT id FROM temp.restore_cards);",
+    )?;
+
+    // Decks: the selected ones and any a restored card is in.
+    let mut needed: BTreeSet<i64> = ids(&tx, "SELECT id FROM temp.restore_decks")?
+        .into_iter()
+        .collect();
+    needed.extend(ids(
+        &tx,
+        "SELECT DISTINCT did FROM main.cards WHERE id IN (SELECT id FROM temp.restore_cards)",
+    )?);
+    let current_decks = deck_names(&tx, modern)?;
+    let by_name: HashMap<&str, i64> = current_decks
+        .iter()
+        .map(|(id, name)| (name.as_str(), *id))
+        .collect();
+    let source_by_name: HashMap<&str, i64> = source_decks
+        .iter()
+        .map(|(id, name)| (name.as_str(), *id))
+        .collect();
+    let mut copy_decks = BTreeSet::new();
+    for id in needed {
+        if current_decks.contains_key(&id) {
+            continue;
+        }
+        let Some(name) = source_decks.get(&id) else {
+            continue;
+        };
+        if let Some(&existing) = by_name.get(name.as_str()) {
+            tx.execute(
+                "UPDATE main.cards SET did = ?1
+                  WHERE did = ?2 AND id IN (SELECT id FROM temp.restore_cards)",
+                params![existing, id],
+            )?;
+            continue;
+        }
+        copy_decks.insert(id);
+        // Parent decks that are gone too.
+        let mut parent = name.as_str();
+        while let Some((rest, _)) = parent.rsplit_once(separator) {
+            parent = rest;
+            if by_name.contains_key(parent) {
+                continue;
+            }
+            // A parent renamed since the backup still exists under its id.
+            if let Some(&parent_id) = source_by_name.get(parent) {
+                if !current_decks.contains_key(&parent_id) {
+                    copy_decks.insert(parent_id);
+                }
+            }
+        }
+    }
+    report.decks_added = copy_decks.len();
+
+    let note_types: Vec<i64> = ids(
+        &tx,
+        "SELECT DISTINCT mid FROM main.notes WHERE id IN (SELECT id FROM temp.restore_notes)",
+    )?;
+    if modern {
+        for &id in &copy_decks {
+            tx.execute(
+                "INSERT INTO main.decks SELECT * FROM backup.decks WHERE id = ?1",
+                params![id],
+            )?;
+            let kind: Vec<u8> = tx.query_row(
+                "SELECT kind FROM backup.decks WHERE id = ?1",
+                params![id],
+                |r| r.get(0),
+            )?;
+            if let Some(config) = deck_config_id(&kind)? {
+                tx.execute(
+                    "INSERT INTO main.deck_config SELECT * FROM backup.deck_config
+                      WHERE id = ?1 AND id NOT IN (SELECT id FROM main.deck_config)",
+                    params![config],
+                )?;
+            }
+        }
+        for &id in &note_types {
+            if exists(&tx, "SELECT 1 FROM main.notetypes WHERE id = ?1", id)? {
+                continue;
+            }
+            let copied = tx.execute(
+                "INSERT INTO main.notetypes SELECT * FROM backup.notetypes WHERE id = ?1",
+                params![id],
+            )?;
+            tx.execute(
+                "INSERT INTO main.fields SELECT * FROM backup.fields WHERE ntid = ?1",
+                params![id],
+            )?;
+            tx.execute(
+                "INSERT INTO main.templates SELECT * FROM backup.templates WHERE ntid = ?1",
+                params![id],
+            )?;
+            report.note_types_added += copied;
+        }
+    } else {
+        let read = |conn: &Connection, schema: &str| -> Result<[Map<String, Value>; 3]> {
+            let (decks, models, dconf): (String, String, String) = conn.query_row(
+                &format!("SELECT decks, models, dconf FROM {schema}.col LIMIT 1"),
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )?;
+            Ok([
+                serde_json::from_str(&decks).context("parse col.decks json")?,
+                serde_json::from_str(&models).context("parse col.models json")?,
+                serde_json::from_str(&dconf).context("parse col.dconf json")?,
+            ])
+        };
+        let [mut decks, mut models, mut dconf] = read(&tx, "main")?;
+        let [source_decks, source_models, source_dconf] = read(&tx, "backup")?;
+        for id in &copy_decks {
+            let Some(deck) = source_decks.get(&id.to_string()) else {
+                continue;
+            };
+            if let Some(config) = deck.get("conf").map(|c| c.to_string()) {
+                if let (false, Some(preset)) =
+                    (dconf.contains_key(&config), source_dconf.get(&config))
+                {
+                    dconf.insert(config, preset.clone());
+                }
+            }
+            decks.insert(id.to_string(), deck.clone());
+        }
+        for id in &note_types {
+            let key = id.to_string();
+            if let (false, Some(model)) = (models.contains_key(&key), source_models.get(&key)) {
+                models.insert(key, model.clone());
+                report.note_types_added += 1;
+            }
+        }
+        tx.execute(
+            "UPDATE main.col SET decks = ?1, models = ?2, dconf = ?3",
+            params![
+                Value::Object(decks).to_string(),
+                Value::Object(models).to_string(),
+                Value::Object(dconf).to_string()
+            ],
+        )?;
+    }
+
+    validate(&tx, modern)?;
+    tx.execute(
+        "UPDATE main.col SET mod = ?1",
+        params![now.timestamp_millis()],
+    )?;
+    tx.commit().context("commit merged collection")?;
+    conn.execute_batch("DETACH DATABASE backup")?;
+    drop(conn);
+
+    let merged = fs::read(&current_path).context("read merged collection")?;
+    check_sqlite_integrity(&merged).context("merged collection failed its integrity check")?;
+    Ok((merged, report))
+}
+
+/// Check that every restored note matches its note type and every restored
+/// card is in a deck.
+fn validate(conn: &Connection, modern: bool) -> Result<()> {
+    let field_counts: HashMap<i64, usize> = if modern {
+        let mut stmt = conn.prepare("SELECT ntid, COUNT(*) FROM main.fields GROUP BY ntid")?;
+        let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get::<_, i64>(1)? as usize)))?;
+        rows.collect::<rusqlite::Result<_>>()?
+    } else {
+        let models: String = conn.query_row("SELECT models FROM main.col", [], |r| r.get(0))?;
+        let models: Map<String, Value> = serde_json::from_str(&models)?;
+        models
+            .iter()
+            .filter_map(|(id, model)| {
+                let fields = model.get("flds")?.as_array()?.len();
+                Some((id.parse().ok()?, fields))
+            })
+            .collect()
+    };
+    let mut stmt = conn.prepare(
+        "SELECT id, mid, flds FROM main.notes WHERE id IN (SELECT id FROM temp.restore_notes)",
+    )?;
+    let notes = stmt.query_map([], |r| {
+        Ok((
+            r.get::<_, i64>(0)?,
+            r.get::<_, i64>(1)?,
+            r.get::<_, String>(2)?,
+        ))
+    })?;
+    for note in notes {
+        let (id, note_type, fields) = note?;
+        let Some(&expected) = field_counts.get(&note_type) else {
+            return Err(RestoreRejected(format!(
+                "restored note {id} has no note type {note_type}"
+            ))
+            .into());
+        };
+        let actual = fields.split('\x1f').count();
+        if actual != expected {
+            return Err(RestoreRejected(format!(
+                "note {id} has {actual} fields in the backup but its note type now has \
+                 {expected}; restore it from a backup taken after the note type changed"
+            ))
+            .into());
+        }
+    }
+
+    let decks = deck_names(conn, modern)?;
+    for deck in ids(
+        conn,
+        "SELECT DISTINCT did FROM main.cards WHERE id IN (SELECT id FROM temp.restore_cards)",
+    )? {
+        if !decks.contains_key(&deck) {
+            return Err(RestoreRejected(format!(
+                "restored cards are in deck {deck}, which neither collection has"
+            ))
+            .into());
+        }
+    }
+    Ok(())
+}
+
+fn deck_names(conn: &Connection, modern: bool) -> Result<HashMap<i64, String>> {
+    if modern {
+        parse_deck_names_new(conn)
+    } else {
+        let json: String =
+            conn.query_row("SELECT decks FROM main.col LIMIT 1", [], |r| r.get(0))?;
+        parse_deck_names_legacy(&json)
+    }
+}
+
+/// The option preset of a normal deck, from its `DeckKindContainer`
+/// protobuf (`normal = 1 { config_id = 1 }`).
+fn deck_config_id(mut kind: &[u8]) -> Result<Option<i64>> {
+    while !kind.is_empty() {
+        if let (1, Field::Bytes(mut normal)) = read_field(&mut kind)? {
+            while !normal.is_empty() {
+                if let (1, Field::Varint(id)) = read_field(&mut normal)? {
+                    return Ok(Some(id as i64));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn exists(conn: &Connection, sql: &str, id: i64) -> Result<bool> {
+    let mut stmt = conn.prepare(sql)?;
+    Ok(stmt.exists(params![id])?)
+}
+
+fn count(conn: &Connection, sql: &str) -> Result<usize> {
+    Ok(conn.query_row(sql, [], |r| r.get::<_, i64>(0))? as usize)
+}
+
+fn ids(conn: &Connection, sql: &str) -> Result<Vec<i64>> {
+    let mut stmt = conn.prepare(sql)?;
+    let ids = stmt
+        .query_map([], |r| r.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::LegacyCollection;
+
+    /// Default (1), Spanish (10) and Spanish::Verbs (11) with presets 1 and
+    /// 2, note types Basic (100, two fields) and Old (200, one field).
+    fn legacy_backup() -> LegacyCollection {
+        LegacyCollection::new()
+            .with_preset(2, "Spanish")
+            .with_deck_preset(10, "Spanish", 2)
+            .with_deck_preset(11, "Spanish::Verbs", 2)
+            .with_note_type(100, "Basic", &["Front", "Back"])
+            .with_note_type(200, "Old", &["Text"])
+            .with_note(1, 100, &["hola", "hello"])
+            .with_note(2, 100, &["ser", "to be"])
+            .with_note(3, 200, &["old"])
+            .with_card(1, 1, 10)
+            .with_card(2, 2, 11)
+            .with_card(3, 3, 1)
+            .with_review(1, 1)
+            .with_review(2, 2)
+    }
+
+    /// The backup after the Spanish decks, their preset, note 2, note 3 and
+    /// note type Old were deleted; note 1 was edited, moved and reviewed.
+    const LEGACY_CURRENT: &str = r#"
+        UPDATE col SET decks = '{"1":{"name":"Default","conf":1}}',
+                       models = '{"100":{"name":"Basic","flds":[{"name":"Front"},{"name":"Back"}]}}',
+                       dconf = '{"1":{"name":"Default"}}';
+        UPDATE notes SET flds = 'hola' || char(31) || 'hi' WHERE id = 1;
+        UPDATE cards SET did = 1, due = 50 WHERE id = 1;
+        DELETE FROM notes WHERE id IN (2, 3);
+        DELETE FROM cards WHERE id IN (2, 3);
+        DELETE FROM revlog WHERE id = 2;
+        INSERT INTO revlog VALUES (3, 1);
+        INSERT INTO graves VALUES (-1, 2, 1), (-1, 2, 0);
+    "#;
+
+    fn collection(sql: &[&str]) -> Vec<u8> {
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        let conn = Connection::open(tmp.path()).unwrap();
+        for sql in sql {
+            conn.execute_batch(sql).unwrap();
+        }
+        drop(conn);
+        fs::read(tmp.path()).unwrap()
+    }
+
+    fn open(bytes: &[u8]) -> (tempfile::NamedTempFile, Connection) {
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        fs::write(tmp.path(), bytes).unwrap();
+        let conn = Connection::open(tmp.path()).unwrap();
+        (tmp, conn)
+    }
+
+    #[test]
+    fn restores_a_deleted_deck_keeping_newer_reviews() {
+        let backup = legacy_backup().build();
+        let current = legacy_backup().with_sql(LEGACY_CURRENT).build();
+        let selection = RestoreSelection {
+            deck_ids: vec![10],
+            ..Default::default()
+        };
+        let (merged, report) = merge_into(&current, &backup, &selection).unwrap();
+        assert_eq!(
+            report,
+            MergeReport {
+                notes_added: 1,
+                notes_reverted: 1,
+                cards_added: 1,
+                revlog_added: 1,
+                decks_added: 2,
+                note_types_added: 0,
+            }
+        );
+
+        let (_tmp, conn) = open(&merged);
+        let flds: String = conn
+            .query_row("SELECT flds FROM notes WHERE id = 1", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(flds, "hola\x1fhello");
+        // Card 1 keeps its current deck and scheduling; its review stays.
+        let (did, due): (i64, i64) = conn
+            .query_row("SELECT did, due FROM cards WHERE id = 1", [], |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((did, due), (1, 50));
+        assert_eq!(ids(&conn, "SELECT id FROM revlog").unwrap(), [1, 2, 3]);
+        assert_eq!(ids(&conn, "SELECT id FROM notes").unwrap(), [1, 2]);
+        assert_eq!(
+            ids(&conn, "SELECT usn FROM cards WHERE id = 2").unwrap(),
+            [-1]
+        );
+        assert!(ids(&conn, "SELECT oid FROM graves").unwrap().is_empty());
+        let decks = deck_names(&conn, false).unwrap();
+        assert_eq!(decks[&11], "Spanish::Verbs");
+        let dconf: String = conn
+            .query_row("SELECT dconf FROM col", [], |r| r.get(0))
+            .unwrap();
+        assert!(dconf.contains("Spanish"));
+    }
+
+    #[test]
+    fn restores_single_notes_with_their_note_type() {
+        let backup = legacy_backup().build();
+        let current = legacy_backup().with_sql(LEGACY_CURRENT).build();
+        let selection = RestoreSelection {
+            note_ids: vec![3],
+            ..Default::default()
+        };
+        let (merged, report) = merge_into(&current, &backup, &selection).unwrap();
+        assert_eq!(report.notes_added, 1);
+        assert_eq!(report.note_types_added, 1);
+        assert_eq!(report.decks_added, 0);
+        let (_tmp, conn) = open(&merged);
+        assert_eq!(ids(&conn, "SELECT id FROM notes").unwrap(), [1, 3]);
+        let models: String = conn
+            .query_row("SELECT models FROM col", [], |r| r.get(0))
+            .unwrap();
+        assert!(models.contains("Old"));
+
+        let by_card = RestoreSelection {
+            card_ids: vec![2],
+            ..Default::default()
+        };
+        let (_, report) = merge_into(&current, &backup, &by_card).unwrap();
+        assert_eq!((report.notes_added, report.decks_added), (1, 2));
+    }
+
+    #[test]
+    fn keeps_renamed_parent_decks() {
+        let backup = legacy_backup().build();
+        let current = legacy_backup()
+            .with_sql(
+                r#"UPDATE col SET decks = '{"1":{"name":"Default","conf":1},
+                                          "10":{"name":"Español","conf":2}}';
+                   DELETE FROM notes WHERE id = 2;
+                   DELETE FROM cards WHERE id = 2;"#,
+            )
+            .build();
+        let selection = RestoreSelection {
+            deck_ids: vec![11],
+            ..Default::default()
+        };
+        let (merged, report) = merge_into(&current, &backup, &selection).unwrap();
+        assert_eq!(report.decks_added, 1);
+        let (_tmp, conn) = open(&merged);
+        let decks = deck_names(&conn, false).unwrap();
+        assert_eq!(decks[&10], "Español");
+        assert_eq!(decks[&11], "Spanish::Verbs");
+    }
+
+    #[test]
+    fn rejects_unknown_selections_and_mismatched_note_types() {
+        let backup = legacy_backup().build();
+        let current = legacy_backup().with_sql(LEGACY_CURRENT).build();
+        for selection in [
+            RestoreSelection::default(),
+            RestoreSelection {
+                note_ids: vec![99],
+                ..Default::default()
+            },
+            RestoreSelection {
+                card_ids: vec![99],
+                ..Default::default()
+            },
+            RestoreSelection {
+                deck_ids: vec![99],
+                ..Default::default()
+            },
+        ] {
+            let err = merge_into(&current, &backup, &selection).unwrap_err();
+            assert!(RestoreRejected::classify(&err).is_some(), "{selection:?}");
+        }
+
+        let reshaped = legacy_backup()
+            .with_sql(LEGACY_CURRENT)
+            .with_sql(
+                r#"UPDATE col SET models =
+                    '{"100":{"name":"Basic","flds":[{"name":"Front"},{"name":"Back"},{"name":"Extra"}]}}';
+                   UPDATE notes SET flds = flds || char(31) WHERE id = 1;"#,
+            )
+            .build();
+        let err = merge_into(
+            &reshaped,
+            &backup,
+            &RestoreSelection {
+                note_ids: vec![1],
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("note type now has 3"), "{err}");
+    }
+
+    #[test]
+    fn reuses_decks_by_name_and_copies_presets_in_table_schemas() {
+        let schema = "
+            CREATE TABLE col (mod INTEGER NOT NULL);
+            CREATE TABLE decks (id INTEGER PRIMARY KEY, name TEXT NOT NULL, kind BLOB NOT NULL);
+            CREATE TABLE deck_config (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+            CREATE TABLE notetypes (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+            CREATE TABLE fields (ntid INTEGER NOT NULL, ord INTEGER NOT NULL, name TEXT NOT NULL);
+            CREATE TABLE templates (ntid INTEGER NOT NULL, ord INTEGER NOT NULL);
+            CREATE TABLE notes (id INTEGER PRIMARY KEY, mid INTEGER NOT NULL, mod INTEGER NOT NULL,
+                                usn INTEGER NOT NULL, tags TEXT NOT NULL, flds TEXT NOT NULL);
+            CREATE TABLE cards (id INTEGER PRIMARY KEY, nid INTEGER NOT NULL, did INTEGER NOT NULL,
+                                mod INTEGER NOT NULL, usn INTEGER NOT NULL, due INTEGER NOT NULL,
+                                odue INTEGER NOT NULL, odid INTEGER NOT NULL);
+            CREATE TABLE revlog (id INTEGER PRIMARY KEY, cid INTEGER NOT NULL);
+            CREATE TABLE graves (oid INTEGER, type INTEGER, usn INTEGER);
+            INSERT INTO col VALUES (0);
+            INSERT INTO deck_config VALUES (1, 'Default');
+            INSERT INTO decks VALUES (1, 'Default', x'0a020801');
+            INSERT INTO notetypes VALUES (100, 'Basic');
+            INSERT INTO fields VALUES (100, 0, 'Front');
+            INSERT INTO templates VALUES (100, 0);";
+        let backup = collection(&[
+            schema,
+            "INSERT INTO deck_config VALUES (5, 'Languages');
+             INSERT INTO decks VALUES (10, 'Spanish', x'0a020805'), (20, 'French', x'0a020805');
+             INSERT INTO notes VALUES (1, 100, 0, 0, '', 'hola'), (2, 100, 0, 0, '', 'bonjour');
+             INSERT INTO cards VALUES (1, 1, 10, 0, 0, 0, 0, 0), (2, 2, 20, 0, 0, 0, 0, 0);",
+        ]);
+        // Spanish was deleted and recreated with a new id; French is gone.
+        let current = collection(&[
+            schema,
+            "INSERT INTO decks VALUES (11, 'Spanish', x'0a020801');",
+        ]);
+        let selection = RestoreSelection {
+            note_ids: vec![1, 2],
+            ..Default::default()
+        };
+        let (merged, report) = merge_into(&current, &backup, &selection).unwrap();
+        assert_eq!((report.cards_added, report.decks_added), (2, 1));
+        let (_tmp, conn) = open(&merged);
+        assert_eq!(ids(&conn, "SELECT did FROM cards").unwrap(), [11, 20]);
+        assert_eq!(ids(&conn, "SELECT id FROM decks").unwrap(), [1, 11, 20]);
+        assert_eq!(ids(&conn, "SELECT id FROM deck_config").unwrap(), [1, 5]);
+    }
+}
@@ -0,0 +1,286 @@
+//! Grandfather-father-son retention planning.
+//!
+//! The planner is pure: it takes the backup list from whichever
+//! [`MetadataStore`](crate::MetadataStore) is in use and decides which created
+//! backups survive. Every window keeps the *newest* backup in each calendar
+//! bucket (UTC), and the newest backup of every year is kept forever.
+//! Pinned backups and the backup the current pointer names are always kept
+//! and do not use up a bucket.
+
+use std::collections::HashSet;
+
+use anki_backup_core::{BackupEntry, BackupStatus};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Keep every backup younger than this many hours.
+    pub keep_all_hours: i64,
+    /// Keep one backup per day for this many days.
+    pub daily_days: i64,
+    /// Keep one backup per ISO week for this many weeks.
+    pub weekly_weeks: i64,
+    /// Keep one backup per month for this many months.
+    pub monthly_months: i64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            keep_all_hours: 24,
+            daily_days: 7,
+            weekly_weeks: 4,
+            monthly_months: 12,
+        }
+    }
+}
+
+/// Why a backup survives a retention pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionReason {
+    Pinned,
+    /// The backup `state/current-pointer.json` names.
+    Current,
+    Recent,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+impl RetentionReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RetentionReason::Pinned => "pinned",
+            RetentionReason::Current => "current",
+            RetentionReason::Recent => "recent",
+            RetentionReason::Daily => "daily",
+            RetentionReason::Weekly => "weekly",
+            RetentionReason::Monthly => "monthly",
+            RetentionReason::Yearly => "yearly",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetainedBackup {
+    pub backup: BackupEntry,
+    pub reasons: Vec<RetentionReason>,
+}
+
+/// Outcome of [`plan_retention`]: created backups to keep and to remove,
+/// each ordered newest first. Skipped entries are never part of a plan.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPlan {
+    pub keep: Vec<RetainedBackup>,
+    pub remove: Vec<BackupEntry>,
+}
+
+/// Decide which created backups `policy` keeps at `now`. `current` is the
+/// backup the current pointer names, if any.
+pub fn plan_retention(
+    policy: &RetentionPolicy,
+    backups: &[BackupEntry],
+    current: Option<Uuid>,
+    now: DateTime<Utc>,
+) -> RetentionPlan {
+    let mut created: Vec<&BackupEntry> = backups
+        .iter()
+        .filter(|b| b.status == BackupStatus::Created)
+        .collect();
+    created.sort_by_key(|b| std::cmp::Reverse(b.created_at));
+
+    let today = now.date_naive();
+    let mut days = HashSet::new();
+    let mut weeks = HashSet::new();
+    let mut months = HashSet::new();
+    let mut years = HashSet::new();
+    let mut plan = RetentionPlan::default();
+
+    for backup in created {
+        let exempt: Vec<_> = [
+            (backup.pinned, RetentionReason::Pinned),
+            (current == Some(backup.id), RetentionReason::Current),
+        ]
+        .into_iter()
+        .filter_map(|(applies, reason)| applies.then_some(reason))
+        .collect();
+        if !exempt.is_empty() {
+            plan.keep.push(RetainedBackup {
+                backup: backup.clone(),
+                reasons: exempt,
+            });
+            continue;
+        }
+
+        let date = backup.created_at.date_naive();
+        let mut reasons = Vec::new();
+
+        if now - backup.created_at < Duration::hours(policy.keep_all_hours) {
+            reasons.push(RetentionReason::Recent);
+        }
+        if (today - date).num_days() < policy.daily_days && days.insert(date) {
+            reasons.push(RetentionReason::Daily);
+        }
+        let week = week_start(date);
+        if (week_start(today) - week).num_days() / 7 < policy.weekly_weeks && weeks.insert(week) {
+            reasons.push(RetentionReason::Weekly);
+        }
+        let month = month_index(date);
+        if month_index(today) - month < policy.monthly_months && months.insert(month) {
+            reasons.push(RetentionReason::Monthly);
+        }
+        if years.insert(date.year()) {
+            reasons.push(RetentionReason::Yearly);
+        }
+
+        if reasons.is_empty() {
+            plan.remove.push(backup.clone());
+        } else {
+            plan.keep.push(RetainedBackup {
+                backup: backup.clone(),
+                reasons,
+            });
+        }
+    }
+    plan
+}
+
+/// Monday of the ISO week containing `date`.
+fn week_start(date: NaiveDate) -> NaiveDate {
+    date - Duration::days(date.weekday().num_days_from_monday().into())
+}
+
+fn month_index(date: NaiveDate) -> i64 {
+    i64::from(date.year()) * 12 + i64::from(date.month0())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use uuid::Uuid;
+
+    fn backup_at(created_at: DateTime<Utc>) -> BackupEntry {
+        BackupEntry {
+            id: Uuid::new_v4(),
+            created_at,
+            timestamp_dir: created_at.to_rfc3339(),
+            content_hash: String::new(),
+            status: BackupStatus::Created,
+            skip_reason: None,
+            source_revision: None,
+            sync_duration_ms: None,
+            size_bytes: 0,
+            stored_size_bytes: None,
+            stats: None,
+            pinned: false,
+            note: None,
+            verification: None,
+            media_set: None,
+        }
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 6, 15, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn keeps_everything_recent_and_newest_per_day() {
+        let now = now();
+        let backups: Vec<_> = (0..72)
+            .map(|h| backup_at(now - Duration::hours(h)))
+            .collect();
+        let plan = plan_retention(&RetentionPolicy::default(), &backups, None, now);
+
+        // 24 recent hours, plus the newest backup of each older day.
+        let recent = plan
+            .keep
+            .iter()
+            .filter(|k| k.reasons.contains(&RetentionReason::Recent))
+            .count();
+        assert_eq!(recent, 24);
+        assert_eq!(plan.keep.len(), 24 + 2);
+        assert_eq!(plan.keep.len() + plan.remove.len(), backups.len());
+        let oldest_kept = plan.keep.last().unwrap();
+        assert_eq!(
+            oldest_kept.backup.created_at,
+            Utc.with_ymd_and_hms(2026, 6, 12, 23, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn thins_long_history_to_gfs_buckets() {
+        let now = now();
+        let backups: Vec<_> = (0..(4 * 365 * 4))
+            .map(|i| backup_at(now - Duration::hours(6 * i)))
+            .collect();
+        let plan = plan_retention(&RetentionPolicy::default(), &backups, None, now);
+
+        let count = |reason| {
+            plan.keep
+                .iter()
+                .filter(|k| k.reasons.contains(&reason))
+                .count()
+        };
+        assert_eq!(count(RetentionReason::Recent), 4);
+        assert_eq!(count(RetentionReason::Daily), 7);
+        assert_eq!(count(RetentionReason::Weekly), 4);
+        assert_eq!(count(RetentionReason::Monthly), 12);
+        // 2022 (partial) through 2026.
+        assert_eq!(count(RetentionReason::Yearly), 5);
+        assert!(plan.keep.len() < 40);
+        assert!(plan
+            .remove
+            .iter()
+            .all(|b| now - b.created_at >= Duration::hours(24)));
+    }
+
+    #[test]
+    fn pinned_backups_are_always_kept() {
+        let now = now();
+        let mut pinned = backup_at(now - Duration::days(40));
+        pinned.pinned = true;
+        let unpinned = backup_at(now - Duration::days(40) - Duration::hours(1));
+        let newest = backup_at(now);
+        let plan = plan_retention(
+            &RetentionPolicy::default(),
+            &[pinned.clone(), unpinned.clone(), newest],
+            None,
+            now,
+        );
+        let kept = plan.keep.iter().find(|k| k.backup.id == pinned.id).unwrap();
+        assert_eq!(kept.reasons, [RetentionReason::Pinned]);
+        // The pin does not occupy the monthly bucket.
+        assert!(plan.keep.iter().any(|k| k.backup.id == unpinned.id));
+    }
+
+    #[test]
+    fn current_backup_is_always_kept() {
+        let now = now();
+        let old = backup_at(now - Duration::days(3 * 365));
+        let older = backup_at(now - Duration::days(3 * 365) - Duration::hours(1));
+        let newest = backup_at(now);
+        let backups = [old.clone(), older.clone(), newest];
+
+        let plan = plan_retention(&RetentionPolicy::default(), &backups, None, now);
+        assert!(plan.remove.iter().any(|b| b.id == older.id));
+
+        let plan = plan_retention(&RetentionPolicy::default(), &backups, Some(older.id), now);
+        let kept = plan.keep.iter().find(|k| k.backup.id == older.id).unwrap();
+        assert_eq!(kept.reasons, [RetentionReason::Current]);
+        // Like a pin, it leaves the yearly bucket to the newest backup.
+        assert!(plan.keep.iter().any(|k| k.backup.id == old.id));
+        assert!(plan.remove.is_empty());
+    }
+
+    #[test]
+    fn ignores_skipped_entries() {
+        let now = now();
+        let mut skipped = backup_at(now - Duration::days(400));
+        skipped.status = BackupStatus::Skipped;
+        let plan = plan_retention(&RetentionPolicy::default(), &[skipped], None, now);
+        assert!(plan.keep.is_empty() && plan.remove.is_empty());
+    }
+}
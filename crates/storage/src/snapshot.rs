@@ -0,0 +1,176 @@
+//! Loading the notes, cards, decks and note types of a stored collection for
+//! [`CollectionDiff`](anki_backup_core::CollectionDiff).
+
+use std::collections::HashMap;
+use std::fs;
+
+use anki_backup_core::{Card, CollectionSnapshot, Note, NoteType};
+use anyhow::{Context, Result};
+use rusqlite::{Connection, OpenFlags};
+use serde::Deserialize;
+
+use crate::deck::table_exists;
+use crate::repository::{parse_deck_names_legacy, parse_deck_names_new};
+
+/// Field separator in `notes.flds`, and deck name separator in schema 15+.
+const SEPARATOR: char = '\x1f';
+
+/// A note type in the legacy `col.models` JSON.
+#[derive(Deserialize)]
+struct LegacyNoteType {
+    name: String,
+    #[serde(default)]
+    flds: Vec<LegacyField>,
+}
+
+#[derive(Deserialize)]
+struct LegacyField {
+    name: String,
+    #[serde(default)]
+    ord: i64,
+}
+
+pub(crate) fn read_snapshot(collection: &[u8]) -> Result<CollectionSnapshot> {
+    let tmp = tempfile::NamedTempFile::new().context("create temp collection file")?;
+    fs::write(tmp.path(), collection).context("write temp collection file")?;
+    let conn = Connection::open_with_flags(tmp.path(), OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .context("open collection copy")?;
+
+    let mut snapshot = CollectionSnapshot::default();
+    let mut stmt = conn.prepare("SELECT id, mid, flds, tags FROM notes")?;
+    for row in stmt.query_map([], |r| {
+        Ok((
+            r.get::<_, i64>(0)?,
+            r.get::<_, i64>(1)?,
+            r.get::<_, String>(2)?,
+            r.get::<_, String>(3)?,
+        ))
+    })? {
+        let (id, note_type_id, fields, tags) = row?;
+        snapshot.notes.insert(
+            id,
+            Note {
+                note_type_id,
+                fields: fields.split(SEPARATOR).map(str::to_owned).collect(),
+                tags,
+            },
+        );
+    }
+
+    let mut stmt = conn.prepare("SELECT id, nid, did FROM cards")?;
+    for row in stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))? {
+        let (id, note_id, deck_id) = row?;
+        snapshot.cards.insert(id, Card { note_id, deck_id });
+    }
+
+    let mut stmt = conn.prepare("SELECT id, cid FROM revlog")?;
+    for row in stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))? {
+        let (id, card_id) = row?;
+        snapshot.reviews.insert(id, card_id);
+    }
+
+    // Schema 15+ keeps decks and note types in tables, older schemas as JSON
+    // in `col`.
+    if table_exists(&conn, "decks")? {
+        snapshot.decks = parse_deck_names_new(&conn)?
+            .into_iter()
+            .map(|(id, name)| (id, name.replace(SEPARATOR, "::")))
+            .collect();
+        let mut stmt = conn.prepare("SELECT id, name FROM notetypes")?;
+        for row in stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))? {
+            let (id, name) = row?;
+            snapshot.note_types.insert(
+                id,
+                NoteType {
+                    name,
+                    fields: Vec::new(),
+                },
+            );
+        }
+        let mut stmt = conn.prepare("SELECT ntid, name FROM fields ORDER BY ntid, ord")?;
+        for row in stmt.query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get(1)?)))? {
+            let (note_type_id, name) = row?;
+            if let Some(note_type) = snapshot.note_types.get_mut(&note_type_id) {
+                note_type.fields.push(name);
+            }
+        }
+    } else {
+        let (decks, models): (String, String) =
+            conn.query_row("SELECT decks, models FROM col LIMIT 1", [], |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })?;
+        snapshot.decks = parse_deck_names_legacy(&decks)?.into_iter().collect();
+        let models: HashMap<String, LegacyNoteType> =
+            serde_json::from_str(&models).context("parse col.models json")?;
+        for (id, mut model) in models {
+            let Ok(id) = id.parse() else { continue };
+            model.flds.sort_by_key(|f| f.ord);
+            snapshot.note_types.insert(
+                id,
+                NoteType {
+                    name: model.name,
+                    fields: model.flds.into_iter().map(|f| f.name).collect(),
+                },
+            );
+        }
+    }
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::LegacyCollection;
+
+    /// Note 1 with card 10 in deck 2, reviewed once.
+    fn notes_and_cards() -> LegacyCollection {
+        LegacyCollection::new()
+            .with_tagged_note(1, 100, &["hola", "hello"], " spanish ")
+            .with_card(10, 1, 2)
+            .with_review(1_700_000_000_000, 10)
+    }
+
+    #[test]
+    fn reads_legacy_collections() {
+        // Fields are listed out of order; `ord` decides.
+        let collection = notes_and_cards()
+            .with_deck(2, "Spanish::Basics")
+            .with_sql(
+                r#"UPDATE col SET models =
+                    '{"100":{"name":"Basic","flds":[{"name":"Back","ord":1},{"name":"Front","ord":0}]}}';"#,
+            )
+            .build();
+        let snapshot = read_snapshot(&collection).unwrap();
+        assert_eq!(snapshot.notes[&1].fields, ["hola", "hello"]);
+        assert_eq!(snapshot.notes[&1].tags, " spanish ");
+        assert_eq!(
+            snapshot.cards[&10],
+            Card {
+                note_id: 1,
+                deck_id: 2
+            }
+        );
+        assert_eq!(snapshot.reviews[&1_700_000_000_000], 10);
+        assert_eq!(snapshot.decks[&2], "Spanish::Basics");
+        assert_eq!(snapshot.note_types[&100].name, "Basic");
+        assert_eq!(snapshot.note_types[&100].fields, ["Front", "Back"]);
+    }
+
+    #[test]
+    fn reads_table_schemas() {
+        // A `decks` table takes precedence over the JSON in `col`.
+        let collection = notes_and_cards()
+            .with_sql(
+                "CREATE TABLE decks (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+                 CREATE TABLE notetypes (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+                 CREATE TABLE fields (ntid INTEGER NOT NULL, ord INTEGER NOT NULL, name TEXT NOT NULL);
+                 INSERT INTO decks VALUES (2, 'Spanish' || char(31) || 'Basics');
+                 INSERT INTO notetypes VALUES (100, 'Basic');
+                 INSERT INTO fields VALUES (100, 1, 'Back'), (100, 0, 'Front');",
+            )
+            .build();
+        let snapshot = read_snapshot(&collection).unwrap();
+        assert_eq!(snapshot.decks[&2], "Spanish::Basics");
+        assert_eq!(snapshot.note_types[&100].fields, ["Front", "Back"]);
+    }
+}
@@ -1,21 +1,37 @@
 use std::path::PathBuf;
+use std::sync::Arc;
 
-use anki_backup_core::{BackupEntry, BackupSkipReason, BackupStats, BackupStatus};
+use anki_backup_core::{
+    BackupEntry, BackupSkipReason, BackupStats, BackupStatus, BackupVerification, RollbackEvent,
+    RollbackUpload, RollbackVerification, UploadCheckStatus, VerificationStatus,
+};
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use rusqlite::{params, Connection, OptionalExtension};
 use uuid::Uuid;
 
-use crate::store::MetadataStore;
+use crate::migrations;
+use crate::store::{MetadataStore, DEFAULT_PROFILE};
+
+/// Columns read by [`row_to_entry`], in order.
+const BACKUP_COLUMNS: &str = "id, created_at, timestamp_dir, content_hash, status, skip_reason,
+    source_revision, sync_duration_ms, size_bytes, stats_json, stored_size_bytes, pinned, note,
+    verification_status, verified_at, verification_detail, media_set";
 
 /// SQLite-backed metadata store. Each method opens a fresh connection (matches original behaviour).
+#[derive(Clone)]
 pub struct SqliteStore {
     db_path: PathBuf,
+    /// Profile whose rows this store reads and writes.
+    profile: String,
 }
 
 impl SqliteStore {
     pub fn new(db_path: PathBuf) -> Result<Self> {
-        let store = Self { db_path };
+        let store = Self {
+            db_path,
+            profile: DEFAULT_PROFILE.to_owned(),
+        };
         store.init_db()?;
         Ok(store)
     }
@@ -25,26 +41,8 @@ impl SqliteStore {
     }
 
     fn init_db(&self) -> Result<()> {
-        let conn = self.connect()?;
-        conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS backups (
-                id TEXT PRIMARY KEY,
-                created_at TEXT NOT NULL,
-                timestamp_dir TEXT NOT NULL,
-                content_hash TEXT NOT NULL,
-                status TEXT NOT NULL,
-                skip_reason TEXT,
-                source_revision TEXT,
-                sync_duration_ms INTEGER,
-                size_bytes INTEGER NOT NULL DEFAULT 0,
-                stats_json TEXT
-            );
-            CREATE TABLE IF NOT EXISTS rollback_events (
-                id TEXT PRIMARY KEY,
-                backup_id TEXT NOT NULL,
-                created_at TEXT NOT NULL
-            );",
-        )?;
+        let mut conn = self.connect()?;
+        migrations::migrate_sqlite(&mut conn)?;
         Ok(())
     }
 }
@@ -54,12 +52,14 @@ impl MetadataStore for SqliteStore {
     async fn insert_entry(&self, entry: &BackupEntry) -> Result<()> {
         let entry = entry.clone();
         let db_path = self.db_path.clone();
+        let profile = self.profile.clone();
         tokio::task::spawn_blocking(move || {
             let conn = Connection::open(&db_path).context("open metadata db")?;
             conn.execute(
                 "INSERT INTO backups (id, created_at, timestamp_dir, content_hash, status, skip_reason,
-                 source_revision, sync_duration_ms, size_bytes, stats_json)
-                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                 source_revision, sync_duration_ms, size_bytes, stats_json, stored_size_bytes, pinned, note,
+                 verification_status, verified_at, verification_detail, media_set, profile)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
                 params![
                     entry.id.to_string(),
                     entry.created_at.to_rfc3339(),
@@ -70,7 +70,15 @@ impl MetadataStore for SqliteStore {
                     entry.source_revision,
                     entry.sync_duration_ms,
                     entry.size_bytes,
-                    entry.stats.as_ref().map(serde_json::to_string).transpose()?
+                    entry.stats.as_ref().map(serde_json::to_string).transpose()?,
+                    entry.stored_size_bytes,
+                    entry.pinned,
+                    entry.note,
+                    entry.verification.as_ref().map(|v| v.status.as_str()),
+                    entry.verification.as_ref().map(|v| v.checked_at.to_rfc3339()),
+                    entry.verification.as_ref().and_then(|v| v.detail.clone()),
+                    entry.media_set,
+                    profile
                 ],
             )?;
             Ok(())
@@ -80,44 +88,127 @@ impl MetadataStore for SqliteStore {
 
     async fn list_backups(&self) -> Result<Vec<BackupEntry>> {
         let db_path = self.db_path.clone();
+        let profile = self.profile.clone();
         tokio::task::spawn_blocking(move || {
             let conn = Connection::open(&db_path).context("open metadata db")?;
-            let mut stmt = conn.prepare(
-                "SELECT id, created_at, timestamp_dir, content_hash, status, skip_reason, source_revision,
-                 sync_duration_ms, size_bytes, stats_json
-                 FROM backups ORDER BY created_at DESC",
-            )?;
-            let rows = stmt.query_map([], row_to_entry)?;
-            rows.collect::<std::result::Result<Vec<_>, _>>().map_err(Into::into)
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {BACKUP_COLUMNS} FROM backups WHERE profile = ?1 ORDER BY created_at DESC"
+            ))?;
+            let rows = stmt.query_map([profile], row_to_entry)?;
+            rows.collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(Into::into)
         })
         .await?
     }
 
     async fn get_backup(&self, id: Uuid) -> Result<Option<BackupEntry>> {
         let db_path = self.db_path.clone();
+        let profile = self.profile.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = Connection::open(&db_path).context("open metadata db")?;
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {BACKUP_COLUMNS} FROM backups WHERE id = ?1 AND profile = ?2"
+            ))?;
+            let found = stmt
+                .query_row([id.to_string(), profile], row_to_entry)
+                .optional()?;
+            Ok(found)
+        })
+        .await?
+    }
+
+    async fn insert_rollback_event(&self, event: &RollbackEvent) -> Result<()> {
+        let event = event.clone();
+        let db_path = self.db_path.clone();
+        let profile = self.profile.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = Connection::open(&db_path).context("open metadata db")?;
+            conn.execute(
+                "INSERT INTO rollback_events (id, backup_id, created_at, profile,
+                 verification_status, verified_at, verification_detail, actor, upload_status)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    event.id.to_string(),
+                    event.backup_id.to_string(),
+                    event.created_at.to_rfc3339(),
+                    profile,
+                    event.verification.as_ref().map(|v| v.status.as_str()),
+                    event
+                        .verification
+                        .as_ref()
+                        .map(|v| v.checked_at.to_rfc3339()),
+                    event.verification.as_ref().and_then(|v| v.detail.clone()),
+                    event.actor,
+                    event.upload.map(RollbackUpload::as_str),
+                ],
+            )?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn list_rollback_events(&self) -> Result<Vec<RollbackEvent>> {
+        let db_path = self.db_path.clone();
+        let profile = self.profile.clone();
         tokio::task::spawn_blocking(move || {
             let conn = Connection::open(&db_path).context("open metadata db")?;
             let mut stmt = conn.prepare(
-                "SELECT id, created_at, timestamp_dir, content_hash, status, skip_reason, source_revision,
-                 sync_duration_ms, size_bytes, stats_json
-                 FROM backups WHERE id = ?1",
+                "SELECT id, backup_id, created_at, verification_status, verified_at,
+                 verification_detail, actor, upload_status FROM rollback_events WHERE profile = ?1
+                 ORDER BY created_at DESC",
             )?;
-            let found = stmt.query_row([id.to_string()], row_to_entry).optional()?;
-            Ok(found)
+            let rows = stmt.query_map([profile], |row| {
+                Ok(RollbackEvent {
+                    id: parse_uuid(row.get(0)?),
+                    backup_id: parse_uuid(row.get(1)?),
+                    created_at: parse_ts(row.get(2)?),
+                    actor: row.get(6)?,
+                    upload: row
+                        .get::<_, Option<String>>(7)?
+                        .as_deref()
+                        .and_then(RollbackUpload::parse),
+                    verification: match (
+                        row.get::<_, Option<String>>(3)?
+                            .as_deref()
+                            .and_then(UploadCheckStatus::parse),
+                        row.get::<_, Option<String>>(4)?,
+                    ) {
+                        (Some(status), Some(checked_at)) => Some(RollbackVerification {
+                            status,
+                            checked_at: parse_ts(checked_at),
+                            detail: row.get(5)?,
+                        }),
+                        _ => None,
+                    },
+                })
+            })?;
+            rows.collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(Into::into)
         })
         .await?
     }
 
-    async fn insert_rollback_event(&self, backup_id: Uuid) -> Result<()> {
+    async fn record_rollback_upload(
+        &self,
+        id: Uuid,
+        upload: RollbackUpload,
+        verification: Option<&RollbackVerification>,
+    ) -> Result<()> {
+        let verification = verification.cloned();
         let db_path = self.db_path.clone();
+        let profile = self.profile.clone();
         tokio::task::spawn_blocking(move || {
             let conn = Connection::open(&db_path).context("open metadata db")?;
             conn.execute(
-                "INSERT INTO rollback_events (id, backup_id, created_at) VALUES (?1, ?2, ?3)",
+                "UPDATE rollback_events SET upload_status = ?1, verification_status = ?2,
+                 verified_at = ?3, verification_detail = ?4 WHERE id = ?5 AND profile = ?6",
                 params![
-                    Uuid::new_v4().to_string(),
-                    backup_id.to_string(),
-                    Utc::now().to_rfc3339()
+                    upload.as_str(),
+                    verification.as_ref().map(|v| v.status.as_str()),
+                    verification.as_ref().map(|v| v.checked_at.to_rfc3339()),
+                    verification.and_then(|v| v.detail),
+                    id.to_string(),
+                    profile
                 ],
             )?;
             Ok(())
@@ -127,26 +218,112 @@ impl MetadataStore for SqliteStore {
 
     async fn last_created_hash(&self) -> Result<Option<String>> {
         let db_path = self.db_path.clone();
+        let profile = self.profile.clone();
         tokio::task::spawn_blocking(move || {
             let conn = Connection::open(&db_path).context("open metadata db")?;
             let mut stmt = conn.prepare(
-                "SELECT content_hash FROM backups WHERE status = 'created' ORDER BY created_at DESC LIMIT 1",
+                "SELECT content_hash FROM backups WHERE status = 'created' AND profile = ?1
+                 ORDER BY created_at DESC LIMIT 1",
             )?;
-            let hash = stmt.query_row([], |row| row.get::<_, String>(0)).optional()?;
+            let hash = stmt
+                .query_row([profile], |row| row.get::<_, String>(0))
+                .optional()?;
             Ok(hash)
         })
         .await?
     }
 
-    async fn prune_created_before(&self, cutoff: DateTime<Utc>) -> Result<Vec<(String, String)>> {
+    async fn set_stored_size(&self, id: Uuid, stored_size_bytes: i64) -> Result<()> {
         let db_path = self.db_path.clone();
+        let profile = self.profile.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = Connection::open(&db_path).context("open metadata db")?;
+            conn.execute(
+                "UPDATE backups SET stored_size_bytes = ?1 WHERE id = ?2 AND profile = ?3",
+                params![stored_size_bytes, id.to_string(), profile],
+            )?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn set_pinned(&self, id: Uuid, pinned: bool, note: Option<&str>) -> Result<()> {
+        let note = note.map(str::to_owned);
+        let db_path = self.db_path.clone();
+        let profile = self.profile.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = Connection::open(&db_path).context("open metadata db")?;
+            conn.execute(
+                "UPDATE backups SET pinned = ?1, note = ?2 WHERE id = ?3 AND profile = ?4",
+                params![pinned, note, id.to_string(), profile],
+            )?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn record_verification(&self, id: Uuid, verification: &BackupVerification) -> Result<()> {
+        let verification = verification.clone();
+        let db_path = self.db_path.clone();
+        let profile = self.profile.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = Connection::open(&db_path).context("open metadata db")?;
+            conn.execute(
+                "UPDATE backups SET verification_status = ?1, verified_at = ?2, verification_detail = ?3
+                 WHERE id = ?4 AND profile = ?5",
+                params![
+                    verification.status.as_str(),
+                    verification.checked_at.to_rfc3339(),
+                    verification.detail,
+                    id.to_string(),
+                    profile
+                ],
+            )?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn delete_backups(&self, ids: &[Uuid]) -> Result<Vec<Uuid>> {
+        let ids = ids.to_vec();
+        let db_path = self.db_path.clone();
+        let profile = self.profile.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = Connection::open(&db_path).context("open metadata db")?;
+            let tx = conn.transaction()?;
+            let mut deleted = Vec::new();
+            for id in ids {
+                let changed = tx.execute(
+                    "DELETE FROM backups WHERE id = ?1 AND profile = ?2 AND pinned = 0",
+                    [&id.to_string(), &profile],
+                )?;
+                if changed > 0 {
+                    deleted.push(id);
+                }
+            }
+            tx.commit()?;
+            Ok(deleted)
+        })
+        .await?
+    }
+
+    async fn prune_created_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        keep: Option<Uuid>,
+    ) -> Result<Vec<(String, String)>> {
+        let db_path = self.db_path.clone();
+        let profile = self.profile.clone();
+        let keep = keep.map(|id| id.to_string()).unwrap_or_default();
         tokio::task::spawn_blocking(move || {
             let conn = Connection::open(&db_path).context("open metadata db")?;
             let mut stmt = conn.prepare(
-                "SELECT id, timestamp_dir FROM backups WHERE status = 'created' AND created_at < ?1",
+                "SELECT id, timestamp_dir FROM backups
+                 WHERE status = 'created' AND pinned = 0 AND created_at < ?1 AND profile = ?2
+                 AND id != ?3",
             )?;
             let doomed = stmt
-                .query_map([cutoff.to_rfc3339()], |r| {
+                .query_map([cutoff.to_rfc3339(), profile, keep], |r| {
                     Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?))
                 })?
                 .collect::<std::result::Result<Vec<_>, _>>()?;
@@ -158,6 +335,28 @@ impl MetadataStore for SqliteStore {
         })
         .await?
     }
+
+    fn with_profile(&self, profile: &str) -> Arc<dyn MetadataStore> {
+        Arc::new(Self {
+            db_path: self.db_path.clone(),
+            profile: profile.to_owned(),
+        })
+    }
+
+    async fn list_profiles(&self) -> Result<Vec<String>> {
+        let db_path = self.db_path.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = Connection::open(&db_path).context("open metadata db")?;
+            let mut stmt = conn.prepare(
+                "SELECT profile FROM backups UNION SELECT profile FROM rollback_events
+                 ORDER BY profile",
+            )?;
+            let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+            rows.collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(Into::into)
+        })
+        .await?
+    }
 }
 
 fn row_to_entry(row: &rusqlite::Row) -> rusqlite::Result<BackupEntry> {
@@ -174,10 +373,27 @@ fn row_to_entry(row: &rusqlite::Row) -> rusqlite::Result<BackupEntry> {
         source_revision: row.get(6)?,
         sync_duration_ms: row.get(7)?,
         size_bytes: row.get(8)?,
+        stored_size_bytes: row.get(10)?,
         stats: stats_json
             .map(|raw| serde_json::from_str::<BackupStats>(&raw))
             .transpose()
             .map_err(to_sql_err)?,
+        pinned: row.get(11)?,
+        note: row.get(12)?,
+        verification: match (
+            row.get::<_, Option<String>>(13)?
+                .as_deref()
+                .and_then(VerificationStatus::parse),
+            row.get::<_, Option<String>>(14)?,
+        ) {
+            (Some(status), Some(checked_at)) => Some(BackupVerification {
+                status,
+                checked_at: parse_ts(checked_at),
+                detail: row.get(15)?,
+            }),
+            _ => None,
+        },
+        media_set: row.get(16)?,
     })
 }
 
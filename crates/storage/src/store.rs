@@ -1,9 +1,22 @@
-use anki_backup_core::BackupEntry;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anki_backup_core::{
+    BackupEntry, BackupVerification, RollbackEvent, RollbackHistoryEntry, RollbackUpload,
+    RollbackVerification,
+};
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
+/// Profile that stores start out scoped to, and that rows written before
+/// profiles existed belong to.
+pub const DEFAULT_PROFILE: &str = "default";
+
 /// Pure metadata-database operations, implemented by both SQLite and Postgres backends.
+///
+/// A store only sees the rows of one profile; [`MetadataStore::with_profile`]
+/// gives a view of another profile in the same database.
 #[async_trait::async_trait]
 pub trait MetadataStore: Send + Sync {
     /// Insert a fully-formed backup entry.
@@ -16,11 +29,65 @@ pub trait MetadataStore: Send + Sync {
     async fn get_backup(&self, id: Uuid) -> Result<Option<BackupEntry>>;
 
     /// Record a rollback event.
-    async fn insert_rollback_event(&self, backup_id: Uuid) -> Result<()>;
+    async fn insert_rollback_event(&self, event: &RollbackEvent) -> Result<()>;
+
+    /// List all rollback events ordered by created_at DESC.
+    async fn list_rollback_events(&self) -> Result<Vec<RollbackEvent>>;
+
+    /// Record how uploading a rollback went, and the check of the upload.
+    async fn record_rollback_upload(
+        &self,
+        id: Uuid,
+        upload: RollbackUpload,
+        verification: Option<&RollbackVerification>,
+    ) -> Result<()>;
+
+    /// Rollback events, newest first, each with the backup it targeted.
+    async fn list_rollback_history(&self) -> Result<Vec<RollbackHistoryEntry>> {
+        let backups: HashMap<Uuid, BackupEntry> = self
+            .list_backups()
+            .await?
+            .into_iter()
+            .map(|b| (b.id, b))
+            .collect();
+        Ok(self
+            .list_rollback_events()
+            .await?
+            .into_iter()
+            .map(|event| RollbackHistoryEntry {
+                backup: backups.get(&event.backup_id).cloned(),
+                event,
+            })
+            .collect())
+    }
 
     /// Hash of the most recent "created" backup.
     async fn last_created_hash(&self) -> Result<Option<String>>;
 
-    /// Return (id, timestamp_dir) of created backups older than `cutoff`, then delete them.
-    async fn prune_created_before(&self, cutoff: DateTime<Utc>) -> Result<Vec<(String, String)>>;
+    /// Update the at-rest size recorded for a backup (after recompression).
+    async fn set_stored_size(&self, id: Uuid, stored_size_bytes: i64) -> Result<()>;
+
+    /// Pin or unpin a backup and replace its note.
+    async fn set_pinned(&self, id: Uuid, pinned: bool, note: Option<&str>) -> Result<()>;
+
+    /// Record the outcome of an integrity check.
+    async fn record_verification(&self, id: Uuid, verification: &BackupVerification) -> Result<()>;
+
+    /// Delete the given backup rows and return the ids actually deleted.
+    /// Pinned rows are left in place.
+    async fn delete_backups(&self, ids: &[Uuid]) -> Result<Vec<Uuid>>;
+
+    /// Return (id, timestamp_dir) of unpinned created backups older than `cutoff`,
+    /// other than `keep`, then delete them.
+    async fn prune_created_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        keep: Option<Uuid>,
+    ) -> Result<Vec<(String, String)>>;
+
+    /// The same database, scoped to `profile`.
+    fn with_profile(&self, profile: &str) -> Arc<dyn MetadataStore>;
+
+    /// Every profile with at least one backup or rollback event, sorted.
+    async fn list_profiles(&self) -> Result<Vec<String>>;
 }
@@ -0,0 +1,201 @@
+//! Builders for the collections tests run against, shared with other crates'
+//! tests through the `test-support` feature.
+
+use std::fs;
+
+use rusqlite::{params, Connection};
+use serde_json::{json, Map, Value};
+
+/// The tables of a legacy-schema collection with every column the snapshot
+/// reader, deck export and merge-restore touch.
+const LEGACY_SCHEMA: &str = "
+    CREATE TABLE col (mod INTEGER NOT NULL, decks TEXT NOT NULL, models TEXT NOT NULL,
+                      dconf TEXT NOT NULL);
+    CREATE TABLE notes (id INTEGER PRIMARY KEY, mid INTEGER NOT NULL, mod INTEGER NOT NULL,
+                        usn INTEGER NOT NULL, tags TEXT NOT NULL, flds TEXT NOT NULL);
+    CREATE TABLE cards (id INTEGER PRIMARY KEY, nid INTEGER NOT NULL, did INTEGER NOT NULL,
+                        mod INTEGER NOT NULL, usn INTEGER NOT NULL, due INTEGER NOT NULL,
+                        odue INTEGER NOT NULL, odid INTEGER NOT NULL);
+    CREATE TABLE revlog (id INTEGER PRIMARY KEY, cid INTEGER NOT NULL);
+    CREATE TABLE graves (usn INTEGER, oid INTEGER, type INTEGER);";
+
+/// `due` of a card sitting in a filtered deck.
+const FILTERED_DUE: i64 = -100_000;
+
+/// A legacy-schema (pre-2.1.28) collection, with decks, note types and deck
+/// presets as JSON in `col`. Starts with the Default deck and preset (1).
+#[derive(Debug, Clone)]
+pub struct LegacyCollection {
+    decks: Map<String, Value>,
+    note_types: Map<String, Value>,
+    presets: Map<String, Value>,
+    /// `(id, note type, tags, fields)`.
+    notes: Vec<(i64, i64, String, String)>,
+    /// `(id, note, deck, due, home due, home deck)`.
+    cards: Vec<(i64, i64, i64, i64, i64, i64)>,
+    /// `(id, card)`.
+    reviews: Vec<(i64, i64)>,
+    /// `(usn, id, kind)`.
+    graves: Vec<(i64, i64, i64)>,
+    changes: Vec<String>,
+}
+
+impl Default for LegacyCollection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LegacyCollection {
+    pub fn new() -> Self {
+        let mut collection = Self {
+            decks: Map::new(),
+            note_types: Map::new(),
+            presets: Map::new(),
+            notes: Vec::new(),
+            cards: Vec::new(),
+            reviews: Vec::new(),
+            graves: Vec::new(),
+            changes: Vec::new(),
+        };
+        collection
+            .presets
+            .insert("1".to_owned(), json!({"name": "Default"}));
+        collection.with_deck(1, "Default")
+    }
+
+    /// A regular deck using the Default preset.
+    pub fn with_deck(self, id: i64, name: &str) -> Self {
+        self.with_deck_preset(id, name, 1)
+    }
+
+    pub fn with_deck_preset(mut self, id: i64, name: &str, preset: i64) -> Self {
+        self.decks
+            .insert(id.to_string(), json!({"name": name, "conf": preset}));
+        self
+    }
+
+    pub fn with_filtered_deck(mut self, id: i64, name: &str) -> Self {
+        self.decks
+            .insert(id.to_string(), json!({"name": name, "dyn": 1}));
+        self
+    }
+
+    pub fn with_preset(mut self, id: i64, name: &str) -> Self {
+        self.presets.insert(id.to_string(), json!({"name": name}));
+        self
+    }
+
+    /// A note type with `fields`, in order.
+    pub fn with_note_type(mut self, id: i64, name: &str, fields: &[&str]) -> Self {
+        let fields: Vec<_> = fields
+            .iter()
+            .enumerate()
+            .map(|(ord, name)| json!({"name": name, "ord": ord}))
+            .collect();
+        self.note_types
+            .insert(id.to_string(), json!({"name": name, "flds": fields}));
+        self
+    }
+
+    pub fn with_note(self, id: i64, note_type: i64, fields: &[&str]) -> Self {
+        self.with_tagged_note(id, note_type, fields, "")
+    }
+
+    /// A note with `tags` as stored, space-separated and padded.
+    pub fn with_tagged_note(
+        mut self,
+        id: i64,
+        note_type: i64,
+        fields: &[&str],
+        tags: &str,
+    ) -> Self {
+        self.notes
+            .push((id, note_type, tags.to_owned(), fields.join("\x1f")));
+        self
+    }
+
+    pub fn with_card(mut self, id: i64, note: i64, deck: i64) -> Self {
+        self.cards.push((id, note, deck, 0, 0, 0));
+        self
+    }
+
+    /// A card moved into filtered deck `deck` from `home_deck`, where it
+    /// was due at `home_due`.
+    pub fn with_filtered_card(
+        mut self,
+        id: i64,
+        note: i64,
+        deck: i64,
+        home_deck: i64,
+        home_due: i64,
+    ) -> Self {
+        self.cards
+            .push((id, note, deck, FILTERED_DUE, home_due, home_deck));
+        self
+    }
+
+    pub fn with_review(mut self, id: i64, card: i64) -> Self {
+        self.reviews.push((id, card));
+        self
+    }
+
+    /// A deletion record; `kind` is 0 for cards, 1 for notes and 2 for decks.
+    pub fn with_grave(mut self, usn: i64, id: i64, kind: i64) -> Self {
+        self.graves.push((usn, id, kind));
+        self
+    }
+
+    /// Run `sql` once everything else is in place, e.g. to model changes
+    /// made since an earlier copy.
+    pub fn with_sql(mut self, sql: &str) -> Self {
+        self.changes.push(sql.to_owned());
+        self
+    }
+
+    /// The collection file's bytes.
+    pub fn build(&self) -> Vec<u8> {
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        let conn = Connection::open(tmp.path()).unwrap();
+        conn.execute_batch(LEGACY_SCHEMA).unwrap();
+        conn.execute(
+            "INSERT INTO col VALUES (0, ?1, ?2, ?3)",
+            params![
+                Value::Object(self.decks.clone()).to_string(),
+                Value::Object(self.note_types.clone()).to_string(),
+                Value::Object(self.presets.clone()).to_string(),
+            ],
+        )
+        .unwrap();
+        for (id, note_type, tags, fields) in &self.notes {
+            conn.execute(
+                "INSERT INTO notes VALUES (?1, ?2, 0, 0, ?3, ?4)",
+                params![id, note_type, tags, fields],
+            )
+            .unwrap();
+        }
+        for (id, note, deck, due, home_due, home_deck) in &self.cards {
+            conn.execute(
+                "INSERT INTO cards VALUES (?1, ?2, ?3, 0, 0, ?4, ?5, ?6)",
+                params![id, note, deck, due, home_due, home_deck],
+            )
+            .unwrap();
+        }
+        for (id, card) in &self.reviews {
+            conn.execute("INSERT INTO revlog VALUES (?1, ?2)", params![id, card])
+                .unwrap();
+        }
+        for (usn, id, kind) in &self.graves {
+            conn.execute(
+                "INSERT INTO graves VALUES (?1, ?2, ?3)",
+                params![usn, id, kind],
+            )
+            .unwrap();
+        }
+        for sql in &self.changes {
+            conn.execute_batch(sql).unwrap();
+        }
+        drop(conn);
+        fs::read(tmp.path()).unwrap()
+    }
+}
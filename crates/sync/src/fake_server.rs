@@ -0,0 +1,406 @@
+//! In-process fake sync server for offline end-to-end tests.
+//!
+//! Implements the v11 endpoints this client uses — `hostKey`, `meta`,
+//! `download`, `upload` and the media `begin` / `mediaChanges` /
+//! `downloadFiles` — with zstd bodies and the `anki-sync` header. The served
+//! collection, session behaviour and injected faults can be changed while
+//! the server runs, and every request is recorded for assertions.
+//!
+//! Enabled by the `fake-server` feature; not meant for production builds.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{Cursor, Write};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::local::sha1_hex;
+use crate::{RetryPolicy, SyncConfig};
+
+/// Username accepted by the fake server.
+pub const FAKE_USERNAME: &str = "user@example.com";
+/// Password accepted by the fake server.
+pub const FAKE_PASSWORD: &str = "fake-password";
+
+/// A failure injected into the next request for a method.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Answer with this status and a plain-text body.
+    Status(u16),
+    /// Answer 429, optionally with `Retry-After` in seconds.
+    RateLimited { retry_after_secs: Option<u64> },
+    /// Answer 200 with a body that is not valid zstd.
+    Corrupt,
+}
+
+/// A request the server received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedRequest {
+    /// Method name, e.g. `meta` or `mediaChanges`.
+    pub method: String,
+    /// Full request path, including any shard prefix.
+    pub path: String,
+    pub sync_version: u8,
+    /// Short client version from the `anki-sync` header.
+    pub client_version: String,
+    pub host_key: String,
+}
+
+struct ServerState {
+    collection: Vec<u8>,
+    host_key: String,
+    host_key_generation: u32,
+    empty: bool,
+    refusal: Option<String>,
+    versions: RangeInclusive<u8>,
+    redirect: Option<String>,
+    /// Accept uploads without replacing the served collection.
+    discard_uploads: bool,
+    /// Name -> (usn, contents); `None` contents record a deletion.
+    media: BTreeMap<String, (i64, Option<Vec<u8>>)>,
+    faults: HashMap<String, VecDeque<Fault>>,
+    requests: Vec<RecordedRequest>,
+    uploads: Vec<Vec<u8>>,
+}
+
+/// A fake sync server listening on a random local port. Stops when dropped.
+pub struct FakeSyncServer {
+    endpoint: String,
+    state: Arc<Mutex<ServerState>>,
+    task: JoinHandle<()>,
+}
+
+impl FakeSyncServer {
+    /// Start serving `collection` on `127.0.0.1`.
+    pub async fn start(collection: Vec<u8>) -> Self {
+        let state = Arc::new(Mutex::new(ServerState {
+            collection,
+            host_key: host_key_for(1),
+            host_key_generation: 1,
+            empty: false,
+            refusal: None,
+            versions: crate::SYNC_VERSION_MIN..=crate::SYNC_VERSION_MAX,
+            redirect: None,
+            discard_uploads: false,
+            media: BTreeMap::new(),
+            faults: HashMap::new(),
+            requests: Vec::new(),
+            uploads: Vec::new(),
+        }));
+        let app = Router::new().fallback(handle).with_state(state.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind fake sync server");
+        let addr = listener.local_addr().expect("fake sync server address");
+        let task = tokio::spawn(async move {
+            axum::serve(listener, app).await.ok();
+        });
+        Self {
+            endpoint: format!("http://{addr}/"),
+            state,
+            task,
+        }
+    }
+
+    /// Base URL to use as [`SyncConfig::endpoint`].
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// A config that logs in to this server, with retries fast enough for
+    /// tests.
+    pub fn sync_config(&self) -> SyncConfig {
+        SyncConfig {
+            username: FAKE_USERNAME.to_owned(),
+            password: FAKE_PASSWORD.to_owned(),
+            endpoint: Some(self.endpoint.clone()),
+            retry: RetryPolicy {
+                budget: Duration::from_secs(2),
+                initial_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(50),
+            },
+            ..SyncConfig::default()
+        }
+    }
+
+    /// The host key `hostKey` currently hands out.
+    pub fn host_key(&self) -> String {
+        self.state().host_key.clone()
+    }
+
+    /// Invalidate every issued host key, as a password change would.
+    pub fn rotate_host_key(&self) {
+        let mut state = self.state();
+        state.host_key_generation += 1;
+        state.host_key = host_key_for(state.host_key_generation);
+    }
+
+    /// The collection `download` returns; replaced by every upload.
+    pub fn collection(&self) -> Vec<u8> {
+        self.state().collection.clone()
+    }
+
+    pub fn set_collection(&self, collection: Vec<u8>) {
+        self.state().collection = collection;
+    }
+
+    /// Accept and record uploads but keep serving the previous collection,
+    /// like a server that lost them.
+    pub fn discard_uploads(&self, discard: bool) {
+        self.state().discard_uploads = discard;
+    }
+
+    /// Report the collection as empty in `meta` (it is still served).
+    pub fn set_empty(&self, empty: bool) {
+        self.state().empty = empty;
+    }
+
+    /// Refuse sync sessions with `message`, or accept them again with `None`.
+    pub fn set_refusal(&self, message: Option<&str>) {
+        self.state().refusal = message.map(str::to_owned);
+    }
+
+    /// Protocol versions to accept; others get a 501.
+    pub fn set_versions(&self, versions: RangeInclusive<u8>) {
+        self.state().versions = versions;
+    }
+
+    /// Redirect `meta` requests that do not already carry a `/shard1` prefix
+    /// to `location`. The server answers on both paths.
+    pub fn redirect_meta(&self, location: Option<&str>) {
+        self.state().redirect = location.map(str::to_owned);
+    }
+
+    /// Add or replace a media file; returns its usn.
+    pub fn add_media(&self, name: &str, contents: &[u8]) -> i64 {
+        let mut state = self.state();
+        let usn = state.media_usn() + 1;
+        state
+            .media
+            .insert(name.to_owned(), (usn, Some(contents.to_vec())));
+        usn
+    }
+
+    /// Delete a media file; returns the usn of the deletion.
+    pub fn remove_media(&self, name: &str) -> i64 {
+        let mut state = self.state();
+        let usn = state.media_usn() + 1;
+        state.media.insert(name.to_owned(), (usn, None));
+        usn
+    }
+
+    /// Fail the next request for `method` (e.g. `download`) with `fault`.
+    /// Faults queued for the same method are used in order, one per request.
+    pub fn fail_next(&self, method: &str, fault: Fault) {
+        self.state()
+            .faults
+            .entry(method.to_owned())
+            .or_default()
+            .push_back(fault);
+    }
+
+    /// Every request received so far, oldest first.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state().requests.clone()
+    }
+
+    /// How many requests for `method` were received.
+    pub fn request_count(&self, method: &str) -> usize {
+        self.state()
+            .requests
+            .iter()
+            .filter(|r| r.method == method)
+            .count()
+    }
+
+    /// Bodies of every successful upload, oldest first.
+    pub fn uploads(&self) -> Vec<Vec<u8>> {
+        self.state().uploads.clone()
+    }
+
+    fn state(&self) -> MutexGuard<'_, ServerState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for FakeSyncServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl ServerState {
+    fn media_usn(&self) -> i64 {
+        self.media.values().map(|(usn, _)| *usn).max().unwrap_or(0)
+    }
+}
+
+fn host_key_for(generation: u32) -> String {
+    format!("fake-host-key-{generation}")
+}
+
+async fn handle(
+    State(state): State<Arc<Mutex<ServerState>>>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+    let header: Value = headers
+        .get("anki-sync")
+        .and_then(|v| serde_json::from_slice(v.as_bytes()).ok())
+        .unwrap_or_default();
+    let full_path = uri.path();
+    let path = full_path.strip_prefix("/shard1").unwrap_or(full_path);
+    let method = path.rsplit('/').next().unwrap_or_default().to_owned();
+    let version = header["v"].as_u64().unwrap_or_default() as u8;
+    state.requests.push(RecordedRequest {
+        method: method.clone(),
+        path: full_path.to_owned(),
+        sync_version: version,
+        client_version: header["c"].as_str().unwrap_or_default().to_owned(),
+        host_key: header["k"].as_str().unwrap_or_default().to_owned(),
+    });
+
+    if !state.versions.contains(&version) {
+        return (StatusCode::NOT_IMPLEMENTED, "unsupported sync version").into_response();
+    }
+    if path == full_path && path == "/sync/meta" {
+        if let Some(location) = &state.redirect {
+            return (
+                StatusCode::PERMANENT_REDIRECT,
+                [(header::LOCATION, location.clone())],
+            )
+                .into_response();
+        }
+    }
+    if let Some(fault) = state.faults.get_mut(&method).and_then(VecDeque::pop_front) {
+        return fault_response(fault);
+    }
+    let Ok(body) = zstd::decode_all(body.as_ref()) else {
+        return (StatusCode::BAD_REQUEST, "body is not zstd").into_response();
+    };
+    let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+    if path != "/sync/hostKey" && header["k"] != state.host_key.as_str() {
+        return (StatusCode::FORBIDDEN, "invalid host key").into_response();
+    }
+
+    match path {
+        "/sync/hostKey" => {
+            if request == json!({"u": FAKE_USERNAME, "p": FAKE_PASSWORD}) {
+                json_reply(&json!({ "key": state.host_key }), version)
+            } else {
+                (StatusCode::FORBIDDEN, "invalid credentials").into_response()
+            }
+        }
+        "/sync/meta" => json_reply(
+            &json!({
+            "mod": 0,
+            "scm": 0,
+            "usn": 0,
+            "ts": 0,
+            "hostNum": 0,
+            "msg": state.refusal.clone().unwrap_or_default(),
+            "cont": state.refusal.is_none(),
+            "empty": state.empty,
+            }),
+            version,
+        ),
+        "/sync/download" => reply(&state.collection, version),
+        "/sync/upload" => {
+            if !state.discard_uploads {
+                state.collection = body.clone();
+            }
+            state.uploads.push(body);
+            reply(b"OK", version)
+        }
+        "/msync/begin" => json_reply(
+            &json!({
+            "data": {"sk": "fake-media-session", "usn": state.media_usn()},
+            "err": "",
+            }),
+            version,
+        ),
+        "/msync/mediaChanges" => {
+            let last = request["lastUsn"].as_i64().unwrap_or_default();
+            let changes: Vec<Value> = state
+                .media
+                .iter()
+                .filter(|(_, (usn, _))| *usn > last)
+                .map(|(name, (usn, contents))| {
+                    let sha1 = contents.as_deref().map(sha1_hex).unwrap_or_default();
+                    json!([name, usn, sha1])
+                })
+                .collect();
+            json_reply(&json!({"data": changes, "err": ""}), version)
+        }
+        "/msync/downloadFiles" => match media_zip(&state, &request["files"]) {
+            Ok(zip) => reply(&zip, version),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+        },
+        _ => (StatusCode::NOT_FOUND, "no such method").into_response(),
+    }
+}
+
+fn fault_response(fault: Fault) -> Response {
+    match fault {
+        Fault::Status(code) => {
+            let status = StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            (status, "injected fault").into_response()
+        }
+        Fault::RateLimited { retry_after_secs } => {
+            let mut resp = (StatusCode::TOO_MANY_REQUESTS, "slow down").into_response();
+            if let Some(secs) = retry_after_secs {
+                resp.headers_mut()
+                    .insert(header::RETRY_AFTER, secs.to_string().parse().unwrap());
+            }
+            resp
+        }
+        // zstd magic followed by junk, so the client tries and fails to decode it.
+        Fault::Corrupt => vec![0x28, 0xb5, 0x2f, 0xfd, 0xde, 0xad].into_response(),
+    }
+}
+
+fn media_zip(state: &ServerState, files: &Value) -> Result<Vec<u8>, String> {
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options = zip::write::SimpleFileOptions::default();
+    let mut meta = serde_json::Map::new();
+    let names = files.as_array().map(Vec::as_slice).unwrap_or_default();
+    for (i, name) in names.iter().filter_map(Value::as_str).enumerate() {
+        let Some((_, Some(contents))) = state.media.get(name) else {
+            continue;
+        };
+        zip.start_file(i.to_string(), options)
+            .and_then(|_| Ok(zip.write_all(contents)?))
+            .map_err(|e| e.to_string())?;
+        meta.insert(i.to_string(), json!(name));
+    }
+    zip.start_file("_meta", options)
+        .and_then(|_| Ok(zip.write_all(Value::Object(meta).to_string().as_bytes())?))
+        .and_then(|_| zip.finish())
+        .map(|cursor| cursor.into_inner())
+        .map_err(|e| e.to_string())
+}
+
+/// Replies are zstd-compressed from protocol v11 on, and plain before.
+fn reply(data: &[u8], version: u8) -> Response {
+    if version < 11 {
+        return data.to_vec().into_response();
+    }
+    match zstd::encode_all(data, 0) {
+        Ok(body) => body.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+fn json_reply(value: &Value, version: u8) -> Response {
+    reply(value.to_string().as_bytes(), version)
+}
@@ -1,34 +1,108 @@
 //! Direct AnkiWeb sync client.
 //!
 //! Implements the minimal subset of Anki's sync protocol needed to
-//! authenticate and download a full collection backup from AnkiWeb.
-//! No external commands required.
+//! authenticate and download a full collection backup from AnkiWeb or a
+//! self-hosted `anki-sync-server` (or anything speaking the same protocol).
+//! No external commands required. Collections can also be snapshotted from a
+//! local Anki profile instead (see [`LocalProfile`]).
 
 use std::io::{Cursor, Read, Write};
-use std::time::Instant;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-/// Sync protocol version. We use v11 (direct post with zstd, Jan 2023+).
-const SYNC_VERSION: u8 = 11;
+#[cfg(feature = "fake-server")]
+pub mod fake_server;
+mod local;
+mod media;
+mod retry;
+
+pub use local::{LocalMediaFile, LocalProfile};
+pub use media::{MediaChange, MediaSyncClient};
+pub use retry::RetryPolicy;
+
+/// Oldest sync protocol version we fall back to. Servers also accept v10
+/// requests sent as a direct POST with the `anki-sync` header, but answer
+/// them without zstd, which [`post_once`] handles.
+pub const SYNC_VERSION_MIN: u8 = 10;
+
+/// Newest sync protocol version we speak; tried first.
+pub const SYNC_VERSION_MAX: u8 = 11;
 
 /// Default AnkiWeb sync endpoint.
 const DEFAULT_ENDPOINT: &str = "https://sync.ankiweb.net/";
 
-/// Client version for the SyncHeader `c` field (short form).
-const CLIENT_VERSION_SHORT: &str = "25.09.2,dev,linux";
+/// Anki release we identify as unless configured otherwise.
+const DEFAULT_ANKI_VERSION: &str = "25.09.2";
 
-/// Client version for request bodies like MetaRequest `cv` field (long form).
-const CLIENT_VERSION_LONG: &str = "anki,25.09.2 (dev),linux";
+/// Redirects followed for a single request before giving up.
+const MAX_REDIRECTS: usize = 3;
 
-#[derive(Debug, Clone)]
+/// How the client identifies itself to the sync server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientVersion {
+    /// Sent in the `anki-sync` header's `c` field, e.g. `25.09.2,dev,linux`.
+    pub short: String,
+    /// Sent as `cv` in `meta` and as `v` in media `begin`, e.g.
+    /// `anki,25.09.2 (dev),linux`.
+    pub long: String,
+}
+
+impl ClientVersion {
+    /// Identify as the given Anki desktop release, in the formats Anki uses.
+    pub fn anki(version: &str) -> Self {
+        Self {
+            short: format!("{version},dev,linux"),
+            long: format!("anki,{version} (dev),linux"),
+        }
+    }
+}
+
+impl Default for ClientVersion {
+    fn default() -> Self {
+        Self::anki(DEFAULT_ANKI_VERSION)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct SyncConfig {
     pub username: String,
     pub password: String,
-    /// Override the sync endpoint (default: AnkiWeb).
+    /// Override the sync endpoint (default: AnkiWeb), e.g. the base URL of
+    /// a self-hosted `anki-sync-server`.
     pub endpoint: Option<String>,
+    /// Host key from an earlier login. When set, no login is made and the
+    /// password is not needed.
+    pub host_key: Option<String>,
+    /// Retrying of transient request failures.
+    pub retry: RetryPolicy,
+    pub client_version: ClientVersion,
+    /// Pin the sync protocol version instead of negotiating it.
+    pub sync_version: Option<u8>,
+}
+
+/// Where a backup's collection (and media) comes from.
+#[derive(Debug, Clone)]
+pub enum CollectionSource {
+    /// Download the collection from AnkiWeb or a self-hosted sync server.
+    Sync(SyncConfig),
+    /// Snapshot an Anki profile directory on this machine.
+    Local(LocalProfile),
+}
+
+impl From<SyncConfig> for CollectionSource {
+    fn from(config: SyncConfig) -> Self {
+        Self::Sync(config)
+    }
+}
+
+impl From<LocalProfile> for CollectionSource {
+    fn from(profile: LocalProfile) -> Self {
+        Self::Local(profile)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,12 +114,103 @@ pub struct SyncResult {
 
 #[derive(Debug, Error)]
 pub enum SyncError {
-    #[error("ankiweb credentials are missing")]
+    #[error("sync credentials are missing")]
     MissingCredentials,
-    #[error("ankiweb login failed: {0}")]
-    LoginFailed(String),
-    #[error("ankiweb download failed: {0}")]
-    DownloadFailed(String),
+    /// The server rejected the credentials or host key.
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    #[error("rate limited by sync server")]
+    RateLimited { retry_after: Option<Duration> },
+    /// The server answered with a 5xx status.
+    #[error("sync server error ({status}): {message}")]
+    Server { status: u16, message: String },
+    /// The request never got an answer (connect failure, timeout, reset).
+    #[error("network error: {0}")]
+    Network(String),
+    /// The server answered with something we do not understand or asked us
+    /// to do something we cannot.
+    #[error("sync protocol error: {0}")]
+    Protocol(String),
+    /// The server does not accept our protocol or client version.
+    #[error("sync server rejected protocol v{version}: {message}")]
+    UnsupportedVersion { version: u8, message: String },
+}
+
+impl SyncError {
+    /// Whether retrying the same request later may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::RateLimited { .. } | Self::Server { .. } | Self::Network(_)
+        )
+    }
+
+    /// Minimum wait the server asked for, if any.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Short machine-readable name of the failure class.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::MissingCredentials => "missing_credentials",
+            Self::Auth(_) => "auth",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::Server { .. } => "server",
+            Self::Network(_) => "network",
+            Self::Protocol(_) => "protocol",
+            Self::UnsupportedVersion { .. } => "unsupported_version",
+        }
+    }
+
+    /// The classified sync failure behind `err`, looking through any context
+    /// added on the way up.
+    pub fn classify(err: &anyhow::Error) -> Option<&SyncError> {
+        err.chain().find_map(|e| e.downcast_ref::<SyncError>())
+    }
+
+    fn from_status(
+        status: reqwest::StatusCode,
+        retry_after: Option<Duration>,
+        version: u8,
+        body: String,
+    ) -> Self {
+        match status.as_u16() {
+            401 | 403 => Self::Auth(format!("{status}: {body}")),
+            // 426 Upgrade Required from AnkiWeb, 501 from anki-sync-server;
+            // any other 501 is an endpoint the server lacks.
+            426 => Self::UnsupportedVersion {
+                version,
+                message: format!("{status}: {body}"),
+            },
+            501 if body.to_ascii_lowercase().contains("version") => Self::UnsupportedVersion {
+                version,
+                message: format!("{status}: {body}"),
+            },
+            501 => Self::Protocol(format!("unexpected status {status}: {body}")),
+            429 => Self::RateLimited { retry_after },
+            code if status.is_server_error() => Self::Server {
+                status: code,
+                message: body,
+            },
+            _ => Self::Protocol(format!("unexpected status {status}: {body}")),
+        }
+    }
+}
+
+/// `Retry-After` in its delay-seconds form; HTTP dates are ignored.
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
 }
 
 /// The `anki-sync` request header, matching upstream's SyncHeader format.
@@ -73,7 +238,7 @@ struct HostKeyRequest {
     password: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize)]
 struct HostKeyResponse {
     key: String,
 }
@@ -119,81 +284,233 @@ struct SyncRequestResult {
     new_endpoint: Option<String>,
 }
 
+/// HTTP client shared by every request of one sync operation, together with
+/// the retry policy and the negotiated protocol version.
+struct Http {
+    client: reqwest::Client,
+    retry: RetryPolicy,
+    client_version: ClientVersion,
+    sync_version: AtomicU8,
+    /// Whether `sync_version` was configured rather than negotiated.
+    version_pinned: bool,
+}
+
+impl Http {
+    fn new(config: &SyncConfig) -> Result<Self> {
+        if let Some(version) = config.sync_version {
+            if !(SYNC_VERSION_MIN..=SYNC_VERSION_MAX).contains(&version) {
+                return Err(SyncError::UnsupportedVersion {
+                    version,
+                    message: format!(
+                        "this client speaks v{SYNC_VERSION_MIN} to v{SYNC_VERSION_MAX}"
+                    ),
+                }
+                .into());
+            }
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(300))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        Ok(Self {
+            client,
+            retry: config.retry.clone(),
+            client_version: config.client_version.clone(),
+            sync_version: AtomicU8::new(config.sync_version.unwrap_or(SYNC_VERSION_MAX)),
+            version_pinned: config.sync_version.is_some(),
+        })
+    }
+
+    fn sync_version(&self) -> u8 {
+        self.sync_version.load(Ordering::Relaxed)
+    }
+
+    /// Fall back to the next older protocol version after the server
+    /// rejected the current one. Returns false when there is none to try.
+    fn downgrade(&self) -> bool {
+        let current = self.sync_version();
+        if self.version_pinned || current <= SYNC_VERSION_MIN {
+            return false;
+        }
+        self.sync_version.store(current - 1, Ordering::Relaxed);
+        tracing::info!(
+            from = current,
+            to = current - 1,
+            "sync server rejected protocol version; falling back"
+        );
+        true
+    }
+
+    fn meta_request(&self) -> MetaRequest {
+        MetaRequest {
+            sync_version: self.sync_version(),
+            client_version: self.client_version.long.clone(),
+        }
+    }
+}
+
 /// Make a sync request to a given method endpoint.
 async fn sync_request(
-    client: &reqwest::Client,
+    http: &Http,
     endpoint: &str,
     method: &str,
     hkey: &str,
     session_key: &str,
     body: &[u8],
 ) -> Result<SyncRequestResult> {
-    let url = format!("{}/sync/{}", endpoint.trim_end_matches('/'), method);
-    tracing::debug!(%url, %method, "sync request");
-
-    let header = SyncHeader {
-        sync_version: SYNC_VERSION,
-        sync_key: hkey.to_string(),
-        client_ver: CLIENT_VERSION_SHORT.to_string(),
-        session_key: session_key.to_string(),
+    post(http, endpoint, "sync", method, hkey, session_key, body).await
+}
+
+/// POST a zstd-framed request to `<endpoint>/<service>/<method>`, where
+/// `service` is `sync` for collection sync and `msync` for media sync.
+///
+/// Transient failures are retried according to the client's [`RetryPolicy`],
+/// and a rejected protocol version is retried with an older one; the error
+/// returned after the last attempt is a [`SyncError`].
+async fn post(
+    http: &Http,
+    endpoint: &str,
+    service: &str,
+    method: &str,
+    hkey: &str,
+    session_key: &str,
+    body: &[u8],
+) -> Result<SyncRequestResult> {
+    let request = PreparedRequest {
+        base: endpoint.trim_end_matches('/').to_string(),
+        path: format!("{service}/{method}"),
+        body: zstd_compress(body)?,
     };
+    tracing::debug!(url = %request.url(&request.base), %method, body_len = body.len(), compressed_len = request.body.len(), "sync request");
+
+    let started = Instant::now();
+    let mut attempt = 0;
+    loop {
+        let header = SyncHeader {
+            sync_version: http.sync_version(),
+            sync_key: hkey.to_string(),
+            client_ver: http.client_version.short.clone(),
+            session_key: session_key.to_string(),
+        };
+        let header_json = serde_json::to_string(&header)?;
+        let err = match post_once(&http.client, &request, &header_json, header.sync_version).await {
+            Ok(result) => return Ok(result),
+            Err(err) => err,
+        };
+        if matches!(err, SyncError::UnsupportedVersion { .. }) && http.downgrade() {
+            continue;
+        }
+        let Some(delay) = http.retry.next_delay(attempt, started.elapsed(), &err) else {
+            let context = format!("{method} request failed");
+            return Err(anyhow::Error::new(err).context(context));
+        };
+        attempt += 1;
+        tracing::warn!(
+            %method,
+            attempt,
+            kind = err.kind(),
+            error = %err,
+            delay_ms = delay.as_millis() as u64,
+            "transient sync failure; retrying"
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
 
-    let compressed_body = zstd_compress(body)?;
-    let header_json = serde_json::to_string(&header)?;
-    tracing::debug!(%header_json, body_len = body.len(), compressed_len = compressed_body.len(), "request details");
+/// A request body ready to be (re)sent.
+struct PreparedRequest {
+    base: String,
+    /// `<service>/<method>`, appended to the base URL.
+    path: String,
+    body: Vec<u8>,
+}
 
-    let resp = client
-        .post(&url)
-        .header("anki-sync", &header_json)
-        .header("content-type", "application/octet-stream")
-        .body(compressed_body.clone())
-        .send()
-        .await
-        .with_context(|| format!("POST {url}"))?;
-
-    // Handle redirects manually (reqwest converts POST→GET on redirect).
-    // AnkiWeb redirects to a shard like sync32.ankiweb.net — the Location
-    // header is the new *base* URL, so we re-derive the full method URL.
-    tracing::info!(status = %resp.status(), "initial response status");
-    let (resp, new_endpoint) = if resp.status().is_redirection() {
-        tracing::info!(status = %resp.status(), headers = ?resp.headers(), "got redirect response");
-        if let Some(location) = resp.headers().get("location").and_then(|v| v.to_str().ok()) {
-            let new_base = location.trim_end_matches('/').to_string();
-            let redirect_url = format!("{}/sync/{}", new_base, method);
-            let header_json2 = serde_json::to_string(&header)?;
-            tracing::info!(%redirect_url, %header_json2, compressed_len = compressed_body.len(), "following redirect to shard");
-            let resp = client
-                .post(&redirect_url)
-                .header("anki-sync", &header_json2)
-                .header("content-type", "application/octet-stream")
-                .body(compressed_body)
-                .send()
-                .await
-                .with_context(|| format!("POST {redirect_url} (redirect)"))?;
-            (resp, Some(new_base))
-        } else {
-            (resp, None)
+impl PreparedRequest {
+    fn url(&self, base: &str) -> String {
+        format!("{base}/{}", self.path)
+    }
+
+    async fn send(
+        &self,
+        client: &reqwest::Client,
+        base: &str,
+        header_json: &str,
+    ) -> Result<reqwest::Response, SyncError> {
+        let url = self.url(base);
+        client
+            .post(&url)
+            .header("anki-sync", header_json)
+            .header("content-type", "application/octet-stream")
+            .body(self.body.clone())
+            .send()
+            .await
+            .map_err(|e| SyncError::Network(format!("POST {url}: {e}")))
+    }
+}
+
+/// One attempt at a request, following redirects.
+///
+/// Redirects are followed manually because reqwest turns a redirected POST
+/// into a GET. AnkiWeb answers with the new shard's *base* URL (e.g.
+/// `https://sync32.ankiweb.net/`), while other servers and proxies may send
+/// the full method URL or a relative one; either way the new base is kept
+/// for the rest of the session.
+async fn post_once(
+    client: &reqwest::Client,
+    request: &PreparedRequest,
+    header_json: &str,
+    sync_version: u8,
+) -> Result<SyncRequestResult, SyncError> {
+    let mut base = request.base.clone();
+    let mut new_endpoint = None;
+    let mut redirects = 0;
+    let resp = loop {
+        let resp = request.send(client, &base, header_json).await?;
+        if !resp.status().is_redirection() {
+            break resp;
         }
-    } else {
-        (resp, None)
+        let location = resp
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| {
+                SyncError::Protocol(format!("{} redirect without a Location", resp.status()))
+            })?;
+        redirects += 1;
+        if redirects > MAX_REDIRECTS {
+            return Err(SyncError::Protocol(format!(
+                "more than {MAX_REDIRECTS} redirects for {}",
+                request.path
+            )));
+        }
+        base = redirect_base(&request.url(&base), location, &request.path)?;
+        tracing::info!(status = %resp.status(), new_base = %base, "following redirect");
+        new_endpoint = Some(base.clone());
     };
 
     if !resp.status().is_success() {
         let status = resp.status();
+        let retry_after = parse_retry_after(resp.headers());
         let headers = format!("{:?}", resp.headers());
         let body = resp.text().await.unwrap_or_default();
         tracing::error!(%status, %headers, body_len = body.len(), "sync request failed");
-        return Err(anyhow!(
-            "sync request to {method} failed ({status}): {body}"
+        return Err(SyncError::from_status(
+            status,
+            retry_after,
+            sync_version,
+            body,
         ));
     }
 
-    let resp_bytes = resp.bytes().await?;
+    let resp_bytes = resp
+        .bytes()
+        .await
+        .map_err(|e| SyncError::Network(format!("reading response: {e}")))?;
     // Response may be raw (for downloads) or zstd-compressed
     let data = if resp_bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
         // zstd magic number detected
         zstd_decompress(&resp_bytes)
-            .with_context(|| format!("decompressing response from {method}"))?
+            .map_err(|e| SyncError::Protocol(format!("decompressing response: {e}")))?
     } else {
         resp_bytes.to_vec()
     };
@@ -201,9 +518,24 @@ async fn sync_request(
     Ok(SyncRequestResult { data, new_endpoint })
 }
 
-/// Login to AnkiWeb and obtain a host key (auth token).
+/// Base URL to use after a redirect from `url` to `location`.
+///
+/// `location` may be absolute or relative, and may point at the new base or
+/// at the same `path` under it.
+fn redirect_base(url: &str, location: &str, path: &str) -> Result<String, SyncError> {
+    let target = reqwest::Url::parse(url)
+        .and_then(|u| u.join(location))
+        .map_err(|e| SyncError::Protocol(format!("bad redirect location {location:?}: {e}")))?;
+    let target = target.as_str().trim_end_matches('/');
+    Ok(target
+        .strip_suffix(&format!("/{path}"))
+        .unwrap_or(target)
+        .to_string())
+}
+
+/// Log in to the sync server and obtain a host key (auth token).
 async fn login(
-    client: &reqwest::Client,
+    http: &Http,
     endpoint: &str,
     username: &str,
     password: &str,
@@ -215,17 +547,45 @@ async fn login(
     };
     let body = serde_json::to_vec(&req)?;
 
-    let result = sync_request(client, endpoint, "hostKey", "", &session_key, &body)
+    let result = sync_request(http, endpoint, "hostKey", "", &session_key, &body)
         .await
-        .map_err(|e| SyncError::LoginFailed(e.to_string()))?;
+        .context("sync login failed")?;
 
-    let resp: HostKeyResponse =
-        serde_json::from_slice(&result.data).with_context(|| "parsing hostKey response")?;
+    let resp: HostKeyResponse = serde_json::from_slice(&result.data)
+        .map_err(|e| SyncError::Protocol(format!("parsing hostKey response: {e}")))?;
 
-    tracing::info!(?resp, "AnkiWeb login successful");
+    tracing::info!("sync login successful");
     Ok((resp.key, session_key))
 }
 
+/// Host key and session key for a sync operation: the configured host key
+/// if there is one, otherwise a fresh login.
+async fn authenticate(
+    http: &Http,
+    endpoint: &str,
+    config: &SyncConfig,
+) -> Result<(String, String)> {
+    if let Some(host_key) = config.host_key.as_deref().filter(|k| !k.is_empty()) {
+        return Ok((host_key.to_owned(), simple_session_id()));
+    }
+    if config.username.is_empty() || config.password.is_empty() {
+        return Err(SyncError::MissingCredentials.into());
+    }
+    login(http, endpoint, &config.username, &config.password).await
+}
+
+/// Log in with the configured username and password and return the host
+/// key, for callers that cache it and pass it back in [`SyncConfig::host_key`].
+pub async fn fetch_host_key(config: &SyncConfig) -> Result<String> {
+    if config.username.is_empty() || config.password.is_empty() {
+        return Err(SyncError::MissingCredentials.into());
+    }
+    let endpoint = config.endpoint.as_deref().unwrap_or(DEFAULT_ENDPOINT);
+    let http = Http::new(config)?;
+    let (host_key, _) = login(&http, endpoint, &config.username, &config.password).await?;
+    Ok(host_key)
+}
+
 /// Meta request sent to the server to negotiate sync state.
 #[derive(Serialize)]
 struct MetaRequest {
@@ -247,8 +607,7 @@ struct MetaResponse {
     #[serde(default)]
     empty: bool,
     /// Whether the client should continue (T&C accepted, etc.)
-    #[serde(default = "default_true")]
-    #[allow(dead_code)]
+    #[serde(rename = "cont", default = "default_true")]
     should_continue: bool,
 }
 
@@ -256,72 +615,79 @@ fn default_true() -> bool {
     true
 }
 
-/// Download the full collection from AnkiWeb.
+/// Call `meta` to open a sync session. Returns the server's answer and the
+/// endpoint to use from now on, which differs from `endpoint` when the
+/// server redirected us to a shard.
+async fn open_session(
+    http: &Http,
+    endpoint: &str,
+    hkey: &str,
+    session_key: &str,
+) -> Result<(MetaResponse, String)> {
+    let meta_body = serde_json::to_vec(&http.meta_request())?;
+    let meta_result = sync_request(http, endpoint, "meta", hkey, session_key, &meta_body).await?;
+    let endpoint = meta_result
+        .new_endpoint
+        .unwrap_or_else(|| endpoint.to_owned());
+
+    let meta: MetaResponse = serde_json::from_slice(&meta_result.data)
+        .map_err(|e| SyncError::Protocol(format!("parsing meta response: {e}")))?;
+    if !meta.server_message.is_empty() {
+        tracing::info!(message = %meta.server_message, "sync server message");
+    }
+    if !meta.should_continue {
+        return Err(SyncError::Protocol(format!(
+            "server refused to sync: {}",
+            meta.server_message
+        ))
+        .into());
+    }
+    Ok((meta, endpoint))
+}
+
+/// Download the full collection from the sync server.
 ///
 /// Protocol flow:
-/// 1. Authenticate with username/password → host key
+/// 1. Authenticate with username/password → host key (skipped when one is configured)
 /// 2. Call `meta` to initiate sync session
 /// 3. Call `download` to get the complete collection database
 pub async fn sync_collection(config: &SyncConfig) -> Result<SyncResult> {
     let start = Instant::now();
 
-    if config.username.is_empty() || config.password.is_empty() {
-        return Err(SyncError::MissingCredentials.into());
-    }
-
     let endpoint = config.endpoint.as_deref().unwrap_or(DEFAULT_ENDPOINT);
 
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(300))
-        .redirect(reqwest::redirect::Policy::none())
-        .build()?;
+    let http = Http::new(config)?;
 
-    // Step 1: Login
-    let (hkey, session_key) = login(&client, endpoint, &config.username, &config.password).await?;
+    // Step 1: Login (or reuse the configured host key)
+    let (hkey, session_key) = authenticate(&http, endpoint, config).await?;
 
     // Step 2: Meta (required before download to establish sync session)
     // The server may redirect us to a shard; use the new endpoint for download.
-    let meta_req = MetaRequest {
-        sync_version: SYNC_VERSION,
-        client_version: CLIENT_VERSION_LONG.to_string(),
-    };
-    let meta_body = serde_json::to_vec(&meta_req)?;
-    let meta_result = sync_request(&client, endpoint, "meta", &hkey, &session_key, &meta_body)
-        .await
-        .with_context(|| "meta request failed")?;
-
-    // Use redirected endpoint for subsequent requests
-    let endpoint = meta_result.new_endpoint.as_deref().unwrap_or(endpoint);
-
-    let meta: MetaResponse =
-        serde_json::from_slice(&meta_result.data).with_context(|| "parsing meta response")?;
-
-    if !meta.server_message.is_empty() {
-        tracing::info!(message = %meta.server_message, "AnkiWeb server message");
-    }
-
+    let (meta, endpoint) = open_session(&http, endpoint, &hkey, &session_key).await?;
     if meta.empty {
-        return Err(SyncError::DownloadFailed("server collection is empty".to_string()).into());
+        // A fresh server still serves its (empty) collection; back that up
+        // rather than failing.
+        tracing::info!("server reports an empty collection");
     }
 
     // Step 3: Download full collection
     let empty_body = b"{}";
     let download_result = sync_request(
-        &client,
-        endpoint,
+        &http,
+        &endpoint,
         "download",
         &hkey,
         &session_key,
         empty_body,
     )
-    .await
-    .map_err(|e| SyncError::DownloadFailed(e.to_string()))?;
+    .await?;
     let collection_bytes = download_result.data;
 
     tracing::info!(
         bytes = collection_bytes.len(),
         elapsed_ms = start.elapsed().as_millis() as i64,
-        "Downloaded collection from AnkiWeb"
+        %endpoint,
+        "Downloaded collection from sync server"
     );
 
     Ok(SyncResult {
@@ -331,54 +697,43 @@ pub async fn sync_collection(config: &SyncConfig) -> Result<SyncResult> {
     })
 }
 
-/// Upload (force-replace) a full collection to AnkiWeb.
+/// Upload (force-replace) a full collection to the sync server.
 ///
 /// Protocol flow:
-/// 1. Authenticate with username/password → host key
+/// 1. Authenticate with username/password → host key (skipped when one is configured)
 /// 2. Call `meta` to initiate sync session and get shard redirect
 /// 3. Call `upload` with the raw collection database bytes
+///
+/// Login and `meta` are retried as usual, but the upload itself never is,
+/// whatever `config.retry` says: if a response is lost after the server took
+/// the collection, resending it could overwrite changes made in between.
 pub async fn upload_collection(config: &SyncConfig, collection_bytes: &[u8]) -> Result<()> {
-    if config.username.is_empty() || config.password.is_empty() {
-        return Err(SyncError::MissingCredentials.into());
-    }
-
     let endpoint = config.endpoint.as_deref().unwrap_or(DEFAULT_ENDPOINT);
 
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(300))
-        .redirect(reqwest::redirect::Policy::none())
-        .build()?;
+    let mut http = Http::new(config)?;
 
-    // Step 1: Login
-    let (hkey, session_key) = login(&client, endpoint, &config.username, &config.password).await?;
+    // Step 1: Login (or reuse the configured host key)
+    let (hkey, session_key) = authenticate(&http, endpoint, config).await?;
 
     // Step 2: Meta
-    let meta_req = MetaRequest {
-        sync_version: SYNC_VERSION,
-        client_version: CLIENT_VERSION_LONG.to_string(),
-    };
-    let meta_body = serde_json::to_vec(&meta_req)?;
-    let meta_result = sync_request(&client, endpoint, "meta", &hkey, &session_key, &meta_body)
-        .await
-        .with_context(|| "meta request failed")?;
-
-    let endpoint = meta_result.new_endpoint.as_deref().unwrap_or(endpoint);
+    let (_, endpoint) = open_session(&http, endpoint, &hkey, &session_key).await?;
 
     // Step 3: Upload — the body is the raw collection database
+    http.retry = RetryPolicy::none();
     let _upload_result = sync_request(
-        &client,
-        endpoint,
+        &http,
+        &endpoint,
         "upload",
         &hkey,
         &session_key,
         collection_bytes,
     )
-    .await
-    .with_context(|| "upload request failed")?;
+    .await?;
 
     tracing::info!(
         bytes = collection_bytes.len(),
-        "Uploaded collection to AnkiWeb"
+        %endpoint,
+        "Uploaded collection to sync server"
     );
 
     Ok(())
@@ -395,11 +750,117 @@ mod tests {
             username: String::new(),
             password: String::new(),
             endpoint: None,
+            host_key: None,
+            retry: RetryPolicy::none(),
+            ..Default::default()
         };
         let err = rt.block_on(sync_collection(&cfg)).unwrap_err();
         assert!(err.to_string().contains("credentials"));
     }
 
+    #[test]
+    fn classifies_http_failures() {
+        use reqwest::StatusCode;
+        let limited = SyncError::from_status(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(Duration::from_secs(30)),
+            11,
+            String::new(),
+        );
+        assert_eq!(limited.kind(), "rate_limited");
+        assert_eq!(limited.retry_after(), Some(Duration::from_secs(30)));
+        let server = SyncError::from_status(StatusCode::BAD_GATEWAY, None, 11, String::new());
+        assert!(server.is_transient());
+        let auth = SyncError::from_status(StatusCode::FORBIDDEN, None, 11, String::new());
+        assert_eq!(auth.kind(), "auth");
+        assert!(!auth.is_transient());
+        let protocol = SyncError::from_status(StatusCode::BAD_REQUEST, None, 11, String::new());
+        assert_eq!(protocol.kind(), "protocol");
+        let version = SyncError::from_status(
+            StatusCode::NOT_IMPLEMENTED,
+            None,
+            11,
+            "unsupported version".to_owned(),
+        );
+        assert_eq!(version.kind(), "unsupported_version");
+        assert!(!version.is_transient());
+        let missing = SyncError::from_status(StatusCode::NOT_IMPLEMENTED, None, 11, String::new());
+        assert_eq!(missing.kind(), "protocol");
+        assert!(!missing.is_transient());
+        let upgrade = SyncError::from_status(StatusCode::UPGRADE_REQUIRED, None, 11, String::new());
+        assert_eq!(upgrade.kind(), "unsupported_version");
+
+        let wrapped = anyhow::Error::new(server).context("download request failed");
+        assert_eq!(
+            SyncError::classify(&wrapped).map(SyncError::kind),
+            Some("server")
+        );
+        assert!(SyncError::classify(&anyhow::anyhow!("unrelated")).is_none());
+    }
+
+    #[test]
+    fn resolves_redirect_targets() {
+        let url = "https://sync.ankiweb.net/sync/meta";
+        // AnkiWeb: absolute base URL of the shard.
+        assert_eq!(
+            redirect_base(url, "https://sync32.ankiweb.net/", "sync/meta").unwrap(),
+            "https://sync32.ankiweb.net"
+        );
+        // Full method URL, as a generic HTTP server would send.
+        assert_eq!(
+            redirect_base(url, "https://other.example/anki/sync/meta", "sync/meta").unwrap(),
+            "https://other.example/anki"
+        );
+        // Relative locations resolve against the request URL.
+        assert_eq!(
+            redirect_base(url, "/shard1/", "sync/meta").unwrap(),
+            "https://sync.ankiweb.net/shard1"
+        );
+        assert_eq!(
+            redirect_base(url, "/shard1/sync/meta", "sync/meta").unwrap(),
+            "https://sync.ankiweb.net/shard1"
+        );
+    }
+
+    #[test]
+    fn rejects_pinned_versions_outside_supported_range() {
+        let config = SyncConfig {
+            sync_version: Some(SYNC_VERSION_MAX + 1),
+            ..Default::default()
+        };
+        let err = Http::new(&config).err().unwrap();
+        assert_eq!(
+            SyncError::classify(&err).map(SyncError::kind),
+            Some("unsupported_version")
+        );
+        let http = Http::new(&SyncConfig {
+            sync_version: Some(SYNC_VERSION_MIN),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(!http.downgrade());
+    }
+
+    #[test]
+    fn client_version_follows_anki_format() {
+        let version = ClientVersion::anki("2.1.66");
+        assert_eq!(version.short, "2.1.66,dev,linux");
+        assert_eq!(version.long, "anki,2.1.66 (dev),linux");
+    }
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+        headers.insert(reqwest::header::RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(120)));
+        headers.insert(
+            reqwest::header::RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(parse_retry_after(&headers), None);
+    }
+
     #[test]
     fn zstd_roundtrip() {
         let data = b"hello world this is a test of zstd compression";
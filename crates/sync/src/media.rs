@@ -0,0 +1,268 @@
+//! Read-only media sync (`/msync/`), enough to mirror the server's
+//! `collection.media` folder.
+//!
+//! Flow: log in and call `meta` as for a collection download (which also
+//! resolves the shard redirect), then `begin` a media session, page through
+//! `mediaChanges` from the last USN we know about, and fetch changed files
+//! with `downloadFiles`, which answers with a zip archive of up to
+//! [`MAX_FILES_PER_DOWNLOAD`] files.
+
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{authenticate, open_session, post, Http, SyncConfig, SyncError, DEFAULT_ENDPOINT};
+
+/// The server refuses to put more files than this in one download zip.
+const MAX_FILES_PER_DOWNLOAD: usize = 25;
+
+/// Name of the zip entry mapping archive entry names to media file names.
+const ZIP_META_ENTRY: &str = "_meta";
+
+/// One entry from `mediaChanges`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaChange {
+    pub name: String,
+    pub usn: i64,
+    /// Hex SHA-1 of the new contents; `None` when the file was deleted.
+    pub sha1: Option<String>,
+}
+
+/// Media endpoints wrap every JSON reply in `{"data": .., "err": ".."}`.
+#[derive(Deserialize)]
+struct JsonResult<T> {
+    data: Option<T>,
+    #[serde(default)]
+    err: String,
+}
+
+#[derive(Serialize)]
+struct BeginRequest {
+    #[serde(rename = "v")]
+    client_version: String,
+}
+
+#[derive(Deserialize)]
+struct BeginResponse {
+    usn: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ChangesRequest {
+    last_usn: i64,
+}
+
+#[derive(Serialize)]
+struct DownloadRequest<'a> {
+    files: &'a [String],
+}
+
+/// An authenticated media sync session.
+pub struct MediaSyncClient {
+    http: Http,
+    endpoint: String,
+    hkey: String,
+    session_key: String,
+    server_usn: i64,
+}
+
+impl MediaSyncClient {
+    /// Authenticate and begin a media sync session.
+    pub async fn connect(config: &SyncConfig) -> Result<Self> {
+        let endpoint = config.endpoint.as_deref().unwrap_or(DEFAULT_ENDPOINT);
+        let http = Http::new(config)?;
+
+        let (hkey, session_key) = authenticate(&http, endpoint, config).await?;
+        let (_, endpoint) = open_session(&http, endpoint, &hkey, &session_key).await?;
+
+        let mut media = Self {
+            http,
+            endpoint,
+            hkey,
+            session_key,
+            server_usn: 0,
+        };
+        let begin: BeginResponse = media
+            .request(
+                "begin",
+                &BeginRequest {
+                    client_version: media.http.client_version.long.clone(),
+                },
+            )
+            .await
+            .context("media sync begin failed")?;
+        media.server_usn = begin.usn;
+        tracing::info!(server_usn = begin.usn, "media sync session started");
+        Ok(media)
+    }
+
+    /// The server's current media USN.
+    pub fn server_usn(&self) -> i64 {
+        self.server_usn
+    }
+
+    /// Every change after `last_usn`, oldest first. A file changed several
+    /// times appears several times; the last entry wins.
+    pub async fn changes_since(&self, last_usn: i64) -> Result<Vec<MediaChange>> {
+        let mut changes = Vec::new();
+        let mut last_usn = last_usn;
+        while last_usn < self.server_usn {
+            let page: Vec<(String, i64, String)> = self
+                .request("mediaChanges", &ChangesRequest { last_usn })
+                .await
+                .context("mediaChanges request failed")?;
+            let Some(max_usn) = page.iter().map(|(_, usn, _)| *usn).max() else {
+                break;
+            };
+            if max_usn <= last_usn {
+                return Err(SyncError::Protocol(format!(
+                    "media server returned no progress past usn {last_usn}"
+                ))
+                .into());
+            }
+            last_usn = max_usn;
+            changes.extend(page.into_iter().map(|(name, usn, sha1)| MediaChange {
+                name,
+                usn,
+                sha1: (!sha1.is_empty()).then_some(sha1),
+            }));
+        }
+        Ok(changes)
+    }
+
+    /// Download the named files. The server may answer with fewer files than
+    /// asked for, so this keeps asking until every file has arrived.
+    pub async fn download_files(&self, names: &[String]) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut remaining: Vec<String> = names.to_vec();
+        let mut files = Vec::with_capacity(names.len());
+        while !remaining.is_empty() {
+            let batch = &remaining[..remaining.len().min(MAX_FILES_PER_DOWNLOAD)];
+            let body = serde_json::to_vec(&DownloadRequest { files: batch })?;
+            let response = post(
+                &self.http,
+                &self.endpoint,
+                "msync",
+                "downloadFiles",
+                &self.hkey,
+                &self.session_key,
+                &body,
+            )
+            .await?;
+            let received = unzip_media(&response.data)?;
+            if received.is_empty() {
+                return Err(SyncError::Protocol(format!(
+                    "media server returned none of the {} requested files",
+                    batch.len()
+                ))
+                .into());
+            }
+            remaining.retain(|name| !received.iter().any(|(got, _)| got == name));
+            files.extend(received);
+        }
+        Ok(files)
+    }
+
+    async fn request<T: DeserializeOwned>(&self, method: &str, body: &impl Serialize) -> Result<T> {
+        let body = serde_json::to_vec(body)?;
+        let response = post(
+            &self.http,
+            &self.endpoint,
+            "msync",
+            method,
+            &self.hkey,
+            &self.session_key,
+            &body,
+        )
+        .await?;
+        parse_json_result(&response.data).with_context(|| format!("parsing {method} response"))
+    }
+}
+
+fn parse_json_result<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    let result: JsonResult<T> = serde_json::from_slice(data)?;
+    if !result.err.is_empty() {
+        return Err(SyncError::Protocol(format!("media server error: {}", result.err)).into());
+    }
+    result
+        .data
+        .ok_or_else(|| anyhow!("media server response has no data"))
+}
+
+/// Unpack a `downloadFiles` zip into (media file name, contents) pairs.
+fn unzip_media(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).context("open media zip")?;
+    let names: HashMap<String, String> = {
+        let mut meta = archive
+            .by_name(ZIP_META_ENTRY)
+            .context("media zip has no _meta entry")?;
+        let mut raw = Vec::new();
+        meta.read_to_end(&mut raw)?;
+        serde_json::from_slice(&raw).context("parse media zip _meta")?
+    };
+    let mut files = Vec::with_capacity(names.len());
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if entry.name() == ZIP_META_ENTRY {
+            continue;
+        }
+        let name = names
+            .get(entry.name())
+            .ok_or_else(|| anyhow!("media zip entry {} is not in _meta", entry.name()))?
+            .clone();
+        let mut contents = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut contents)?;
+        files.push((name, contents));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn media_zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        let mut meta = HashMap::new();
+        for (i, (name, data)) in files.iter().enumerate() {
+            zip.start_file(i.to_string(), options).unwrap();
+            zip.write_all(data).unwrap();
+            meta.insert(i.to_string(), name.to_string());
+        }
+        zip.start_file(ZIP_META_ENTRY, options).unwrap();
+        zip.write_all(&serde_json::to_vec(&meta).unwrap()).unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn unzips_download_archive() {
+        let data = media_zip(&[("cat.jpg", b"meow"), ("dog.mp3", b"woof")]);
+        let mut files = unzip_media(&data).unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                ("cat.jpg".to_owned(), b"meow".to_vec()),
+                ("dog.mp3".to_owned(), b"woof".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_json_results() {
+        let changes: Vec<(String, i64, String)> =
+            parse_json_result(br#"{"data": [["a.jpg", 3, "abc"], ["b.jpg", 4, ""]], "err": ""}"#)
+                .unwrap();
+        assert_eq!(changes[1], ("b.jpg".to_owned(), 4, String::new()));
+        let err = parse_json_result::<BeginResponse>(br#"{"data": null, "err": "bad key"}"#)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("bad key"));
+    }
+}
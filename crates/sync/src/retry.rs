@@ -0,0 +1,128 @@
+//! Retrying transient sync failures with jittered exponential backoff.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::SyncError;
+
+/// How long to keep retrying a request that failed transiently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Give up once this much time has passed since the first attempt.
+    /// Zero disables retries.
+    pub budget: Duration,
+    /// Backoff before the first retry; doubled for every later one.
+    pub initial_delay: Duration,
+    /// Upper bound on a single backoff (before honouring `Retry-After`).
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            budget: Duration::from_secs(120),
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A single attempt, no retries.
+    pub fn none() -> Self {
+        Self {
+            budget: Duration::ZERO,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_budget(mut self, budget: Duration) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// How long to wait before retrying after `error`, or `None` to give up.
+    ///
+    /// `attempt` counts retries already made and `elapsed` is the time since
+    /// the first attempt. The delay is drawn from the upper half of the
+    /// current backoff window so concurrent clients spread out, and is never
+    /// shorter than a server-sent `Retry-After`.
+    pub(crate) fn next_delay(
+        &self,
+        attempt: u32,
+        elapsed: Duration,
+        error: &SyncError,
+    ) -> Option<Duration> {
+        if !error.is_transient() {
+            return None;
+        }
+        let window = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let mut delay = window.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+        if let Some(after) = error.retry_after() {
+            delay = delay.max(after);
+        }
+        (elapsed + delay <= self.budget).then_some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_error() -> SyncError {
+        SyncError::Server {
+            status: 502,
+            message: "bad gateway".to_owned(),
+        }
+    }
+
+    #[test]
+    fn backs_off_exponentially_with_jitter() {
+        let policy = RetryPolicy::default();
+        for attempt in 0..8 {
+            let window = Duration::from_secs(1 << attempt).min(policy.max_delay);
+            let delay = policy
+                .next_delay(attempt, Duration::ZERO, &server_error())
+                .unwrap();
+            assert!(
+                delay >= window / 2 && delay <= window,
+                "{attempt}: {delay:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn gives_up_on_permanent_errors_and_exhausted_budget() {
+        let policy = RetryPolicy::default();
+        let auth = SyncError::Auth("bad password".to_owned());
+        assert_eq!(policy.next_delay(0, Duration::ZERO, &auth), None);
+        assert_eq!(
+            policy.next_delay(0, Duration::from_secs(120), &server_error()),
+            None
+        );
+        assert_eq!(
+            RetryPolicy::none().next_delay(0, Duration::ZERO, &server_error()),
+            None
+        );
+    }
+
+    #[test]
+    fn honours_retry_after() {
+        let policy = RetryPolicy::default();
+        let limited = SyncError::RateLimited {
+            retry_after: Some(Duration::from_secs(45)),
+        };
+        assert_eq!(
+            policy.next_delay(0, Duration::ZERO, &limited),
+            Some(Duration::from_secs(45))
+        );
+        assert_eq!(
+            policy.next_delay(0, Duration::from_secs(100), &limited),
+            None
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! Download, upload and retry behaviour against the in-process fake server.
+
+use anki_backup_sync::fake_server::{FakeSyncServer, Fault};
+use anki_backup_sync::{sync_collection, upload_collection, RetryPolicy, SyncConfig, SyncError};
+
+const COLLECTION: &[u8] = b"SQLite format 3\0fake collection";
+
+#[tokio::test]
+async fn upload_replaces_the_served_collection() {
+    let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+    let config = server.sync_config();
+
+    upload_collection(&config, b"rolled back").await.unwrap();
+    let result = sync_collection(&config).await.unwrap();
+    assert_eq!(result.collection_bytes, b"rolled back");
+    assert_eq!(server.uploads(), [b"rolled back".to_vec()]);
+}
+
+#[tokio::test]
+async fn uploads_are_never_retried() {
+    let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+    server.fail_next("meta", Fault::Status(503));
+    server.fail_next("upload", Fault::Status(503));
+
+    let err = upload_collection(&server.sync_config(), b"rolled back")
+        .await
+        .unwrap_err();
+    assert_eq!(
+        SyncError::classify(&err).map(SyncError::kind),
+        Some("server")
+    );
+    assert_eq!(server.request_count("meta"), 2);
+    assert_eq!(server.request_count("upload"), 1);
+    assert_eq!(
+        sync_collection(&server.sync_config())
+            .await
+            .unwrap()
+            .collection_bytes,
+        COLLECTION
+    );
+}
+
+#[tokio::test]
+async fn transient_faults_are_retried() {
+    let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+    server.fail_next("download", Fault::Status(503));
+    server.fail_next(
+        "download",
+        Fault::RateLimited {
+            retry_after_secs: None,
+        },
+    );
+
+    let result = sync_collection(&server.sync_config()).await.unwrap();
+    assert_eq!(result.collection_bytes, COLLECTION);
+    assert_eq!(server.request_count("download"), 3);
+}
+
+#[tokio::test]
+async fn permanent_faults_are_not_retried() {
+    let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+    server.fail_next("download", Fault::Corrupt);
+
+    let err = sync_collection(&server.sync_config()).await.unwrap_err();
+    assert_eq!(
+        SyncError::classify(&err).map(SyncError::kind),
+        Some("protocol")
+    );
+    assert_eq!(server.request_count("download"), 1);
+}
+
+#[tokio::test]
+async fn retry_after_beyond_the_budget_fails_immediately() {
+    let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+    server.fail_next(
+        "meta",
+        Fault::RateLimited {
+            retry_after_secs: Some(60),
+        },
+    );
+
+    let err = sync_collection(&server.sync_config()).await.unwrap_err();
+    let sync = SyncError::classify(&err).unwrap();
+    assert_eq!(sync.kind(), "rate_limited");
+    assert_eq!(sync.retry_after().map(|d| d.as_secs()), Some(60));
+    assert_eq!(server.request_count("meta"), 1);
+}
+
+#[tokio::test]
+async fn retries_can_be_disabled() {
+    let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+    server.fail_next("hostKey", Fault::Status(502));
+    let config = SyncConfig {
+        retry: RetryPolicy::none(),
+        ..server.sync_config()
+    };
+
+    let err = sync_collection(&config).await.unwrap_err();
+    assert_eq!(
+        SyncError::classify(&err).map(SyncError::kind),
+        Some("server")
+    );
+    assert_eq!(server.request_count("hostKey"), 1);
+}
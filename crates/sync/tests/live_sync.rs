@@ -1,7 +1,7 @@
-//! Live sync test against AnkiWeb.
+//! Live sync tests against AnkiWeb.
 //! Run with: cargo test -p anki-backup-sync --test live_sync -- --ignored --nocapture
 
-use anki_backup_sync::{sync_collection, SyncConfig};
+use anki_backup_sync::{sync_collection, MediaSyncClient, SyncConfig};
 
 #[tokio::test]
 #[ignore] // requires ANKIWEB_USERNAME and ANKIWEB_PASSWORD
@@ -10,6 +10,7 @@ async fn test_live_sync() {
         username: std::env::var("ANKIWEB_USERNAME").expect("ANKIWEB_USERNAME"),
         password: std::env::var("ANKIWEB_PASSWORD").expect("ANKIWEB_PASSWORD"),
         endpoint: None,
+        ..Default::default()
     };
 
     let result = sync_collection(&config).await.unwrap();
@@ -23,3 +24,30 @@ async fn test_live_sync() {
         "collection should not be empty"
     );
 }
+
+#[tokio::test]
+#[ignore] // requires ANKIWEB_USERNAME and ANKIWEB_PASSWORD
+async fn test_live_media_sync() {
+    let config = SyncConfig {
+        username: std::env::var("ANKIWEB_USERNAME").expect("ANKIWEB_USERNAME"),
+        password: std::env::var("ANKIWEB_PASSWORD").expect("ANKIWEB_PASSWORD"),
+        endpoint: None,
+        ..Default::default()
+    };
+
+    let media = MediaSyncClient::connect(&config).await.unwrap();
+    let changes = media.changes_since(0).await.unwrap();
+    println!(
+        "Server media usn {}, {} changes",
+        media.server_usn(),
+        changes.len()
+    );
+    let names: Vec<String> = changes
+        .iter()
+        .filter(|c| c.sha1.is_some())
+        .take(3)
+        .map(|c| c.name.clone())
+        .collect();
+    let files = media.download_files(&names).await.unwrap();
+    assert_eq!(files.len(), names.len());
+}
@@ -0,0 +1,157 @@
+//! Sync against the fake server configured like a self-hosted
+//! `anki-sync-server`: custom client versions, shard redirects, empty
+//! collections, refused sessions and protocol version mismatches.
+
+use anki_backup_sync::fake_server::{FakeSyncServer, Fault};
+use anki_backup_sync::{
+    fetch_host_key, sync_collection, upload_collection, ClientVersion, MediaSyncClient, SyncConfig,
+    SyncError, SYNC_VERSION_MAX, SYNC_VERSION_MIN,
+};
+
+const COLLECTION: &[u8] = b"SQLite format 3\0fake collection";
+
+#[tokio::test]
+async fn downloads_and_uploads_with_configured_client_version() {
+    let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+    let config = SyncConfig {
+        client_version: ClientVersion::anki("2.1.66"),
+        ..server.sync_config()
+    };
+
+    let result = sync_collection(&config).await.unwrap();
+    assert_eq!(result.collection_bytes, COLLECTION);
+
+    upload_collection(&config, b"replacement").await.unwrap();
+    assert_eq!(server.uploads(), [b"replacement".to_vec()]);
+    assert!(server
+        .requests()
+        .iter()
+        .all(|r| r.sync_version == 11 && r.client_version == "2.1.66,dev,linux"));
+}
+
+#[tokio::test]
+async fn follows_full_and_relative_shard_redirects() {
+    for location in ["{base}shard1/sync/meta", "/shard1/"] {
+        let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+        server.redirect_meta(Some(&location.replace("{base}", server.endpoint())));
+
+        sync_collection(&server.sync_config()).await.unwrap();
+        let requests = server.requests();
+        assert!(
+            requests.iter().any(|r| r.path == "/shard1/sync/download"),
+            "{location}: {requests:?}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn empty_server_collection_is_still_downloaded() {
+    let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+    server.set_empty(true);
+    let result = sync_collection(&server.sync_config()).await.unwrap();
+    assert_eq!(result.collection_bytes, COLLECTION);
+}
+
+#[tokio::test]
+async fn refused_session_reports_server_message() {
+    let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+    server.set_refusal(Some("terms of service must be accepted"));
+    let err = sync_collection(&server.sync_config()).await.unwrap_err();
+    assert_eq!(
+        SyncError::classify(&err).map(SyncError::kind),
+        Some("protocol")
+    );
+    assert!(format!("{err:#}").contains("terms of service"));
+}
+
+#[tokio::test]
+async fn unsupported_protocol_version_is_reported() {
+    let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+    server.set_versions(12..=12);
+    let err = sync_collection(&server.sync_config()).await.unwrap_err();
+    assert_eq!(
+        SyncError::classify(&err).map(SyncError::kind),
+        Some("unsupported_version")
+    );
+    let tried: Vec<u8> = server.requests().iter().map(|r| r.sync_version).collect();
+    assert_eq!(
+        tried,
+        (SYNC_VERSION_MIN..=SYNC_VERSION_MAX)
+            .rev()
+            .collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn older_server_is_negotiated_down_to() {
+    const { assert!(SYNC_VERSION_MIN < SYNC_VERSION_MAX) };
+    let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+    server.set_versions(SYNC_VERSION_MIN..=SYNC_VERSION_MIN);
+
+    let result = sync_collection(&server.sync_config()).await.unwrap();
+    assert_eq!(result.collection_bytes, COLLECTION);
+    let requests = server.requests();
+    assert_eq!(requests[0].sync_version, SYNC_VERSION_MAX);
+    assert!(requests[1..]
+        .iter()
+        .all(|r| r.sync_version == SYNC_VERSION_MIN));
+}
+
+#[tokio::test]
+async fn missing_endpoint_is_not_a_version_rejection() {
+    let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+    server.fail_next("download", Fault::Status(501));
+    let err = sync_collection(&server.sync_config()).await.unwrap_err();
+    assert_eq!(
+        SyncError::classify(&err).map(SyncError::kind),
+        Some("protocol")
+    );
+    assert!(server
+        .requests()
+        .iter()
+        .all(|r| r.sync_version == SYNC_VERSION_MAX));
+}
+
+#[tokio::test]
+async fn host_key_replaces_login() {
+    let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+    let host_key = fetch_host_key(&server.sync_config()).await.unwrap();
+    assert_eq!(host_key, server.host_key());
+
+    let with_key = SyncConfig {
+        password: String::new(),
+        host_key: Some(host_key),
+        ..server.sync_config()
+    };
+    sync_collection(&with_key).await.unwrap();
+    assert_eq!(server.request_count("hostKey"), 1);
+
+    server.rotate_host_key();
+    let err = sync_collection(&with_key).await.unwrap_err();
+    assert_eq!(SyncError::classify(&err).map(SyncError::kind), Some("auth"));
+}
+
+#[tokio::test]
+async fn mirrors_media_changes() {
+    let server = FakeSyncServer::start(COLLECTION.to_vec()).await;
+    server.add_media("cat.jpg", b"meow");
+    server.add_media("dog.mp3", b"woof");
+    server.remove_media("cat.jpg");
+
+    let client = MediaSyncClient::connect(&server.sync_config())
+        .await
+        .unwrap();
+    assert_eq!(client.server_usn(), 3);
+    let changes = client.changes_since(1).await.unwrap();
+    let summary: Vec<_> = changes
+        .iter()
+        .map(|c| (c.name.as_str(), c.sha1.is_some()))
+        .collect();
+    assert_eq!(summary, [("cat.jpg", false), ("dog.mp3", true)]);
+
+    let files = client
+        .download_files(&["dog.mp3".to_owned()])
+        .await
+        .unwrap();
+    assert_eq!(files, [("dog.mp3".to_owned(), b"woof".to_vec())]);
+}